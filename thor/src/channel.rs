@@ -1,29 +1,54 @@
+pub mod machine;
 pub mod protocols;
 
-pub use protocols::create::{BuildFundingPsbt, SignFundingPsbt};
-use protocols::{close, create, punish::punish, splice, update};
+pub use protocols::{
+    close::CloseState,
+    create::{BuildFundingPsbt, CreateState, ReleaseFundingInputs, SignFundingPsbt},
+    propose::{AcceptAll, HandledProposal, Policy, Proposal, ProposalRejected},
+    recover::{Recovery, StaticBackup},
+    splice::SpliceState,
+    update::UpdateState,
+    Transition,
+};
+use protocols::{
+    propose::{self, ProposeClose, ProposeSplice, ProposeUpdate},
+    punish::{presign_punish_transaction, punish},
+    recover, reestablish, update,
+};
 
+#[cfg(feature = "serde")]
+use crate::watchtower::JusticeKit;
+#[cfg(feature = "serde")]
+use crate::wire::MIN_PROTOCOL_VERSION;
 use crate::{
+    fee::{FeeRate, FeeRateBounds},
     keys::{
-        OwnershipKeyPair, OwnershipPublicKey, PublishingKeyPair, PublishingPublicKey,
+        ChannelKeys, OwnershipKeyPair, OwnershipPublicKey, PublishingKeyPair, PublishingPublicKey,
         RevocationKeyPair, RevocationPublicKey, RevocationSecretKey,
     },
+    shachain::RevocationSecretStore,
     signature,
-    transaction::{ptlc, FundingTransaction},
-    Balance, CommitTransaction, EncryptedSignature, GetRawTransaction, MedianTime, Message, Ptlc,
-    PtlcPoint, PtlcSecret, Role, Signature, Splice, SplitOutput, SplitTransaction,
+    swap::{self, SwapConfig},
+    transaction::{anchor::CpfpTransaction, ptlc, FundOutput, FundingTransaction},
+    Abort, AbortReason, Balance, ChannelId, CommitTransaction, Confirmation, EncryptedSignature,
+    Error, EstimateFeeRate, GetConfirmations, GetRawTransaction, MedianTime, Message,
+    ProtocolAborted, Ptlc, PtlcPoint, PtlcSecret, Role, Signature, Splice, SplitOutput,
+    SplitTransaction, WatchConfirmations,
 };
 use ::serde::{Deserialize, Serialize};
 use anyhow::{anyhow, bail, Result};
 use async_trait::async_trait;
-use bitcoin::{Address, Amount, Transaction, Txid};
+use bitcoin::{Address, Amount, OutPoint, Transaction, Txid};
 use enum_as_inner::EnumAsInner;
 use futures::{
     future::{Either, FutureExt},
     pin_mut, Future,
 };
 use genawaiter::sync::Gen;
-use std::{convert::TryInto, time::Duration};
+use std::{
+    convert::{TryFrom, TryInto},
+    time::Duration,
+};
 use tokio::time;
 
 #[cfg(test)]
@@ -54,31 +79,136 @@ pub trait ReceiveMessage {
 /// the received message. This macro combines these three into a single step.
 /// Returns the output of transitioning to the next state.
 macro_rules! step {
-    ($transport:expr, $state:expr) => {{
+    ($transport:expr, $state:expr, $timeout:expr) => {{
         let transport = $transport;
         let state = $state;
 
-        transport.send_message(state.compose().into()).await?;
-        let response = transport.receive_message().await?.try_into()?;
-        let res = state.interpret(response)?;
+        transport
+            .send_message(state.compose().into())
+            .await
+            .map_err(Error::Transport)?;
+        let response = receive_within(transport, $timeout).await?;
+        let res = match interpret(response, |message| Ok(state.interpret(message)?)) {
+            Ok(res) => res,
+            Err(e) => {
+                return Err(abort(transport, AbortReason::InvalidMessage, e)
+                    .await
+                    .into())
+            }
+        };
 
         (transport, res)
     }};
 }
 
-/// The same as [step] but passes `wallet` into `interpret()`.
-macro_rules! step_wallet {
-    ($transport:expr, $state:expr, $wallet:expr) => {{
-        let transport = $transport;
-        let state = $state;
-        let wallet = $wallet;
+/// Convert the `message` received from the counterparty to the type expected
+/// by the current step of a channel protocol, and `interpret` it.
+fn interpret<M, O>(message: Message, interpret: impl FnOnce(M) -> Result<O>) -> Result<O>
+where
+    M: TryFrom<Message, Error = UnexpectedMessage>,
+{
+    interpret(message.try_into()?)
+}
 
-        transport.send_message(state.compose().into()).await?;
-        let response = transport.receive_message().await?.try_into()?;
-        let res = state.interpret(response, wallet).await?;
+/// Receive the next message of a channel protocol from the counterparty,
+/// failing with `ProtocolAborted` if they have aborted the protocol.
+pub(crate) async fn receive<T>(transport: &mut T) -> Result<Message>
+where
+    T: ReceiveMessage + ?Sized,
+{
+    match transport
+        .receive_message()
+        .await
+        .map_err(Error::Transport)?
+    {
+        Message::Abort(abort) => Err(ProtocolAborted::from(abort).into()),
+        message => Ok(message),
+    }
+}
 
-        (transport, res)
-    }};
+/// Receive the next message of a channel protocol from the counterparty,
+/// aborting the protocol with `TimedOut` if they do not send it within
+/// `timeout`.
+pub(crate) async fn receive_within<T>(transport: &mut T, timeout: Duration) -> Result<Message>
+where
+    T: SendMessage + ReceiveMessage + ?Sized,
+{
+    let message = time::timeout(timeout, receive(transport)).await;
+
+    match message {
+        Ok(message) => message,
+        Err(_) => Err(abort(transport, AbortReason::TimedOut, TimedOut(timeout).into()).await),
+    }
+}
+
+/// How long to wait for each message of the counterparty during the channel
+/// protocols, per operation.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timeouts {
+    pub create: Duration,
+    pub update: Duration,
+    pub close: Duration,
+    pub splice: Duration,
+    /// Used when reestablishing the channel and when assisting the
+    /// counterparty to recover it.
+    pub reestablish: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        let timeout = Duration::from_secs(60);
+
+        Self {
+            create: timeout,
+            update: timeout,
+            close: timeout,
+            splice: timeout,
+            reestablish: timeout,
+        }
+    }
+}
+
+/// Protocol version of channels persisted before it was negotiated, which
+/// only supported the first version.
+#[cfg(feature = "serde")]
+fn min_protocol_version() -> u16 {
+    MIN_PROTOCOL_VERSION
+}
+
+/// Error returned when the counterparty does not send the next message of a
+/// channel protocol in time.
+#[derive(Clone, Copy, Debug, thiserror::Error)]
+#[error("counterparty did not respond within {0:?}")]
+pub struct TimedOut(pub Duration);
+
+/// Let the counterparty know that we are aborting a channel protocol because
+/// of the `error`, which is returned so that it can be propagated.
+///
+/// Nothing is sent if the counterparty aborted the protocol themselves. If the
+/// `reason` is an invalid message, the `error` is categorised as a protocol
+/// error unless it is known to be caused by something else.
+pub(crate) async fn abort<T>(
+    transport: &mut T,
+    reason: AbortReason,
+    error: anyhow::Error,
+) -> anyhow::Error
+where
+    T: SendMessage + ?Sized,
+{
+    if error.is::<ProtocolAborted>() {
+        return error;
+    }
+
+    // The protocol has failed whether or not the counterparty is notified
+    let _ = transport
+        .send_message(Abort::new(reason, &error).into())
+        .await;
+
+    match reason {
+        AbortReason::InvalidMessage => Error::protocol(error).into(),
+        _ => error,
+    }
 }
 
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -90,43 +220,90 @@ pub struct Channel {
     final_address_other: Address,
     tx_f_body: FundingTransaction,
     current_state: ChannelState,
-    revoked_states: Vec<RevokedState>,
+    /// Number of the current state. It is incremented with every channel
+    /// update and used to derive the keys of each state.
+    state_number: u64,
+    keys: ChannelKeys,
+    revoked_states: RevokedStates,
+    /// The next state of the channel, if an update was interrupted after
+    /// signatures for it were exchanged but before it was completed. It is
+    /// resolved by calling `Channel::reestablish`.
+    pending_state: Option<ChannelState>,
+    /// Range of fee rates that we accept from the counterparty when updating
+    /// the channel.
+    fee_rate_bounds: FeeRateBounds,
+    /// How long to wait for the counterparty during the operations on the
+    /// channel.
+    #[cfg_attr(feature = "serde", serde(default))]
+    timeouts: Timeouts,
+    /// Version of the channel protocols agreed upon with the counterparty when
+    /// the channel was created.
+    #[cfg_attr(feature = "serde", serde(default = "min_protocol_version"))]
+    protocol_version: u16,
+    /// The splice of the channel whose splice transaction is not confirmed
+    /// yet. It is resolved by calling `Channel::resolve_pending_splice`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pending_splice: Option<PendingSplice>,
 }
 
 impl Channel {
     /// Create a channel.
     ///
+    /// The fee rate used for the channel transactions is estimated by the
+    /// `wallet` and agreed upon with the counterparty, as long as the one they
+    /// propose is within `fee_rate_bounds`.
+    ///
+    /// The `timeouts` apply to this and every later operation on the channel,
+    /// until they are changed with `Channel::set_timeouts`. If the creation
+    /// fails after the `wallet` has provided our input of the
+    /// `FundingTransaction`, the input is released, or double-spent if the
+    /// counterparty already has our signature for it. In the latter case, the
+    /// counterparty may still publish the `FundingTransaction` first, so the
+    /// channel is returned in an `Error::Interrupted`.
+    ///
+    /// The keys of the channel are derived from `keys`, which should be
+    /// obtained from a `KeyManager` using an index which has not been used for
+    /// any other channel.
+    ///
     /// Consumers should implement the traits `SendMessage` and `ReceiveMessage`
     /// on the `transport` they provide, allowing the parties to communicate
     /// with each other.
     pub async fn create<T, W>(
         transport: &mut T,
         wallet: &W,
+        keys: ChannelKeys,
         balance: Balance,
         time_lock: u32,
-    ) -> Result<Self>
+        fee_rate_bounds: FeeRateBounds,
+        timeouts: Timeouts,
+    ) -> Result<Self, Error>
     where
         T: SendMessage + ReceiveMessage,
-        W: BuildFundingPsbt + SignFundingPsbt + BroadcastSignedTransaction + NewAddress,
+        W: BuildFundingPsbt
+            + SignFundingPsbt
+            + ReleaseFundingInputs
+            + BroadcastSignedTransaction
+            + NewAddress
+            + EstimateFeeRate,
     {
-        let final_address = wallet.new_address().await?;
-        let state = create::State0::new(balance, time_lock, final_address);
-
-        let (transport, state) = step_wallet!(transport, state, wallet);
-
-        let (transport, state) = step!(transport, state);
-        let (transport, state) = step!(transport, state);
-        let (transport, state) = step!(transport, state);
-        let (transport, state) = step!(transport, state);
-
-        // No step macro because compose() requires the wallet.
-        transport
-            .send_message(state.compose(wallet).await?.into())
-            .await?;
-        let response = transport.receive_message().await?.try_into()?;
-        let (channel, transaction) = state.interpret(response, wallet).await?;
-
-        wallet.broadcast_signed_transaction(transaction).await?;
+        let final_address = wallet.new_address().await.map_err(Error::Wallet)?;
+        let fee_rate = wallet.estimate_fee_rate().await.map_err(Error::Wallet)?;
+        let state = CreateState::new(
+            keys,
+            balance,
+            time_lock,
+            final_address,
+            fee_rate,
+            fee_rate_bounds,
+            timeouts,
+        );
+
+        let (channel, transaction) =
+            machine::run(state, transport, wallet, timeouts.create).await?;
+        wallet
+            .broadcast_signed_transaction(transaction)
+            .await
+            .map_err(Error::Wallet)?;
 
         Ok(channel)
     }
@@ -135,25 +312,158 @@ impl Channel {
     ///
     /// It assumes that the counterparty has already agreed to update the
     /// channel with the same balance and `timelock` and will call the same API
-    /// (or an equivalent one).
+    /// (or an equivalent one). Use `Channel::propose_update_balance` instead
+    /// to ask the counterparty to agree first. The fee rate of the new state
+    /// is estimated by the `wallet` and agreed upon with the counterparty.
     ///
     /// Consumers should implement the traits `SendMessage` and `ReceiveMessage`
     /// on the `transport` they provide, allowing the parties to communicate
     /// with each other.
-    pub async fn update_balance<T>(
+    pub async fn update_balance<T, W>(
+        &mut self,
+        transport: &mut T,
+        wallet: &W,
+        balance: Balance,
+        time_lock: u32,
+    ) -> Result<(), Error>
+    where
+        T: SendMessage + ReceiveMessage,
+        W: EstimateFeeRate,
+    {
+        let new_split_outputs = self.split_outputs_for_balance(balance);
+        let fee_rate = wallet.estimate_fee_rate().await.map_err(Error::Wallet)?;
+
+        self.update(transport, new_split_outputs, time_lock, fee_rate)
+            .await
+            .map_err(Error::from)
+    }
+
+    /// Propose to the counterparty to update the distribution of coins in the
+    /// channel, and update it if they accept.
+    ///
+    /// The counterparty must be handling proposals with
+    /// `Channel::handle_incoming`. If they reject the proposal,
+    /// `Error::ProposalRejected` is returned and the channel is unchanged.
+    pub async fn propose_update_balance<T, W>(
         &mut self,
         transport: &mut T,
-        Balance { ours, theirs }: Balance,
+        wallet: &W,
+        balance: Balance,
         time_lock: u32,
-    ) -> Result<()>
+    ) -> Result<(), Error>
+    where
+        T: SendMessage + ReceiveMessage,
+        W: EstimateFeeRate,
+    {
+        let new_split_outputs = self.split_outputs_for_balance(balance);
+        propose::propose(
+            transport,
+            ProposeUpdate::new(&new_split_outputs, time_lock),
+            self.timeouts.update,
+        )
+        .await?;
+
+        let fee_rate = wallet.estimate_fee_rate().await.map_err(Error::Wallet)?;
+
+        self.update(transport, new_split_outputs, time_lock, fee_rate)
+            .await
+            .map_err(Error::from)
+    }
+
+    /// Handle the next proposal of the counterparty, made with one of the
+    /// `Channel::propose_*` APIs.
+    ///
+    /// The `policy` decides whether to accept the proposal. If it does, the
+    /// proposed operation is carried out with the counterparty. A proposal
+    /// which is invalid for this channel is rejected and returned as an error.
+    pub async fn handle_incoming<T, W, P>(
+        &mut self,
+        transport: &mut T,
+        wallet: &W,
+        policy: &P,
+    ) -> Result<HandledProposal, Error>
     where
         T: SendMessage + ReceiveMessage,
+        W: BuildFundingPsbt
+            + SignFundingPsbt
+            + ReleaseFundingInputs
+            + BroadcastSignedTransaction
+            + NewAddress
+            + EstimateFeeRate,
+        P: Policy,
     {
+        let proposal = propose::receive_proposal(transport, self).await?;
+
+        if let Err(reason) = policy.evaluate(self, &proposal) {
+            propose::reject(transport, propose::UNACCEPTABLE_PROPOSAL).await?;
+
+            return Ok(HandledProposal::Rejected { proposal, reason });
+        }
+        propose::accept(transport).await?;
+
+        match &proposal {
+            Proposal::Update {
+                split_outputs,
+                time_lock,
+                ..
+            } => {
+                let fee_rate = wallet.estimate_fee_rate().await.map_err(Error::Wallet)?;
+
+                self.update(transport, split_outputs.clone(), *time_lock, fee_rate)
+                    .await?
+            }
+            Proposal::Close => self.close(transport, wallet).await?,
+            Proposal::Splice(splice) => {
+                *self = self
+                    .clone()
+                    .splice_with(transport, wallet, Splice::default(), Some(splice.clone()))
+                    .await?;
+            }
+        }
+
+        Ok(HandledProposal::Accepted(proposal))
+    }
+
+    /// Start updating the distribution of coins in the channel, as an
+    /// alternative to `Channel::update_balance` which lets the caller drive
+    /// the protocol.
+    ///
+    /// The returned `UpdateState` can be persisted after every step, so that
+    /// the update can be resumed if the process crashes. Once the protocol is
+    /// done, the updated `Channel` replaces this one. If the protocol does not
+    /// finish, the channel returned by `UpdateState::interrupted` replaces
+    /// this one when there is one, and the channel must be reestablished.
+    ///
+    /// It cannot be used while a splice is pending, since the channel must
+    /// then be updated on both funding transactions.
+    pub fn start_update_balance(
+        &self,
+        balance: Balance,
+        time_lock: u32,
+        fee_rate: FeeRate,
+    ) -> Result<UpdateState, Error> {
+        if self.pending_splice.is_some() {
+            return Err(Error::Validation(anyhow!(
+                "channel with a pending splice cannot be updated step by step"
+            )));
+        }
+
+        let new_split_outputs = self.split_outputs_for_balance(balance);
+
+        self.start_update(new_split_outputs, time_lock, fee_rate)
+            .map_err(Error::from)
+    }
+
+    /// Split outputs paying `ours` and `theirs` to the final address of each
+    /// party, keeping all the PTLCs of the current state.
+    fn split_outputs_for_balance(&self, Balance { ours, theirs }: Balance) -> Vec<SplitOutput> {
         let out_ours = self.split_balance_output_ours(ours);
         let out_theirs = self.split_balance_output_theirs(theirs);
 
-        self.update(transport, vec![out_ours, out_theirs], time_lock)
-            .await
+        let mut new_split_outputs = vec![out_ours, out_theirs];
+        new_split_outputs.extend(self.split_ptlc_outputs());
+
+        new_split_outputs
     }
 
     fn split_balance_output_ours(&self, amount: Amount) -> SplitOutput {
@@ -170,8 +480,33 @@ impl Channel {
         }
     }
 
+    /// Split outputs for all the PTLCs of the current state, so that they are
+    /// kept in the new state when updating the channel.
+    fn split_ptlc_outputs(&self) -> Vec<SplitOutput> {
+        self.current_state
+            .ptlcs()
+            .iter()
+            .map(|signed_ptlc| SplitOutput::Ptlc(signed_ptlc.ptlc.clone()))
+            .collect()
+    }
+
+    /// Split outputs for all the PTLCs of the current state except for the one
+    /// identified by `point`, which is being removed from the channel.
+    fn split_ptlc_outputs_except(&self, point: &PtlcPoint) -> Vec<SplitOutput> {
+        self.current_state
+            .ptlcs()
+            .iter()
+            .filter(|signed_ptlc| signed_ptlc.point() != *point)
+            .map(|signed_ptlc| SplitOutput::Ptlc(signed_ptlc.ptlc.clone()))
+            .collect()
+    }
+
     /// Perform an atomic swap with a thor channel as beta ledger in the
     /// role of Alice.
+    ///
+    /// The swap is aborted before adding the PTLC output if the expiries are
+    /// not safe according to the safety margins of the `config`, and before
+    /// revealing the secret if it is too late to do so.
     #[allow(clippy::too_many_arguments)]
     pub async fn swap_beta_ptlc_alice<T, W>(
         &mut self,
@@ -182,96 +517,301 @@ impl Channel {
         alpha_absolute_expiry: u32,
         tx_s_time_lock: u32,
         ptlc_refund_time_lock: u32,
-    ) -> Result<()>
+        config: SwapConfig,
+    ) -> Result<(), Error>
     where
         T: SendMessage + ReceiveMessage,
-        W: NewAddress + BroadcastSignedTransaction,
+        W: MedianTime + NewAddress + BroadcastSignedTransaction + EstimateFeeRate,
     {
-        let tx_ptlc_redeem = self
-            .add_ptlc_redeemer(
-                transport,
-                ptlc_amount,
-                secret.clone(),
-                tx_s_time_lock,
-                ptlc_refund_time_lock,
-            )
-            .await?;
+        swap::validate_expiries(
+            &config.safety_margins,
+            wallet.median_time().await.map_err(Error::Chain)?,
+            alpha_absolute_expiry,
+            tx_s_time_lock,
+            ptlc_refund_time_lock,
+        )
+        .map_err(|e| Error::Validation(e.into()))?;
+
+        self.add_ptlc_redeemer(
+            transport,
+            wallet,
+            ptlc_amount,
+            secret.clone(),
+            tx_s_time_lock,
+            ptlc_refund_time_lock,
+        )
+        .await?;
 
         self.redeem_ptlc_redeemer(
             transport,
             wallet,
             ptlc_amount,
             secret,
-            alpha_absolute_expiry,
             tx_s_time_lock,
             ptlc_refund_time_lock,
-            tx_ptlc_redeem,
+            config,
         )
         .await?;
 
         Ok(())
     }
 
+    /// Update the channel to add a PTLC output.
+    ///
+    /// The PTLC output is funded by us if `direction` is
+    /// `PtlcDirection::Offered` and by the counterparty if it is
+    /// `PtlcDirection::Received`. If redeemed, it pays to the other party. The
+    /// PTLC is identified by its `point`, which must not be shared with any
+    /// other PTLC in the channel. The funder can refund the PTLC output after
+    /// `refund_time_lock`.
+    ///
+    /// It assumes that the counterparty has already agreed to add the PTLC and
+    /// will call the same API with the opposite `direction`.
+    ///
+    /// Consumers should implement the traits `SendMessage` and `ReceiveMessage`
+    /// on the `transport` they provide, allowing the parties to communicate
+    /// with each other.
+    pub async fn add_ptlc<T, W>(
+        &mut self,
+        transport: &mut T,
+        wallet: &W,
+        direction: PtlcDirection,
+        amount: Amount,
+        point: PtlcPoint,
+        refund_time_lock: u32,
+    ) -> Result<(), Error>
+    where
+        T: SendMessage + ReceiveMessage,
+        W: EstimateFeeRate,
+    {
+        let (X_funder, X_redeemer) = match direction {
+            PtlcDirection::Offered => (self.x_self.public(), self.X_other.clone()),
+            PtlcDirection::Received => (self.X_other.clone(), self.x_self.public()),
+        };
+
+        let ptlc = Ptlc {
+            amount,
+            X_funder,
+            X_redeemer,
+            role: Role::Bob { point },
+            refund_time_lock,
+        };
+
+        let time_lock = self.time_lock();
+        self.add_ptlc_output(transport, wallet, ptlc, time_lock)
+            .await
+            .map_err(Error::from)
+    }
+
+    /// Update the channel to remove the PTLC output unlocked by `secret`,
+    /// merging its amount into the balance output of the redeemer.
+    ///
+    /// If we are the redeemer, the `secret` is recorded before updating the
+    /// channel, so that the PTLC output is redeemed if we end up force closing
+    /// the channel.
+    ///
+    /// It assumes that the counterparty has already agreed to settle the PTLC
+    /// and will call the same API.
+    pub async fn settle_ptlc<T, W>(
+        &mut self,
+        transport: &mut T,
+        wallet: &W,
+        secret: PtlcSecret,
+    ) -> Result<(), Error>
+    where
+        T: SendMessage + ReceiveMessage,
+        W: EstimateFeeRate,
+    {
+        let point = secret.point();
+        let X_self = self.x_self.public();
+
+        let signed_ptlc = self
+            .current_state
+            .ptlc_mut(&point)
+            .ok_or_else(|| Error::Validation(anyhow!("channel does not contain PTLC output")))?;
+        if signed_ptlc.ptlc.X_redeemer == X_self {
+            signed_ptlc.ptlc.role = Role::Alice { secret };
+        }
+
+        let new_split_outputs = self.split_outputs_resolving_ptlc(&point, true)?;
+        let time_lock = self.time_lock();
+        let fee_rate = wallet.estimate_fee_rate().await.map_err(Error::Wallet)?;
+
+        self.update(transport, new_split_outputs, time_lock, fee_rate)
+            .await
+            .map_err(Error::from)
+    }
+
+    /// Update the channel to remove the PTLC output identified by `point`,
+    /// merging its amount back into the balance output of the funder.
+    ///
+    /// It assumes that the counterparty has already agreed to fail the PTLC
+    /// and will call the same API.
+    pub async fn fail_ptlc<T, W>(
+        &mut self,
+        transport: &mut T,
+        wallet: &W,
+        point: PtlcPoint,
+    ) -> Result<(), Error>
+    where
+        T: SendMessage + ReceiveMessage,
+        W: EstimateFeeRate,
+    {
+        let new_split_outputs = self.split_outputs_resolving_ptlc(&point, false)?;
+        let time_lock = self.time_lock();
+        let fee_rate = wallet.estimate_fee_rate().await.map_err(Error::Wallet)?;
+
+        self.update(transport, new_split_outputs, time_lock, fee_rate)
+            .await
+            .map_err(Error::from)
+    }
+
+    /// Split outputs for a channel state without the PTLC output identified by
+    /// `point`, whose amount goes to the redeemer if it was `redeemed` or back
+    /// to the funder otherwise.
+    fn split_outputs_resolving_ptlc(
+        &self,
+        point: &PtlcPoint,
+        redeemed: bool,
+    ) -> Result<Vec<SplitOutput>> {
+        let ptlc = &self
+            .current_state
+            .ptlc(point)
+            .ok_or_else(|| Error::Validation(anyhow!("channel does not contain PTLC output")))?
+            .ptlc;
+
+        let X_recipient = if redeemed {
+            &ptlc.X_redeemer
+        } else {
+            &ptlc.X_funder
+        };
+
+        let Balance { ours, theirs } = self.balance();
+        let (ours, theirs) = if *X_recipient == self.x_self.public() {
+            (ours + ptlc.amount, theirs)
+        } else {
+            (ours, theirs + ptlc.amount)
+        };
+
+        let out_ours = self.split_balance_output_ours(ours);
+        let out_theirs = self.split_balance_output_theirs(theirs);
+
+        let mut new_split_outputs = vec![out_ours, out_theirs];
+        new_split_outputs.extend(self.split_ptlc_outputs_except(point));
+
+        Ok(new_split_outputs)
+    }
+
     /// Update the channel to add a PTLC output whose funds will come from the
     /// balance output of the counterparty and, if successfully redeemed,
     /// will pay to us.
-    async fn add_ptlc_redeemer<T>(
+    async fn add_ptlc_redeemer<T, W>(
         &mut self,
         transport: &mut T,
+        wallet: &W,
         ptlc_amount: Amount,
         secret: PtlcSecret,
         tx_s_time_lock: u32,
         ptlc_refund_time_lock: u32,
-    ) -> Result<ptlc::RedeemTransaction>
+    ) -> Result<()>
     where
         T: SendMessage + ReceiveMessage,
+        W: EstimateFeeRate,
     {
-        let Balance { ours, theirs } = self.balance();
-
-        let theirs = theirs.checked_sub(ptlc_amount).ok_or_else(|| {
-            anyhow!(
-                "Bob's {} balance cannot cover PTLC output amount: {}",
-                theirs,
-                ptlc_amount
-            )
-        })?;
-
-        let out_ours = self.split_balance_output_ours(ours);
-        let out_theirs = self.split_balance_output_theirs(theirs);
-
-        let ptlc_output = SplitOutput::Ptlc(Ptlc {
+        let ptlc = Ptlc {
             amount: ptlc_amount,
             X_funder: self.X_other.clone(),
             X_redeemer: self.x_self.public(),
-            role: Role::Alice {
-                secret: secret.clone(),
-            },
+            role: Role::Alice { secret },
             refund_time_lock: ptlc_refund_time_lock,
-        });
+        };
 
-        self.update(
-            transport,
-            vec![out_ours, out_theirs, ptlc_output],
-            tx_s_time_lock,
-        )
-        .await?;
+        self.add_ptlc_output(transport, wallet, ptlc, tx_s_time_lock)
+            .await
+    }
+
+    /// Update the channel to add a PTLC output whose funds will come from our
+    /// balance output and, if successfully redeemed, will pay to the
+    /// counterparty.
+    async fn add_ptlc_funder<T, W>(
+        &mut self,
+        transport: &mut T,
+        wallet: &W,
+        ptlc_amount: Amount,
+        point: PtlcPoint,
+        tx_s_time_lock: u32,
+        ptlc_refund_time_lock: u32,
+    ) -> Result<()>
+    where
+        T: SendMessage + ReceiveMessage,
+        W: EstimateFeeRate,
+    {
+        let ptlc = Ptlc {
+            amount: ptlc_amount,
+            X_funder: self.x_self.public(),
+            X_redeemer: self.X_other.clone(),
+            role: Role::Bob { point },
+            refund_time_lock: ptlc_refund_time_lock,
+        };
+
+        self.add_ptlc_output(transport, wallet, ptlc, tx_s_time_lock)
+            .await
+    }
 
-        let tx_ptlc_redeem = {
-            let (_, _, tx_ptlc_redeem, _, encsig_funder, sig_redeemer, ..) = self
-                .current_state
-                .clone()
-                .into_with_ptlc()
-                .expect("current state contains PTLC output");
+    /// Update the channel to add the `ptlc` output, whose funds come from the
+    /// balance output of its funder.
+    ///
+    /// Any PTLC outputs already in the channel are kept.
+    async fn add_ptlc_output<T, W>(
+        &mut self,
+        transport: &mut T,
+        wallet: &W,
+        ptlc: Ptlc,
+        tx_s_time_lock: u32,
+    ) -> Result<()>
+    where
+        T: SendMessage + ReceiveMessage,
+        W: EstimateFeeRate,
+    {
+        if self.current_state.ptlc(&ptlc.point()).is_some() {
+            bail!(Error::Validation(anyhow!(
+                "channel already contains PTLC output with the same point"
+            )))
+        }
 
-            let sig_funder = signature::decrypt(secret.clone().into(), encsig_funder);
+        let Balance { ours, theirs } = self.balance();
 
-            tx_ptlc_redeem.add_signatures(
-                (self.x_self.public(), sig_redeemer),
-                (self.X_other.clone(), sig_funder),
-            )?
+        let (ours, theirs) = if ptlc.X_funder == self.x_self.public() {
+            let ours = ours
+                .checked_sub(ptlc.amount)
+                .ok_or_else(|| Error::InsufficientBalance {
+                    balance: ours,
+                    required: ptlc.amount,
+                })?;
+
+            (ours, theirs)
+        } else {
+            let theirs =
+                theirs
+                    .checked_sub(ptlc.amount)
+                    .ok_or_else(|| Error::InsufficientBalance {
+                        balance: theirs,
+                        required: ptlc.amount,
+                    })?;
+
+            (ours, theirs)
         };
 
-        Ok(tx_ptlc_redeem)
+        let out_ours = self.split_balance_output_ours(ours);
+        let out_theirs = self.split_balance_output_theirs(theirs);
+
+        let mut new_split_outputs = vec![out_ours, out_theirs, SplitOutput::Ptlc(ptlc)];
+        new_split_outputs.extend(self.split_ptlc_outputs());
+
+        let fee_rate = wallet.estimate_fee_rate().await.map_err(Error::Wallet)?;
+
+        self.update(transport, new_split_outputs, tx_s_time_lock, fee_rate)
+            .await
     }
 
     /// Attempt to redeem a PTLC output.
@@ -279,8 +819,9 @@ impl Channel {
     /// If it's still safe (PTLC is not close to expiry), send the secret to the
     /// counterparty and attempt to perform a channel update to merge the PTLC
     /// output into our balance output. If the counterparty does not cooperate
-    /// soon enough after the revelation of the secret, force close the channel
-    /// and publish the redeem transaction.
+    /// within the settlement timeout of the `config` after the revelation of
+    /// the secret, force close the channel, which publishes the redeem
+    /// transaction.
     #[allow(clippy::too_many_arguments)]
     async fn redeem_ptlc_redeemer<T, W>(
         &mut self,
@@ -288,17 +829,27 @@ impl Channel {
         wallet: &W,
         ptlc_amount: Amount,
         secret: PtlcSecret,
-        _alpha_absolute_expiry: u32,
         tx_s_time_lock: u32,
-        _ptlc_refund_time_lock: u32,
-        tx_ptlc_redeem: ptlc::RedeemTransaction,
+        ptlc_refund_time_lock: u32,
+        config: SwapConfig,
     ) -> Result<()>
     where
         T: SendMessage + ReceiveMessage,
-        W: NewAddress + BroadcastSignedTransaction,
+        W: MedianTime + NewAddress + BroadcastSignedTransaction + EstimateFeeRate,
     {
-        // TODO: Check that `ptlc_refund_time_lock` is not close, otherwise abort
-        transport.send_message(Message::Secret(secret)).await?;
+        let point = secret.point();
+
+        let median_time = wallet.median_time().await.map_err(Error::Chain)?;
+        swap::ensure_safe_to_reveal_secret(
+            &config.safety_margins,
+            median_time,
+            tx_s_time_lock,
+            ptlc_refund_time_lock,
+        )?;
+        transport
+            .send_message(Message::Secret(secret))
+            .await
+            .map_err(Error::Transport)?;
 
         // Attempt to perform a channel update to merge PTLC output into Alice's balance
         // output
@@ -307,28 +858,37 @@ impl Channel {
         let out_ours = self.split_balance_output_ours(ours + ptlc_amount);
         let out_theirs = self.split_balance_output_theirs(theirs);
 
-        let channel = self.clone();
-        let final_update = self.update(transport, vec![out_ours, out_theirs], tx_s_time_lock);
+        let mut new_split_outputs = vec![out_ours, out_theirs];
+        new_split_outputs.extend(self.split_ptlc_outputs_except(&point));
+
+        let fee_rate = wallet.estimate_fee_rate().await.map_err(Error::Wallet)?;
 
-        // TODO: Configure timeout based on expiries
-        let timeout = time::delay_for(Duration::from_secs(10));
+        let is_updated = {
+            let final_update = self.update(transport, new_split_outputs, tx_s_time_lock, fee_rate);
 
-        pin_mut!(final_update);
-        pin_mut!(timeout);
+            let timeout = time::delay_for(config.settlement_timeout(
+                median_time,
+                tx_s_time_lock,
+                ptlc_refund_time_lock,
+            ));
 
-        // If the channel update isn't finished before `timeout`, force close and
-        // publish `tx_ptlc_redeem`.
-        match futures::future::select(final_update, timeout).await {
-            Either::Left((Ok(_), _)) => (),
-            Either::Left((Err(_), _)) | Either::Right(_) => {
-                channel.force_close(wallet).await?;
+            pin_mut!(final_update);
+            pin_mut!(timeout);
 
-                wallet
-                    .broadcast_signed_transaction(tx_ptlc_redeem.into())
-                    .await?;
-            }
+            matches!(
+                futures::future::select(final_update, timeout).await,
+                Either::Left((Ok(_), _))
+            )
         };
 
+        // If the channel update isn't finished before `timeout`, force close, which
+        // publishes `tx_ptlc_redeem`. The update is dropped first, so that the next
+        // state is published if we have already revealed the revocation secret of
+        // the current one
+        if !is_updated {
+            self.force_close(wallet).await?;
+        }
+
         Ok(())
     }
 
@@ -336,7 +896,9 @@ impl Channel {
     /// role of Bob.
     ///
     /// Calling this function should only take place once the counterparty has
-    /// funded the alpha asset.
+    /// funded the alpha asset. The swap is aborted before adding the PTLC
+    /// output if the expiries are not safe according to the safety margins of
+    /// the `config`.
     #[allow(clippy::too_many_arguments)]
     pub fn swap_beta_ptlc_bob<'a, T, W>(
         &'a mut self,
@@ -344,62 +906,56 @@ impl Channel {
         wallet: &'a W,
         ptlc_amount: Amount,
         point: PtlcPoint,
-        _alpha_absolute_expiry: u32,
+        alpha_absolute_expiry: u32,
         tx_s_time_lock: u32,
         ptlc_refund_time_lock: u32,
-    ) -> Gen<PtlcSecret, (), impl Future<Output = Result<()>> + 'a>
+        config: SwapConfig,
+    ) -> Gen<PtlcSecret, (), impl Future<Output = Result<(), Error>> + 'a>
     where
         T: SendMessage + ReceiveMessage,
-        W: MedianTime + NewAddress + BroadcastSignedTransaction + GetRawTransaction,
+        W: MedianTime
+            + NewAddress
+            + BroadcastSignedTransaction
+            + GetRawTransaction
+            + EstimateFeeRate,
     {
         Gen::new(|co| async move {
-            let Balance { ours, theirs } = self.balance();
-
-            let ours = ours.checked_sub(ptlc_amount).ok_or_else(|| {
-                anyhow!(
-                    "Bob's {} balance cannot cover PTLC output amount: {}",
-                    ours,
-                    ptlc_amount
-                )
-            })?;
-
-            let out_ours = self.split_balance_output_ours(ours);
-            let out_theirs = self.split_balance_output_theirs(theirs);
-
-            let ptlc_output = SplitOutput::Ptlc(Ptlc {
-                amount: ptlc_amount,
-                X_funder: self.x_self.public(),
-                X_redeemer: self.X_other.clone(),
-                role: Role::Bob {
-                    point: point.clone(),
-                },
-                refund_time_lock: ptlc_refund_time_lock,
-            });
-
-            self.update(
+            swap::validate_expiries(
+                &config.safety_margins,
+                wallet.median_time().await.map_err(Error::Chain)?,
+                alpha_absolute_expiry,
+                tx_s_time_lock,
+                ptlc_refund_time_lock,
+            )
+            .map_err(|e| Error::Validation(e.into()))?;
+
+            self.add_ptlc_funder(
                 transport,
-                vec![out_ours, out_theirs, ptlc_output],
+                wallet,
+                ptlc_amount,
+                point.clone(),
                 tx_s_time_lock,
+                ptlc_refund_time_lock,
             )
             .await?;
 
             // Wait for Alice to send over the `secret`.
 
             let ptlc_almost_expired = async {
-                // `TX_s_time_lock` is a relative timelock in blocks. To convert it to an
-                // estimated relative timelock in seconds we use 10 minutes as the average
-                // blocktime for Bitcoin.
-                let tx_s_time_lock_in_seconds = tx_s_time_lock * 10 * 60;
-                let ptlc_nearing_expiry_time = ptlc_refund_time_lock - tx_s_time_lock_in_seconds;
+                // Alice will not reveal the secret after this deadline, since she would not be
+                // able to redeem the PTLC output on-chain in time
+                let ptlc_nearing_expiry_time = config
+                    .safety_margins
+                    .reveal_secret_deadline(tx_s_time_lock, ptlc_refund_time_lock);
 
                 loop {
-                    let median_time = wallet.median_time().await?;
+                    let median_time = wallet.median_time().await.map_err(Error::Chain)?;
 
                     if median_time >= ptlc_nearing_expiry_time {
                         return Result::<(), anyhow::Error>::Ok(());
                     }
 
-                    time::delay_for(Duration::from_secs(1)).await;
+                    time::delay_for(config.polling_interval).await;
                 }
             };
 
@@ -410,7 +966,7 @@ impl Channel {
 
             let secret_revealed = async {
                 let mut transport = transport.lock().await;
-                transport.receive_message().await
+                receive(&mut **transport).await
             };
 
             pin_mut!(ptlc_almost_expired);
@@ -422,47 +978,49 @@ impl Channel {
                     // the other branch
                     let secret: PtlcSecret = message.try_into()?;
                     if secret.point() != point {
-                        bail!("Alice sent incorrect secret")
+                        return Err(Error::Protocol(anyhow!("Alice sent incorrect secret")));
                     }
 
                     co.yield_(secret).await;
 
                     // Perform a channel update to merge PTLC output into Alice's balance output
+                    let Balance { ours, theirs } = self.balance();
 
-                    let balance_output_self = SplitOutput::Balance {
-                        amount: ours,
-                        address: self.final_address_self.clone(),
-                    };
+                    let out_ours = self.split_balance_output_ours(ours);
+                    let out_theirs = self.split_balance_output_theirs(theirs + ptlc_amount);
 
-                    let balance_output_other = SplitOutput::Balance {
-                        amount: theirs + ptlc_amount,
-                        address: self.final_address_other.clone(),
-                    };
+                    let mut new_split_outputs = vec![out_ours, out_theirs];
+                    new_split_outputs.extend(self.split_ptlc_outputs_except(&point));
+
+                    let fee_rate = wallet.estimate_fee_rate().await.map_err(Error::Wallet)?;
 
                     let mut transport = transport.lock().await;
-                    self.update(
-                        *transport,
-                        vec![balance_output_self, balance_output_other],
-                        tx_s_time_lock,
-                    )
-                    .await?;
+                    self.update(*transport, new_split_outputs, tx_s_time_lock, fee_rate)
+                        .await?;
                 }
                 Either::Left((Err(_), _)) | Either::Right(_) => {
                     self.force_close(wallet).await?;
 
-                    let (_, _, TX_ptlc_redeem, TX_ptlc_refund, encsig_TX_ptlc_redeem_funder, ..) =
-                        self.current_state
-                            .clone()
-                            .into_with_ptlc()
-                            .expect("current state contains PTLC output");
+                    let SignedPtlc {
+                        tx_ptlc_redeem: TX_ptlc_redeem,
+                        tx_ptlc_refund: TX_ptlc_refund,
+                        encsig_tx_ptlc_redeem_funder: encsig_TX_ptlc_redeem_funder,
+                        ..
+                    } = self
+                        .current_state
+                        .ptlc(&point)
+                        .cloned()
+                        .expect("current state contains PTLC output");
 
                     let ptlc_expired = async {
                         loop {
-                            if wallet.median_time().await? >= ptlc_refund_time_lock {
+                            if wallet.median_time().await.map_err(Error::Chain)?
+                                >= ptlc_refund_time_lock
+                            {
                                 return Result::<(), anyhow::Error>::Ok(());
                             }
 
-                            time::delay_for(Duration::from_secs(1)).await;
+                            time::delay_for(config.polling_interval).await;
                         }
                     };
                     let watch_redeem = async {
@@ -473,7 +1031,7 @@ impl Channel {
                                 return transaction;
                             };
 
-                            time::delay_for(Duration::from_secs(1)).await;
+                            time::delay_for(config.polling_interval).await;
                         }
                     };
 
@@ -481,7 +1039,7 @@ impl Channel {
                         _ = ptlc_expired.fuse() => {
                             wallet
                                 .broadcast_signed_transaction(TX_ptlc_refund.into())
-                                .await?;
+                                .await.map_err(Error::Wallet)?;
                         },
                         candidate_transaction = watch_redeem.fuse() => {
                             let sig_TX_ptlc_redeem_funder = ptlc::extract_signature_by_key(
@@ -511,82 +1069,437 @@ impl Channel {
         transport: &mut T,
         new_split_outputs: Vec<SplitOutput>,
         time_lock: u32,
+        fee_rate: FeeRate,
     ) -> Result<()>
     where
         T: SendMessage + ReceiveMessage,
     {
-        use update::*;
-        use State1Kind::*;
+        // While a splice is pending, the channel is updated on the previous
+        // funding transaction too, in case the splice transaction is
+        // double-spent
+        let previous_split_outputs = match &self.pending_splice {
+            Some(pending_splice) => match pending_splice.split_outputs(self, &new_split_outputs) {
+                Ok(split_outputs) => Some(split_outputs),
+                Err(e) => return Err(abort(transport, AbortReason::Internal, e).await),
+            },
+            None => None,
+        };
+
+        let state = self
+            .sign_update(transport, new_split_outputs, time_lock, fee_rate)
+            .await?;
+        let previous_state = match (previous_split_outputs, self.pending_splice.as_mut()) {
+            (Some(split_outputs), Some(pending_splice)) => Some(
+                pending_splice
+                    .previous
+                    .sign_update(transport, split_outputs, time_lock, fee_rate)
+                    .await?,
+            ),
+            _ => None,
+        };
+
+        // The current state has the same state number on both funding
+        // transactions, so revealing its revocation secret revokes it on both
+        // of them. It is therefore revealed only once, after the next state is
+        // signed on both
+        transport
+            .send_message(state.compose())
+            .await
+            .map_err(Error::Transport)?;
+        let response = receive_within(transport, self.timeouts.update).await?;
+        let reveal = match interpret(response, |reveal: update::RevealRevocationSecretKey| {
+            Ok(reveal)
+        }) {
+            Ok(reveal) => reveal,
+            Err(e) => return Err(abort(transport, AbortReason::InvalidMessage, e).await),
+        };
 
-        macro_rules! update {
-            ($transport:expr, $state:expr) => {{
-                let transport = $transport;
-                let state = $state;
+        self.complete_update(transport, state, reveal.clone())
+            .await?;
 
-                let (transport, state) = step!(transport, state);
-                let (transport, state) = step!(transport, state);
-                let (_, updated_channel) = step!(transport, state);
+        if let (Some(state), Some(pending_splice)) = (previous_state, self.pending_splice.as_mut())
+        {
+            let previous = &mut pending_splice.previous;
+            previous.complete_update(transport, state, reveal).await?;
 
-                updated_channel
-            }};
+            self.revoked_states.sync_spliced(
+                &previous.revoked_states,
+                &previous.tx_f_body,
+                pending_splice.splice_txid,
+            );
         }
 
-        let state = State0::new(self.clone(), new_split_outputs, time_lock);
+        Ok(())
+    }
 
-        let (transport, state) = step!(transport, state);
+    /// Exchange the signatures of the next state of the channel on its
+    /// `FundingTransaction`, ignoring any pending splice.
+    ///
+    /// The next state is kept as the pending state of the channel, since the
+    /// counterparty can revoke the current one from then on. The update is
+    /// completed with `Channel::complete_update`.
+    async fn sign_update<T>(
+        &mut self,
+        transport: &mut T,
+        new_split_outputs: Vec<SplitOutput>,
+        time_lock: u32,
+        fee_rate: FeeRate,
+    ) -> Result<UpdateState>
+    where
+        T: SendMessage + ReceiveMessage,
+    {
+        let mut state = match self.start_update(new_split_outputs, time_lock, fee_rate) {
+            Ok(state) => state,
+            Err(e) => return Err(abort(transport, AbortReason::Internal, e).await),
+        };
 
-        let updated_channel = match state {
-            State1(state) => update!(transport, state),
-            State1PtlcFunder(state) => {
-                let (transport, state) = step!(transport, state);
-                update!(transport, state)
+        loop {
+            if let Some(next_state) = state.next_state() {
+                self.pending_state = Some(next_state);
+
+                return Ok(state);
             }
-            State1PtlcRedeemer(state) => {
-                let (transport, state) = step!(transport, state);
-                update!(transport, state)
+
+            transport
+                .send_message(state.compose())
+                .await
+                .map_err(Error::Transport)?;
+            let response = receive_within(transport, self.timeouts.update).await?;
+
+            state = match state.interpret(response) {
+                Ok(Transition::Next(state)) => state,
+                Ok(Transition::Done(_)) => {
+                    unreachable!("update is only done once the current state is revoked")
+                }
+                Err(e) => return Err(abort(transport, AbortReason::InvalidMessage, e).await),
+            };
+        }
+    }
+
+    /// Complete the update started with `Channel::sign_update` given the
+    /// revocation secret of the current state revealed by the counterparty.
+    async fn complete_update<T>(
+        &mut self,
+        transport: &mut T,
+        state: UpdateState,
+        reveal: update::RevealRevocationSecretKey,
+    ) -> Result<()>
+    where
+        T: SendMessage,
+    {
+        match state.interpret(reveal.into()) {
+            Ok(Transition::Done(updated_channel)) => {
+                *self = Channel {
+                    pending_splice: self.pending_splice.take(),
+                    ..updated_channel
+                };
+
+                Ok(())
             }
-        };
+            Ok(Transition::Next(_)) => {
+                unreachable!("update is done once the current state is revoked")
+            }
+            Err(e) => Err(abort(transport, AbortReason::InvalidMessage, e).await),
+        }
+    }
+
+    fn start_update(
+        &self,
+        new_split_outputs: Vec<SplitOutput>,
+        time_lock: u32,
+        fee_rate: FeeRate,
+    ) -> Result<UpdateState> {
+        if self.pending_state.is_some() {
+            bail!(Error::Validation(anyhow!(
+                "previous channel update was interrupted, the channel must be reestablished"
+            )))
+        }
 
-        *self = updated_channel;
+        let state = update::State0::new(self.clone(), new_split_outputs, time_lock, fee_rate);
 
-        Ok(())
+        Ok(state.into())
     }
 
     /// Close the channel collaboratively.
     ///
     /// It assumes that the counterparty has already agreed to close the channel
-    /// and will call the same API (or an equivalent one).
+    /// and will call the same API (or an equivalent one). Use
+    /// `Channel::propose_close` instead to ask the counterparty to agree first.
     ///
     /// Consumers should implement the traits `SendMessage` and `ReceiveMessage`
     /// on the `transport` they provide, allowing the parties to communicate
     /// with each other.
-    pub async fn close<T, W>(&self, transport: &mut T, wallet: &W) -> Result<()>
+    ///
+    /// While a splice is pending, the close transaction is signed on both
+    /// funding transactions. The one spending the previous
+    /// `FundingTransaction` is only broadcast if the other one cannot be,
+    /// because the splice transaction was double-spent.
+    pub async fn close<T, W>(&self, transport: &mut T, wallet: &W) -> Result<(), Error>
     where
         T: SendMessage + ReceiveMessage,
         W: NewAddress + BroadcastSignedTransaction,
     {
-        let state = close::State0::new(&self)?;
+        let state = CloseState::new(&self)?;
+        let close_transaction =
+            machine::run_without_wallet(state, transport, self.timeouts.close).await?;
+
+        let previous_close_transaction = match &self.pending_splice {
+            Some(pending_splice) => {
+                let state = CloseState::new(&pending_splice.previous)?;
+                Some(machine::run_without_wallet(state, transport, self.timeouts.close).await?)
+            }
+            None => None,
+        };
 
-        let (_, close_transaction) = step!(transport, state);
-        wallet
-            .broadcast_signed_transaction(close_transaction)
-            .await?;
+        match (
+            wallet.broadcast_signed_transaction(close_transaction).await,
+            previous_close_transaction,
+        ) {
+            (Ok(()), _) => Ok(()),
+            (Err(_), Some(previous_close_transaction)) => wallet
+                .broadcast_signed_transaction(previous_close_transaction)
+                .await
+                .map_err(Error::Wallet),
+            (Err(e), None) => Err(Error::Wallet(e)),
+        }
+    }
+
+    /// Propose to the counterparty to close the channel collaboratively, and
+    /// close it if they accept.
+    ///
+    /// The counterparty must be handling proposals with
+    /// `Channel::handle_incoming`.
+    pub async fn propose_close<T, W>(&self, transport: &mut T, wallet: &W) -> Result<(), Error>
+    where
+        T: SendMessage + ReceiveMessage,
+        W: NewAddress + BroadcastSignedTransaction,
+    {
+        propose::propose(transport, ProposeClose, self.timeouts.close).await?;
+
+        self.close(transport, wallet).await
+    }
+
+    /// Reestablish the channel with the counterparty after a channel update
+    /// was interrupted, e.g. because the transport failed.
+    ///
+    /// Both parties end up in the same state, rolling forward to the state of
+    /// the interrupted update if either of them has already revoked the
+    /// current one. The counterparty _must_ call the same API, on a new
+    /// `transport` if necessary.
+    ///
+    /// While a splice is pending, the channel is reestablished on both funding
+    /// transactions.
+    pub async fn reestablish<T>(&mut self, transport: &mut T) -> Result<(), Error>
+    where
+        T: SendMessage + ReceiveMessage,
+    {
+        // Rolling forward reveals the `RevocationSecretKey` of the current
+        // state on both funding transactions. If the update was interrupted
+        // before the next state was signed on the previous one, neither party
+        // has revealed it yet, so the update is rolled back instead
+        if let Some(pending_splice) = &self.pending_splice {
+            if pending_splice.previous.pending_state.is_none() {
+                self.pending_state = None;
+            }
+        }
+
+        self.reestablish_funding(transport).await?;
+
+        if let Some(pending_splice) = self.pending_splice.as_mut() {
+            let previous = &mut pending_splice.previous;
+            previous.reestablish_funding(transport).await?;
+
+            self.revoked_states.sync_spliced(
+                &previous.revoked_states,
+                &previous.tx_f_body,
+                pending_splice.splice_txid,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Reestablish the channel on its `FundingTransaction`, ignoring any
+    /// pending splice.
+    async fn reestablish_funding<T>(&mut self, transport: &mut T) -> Result<(), Error>
+    where
+        T: SendMessage + ReceiveMessage,
+    {
+        let state = reestablish::State0::new(self.clone());
+        let timeout = self.timeouts.reestablish;
+
+        let (transport, state) = step!(transport, state, timeout);
+        let (_, channel) = step!(transport, state, timeout);
+
+        *self = Channel {
+            pending_splice: self.pending_splice.take(),
+            ..channel
+        };
 
         Ok(())
     }
 
     /// Close the channel non-collaboratively.
-    pub async fn force_close<W>(&self, wallet: &W) -> Result<()>
+    ///
+    /// Every PTLC output of the current state whose secret we know is redeemed.
+    /// If an update was interrupted after both parties signed the next state,
+    /// the next state is published instead, since the counterparty may already
+    /// be able to punish the publication of the current one.
+    ///
+    /// While a splice is pending, the channel is closed on the spliced
+    /// `FundingTransaction`, unless the `CommitTransaction` spending it cannot
+    /// be broadcast because the splice transaction was double-spent. The
+    /// channel is then closed on the previous `FundingTransaction`.
+    pub async fn force_close<W>(&self, wallet: &W) -> Result<(), Error>
     where
         W: NewAddress + BroadcastSignedTransaction,
     {
-        let state = StandardChannelState::from(self.current_state.clone());
+        let channel = match (self.broadcast_tx_c(wallet).await, &self.pending_splice) {
+            (Ok(()), _) => self,
+            (Err(_), Some(pending_splice)) => {
+                pending_splice.previous.broadcast_tx_c(wallet).await?;
+                &*pending_splice.previous
+            }
+            (Err(e), None) => return Err(e),
+        };
+
+        let state: &StandardChannelState = channel.latest_state().as_ref();
+        let split = state.signed_tx_s.clone();
+        wallet
+            .broadcast_signed_transaction(split.into())
+            .await
+            .map_err(Error::Wallet)?;
+
+        for signed_ptlc in channel.latest_state().ptlcs() {
+            if let Some(redeem) =
+                signed_ptlc.signed_tx_ptlc_redeem(&channel.x_self, &channel.X_other)?
+            {
+                wallet
+                    .broadcast_signed_transaction(redeem.into())
+                    .await
+                    .map_err(Error::Wallet)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Broadcast the `CommitTransaction` of the latest state of the channel
+    /// on its `FundingTransaction`, ignoring any pending splice.
+    async fn broadcast_tx_c<W>(&self, wallet: &W) -> Result<(), Error>
+    where
+        W: BroadcastSignedTransaction,
+    {
+        let state: &StandardChannelState = self.latest_state().as_ref();
 
         let commit = state.signed_tx_c(&self.tx_f_body, &self.x_self, &self.X_other)?;
-        wallet.broadcast_signed_transaction(commit).await?;
+        wallet
+            .broadcast_signed_transaction(commit)
+            .await
+            .map_err(Error::Wallet)
+    }
 
-        let split = state.signed_tx_s;
-        wallet.broadcast_signed_transaction(split.into()).await?;
+    /// Create a backup of the channel which can be used to recover its funds
+    /// with `Channel::recover` if the channel state is lost.
+    ///
+    /// The backup is only invalidated by splicing the channel.
+    pub fn static_backup(&self) -> StaticBackup {
+        StaticBackup::from(self)
+    }
+
+    /// Recover the funds of a channel whose state has been lost, by asking
+    /// the counterparty to force close it.
+    ///
+    /// The counterparty _must_ call `Channel::assist_recovery` on their end.
+    /// We do not publish any transaction, since every `CommitTransaction` that
+    /// we might know of could have been revoked. Instead, the transactions
+    /// published by the counterparty are returned, in case they need to be
+    /// published again.
+    ///
+    /// Fails with `Error::TimedOut` if the counterparty does not respond
+    /// within `timeout`.
+    pub async fn recover<T>(
+        transport: &mut T,
+        backup: StaticBackup,
+        timeout: Duration,
+    ) -> Result<Recovery, Error>
+    where
+        T: SendMessage + ReceiveMessage,
+    {
+        let state = recover::State0::new(backup);
+
+        let (_, recovery) = step!(transport, state, timeout);
+
+        Ok(recovery)
+    }
+
+    /// Force close the channel at the request of a counterparty who has lost
+    /// their channel state and is calling `Channel::recover`.
+    pub async fn assist_recovery<T, W>(&self, transport: &mut T, wallet: &W) -> Result<(), Error>
+    where
+        T: SendMessage + ReceiveMessage,
+        W: NewAddress + BroadcastSignedTransaction,
+    {
+        let request = receive_within(transport, self.timeouts.reestablish).await?;
+        let response = match interpret(request, |request| {
+            recover::share_force_close_transactions(self, request)
+        }) {
+            Ok(response) => response,
+            Err(e) => {
+                return Err(abort(transport, AbortReason::InvalidMessage, e)
+                    .await
+                    .into())
+            }
+        };
+        transport
+            .send_message(response.into())
+            .await
+            .map_err(Error::Transport)?;
+
+        self.force_close(wallet).await
+    }
+
+    /// Bump the fee of one of the transactions published when force closing
+    /// the channel.
+    ///
+    /// A child transaction spending our anchor output of said transaction is
+    /// published, paying enough fees for both transactions to reach
+    /// `fee_rate`. The `wallet` provides the inputs needed to pay for the fee
+    /// bump.
+    pub async fn bump_force_close<W>(
+        &self,
+        wallet: &W,
+        transaction: ForceCloseTransaction,
+        fee_rate: FeeRate,
+    ) -> Result<(), Error>
+    where
+        W: BuildFundingPsbt + SignFundingPsbt + BroadcastSignedTransaction + NewAddress,
+    {
+        let state: &StandardChannelState = self.latest_state().as_ref();
+
+        let anchor = match transaction {
+            ForceCloseTransaction::Commit => state.tx_c.anchor(self.x_self.public())?,
+            ForceCloseTransaction::Split => state.signed_tx_s.anchor(self.x_self.public())?,
+        };
+        let bump_amount = anchor.bump_amount(fee_rate)?;
+
+        let bump_address = wallet.new_address().await.map_err(Error::Wallet)?;
+        let wallet_psbt = wallet
+            .build_funding_psbt(bump_address.clone(), bump_amount)
+            .await
+            .map_err(Error::Wallet)?;
+
+        let tx_cpfp = CpfpTransaction::new(anchor, wallet_psbt, &bump_address)?;
+        let signed_psbt = wallet
+            .sign_funding_psbt(tx_cpfp.psbt())
+            .await
+            .map_err(Error::Wallet)?;
+        let tx_cpfp = tx_cpfp.add_signatures(signed_psbt, &self.x_self)?;
+
+        wallet
+            .broadcast_signed_transaction(tx_cpfp)
+            .await
+            .map_err(Error::Wallet)?;
 
         Ok(())
     }
@@ -595,110 +1508,535 @@ impl Channel {
     ///
     /// This effectively closes the channel, as all of the channel's funds go to
     /// our final address.
-    pub async fn punish<W>(&self, wallet: &W, old_commit_transaction: Transaction) -> Result<()>
+    ///
+    /// After a splice, the revoked commit transactions spending the previous
+    /// funding transaction can be punished too, until they are forgotten with
+    /// `Channel::forget_spliced_fundings`.
+    pub async fn punish<W>(
+        &self,
+        wallet: &W,
+        old_commit_transaction: Transaction,
+    ) -> Result<(), Error>
     where
         W: BroadcastSignedTransaction,
     {
-        let punish_transaction = punish(
-            &self.x_self,
-            &self.revoked_states,
-            self.final_address_self.clone(),
-            old_commit_transaction,
-        )?;
+        let punish_transaction = punish(self, old_commit_transaction)?;
 
         wallet
             .broadcast_signed_transaction(punish_transaction.into())
-            .await?;
+            .await
+            .map_err(Error::Wallet)?;
+
+        Ok(())
+    }
+
+    /// Forget the revoked states of the funding transactions spent by splice
+    /// transactions which are confirmed by at least `depth` blocks.
+    ///
+    /// A deep enough splice transaction can no longer be double-spent by a
+    /// revoked commit transaction spending the same funding transaction, so
+    /// there is nothing left to punish.
+    pub async fn forget_spliced_fundings<C>(&mut self, chain: &C, depth: u32) -> Result<(), Error>
+    where
+        C: GetConfirmations,
+    {
+        for splice_txid in self.revoked_states.splice_txids() {
+            let confirmations = chain
+                .get_confirmations(splice_txid)
+                .await
+                .map_err(Error::Chain)?;
+
+            if confirmations >= depth {
+                self.revoked_states.forget_spliced(splice_txid);
+            }
+        }
 
         Ok(())
     }
 
+    /// Export a `JusticeKit` for every revoked state of the channel.
+    ///
+    /// The kits can be handed over to a third-party watchtower, who will be
+    /// able to punish the publication of a revoked `CommitTransaction` on our
+    /// behalf, without having access to any of our keys.
+    #[cfg(feature = "serde")]
+    pub fn justice_kits(&self) -> Result<Vec<JusticeKit>, Error> {
+        self.revoked_states
+            .iter(&self.tx_f_body)
+            .map(|(tx_f, revoked_state)| {
+                let tx_p = presign_punish_transaction(self, tx_f, revoked_state)?;
+
+                JusticeKit::new(&tx_p)
+            })
+            .collect::<Result<_>>()
+            .map_err(Error::from)
+    }
+
     /// Get the current channel balance.
     pub fn balance(&self) -> Balance {
         let channel_state: &StandardChannelState = self.current_state.as_ref();
         channel_state.balance
     }
 
+    /// Get the fee rate used for the transactions of the current state.
+    pub fn fee_rate(&self) -> FeeRate {
+        let channel_state: &StandardChannelState = self.current_state.as_ref();
+        channel_state.fee_rate()
+    }
+
+    /// Get the address of the output shared by both parties which funds the
+    /// channel.
+    pub fn fund_address(&self) -> Address {
+        FundOutput::new([self.x_self.public(), self.X_other.clone()]).address()
+    }
+
+    /// Get the points identifying the PTLC outputs of the current state.
+    pub fn ptlcs(&self) -> Vec<PtlcPoint> {
+        self.current_state
+            .ptlcs()
+            .iter()
+            .map(SignedPtlc::point)
+            .collect()
+    }
+
+    pub(crate) fn time_lock(&self) -> u32 {
+        let channel_state: &StandardChannelState = self.current_state.as_ref();
+        channel_state.time_lock()
+    }
+
+    /// Get the identifier of the channel, derived from the outpoint of the
+    /// fund output.
+    pub fn id(&self) -> ChannelId {
+        self.fund_outpoint().into()
+    }
+
+    /// Change how long to wait for the counterparty during the operations on
+    /// the channel.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+
+        if let Some(pending_splice) = self.pending_splice.as_mut() {
+            pending_splice.previous.timeouts = timeouts;
+        }
+    }
+
+    /// Get the version of the channel protocols agreed upon with the
+    /// counterparty when the channel was created.
+    pub fn protocol_version(&self) -> u16 {
+        self.protocol_version
+    }
+
+    /// Get the splice of the channel whose splice transaction is not
+    /// confirmed yet, if any.
+    pub fn pending_splice(&self) -> Option<&PendingSplice> {
+        self.pending_splice.as_ref()
+    }
+
+    /// Fail with `Error::Validation` if a splice of the channel is pending,
+    /// since the channel cannot be spliced again until it is resolved.
+    pub(crate) fn ensure_no_pending_splice(&self) -> Result<()> {
+        if self.pending_splice.is_some() {
+            bail!(Error::Validation(anyhow!(
+                "previous splice of the channel is still pending"
+            )))
+        }
+
+        Ok(())
+    }
+
     /// Get the transaction id of the initial fund transaction.
     pub fn tx_f_txid(&self) -> Txid {
         self.tx_f_body.txid()
     }
 
-    /// Retrieve the signed `CommitTransaction` of the state that was revoked
-    /// during the last channel update.
+    /// Outpoint of the fund output, which is spent when the channel is closed
+    /// or spliced.
+    pub(crate) fn fund_outpoint(&self) -> OutPoint {
+        self.tx_f_body.as_txin().previous_output
+    }
+
+    /// The latest state signed by both parties: the pending state of an
+    /// interrupted update if there is one, otherwise the current state.
+    pub(crate) fn latest_state(&self) -> &ChannelState {
+        self.pending_state.as_ref().unwrap_or(&self.current_state)
+    }
+
+    /// Outpoints of every fund output on which a `CommitTransaction` of the
+    /// channel can be published: the current one, the previous one while a
+    /// splice is pending and the ones of previous `FundingTransaction`s with
+    /// revoked states.
+    pub(crate) fn fund_outpoints(&self) -> Vec<OutPoint> {
+        let mut fund_outpoints = vec![self.fund_outpoint()];
+        if let Some(pending_splice) = &self.pending_splice {
+            fund_outpoints.push(pending_splice.previous.fund_outpoint());
+        }
+
+        for fund_outpoint in self.revoked_states.spliced_fund_outpoints() {
+            if !fund_outpoints.contains(&fund_outpoint) {
+                fund_outpoints.push(fund_outpoint);
+            }
+        }
+
+        fund_outpoints
+    }
+
+    /// Transaction id of the `CommitTransaction` of the current state.
     #[cfg(test)]
-    fn latest_revoked_signed_tx_c(&self) -> Result<Option<Transaction>> {
-        self.revoked_states
-            .last()
-            .map(|state| {
-                state.signed_tx_c(&self.tx_f_body, self.x_self.clone(), self.X_other.clone())
-            })
-            .transpose()
+    pub(crate) fn current_tx_c_txid(&self) -> Txid {
+        let channel_state: &StandardChannelState = self.current_state.as_ref();
+        channel_state.tx_c.txid()
+    }
+
+    /// Whether `txid` identifies the `CommitTransaction` of a state which is
+    /// not revoked, i.e. the current or the pending state, on the current
+    /// `FundingTransaction` or on the previous one while a splice is pending.
+    pub(crate) fn is_unrevoked_tx_c(&self, txid: Txid) -> bool {
+        let is_tx_c_of = |channel_state: &ChannelState| {
+            let channel_state: &StandardChannelState = channel_state.as_ref();
+            channel_state.tx_c.txid() == txid
+        };
+
+        is_tx_c_of(&self.current_state)
+            || self.pending_state.as_ref().map_or(false, is_tx_c_of)
+            || self
+                .pending_splice
+                .as_ref()
+                .map_or(false, |pending_splice| {
+                    pending_splice.previous.is_unrevoked_tx_c(txid)
+                })
+    }
+
+    /// Whether `txid` identifies the `CommitTransaction` of a revoked state.
+    pub(crate) fn is_revoked_tx_c(&self, txid: Txid) -> bool {
+        self.revoked_states.find(&self.tx_f_body, txid).is_some()
+    }
+
+    /// Retrieve the signed `CommitTransaction` of the current state. Once the
+    /// state is revoked, publishing it is punishable by the counterparty.
+    #[cfg(test)]
+    fn signed_tx_c(&self) -> Result<Transaction> {
+        let channel_state: &StandardChannelState = self.current_state.as_ref();
+        channel_state.signed_tx_c(&self.tx_f_body, &self.x_self, &self.X_other)
     }
 
     /// Splice a channel.
     ///
     /// Create a new funding transaction using a previous funding transaction as
     /// input. Also inject own funds to channel by passing a splice-in amount.
-    pub async fn splice<T, W>(self, transport: &mut T, wallet: &W, splice: Splice) -> Result<Self>
+    ///
+    /// The PTLC outputs of the channel are carried over to the spliced
+    /// channel, re-signing the transactions which redeem and refund them.
+    ///
+    /// If the splice fails after the `wallet` has provided our splice-in
+    /// input, the input is released, or double-spent if the counterparty
+    /// already has our signature for it. Once the counterparty has our
+    /// signatures for the splice transaction, they may still publish it, so
+    /// the spliced channel is returned in an `Error::Interrupted` with the
+    /// splice pending, and it must replace this one.
+    ///
+    /// The splice stays pending until it is resolved with
+    /// `Channel::resolve_pending_splice`. In the meantime, the spliced channel
+    /// is also kept up to date on the previous funding transaction, so that it
+    /// can be used right away without risking the funds if the splice
+    /// transaction never confirms. The channel cannot be spliced again while a
+    /// splice is pending.
+    pub async fn splice<T, W>(
+        self,
+        transport: &mut T,
+        wallet: &W,
+        splice: Splice,
+    ) -> Result<Self, Error>
     where
-        W: BroadcastSignedTransaction + BuildFundingPsbt + SignFundingPsbt,
+        W: BroadcastSignedTransaction
+            + BuildFundingPsbt
+            + SignFundingPsbt
+            + ReleaseFundingInputs
+            + EstimateFeeRate,
         T: SendMessage + ReceiveMessage,
     {
-        // Re-use timelock, final addresses, balance, ownership keys
-        let final_address_self = self.final_address_self;
-        let final_address_other = self.final_address_other;
-        let current_state = StandardChannelState::from(self.current_state);
-        let time_lock = current_state.time_lock();
-        let balance = current_state.balance;
-        let x_self = self.x_self;
-        let X_other = self.X_other;
+        self.splice_with(transport, wallet, splice, None).await
+    }
 
-        let state = splice::State0::new(
-            time_lock,
-            final_address_self,
-            final_address_other,
-            balance,
-            self.tx_f_body,
-            x_self,
-            X_other,
-            splice,
-            wallet,
+    /// Splice the channel as in `Channel::splice`, failing if the counterparty
+    /// does not splice in and out exactly as in `splice_other`, if any.
+    async fn splice_with<T, W>(
+        self,
+        transport: &mut T,
+        wallet: &W,
+        splice: Splice,
+        splice_other: Option<Splice>,
+    ) -> Result<Self, Error>
+    where
+        W: BroadcastSignedTransaction
+            + BuildFundingPsbt
+            + SignFundingPsbt
+            + ReleaseFundingInputs
+            + EstimateFeeRate,
+        T: SendMessage + ReceiveMessage,
+    {
+        let timeout = self.timeouts.splice;
+        let fee_rate = wallet.estimate_fee_rate().await.map_err(Error::Wallet)?;
+        let previous = self.clone();
+        let mut state = SpliceState::new(self, fee_rate, splice, wallet).await?;
+        if let Some(splice_other) = splice_other {
+            state = state.expect_splice_other(splice_other);
+        }
+
+        let (mut channel, transaction) = match machine::run(state, transport, wallet, timeout).await
+        {
+            Ok(output) => output,
+            // The counterparty may still publish the splice transaction, so
+            // the spliced channel is kept pending on top of the previous one
+            Err(e) => match Error::from(e) {
+                Error::Interrupted {
+                    mut channel,
+                    source,
+                } => {
+                    channel.pending_splice = Some(PendingSplice {
+                        splice_txid: channel.tx_f_body.txid(),
+                        previous: Box::new(previous),
+                    });
+
+                    return Err(Error::Interrupted { channel, source });
+                }
+                e => return Err(e),
+            },
+        };
+        let splice_txid = transaction.txid();
+        wallet
+            .broadcast_signed_transaction(transaction)
+            .await
+            .map_err(Error::Wallet)?;
+
+        channel.pending_splice = Some(PendingSplice {
+            previous: Box::new(previous),
+            splice_txid,
+        });
+
+        Ok(channel)
+    }
+
+    /// Resolve the pending splice of the channel, once its splice transaction
+    /// is either confirmed by `depth` blocks or double-spent, as reported by
+    /// the `chain`.
+    ///
+    /// If the splice transaction is confirmed, the channel stops being updated
+    /// on the previous funding transaction. The revoked states of the previous
+    /// funding transaction are kept until they are forgotten with
+    /// `Channel::forget_spliced_fundings`. If it is double-spent instead, e.g.
+    /// because one of the parties spent their splice-in input elsewhere, the
+    /// channel falls back to the previous funding transaction.
+    ///
+    /// Both parties must resolve the pending splice before updating the
+    /// channel again.
+    pub async fn resolve_pending_splice<C>(
+        &mut self,
+        chain: &C,
+        depth: u32,
+    ) -> Result<Confirmation, Error>
+    where
+        C: WatchConfirmations,
+    {
+        let splice_txid = match &self.pending_splice {
+            Some(pending_splice) => pending_splice.splice_txid,
+            None => {
+                return Err(Error::Validation(anyhow!(
+                    "channel has no pending splice to resolve"
+                )))
+            }
+        };
+
+        let confirmation = chain
+            .watch_for_confirmations(splice_txid, depth)
+            .await
+            .map_err(Error::Chain)?;
+
+        let pending_splice = self.pending_splice.take();
+        if let (Confirmation::DoubleSpent, Some(pending_splice)) = (confirmation, pending_splice) {
+            *self = *pending_splice.previous;
+        }
+
+        Ok(confirmation)
+    }
+
+    /// Propose to the counterparty to splice the channel, and splice it if
+    /// they accept.
+    ///
+    /// The counterparty must be handling proposals with
+    /// `Channel::handle_incoming`.
+    pub async fn propose_splice<T, W>(
+        self,
+        transport: &mut T,
+        wallet: &W,
+        splice: Splice,
+    ) -> Result<Self, Error>
+    where
+        W: BroadcastSignedTransaction
+            + BuildFundingPsbt
+            + SignFundingPsbt
+            + ReleaseFundingInputs
+            + EstimateFeeRate,
+        T: SendMessage + ReceiveMessage,
+    {
+        self.ensure_no_pending_splice()?;
+        propose::propose(
+            transport,
+            ProposeSplice::new(splice.clone()),
+            self.timeouts.splice,
         )
         .await?;
 
-        let (transport, state) = step!(transport, state);
-        let (transport, state) = step!(transport, state);
-        let (transport, state) = step_wallet!(transport, state, wallet);
-        let (_, (channel, transaction)) = step_wallet!(transport, state, wallet);
+        // The counterparty only agreed to our splice
+        self.splice_with(transport, wallet, splice, Some(Splice::default()))
+            .await
+    }
+}
 
-        wallet.broadcast_signed_transaction(transaction).await?;
+/// Splice of a channel whose splice transaction is not confirmed yet.
+///
+/// Until the splice is resolved, the channel is updated in lockstep on both
+/// the spliced and the previous funding transaction. Both are updated to the
+/// same state number, so that revealing the `RevocationSecretKey` of a state
+/// revokes it on both funding transactions at once.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug)]
+pub struct PendingSplice {
+    /// The channel on the previous funding transaction.
+    previous: Box<Channel>,
+    splice_txid: Txid,
+}
 
-        Ok(channel)
+impl PendingSplice {
+    /// Get the transaction id of the splice transaction.
+    pub fn txid(&self) -> Txid {
+        self.splice_txid
+    }
+
+    /// Get the channel balance on the previous funding transaction.
+    pub fn balance(&self) -> Balance {
+        self.previous.balance()
+    }
+
+    /// Split outputs of the state on the previous funding transaction which
+    /// matches the state of the spliced `channel` with `split_outputs`.
+    ///
+    /// The PTLC outputs are the same on both funding transactions, but the
+    /// balance outputs do not include what either party spliced in or out.
+    fn split_outputs(
+        &self,
+        channel: &Channel,
+        split_outputs: &[SplitOutput],
+    ) -> Result<Vec<SplitOutput>> {
+        let previous = &self.previous;
+        if previous.pending_state.is_some() {
+            bail!(Error::Validation(anyhow!(
+                "previous channel update was interrupted, the channel must be reestablished"
+            )))
+        }
+        if previous.state_number != channel.state_number {
+            bail!(Error::Validation(anyhow!(
+                "channel is in state {} on the previous funding transaction but in state {} on the spliced one",
+                previous.state_number,
+                channel.state_number
+            )))
+        }
+
+        let previous_balance = previous.balance();
+        let spliced_balance = channel.balance();
+        let shift = |amount: Amount, previous: Amount, spliced: Amount| {
+            (amount + previous)
+                .checked_sub(spliced)
+                .ok_or_else(|| Error::InsufficientBalance {
+                    balance: previous,
+                    required: spliced - amount,
+                })
+        };
+
+        split_outputs
+            .iter()
+            .map(|output| -> Result<SplitOutput> {
+                match output {
+                    SplitOutput::Balance { amount, address }
+                        if *address == previous.final_address_self =>
+                    {
+                        let amount = shift(*amount, previous_balance.ours, spliced_balance.ours)?;
+
+                        Ok(previous.split_balance_output_ours(amount))
+                    }
+                    SplitOutput::Balance { amount, address }
+                        if *address == previous.final_address_other =>
+                    {
+                        let amount =
+                            shift(*amount, previous_balance.theirs, spliced_balance.theirs)?;
+
+                        Ok(previous.split_balance_output_theirs(amount))
+                    }
+                    output => Ok(output.clone()),
+                }
+            })
+            .collect()
     }
 }
 
+/// Direction of a PTLC from our point of view.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PtlcDirection {
+    /// The PTLC output is funded by us and pays to the counterparty.
+    Offered,
+    /// The PTLC output is funded by the counterparty and pays to us.
+    Received,
+}
+
+/// Transactions published when force closing the channel, whose fee can be
+/// bumped using their anchor outputs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ForceCloseTransaction {
+    Commit,
+    Split,
+}
+
 #[allow(clippy::large_enum_variant)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Clone, Debug, EnumAsInner)]
 pub(crate) enum ChannelState {
     Standard(StandardChannelState),
-    WithPtlc {
+    WithPtlcs {
         inner: StandardChannelState,
-        ptlc: Ptlc,
-        tx_ptlc_redeem: ptlc::RedeemTransaction,
-        tx_ptlc_refund: ptlc::RefundTransaction,
-        encsig_tx_ptlc_redeem_funder: EncryptedSignature,
-        sig_tx_ptlc_redeem_redeemer: Signature,
-        sig_tx_ptlc_refund_funder: Signature,
-        sig_tx_ptlc_refund_redeemer: Signature,
+        ptlcs: Vec<SignedPtlc>,
     },
 }
 
+impl ChannelState {
+    /// The PTLC outputs of the current `SplitTransaction`.
+    pub fn ptlcs(&self) -> &[SignedPtlc] {
+        match self {
+            ChannelState::Standard(_) => &[],
+            ChannelState::WithPtlcs { ptlcs, .. } => ptlcs,
+        }
+    }
+
+    /// The PTLC output identified by `point`, if any.
+    pub fn ptlc(&self, point: &PtlcPoint) -> Option<&SignedPtlc> {
+        self.ptlcs()
+            .iter()
+            .find(|signed_ptlc| signed_ptlc.point() == *point)
+    }
+
+    fn ptlc_mut(&mut self, point: &PtlcPoint) -> Option<&mut SignedPtlc> {
+        match self {
+            ChannelState::Standard(_) => None,
+            ChannelState::WithPtlcs { ptlcs, .. } => ptlcs
+                .iter_mut()
+                .find(|signed_ptlc| signed_ptlc.point() == *point),
+        }
+    }
+}
+
 impl From<ChannelState> for StandardChannelState {
     fn from(from: ChannelState) -> Self {
         match from {
-            ChannelState::Standard(state) | ChannelState::WithPtlc { inner: state, .. } => state,
+            ChannelState::Standard(state) | ChannelState::WithPtlcs { inner: state, .. } => state,
         }
     }
 }
@@ -706,11 +2044,54 @@ impl From<ChannelState> for StandardChannelState {
 impl AsRef<StandardChannelState> for ChannelState {
     fn as_ref(&self) -> &StandardChannelState {
         match self {
-            ChannelState::Standard(state) | ChannelState::WithPtlc { inner: state, .. } => state,
+            ChannelState::Standard(state) | ChannelState::WithPtlcs { inner: state, .. } => state,
         }
     }
 }
 
+/// A PTLC output of a `SplitTransaction`, together with the transactions
+/// spending it and all the signatures exchanged for them.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug)]
+pub(crate) struct SignedPtlc {
+    ptlc: Ptlc,
+    tx_ptlc_redeem: ptlc::RedeemTransaction,
+    tx_ptlc_refund: ptlc::RefundTransaction,
+    encsig_tx_ptlc_redeem_funder: EncryptedSignature,
+    sig_tx_ptlc_redeem_redeemer: Signature,
+    sig_tx_ptlc_refund_funder: Signature,
+    sig_tx_ptlc_refund_redeemer: Signature,
+}
+
+impl SignedPtlc {
+    pub fn point(&self) -> PtlcPoint {
+        self.ptlc.point()
+    }
+
+    /// Add signatures to the `RedeemTransaction`, if we know the secret to
+    /// decrypt the signature of the funder.
+    fn signed_tx_ptlc_redeem(
+        &self,
+        x_self: &OwnershipKeyPair,
+        X_other: &OwnershipPublicKey,
+    ) -> Result<Option<ptlc::RedeemTransaction>> {
+        let secret = match &self.ptlc.role {
+            Role::Alice { secret } => secret.clone(),
+            Role::Bob { .. } => return Ok(None),
+        };
+
+        let sig_funder =
+            signature::decrypt(secret.into(), self.encsig_tx_ptlc_redeem_funder.clone());
+
+        let tx_ptlc_redeem = self.tx_ptlc_redeem.add_signatures(
+            (x_self.public(), self.sig_tx_ptlc_redeem_redeemer.clone()),
+            (X_other.clone(), sig_funder),
+        )?;
+
+        Ok(Some(tx_ptlc_redeem))
+    }
+}
+
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Clone, Debug)]
 pub struct StandardChannelState {
@@ -757,30 +2138,178 @@ impl StandardChannelState {
         self.tx_c.time_lock()
     }
 
-    pub fn encsign_tx_c_self(&self, x_self: &OwnershipKeyPair) -> EncryptedSignature {
-        self.tx_c.encsign(x_self, self.Y_other.clone())
+    pub fn fee_rate(&self) -> FeeRate {
+        self.tx_c.fee_rate()
     }
 }
 
+/// Data needed to punish the publication of the `CommitTransaction` of any
+/// revoked state.
+///
+/// Only the information which cannot be derived from the channel itself is
+/// kept for each revoked state, and the `RevocationSecretKey`s revealed by the
+/// counterparty are stored in logarithmic space. This keeps the size of a
+/// channel small no matter how many times it is updated.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RevokedStates {
+    r_others: RevocationSecretStore,
+    /// Revoked states of the current `FundingTransaction`.
+    states: Vec<RevokedState>,
+    /// Revoked states of the previous `FundingTransaction`s, whose
+    /// `CommitTransaction`s can be published until the splice transaction
+    /// spending them is confirmed.
+    #[cfg_attr(feature = "serde", serde(default))]
+    spliced: Vec<SplicedFunding>,
+}
+
+/// A `FundingTransaction` which was spent by a splice transaction, together
+/// with its revoked states.
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Clone, Debug)]
-pub(crate) struct RevokedState {
-    channel_state: ChannelState,
-    r_other: RevocationSecretKey,
+struct SplicedFunding {
+    tx_f: FundingTransaction,
+    splice_txid: Txid,
+    states: Vec<RevokedState>,
 }
 
-impl RevokedState {
-    /// Add signatures to the `CommitTransaction`. Publishing the resulting
-    /// transaction is punishable by the counterparty, as they can recover the
-    /// `PublishingSecretKey` from it and they already know the
-    /// `RevocationSecretKey`, since this state has already been revoked.
-    #[cfg(test)]
-    fn signed_tx_c(
-        &self,
-        tx_f: &FundingTransaction,
-        x_self: OwnershipKeyPair,
-        X_other: OwnershipPublicKey,
-    ) -> Result<Transaction> {
-        StandardChannelState::from(self.channel_state.clone()).signed_tx_c(tx_f, &x_self, &X_other)
+impl RevokedStates {
+    /// Record that the state with number `state_number` has been revoked by
+    /// the counterparty revealing `r_other`.
+    pub fn insert(
+        &mut self,
+        state_number: u64,
+        channel_state: &StandardChannelState,
+        r_other: &RevocationSecretKey,
+    ) -> Result<()> {
+        self.r_others.insert(state_number, r_other)?;
+        self.states.push(RevokedState {
+            state_number,
+            tx_c_txid: channel_state.tx_c.txid(),
+            time_lock: channel_state.time_lock(),
+            fee_rate: channel_state.fee_rate(),
+            Y_self: channel_state.y_self.public(),
+            Y_other: channel_state.Y_other.clone(),
+        });
+
+        Ok(())
+    }
+
+    /// Keep the revoked states of the `previous_tx_f`, which is spent by the
+    /// splice transaction with id `splice_txid`, apart from the ones of the
+    /// new `FundingTransaction`.
+    pub fn splice(&mut self, previous_tx_f: FundingTransaction, splice_txid: Txid) {
+        let states = std::mem::take(&mut self.states);
+        if states.is_empty() {
+            return;
+        }
+
+        self.spliced.push(SplicedFunding {
+            tx_f: previous_tx_f,
+            splice_txid,
+            states,
+        });
+    }
+
+    /// Replace the revoked states kept for the `previous_tx_f`, which is
+    /// spent by the splice transaction with id `splice_txid`, with the ones
+    /// of the channel which is still being updated on it.
+    pub fn sync_spliced(
+        &mut self,
+        previous: &RevokedStates,
+        previous_tx_f: &FundingTransaction,
+        splice_txid: Txid,
+    ) {
+        let states = previous.states.clone();
+
+        match self
+            .spliced
+            .iter_mut()
+            .find(|spliced| spliced.splice_txid == splice_txid)
+        {
+            Some(spliced) => spliced.states = states,
+            None if !states.is_empty() => self.spliced.push(SplicedFunding {
+                tx_f: previous_tx_f.clone(),
+                splice_txid,
+                states,
+            }),
+            None => (),
+        }
+    }
+
+    /// Outpoints of the fund outputs of the previous `FundingTransaction`s
+    /// with revoked states.
+    pub fn spliced_fund_outpoints(&self) -> Vec<OutPoint> {
+        self.spliced
+            .iter()
+            .map(|spliced| spliced.tx_f.as_txin().previous_output)
+            .collect()
+    }
+
+    /// Transaction ids of the splice transactions spending a previous
+    /// `FundingTransaction` with revoked states.
+    pub fn splice_txids(&self) -> Vec<Txid> {
+        self.spliced
+            .iter()
+            .map(|spliced| spliced.splice_txid)
+            .collect()
+    }
+
+    /// Forget the revoked states of the `FundingTransaction` spent by the
+    /// splice transaction with id `splice_txid`, once their
+    /// `CommitTransaction`s can no longer be published.
+    pub fn forget_spliced(&mut self, splice_txid: Txid) {
+        self.spliced
+            .retain(|spliced| spliced.splice_txid != splice_txid);
     }
+
+    /// Find the revoked state whose `CommitTransaction` has id `tx_c_txid`,
+    /// together with the `FundingTransaction` spent by it. The revoked states
+    /// of the current state are funded by `tx_f`.
+    pub fn find<'a>(
+        &'a self,
+        tx_f: &'a FundingTransaction,
+        tx_c_txid: Txid,
+    ) -> Option<(&'a FundingTransaction, &'a RevokedState)> {
+        self.iter(tx_f)
+            .find(|(_, state)| state.tx_c_txid == tx_c_txid)
+    }
+
+    /// The `RevocationSecretKey` revealed by the counterparty for the state
+    /// with number `state_number`.
+    pub fn r_other(&self, state_number: u64) -> Option<RevocationSecretKey> {
+        self.r_others.get(state_number)
+    }
+
+    /// Every revoked state, together with the `FundingTransaction` spent by
+    /// its `CommitTransaction`. The revoked states of the current state are
+    /// funded by `tx_f`.
+    pub fn iter<'a>(
+        &'a self,
+        tx_f: &'a FundingTransaction,
+    ) -> impl Iterator<Item = (&'a FundingTransaction, &'a RevokedState)> {
+        let current = self.states.iter().map(move |state| (tx_f, state));
+        let spliced = self.spliced.iter().flat_map(|spliced| {
+            spliced
+                .states
+                .iter()
+                .map(move |state| (&spliced.tx_f, state))
+        });
+
+        current.chain(spliced)
+    }
+}
+
+/// Minimal information about a revoked state needed to rebuild its
+/// `CommitTransaction`. The rest of the keys are either constant for the
+/// whole channel or derived from the `state_number`.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug)]
+pub(crate) struct RevokedState {
+    state_number: u64,
+    tx_c_txid: Txid,
+    time_lock: u32,
+    fee_rate: FeeRate,
+    Y_self: PublishingPublicKey,
+    Y_other: PublishingPublicKey,
 }
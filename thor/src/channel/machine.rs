@@ -0,0 +1,531 @@
+//! Sans-IO interface to the channel protocols.
+//!
+//! A [`Machine`] does not communicate with the counterparty or the wallet by
+//! itself. Instead, the caller feeds it the messages received from the
+//! counterparty and the responses of the wallet, and carries out the
+//! [`Action`]s it returns. This allows running channel protocols over any
+//! kind of transport, e.g. many channels multiplexed over one connection or
+//! within an actor system, as well as in synchronous code.
+//!
+//! An error returned by a machine aborts the protocol. Unless the error is a
+//! `ProtocolAborted` error, the counterparty should be notified by sending
+//! them an `Abort` message.
+//!
+//! Machines do not keep track of time either. If the caller gives up waiting
+//! for the counterparty, `Machine::funding_inputs` tells whether our inputs
+//! to a transaction funding the channel need to be released or double-spent.
+//!
+//! If a protocol does not finish, `Machine::interrupted` tells whether the
+//! channel it started from must be replaced, e.g. to keep the pending state
+//! of an interrupted update so that the channel can be reestablished.
+
+use crate::{
+    channel::{
+        abort,
+        protocols::{
+            close::CloseState, create::CreateState, splice::SpliceState, update::UpdateState,
+            Transition,
+        },
+        receive_within, BuildFundingPsbt, Channel, ReceiveMessage, ReleaseFundingInputs,
+        SendMessage, SignFundingPsbt,
+    },
+    AbortReason, Error, Message, ProtocolAborted,
+};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use bitcoin::{util::psbt::PartiallySignedTransaction, Address, Amount};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, time::Duration};
+
+pub type CreateMachine = Machine<CreateState>;
+pub type UpdateMachine = Machine<UpdateState>;
+pub type CloseMachine = Machine<CloseState>;
+pub type SpliceMachine = Machine<SpliceState>;
+
+/// Something the caller must do on behalf of a `Machine`.
+#[derive(Debug)]
+pub enum Action<T> {
+    /// Send the message to the counterparty.
+    SendMessage(Message),
+    /// Build a PSBT with our inputs paying `output_amount` to
+    /// `output_address`, and pass it to `Machine::handle_psbt`.
+    BuildFundingPsbt {
+        output_address: Address,
+        output_amount: Amount,
+    },
+    /// Sign our inputs of the PSBT, and pass it to `Machine::handle_psbt`.
+    SignFundingPsbt(PartiallySignedTransaction),
+    /// The protocol has finished, producing its output.
+    Done(T),
+}
+
+/// Something a protocol needs from the wallet before it can proceed.
+#[derive(Debug)]
+pub enum WalletRequest {
+    BuildFundingPsbt {
+        output_address: Address,
+        output_amount: Amount,
+    },
+    SignFundingPsbt(PartiallySignedTransaction),
+}
+
+/// Our inputs to a transaction funding the channel, added by a protocol
+/// which has not finished yet.
+#[derive(Debug)]
+pub enum FundingInputs {
+    /// The counterparty does not have our signatures for the inputs, so
+    /// they only need to be released.
+    Unsigned(PartiallySignedTransaction),
+    /// The counterparty has our signatures for the inputs and may still
+    /// publish the transaction, so they _must_ be double-spent.
+    Signed(PartiallySignedTransaction),
+}
+
+impl<T> From<WalletRequest> for Action<T> {
+    fn from(from: WalletRequest) -> Self {
+        match from {
+            WalletRequest::BuildFundingPsbt {
+                output_address,
+                output_amount,
+            } => Action::BuildFundingPsbt {
+                output_address,
+                output_amount,
+            },
+            WalletRequest::SignFundingPsbt(psbt) => Action::SignFundingPsbt(psbt),
+        }
+    }
+}
+
+/// A channel protocol made up of steps in which each party composes a
+/// message, sends it to the counterparty and interprets the message received
+/// from them.
+///
+/// Composing or interpreting a message may require a PSBT from the wallet,
+/// which is requested beforehand.
+pub trait Protocol: Sized {
+    type Output;
+
+    /// Request to the wallet needed to compose the message of the current
+    /// step, if any.
+    fn compose_request(&self) -> Result<Option<WalletRequest>> {
+        Ok(None)
+    }
+
+    /// Compose the message of the current step, given the `response` of the
+    /// wallet to `compose_request`.
+    fn compose_with(&self, response: Option<PartiallySignedTransaction>) -> Result<Message>;
+
+    /// Request to the wallet needed to interpret the `message` received from
+    /// the counterparty in the current step, if any.
+    fn interpret_request(&self, _message: &Message) -> Result<Option<WalletRequest>> {
+        Ok(None)
+    }
+
+    /// Interpret the `message` received from the counterparty in the current
+    /// step, given the `response` of the wallet to `interpret_request`.
+    fn interpret_with(
+        self,
+        message: Message,
+        response: Option<PartiallySignedTransaction>,
+    ) -> Result<Transition<Self, Self::Output>>;
+
+    /// Our inputs to the transaction funding the channel while waiting for
+    /// the message of the current step, if the wallet has provided any.
+    fn funding_inputs(&self) -> Option<FundingInputs> {
+        None
+    }
+
+    /// The channel which must replace the one the protocol started from if
+    /// the protocol is interrupted in the current step, if any.
+    fn interrupted(&self) -> Option<Channel> {
+        None
+    }
+}
+
+/// Sans-IO driver of a channel protocol.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+pub struct Machine<P> {
+    /// State of the protocol, until it is done.
+    state: Option<P>,
+    phase: Phase,
+    /// Message received from the counterparty while waiting for the wallet,
+    /// to be interpreted as soon as possible.
+    buffered_message: Option<Message>,
+    /// The channel to keep if the protocol is interrupted, as of the last
+    /// step which required replacing the one the protocol started from.
+    #[cfg_attr(feature = "serde", serde(default))]
+    interrupted: Option<Channel>,
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+enum Phase {
+    Start,
+    AwaitingComposeResponse,
+    AwaitingMessage,
+    AwaitingInterpretResponse(Message),
+    Done,
+}
+
+impl<P> Machine<P>
+where
+    P: Protocol,
+{
+    pub fn new(state: P) -> Self {
+        Self {
+            interrupted: state.interrupted(),
+            state: Some(state),
+            phase: Phase::Start,
+            buffered_message: None,
+        }
+    }
+
+    /// Start the protocol.
+    pub fn start(&mut self) -> Result<Vec<Action<P::Output>>> {
+        match self.phase {
+            Phase::Start => self.compose(),
+            _ => bail!("protocol has already started"),
+        }
+    }
+
+    /// Feed a `message` received from the counterparty into the machine.
+    ///
+    /// If the counterparty has aborted the protocol, a `ProtocolAborted` error
+    /// is returned.
+    pub fn handle_message(&mut self, message: Message) -> Result<Vec<Action<P::Output>>> {
+        if let Message::Abort(abort) = message {
+            self.state = None;
+            self.phase = Phase::Done;
+
+            return Err(ProtocolAborted::from(abort).into());
+        }
+
+        match self.phase {
+            Phase::AwaitingMessage => self.interpret(message),
+            Phase::AwaitingComposeResponse | Phase::AwaitingInterpretResponse(_)
+                if self.buffered_message.is_none() =>
+            {
+                self.buffered_message = Some(message);
+
+                Ok(Vec::new())
+            }
+            _ => bail!("unexpected message from counterparty: {:?}", message),
+        }
+    }
+
+    /// Feed the `psbt` returned by the wallet in response to an
+    /// `Action::BuildFundingPsbt` or `Action::SignFundingPsbt` into the
+    /// machine.
+    pub fn handle_psbt(
+        &mut self,
+        psbt: PartiallySignedTransaction,
+    ) -> Result<Vec<Action<P::Output>>> {
+        match std::mem::replace(&mut self.phase, Phase::Done) {
+            Phase::AwaitingComposeResponse => {
+                let message = self.state()?.compose_with(Some(psbt))?;
+
+                self.send(message)
+            }
+            Phase::AwaitingInterpretResponse(message) => self.transition(message, Some(psbt)),
+            phase => {
+                self.phase = phase;
+
+                bail!("unexpected PSBT, no wallet request is pending")
+            }
+        }
+    }
+
+    /// Our inputs to the transaction funding the channel, which must be
+    /// released or double-spent if the protocol does not finish.
+    pub fn funding_inputs(&self) -> Option<FundingInputs> {
+        self.state.as_ref().and_then(P::funding_inputs)
+    }
+
+    /// The channel which must replace the one the protocol started from if
+    /// the protocol does not finish, e.g. because it failed or the caller
+    /// gave up waiting for the counterparty.
+    pub fn interrupted(&self) -> Option<&Channel> {
+        self.interrupted.as_ref()
+    }
+
+    fn state(&self) -> Result<&P> {
+        self.state.as_ref().context("protocol has already finished")
+    }
+
+    fn compose(&mut self) -> Result<Vec<Action<P::Output>>> {
+        let state = self.state()?;
+
+        match state.compose_request()? {
+            Some(request) => {
+                self.phase = Phase::AwaitingComposeResponse;
+
+                Ok(vec![request.into()])
+            }
+            None => {
+                let message = state.compose_with(None)?;
+
+                self.send(message)
+            }
+        }
+    }
+
+    fn send(&mut self, message: Message) -> Result<Vec<Action<P::Output>>> {
+        self.phase = Phase::AwaitingMessage;
+
+        let mut actions = vec![Action::SendMessage(message)];
+        if let Some(message) = self.buffered_message.take() {
+            actions.extend(self.interpret(message)?);
+        }
+
+        Ok(actions)
+    }
+
+    fn interpret(&mut self, message: Message) -> Result<Vec<Action<P::Output>>> {
+        match self.state()?.interpret_request(&message)? {
+            Some(request) => {
+                self.phase = Phase::AwaitingInterpretResponse(message);
+
+                Ok(vec![request.into()])
+            }
+            None => self.transition(message, None),
+        }
+    }
+
+    fn transition(
+        &mut self,
+        message: Message,
+        response: Option<PartiallySignedTransaction>,
+    ) -> Result<Vec<Action<P::Output>>> {
+        self.phase = Phase::Done;
+        let state = self.state.take().context("protocol has already finished")?;
+
+        match state.interpret_with(message, response)? {
+            Transition::Next(state) => {
+                if let Some(channel) = state.interrupted() {
+                    self.interrupted = Some(channel);
+                }
+                self.state = Some(state);
+
+                self.compose()
+            }
+            Transition::Done(output) => Ok(vec![Action::Done(output)]),
+        }
+    }
+}
+
+/// Fulfil a `request` of a protocol using the `wallet`.
+pub(crate) async fn fulfil<W>(
+    request: Option<WalletRequest>,
+    wallet: &W,
+) -> Result<Option<PartiallySignedTransaction>>
+where
+    W: BuildFundingPsbt + SignFundingPsbt,
+{
+    let response = match request {
+        Some(WalletRequest::BuildFundingPsbt {
+            output_address,
+            output_amount,
+        }) => Some(
+            wallet
+                .build_funding_psbt(output_address, output_amount)
+                .await
+                .map_err(Error::Wallet)?,
+        ),
+        Some(WalletRequest::SignFundingPsbt(psbt)) => Some(
+            wallet
+                .sign_funding_psbt(psbt)
+                .await
+                .map_err(Error::Wallet)?,
+        ),
+        None => None,
+    };
+
+    Ok(response)
+}
+
+/// Run a protocol to completion starting from `state`, communicating with
+/// the counterparty over the `transport`.
+///
+/// If the protocol fails, e.g. because the counterparty does not send a
+/// message within `timeout`, it is aborted and our inputs to the transaction
+/// funding the channel are released or double-spent. If the counterparty may
+/// still be able to publish the transaction, the channel it funds is returned
+/// in an `Error::Interrupted`.
+pub(crate) async fn run<P, T, W>(
+    state: P,
+    transport: &mut T,
+    wallet: &W,
+    timeout: Duration,
+) -> Result<P::Output>
+where
+    P: Protocol,
+    T: SendMessage + ReceiveMessage,
+    W: BuildFundingPsbt + SignFundingPsbt + ReleaseFundingInputs,
+{
+    let mut machine = Machine::new(state);
+    let mut funding_inputs = None;
+
+    let error = match drive(
+        &mut machine,
+        &mut funding_inputs,
+        transport,
+        wallet,
+        timeout,
+    )
+    .await
+    {
+        Ok(output) => return Ok(output),
+        Err(e) => e,
+    };
+
+    let error = match funding_inputs {
+        Some(inputs) => match abandon(inputs, wallet).await {
+            Ok(()) => error,
+            Err(e) => Error::Wallet(e.context("failed to give up on funding inputs")).into(),
+        },
+        None => error,
+    };
+
+    match machine.interrupted() {
+        Some(channel) => Err(Error::Interrupted {
+            channel: Box::new(channel.clone()),
+            source: Box::new(Error::from(error)),
+        }
+        .into()),
+        None => Err(error),
+    }
+}
+
+/// Drive the `machine` to completion, keeping track of the latest
+/// `funding_inputs` of the protocol so that they can be given up on if it
+/// fails.
+async fn drive<P, T, W>(
+    machine: &mut Machine<P>,
+    funding_inputs: &mut Option<FundingInputs>,
+    transport: &mut T,
+    wallet: &W,
+    timeout: Duration,
+) -> Result<P::Output>
+where
+    P: Protocol,
+    T: SendMessage + ReceiveMessage,
+    W: BuildFundingPsbt + SignFundingPsbt,
+{
+    let mut actions = match machine.start() {
+        Ok(actions) => VecDeque::from(actions),
+        Err(e) => return Err(abort(transport, AbortReason::Internal, e).await),
+    };
+
+    loop {
+        if let Some(inputs) = machine.funding_inputs() {
+            *funding_inputs = Some(inputs);
+        }
+
+        let action = match actions.pop_front() {
+            Some(action) => action,
+            None => {
+                let message = receive_within(transport, timeout).await?;
+                match machine.handle_message(message) {
+                    Ok(new_actions) => actions.extend(new_actions),
+                    Err(e) => return Err(abort(transport, AbortReason::InvalidMessage, e).await),
+                }
+
+                continue;
+            }
+        };
+
+        let psbt = match action {
+            Action::SendMessage(message) => {
+                transport
+                    .send_message(message)
+                    .await
+                    .map_err(Error::Transport)?;
+
+                continue;
+            }
+            Action::BuildFundingPsbt {
+                output_address,
+                output_amount,
+            } => {
+                wallet
+                    .build_funding_psbt(output_address, output_amount)
+                    .await
+            }
+            Action::SignFundingPsbt(psbt) => wallet.sign_funding_psbt(psbt).await,
+            Action::Done(output) => return Ok(output),
+        };
+
+        let psbt = match psbt {
+            Ok(psbt) => psbt,
+            Err(e) => {
+                return Err(abort(transport, AbortReason::Internal, Error::Wallet(e).into()).await)
+            }
+        };
+        match machine.handle_psbt(psbt) {
+            Ok(new_actions) => actions.extend(new_actions),
+            Err(e) => return Err(abort(transport, AbortReason::InvalidMessage, e).await),
+        }
+    }
+}
+
+/// Release or double-spend our funding `inputs` using the `wallet`.
+async fn abandon<W>(inputs: FundingInputs, wallet: &W) -> Result<()>
+where
+    W: ReleaseFundingInputs,
+{
+    match inputs {
+        FundingInputs::Unsigned(psbt) => wallet.release_funding_inputs(psbt).await,
+        FundingInputs::Signed(psbt) => wallet.double_spend_funding_inputs(psbt).await,
+    }
+}
+
+/// Run a protocol which never needs the wallet to completion starting from
+/// `state`, communicating with the counterparty over the `transport`.
+pub(crate) async fn run_without_wallet<P, T>(
+    state: P,
+    transport: &mut T,
+    timeout: Duration,
+) -> Result<P::Output>
+where
+    P: Protocol,
+    T: SendMessage + ReceiveMessage,
+{
+    run(state, transport, &NoWallet, timeout).await
+}
+
+#[derive(Clone, Copy, Debug)]
+struct NoWallet;
+
+#[async_trait]
+impl BuildFundingPsbt for NoWallet {
+    async fn build_funding_psbt(
+        &self,
+        _: Address,
+        _: Amount,
+    ) -> Result<PartiallySignedTransaction> {
+        bail!("protocol unexpectedly requested to build a funding PSBT")
+    }
+}
+
+#[async_trait]
+impl SignFundingPsbt for NoWallet {
+    async fn sign_funding_psbt(
+        &self,
+        _: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction> {
+        bail!("protocol unexpectedly requested to sign a funding PSBT")
+    }
+}
+
+#[async_trait]
+impl ReleaseFundingInputs for NoWallet {
+    async fn release_funding_inputs(&self, _: PartiallySignedTransaction) -> Result<()> {
+        bail!("protocol unexpectedly added funding inputs")
+    }
+
+    async fn double_spend_funding_inputs(&self, _: PartiallySignedTransaction) -> Result<()> {
+        bail!("protocol unexpectedly added funding inputs")
+    }
+}
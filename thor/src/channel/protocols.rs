@@ -1,5 +1,18 @@
 pub mod close;
 pub mod create;
+pub mod propose;
 pub mod punish;
+pub mod recover;
+pub mod reestablish;
 pub mod splice;
 pub mod update;
+
+/// Outcome of interpreting a message received from the counterparty in one of
+/// the resumable channel protocols.
+#[derive(Debug)]
+pub enum Transition<S, T> {
+    /// The protocol continues from the new state.
+    Next(S),
+    /// The protocol has finished, producing its output.
+    Done(T),
+}
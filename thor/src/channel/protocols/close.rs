@@ -1,14 +1,16 @@
 use crate::{
-    channel::Channel,
+    channel::{machine::Protocol, protocols::Transition, Channel},
     keys::{OwnershipKeyPair, OwnershipPublicKey},
     transaction::{CloseTransaction, FundingTransaction},
-    Balance,
+    Balance, Message,
 };
 use anyhow::{Context, Result};
-use bitcoin::{Address, Transaction};
+use bitcoin::{util::psbt::PartiallySignedTransaction, Address, Transaction};
 use ecdsa_fun::Signature;
 use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug)]
 pub(crate) struct State0 {
     x_self: OwnershipKeyPair,
@@ -33,7 +35,11 @@ impl State0 {
         let addr_theirs = channel.final_address_other.clone();
         let tx_f = channel.tx_f_body.clone();
 
-        let tx = CloseTransaction::new(&tx_f, [(ours, addr_ours), (theirs, addr_theirs)])?;
+        let tx = CloseTransaction::new(
+            &tx_f,
+            [(ours, addr_ours), (theirs, addr_theirs)],
+            channel.fee_rate(),
+        )?;
 
         Ok(Self {
             x_self: channel.x_self.clone(),
@@ -75,3 +81,32 @@ impl State0 {
         Ok(close_transaction)
     }
 }
+
+/// A collaborative channel close in progress.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+pub struct CloseState(State0);
+
+impl CloseState {
+    /// Start closing the `channel`, paying out the current balance to the
+    /// final address of each party.
+    pub fn new(channel: &Channel) -> Result<Self> {
+        Ok(CloseState(State0::new(channel)?))
+    }
+}
+
+impl Protocol for CloseState {
+    type Output = Transaction;
+
+    fn compose_with(&self, _: Option<PartiallySignedTransaction>) -> Result<Message> {
+        Ok(self.0.compose().into())
+    }
+
+    fn interpret_with(
+        self,
+        message: Message,
+        _: Option<PartiallySignedTransaction>,
+    ) -> Result<Transition<Self, Self::Output>> {
+        Ok(Transition::Done(self.0.interpret(message.try_into()?)?))
+    }
+}
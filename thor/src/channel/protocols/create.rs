@@ -1,23 +1,33 @@
 use crate::{
-    channel::{ChannelState, StandardChannelState},
+    channel::{
+        machine::{fulfil, FundingInputs, Protocol, WalletRequest},
+        protocols::Transition,
+        ChannelState, RevokedStates, StandardChannelState, Timeouts,
+    },
+    fee::{FeeRate, FeeRateBounds},
     keys::{
-        OwnershipKeyPair, OwnershipPublicKey, PublishingKeyPair, PublishingPublicKey,
+        ChannelKeys, OwnershipKeyPair, OwnershipPublicKey, PublishingKeyPair, PublishingPublicKey,
         RevocationKeyPair, RevocationPublicKey,
     },
     transaction::{balance, CommitTransaction, FundOutput, FundingTransaction, SplitTransaction},
-    Balance, Channel, SplitOutput,
+    wire::{negotiate_protocol_version, PROTOCOL_VERSION},
+    Balance, Channel, Message, SplitOutput,
 };
 use anyhow::{Context, Result};
 use async_trait::async_trait;
 use bitcoin::{util::psbt::PartiallySignedTransaction, Address, Amount, Transaction};
 use ecdsa_fun::{adaptor::EncryptedSignature, Signature};
 use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
 
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug)]
 pub struct Message0 {
     X: OwnershipPublicKey,
     final_address: Address,
+    fee_rate: FeeRate,
+    /// Highest version of the channel protocols supported by the sender.
+    protocol_version: u16,
 }
 
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -59,12 +69,17 @@ pub struct Message5 {
     tx_f_signed_once: PartiallySignedTransaction,
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug)]
 pub(crate) struct State0 {
+    keys: ChannelKeys,
     x_self: OwnershipKeyPair,
     final_address_self: Address,
     balance: Balance,
     time_lock: u32,
+    fee_rate_self: FeeRate,
+    fee_rate_bounds: FeeRateBounds,
+    timeouts: Timeouts,
 }
 
 #[async_trait]
@@ -77,14 +92,26 @@ pub trait BuildFundingPsbt {
 }
 
 impl State0 {
-    pub fn new(balance: Balance, time_lock: u32, final_address: Address) -> Self {
-        let x_self = OwnershipKeyPair::new_random();
+    pub fn new(
+        keys: ChannelKeys,
+        balance: Balance,
+        time_lock: u32,
+        final_address: Address,
+        fee_rate: FeeRate,
+        fee_rate_bounds: FeeRateBounds,
+        timeouts: Timeouts,
+    ) -> Self {
+        let x_self = keys.ownership_key_pair();
 
         Self {
+            keys,
             x_self,
             balance,
             final_address_self: final_address,
             time_lock,
+            fee_rate_self: fee_rate,
+            fee_rate_bounds,
+            timeouts,
         }
     }
 
@@ -92,23 +119,41 @@ impl State0 {
         Message0 {
             X: self.x_self.public(),
             final_address: self.final_address_self.clone(),
+            fee_rate: self.fee_rate_self,
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+
+    /// Request to the wallet for our input of the `FundingTransaction`,
+    /// which pays into the fund output shared with the counterparty.
+    pub fn funding_psbt_request(&self, message: &Message0) -> WalletRequest {
+        let fund_output = FundOutput::new([self.x_self.public(), message.X.clone()]);
+
+        WalletRequest::BuildFundingPsbt {
+            output_address: fund_output.address(),
+            output_amount: self.balance.ours,
         }
     }
 
-    pub async fn interpret(
+    pub fn interpret(
         self,
         Message0 {
             X: X_other,
             final_address: final_address_other,
+            fee_rate: fee_rate_other,
+            protocol_version: protocol_version_other,
         }: Message0,
-        wallet: &impl BuildFundingPsbt,
+        input_psbt_self: PartiallySignedTransaction,
     ) -> Result<State1> {
-        let fund_output = FundOutput::new([self.x_self.public(), X_other.clone()]);
-        let input_psbt_self = wallet
-            .build_funding_psbt(fund_output.address(), self.balance.ours)
-            .await?;
+        let protocol_version = negotiate_protocol_version(protocol_version_other)?;
+
+        self.fee_rate_bounds
+            .validate(fee_rate_other)
+            .context("fee rate proposed by counterparty is not acceptable")?;
+        let fee_rate = self.fee_rate_self.agree(fee_rate_other);
 
         Ok(State1 {
+            keys: self.keys,
             x_self: self.x_self,
             X_other,
             final_address_self: self.final_address_self,
@@ -116,19 +161,33 @@ impl State0 {
             balance: self.balance,
             input_psbt_self,
             time_lock: self.time_lock,
+            fee_rate,
+            fee_rate_bounds: self.fee_rate_bounds,
+            timeouts: self.timeouts,
+            protocol_version,
         })
     }
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug)]
 pub(crate) struct State1 {
+    keys: ChannelKeys,
     x_self: OwnershipKeyPair,
     X_other: OwnershipPublicKey,
     final_address_self: Address,
     final_address_other: Address,
     balance: Balance,
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "crate::serde::partially_signed_transaction")
+    )]
     input_psbt_self: PartiallySignedTransaction,
     time_lock: u32,
+    fee_rate: FeeRate,
+    fee_rate_bounds: FeeRateBounds,
+    timeouts: Timeouts,
+    protocol_version: u16,
 }
 
 impl State1 {
@@ -150,8 +209,8 @@ impl State1 {
         ])
         .context("failed to build funding transaction")?;
 
-        let r = RevocationKeyPair::new_random();
-        let y = PublishingKeyPair::new_random();
+        let r = self.keys.revocation_key_pair(0);
+        let y = self.keys.publishing_key_pair(0);
 
         Ok(State2 {
             x_self: self.x_self,
@@ -160,6 +219,11 @@ impl State1 {
             final_address_other: self.final_address_other,
             balance: self.balance,
             time_lock: self.time_lock,
+            fee_rate: self.fee_rate,
+            fee_rate_bounds: self.fee_rate_bounds,
+            timeouts: self.timeouts,
+            protocol_version: self.protocol_version,
+            keys: self.keys,
             r_self: r,
             y_self: y,
             tx_f,
@@ -167,6 +231,7 @@ impl State1 {
     }
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Clone, Debug)]
 pub(crate) struct State2 {
     x_self: OwnershipKeyPair,
@@ -175,6 +240,11 @@ pub(crate) struct State2 {
     final_address_other: Address,
     balance: Balance,
     time_lock: u32,
+    fee_rate: FeeRate,
+    fee_rate_bounds: FeeRateBounds,
+    timeouts: Timeouts,
+    protocol_version: u16,
+    keys: ChannelKeys,
     r_self: RevocationKeyPair,
     y_self: PublishingKeyPair,
     tx_f: FundingTransaction,
@@ -206,6 +276,7 @@ impl State2 {
                 (self.X_other.clone(), R_other.clone(), Y_other.clone()),
             ],
             self.time_lock,
+            self.fee_rate,
         )?;
 
         let split_outputs = vec![
@@ -227,6 +298,7 @@ impl State2 {
             final_address_self: self.final_address_self,
             final_address_other: self.final_address_other,
             split_outputs,
+            keys: self.keys,
             r_self: self.r_self,
             R_other,
             y_self: self.y_self,
@@ -235,10 +307,14 @@ impl State2 {
             tx_c,
             tx_s,
             sig_tx_s_self,
+            fee_rate_bounds: self.fee_rate_bounds,
+            timeouts: self.timeouts,
+            protocol_version: self.protocol_version,
         })
     }
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug)]
 pub(crate) struct Party3 {
     x_self: OwnershipKeyPair,
@@ -246,6 +322,7 @@ pub(crate) struct Party3 {
     final_address_self: Address,
     final_address_other: Address,
     split_outputs: Vec<SplitOutput>,
+    keys: ChannelKeys,
     r_self: RevocationKeyPair,
     R_other: RevocationPublicKey,
     y_self: PublishingKeyPair,
@@ -254,6 +331,9 @@ pub(crate) struct Party3 {
     tx_c: CommitTransaction,
     tx_s: SplitTransaction,
     sig_tx_s_self: Signature,
+    fee_rate_bounds: FeeRateBounds,
+    timeouts: Timeouts,
+    protocol_version: u16,
 }
 
 impl Party3 {
@@ -286,6 +366,7 @@ impl Party3 {
             final_address_self: self.final_address_self,
             final_address_other: self.final_address_other,
             split_outputs: self.split_outputs,
+            keys: self.keys,
             r_self: self.r_self,
             R_other: self.R_other,
             y_self: self.y_self,
@@ -294,10 +375,14 @@ impl Party3 {
             tx_c: self.tx_c,
             signed_tx_s: self.tx_s,
             encsig_tx_c_self,
+            fee_rate_bounds: self.fee_rate_bounds,
+            timeouts: self.timeouts,
+            protocol_version: self.protocol_version,
         })
     }
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug)]
 pub(crate) struct Party4 {
     x_self: OwnershipKeyPair,
@@ -305,6 +390,7 @@ pub(crate) struct Party4 {
     final_address_self: Address,
     final_address_other: Address,
     split_outputs: Vec<SplitOutput>,
+    keys: ChannelKeys,
     r_self: RevocationKeyPair,
     R_other: RevocationPublicKey,
     y_self: PublishingKeyPair,
@@ -313,6 +399,9 @@ pub(crate) struct Party4 {
     tx_c: CommitTransaction,
     signed_tx_s: SplitTransaction,
     encsig_tx_c_self: EncryptedSignature,
+    fee_rate_bounds: FeeRateBounds,
+    timeouts: Timeouts,
+    protocol_version: u16,
 }
 
 impl Party4 {
@@ -342,6 +431,7 @@ impl Party4 {
             final_address_self: self.final_address_self,
             final_address_other: self.final_address_other,
             split_outputs: self.split_outputs,
+            keys: self.keys,
             r_self: self.r_self,
             R_other: self.R_other,
             y_self: self.y_self,
@@ -350,17 +440,22 @@ impl Party4 {
             tx_c: self.tx_c,
             signed_tx_s: self.signed_tx_s,
             encsig_tx_c_other,
+            fee_rate_bounds: self.fee_rate_bounds,
+            timeouts: self.timeouts,
+            protocol_version: self.protocol_version,
         })
     }
 }
 
-#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug)]
 pub(crate) struct Party5 {
     x_self: OwnershipKeyPair,
     X_other: OwnershipPublicKey,
     final_address_self: Address,
     final_address_other: Address,
     split_outputs: Vec<SplitOutput>,
+    keys: ChannelKeys,
     r_self: RevocationKeyPair,
     R_other: RevocationPublicKey,
     y_self: PublishingKeyPair,
@@ -369,6 +464,9 @@ pub(crate) struct Party5 {
     tx_c: CommitTransaction,
     signed_tx_s: SplitTransaction,
     encsig_tx_c_other: EncryptedSignature,
+    fee_rate_bounds: FeeRateBounds,
+    timeouts: Timeouts,
+    protocol_version: u16,
 }
 
 /// Sign one of the inputs of the `FundingTransaction`.
@@ -380,48 +478,235 @@ pub trait SignFundingPsbt {
     ) -> Result<PartiallySignedTransaction>;
 }
 
+/// Give up on our inputs of a transaction funding the channel, when the
+/// counterparty stops responding before it is complete.
+///
+/// The `psbt` passed to either method may contain inputs of the counterparty,
+/// which the wallet must ignore.
+#[async_trait]
+pub trait ReleaseFundingInputs {
+    /// Make our inputs of the `psbt` available to other transactions again,
+    /// e.g. by unlocking the unspent outputs selected by `BuildFundingPsbt`.
+    async fn release_funding_inputs(&self, psbt: PartiallySignedTransaction) -> Result<()>;
+
+    /// Spend at least one of our inputs of the `psbt` back to ourselves, so
+    /// that the counterparty can no longer publish the transaction with our
+    /// signature.
+    async fn double_spend_funding_inputs(&self, psbt: PartiallySignedTransaction) -> Result<()>;
+}
+
 impl Party5 {
-    pub async fn compose(&self, wallet: &impl SignFundingPsbt) -> Result<Message5> {
-        let tx_f_signed_once = wallet
-            .sign_funding_psbt(self.tx_f.clone().into_psbt()?)
-            .await?;
+    /// The `FundingTransaction` for the wallet to sign our input of.
+    pub fn tx_f_psbt(&self) -> Result<PartiallySignedTransaction> {
+        self.tx_f.clone().into_psbt()
+    }
 
-        Ok(Message5 { tx_f_signed_once })
+    pub fn compose(&self, tx_f_signed_once: PartiallySignedTransaction) -> Message5 {
+        Message5 { tx_f_signed_once }
     }
 
-    /// Returns the Channel and the transaction to broadcast.
-    pub async fn interpret(
+    /// Returns the Channel and the transaction to broadcast, given the
+    /// `FundingTransaction` sent by the counterparty once we have signed our
+    /// input of it.
+    pub fn interpret(self, signed_tx_f: PartiallySignedTransaction) -> (Channel, Transaction) {
+        (self.channel(), signed_tx_f.extract_tx())
+    }
+
+    /// The channel funded by the `FundingTransaction`, which the counterparty
+    /// can publish as soon as they have our signature for it.
+    fn channel(&self) -> Channel {
+        Channel {
+            x_self: self.x_self.clone(),
+            X_other: self.X_other.clone(),
+            final_address_self: self.final_address_self.clone(),
+            final_address_other: self.final_address_other.clone(),
+            tx_f_body: self.tx_f.clone(),
+            current_state: ChannelState::Standard(StandardChannelState {
+                balance: balance(
+                    self.split_outputs.clone(),
+                    &self.final_address_self,
+                    &self.final_address_other,
+                ),
+                tx_c: self.tx_c.clone(),
+                encsig_tx_c_other: self.encsig_tx_c_other.clone(),
+                r_self: self.r_self.clone(),
+                R_other: self.R_other.clone(),
+                y_self: self.y_self.clone(),
+                Y_other: self.Y_other.clone(),
+                signed_tx_s: self.signed_tx_s.clone(),
+            }),
+            state_number: 0,
+            keys: self.keys.clone(),
+            revoked_states: RevokedStates::default(),
+            pending_state: None,
+            fee_rate_bounds: self.fee_rate_bounds,
+            timeouts: self.timeouts,
+            protocol_version: self.protocol_version,
+            pending_splice: None,
+        }
+    }
+}
+
+/// A channel creation in progress.
+///
+/// It can be persisted after every step of the protocol, so that the channel
+/// creation can be resumed from the same step if the process crashes.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+pub struct CreateState(Step);
+
+#[allow(clippy::large_enum_variant)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+enum Step {
+    State0(State0),
+    State1(State1),
+    State2(State2),
+    Party3(Party3),
+    Party4(Party4),
+    Party5(Party5),
+}
+
+impl CreateState {
+    /// Start creating a channel.
+    ///
+    /// The keys of the channel are derived from `keys`, and our share of the
+    /// funds will be paid out to `final_address` when the channel is closed.
+    pub fn new(
+        keys: ChannelKeys,
+        balance: Balance,
+        time_lock: u32,
+        final_address: Address,
+        fee_rate: FeeRate,
+        fee_rate_bounds: FeeRateBounds,
+        timeouts: Timeouts,
+    ) -> Self {
+        CreateState(Step::State0(State0::new(
+            keys,
+            balance,
+            time_lock,
+            final_address,
+            fee_rate,
+            fee_rate_bounds,
+            timeouts,
+        )))
+    }
+
+    /// Message to be sent to the counterparty in the current step.
+    ///
+    /// The `wallet` is used to sign our input of the `FundingTransaction` in
+    /// the last step.
+    pub async fn compose<W>(&self, wallet: &W) -> Result<Message>
+    where
+        W: BuildFundingPsbt + SignFundingPsbt,
+    {
+        let response = fulfil(self.compose_request()?, wallet).await?;
+
+        self.compose_with(response)
+    }
+
+    /// Transition to the next step given the `message` received from the
+    /// counterparty. The protocol is done once the `Channel` is returned,
+    /// together with the signed `FundingTransaction` which must be broadcast.
+    pub async fn interpret<W>(
         self,
-        Message5 { tx_f_signed_once }: Message5,
-        wallet: &impl SignFundingPsbt,
-    ) -> Result<(Channel, Transaction)> {
-        let signed_tx_f = wallet.sign_funding_psbt(tx_f_signed_once).await?;
-        let signed_tx_f = signed_tx_f.extract_tx();
-
-        Ok((
-            Channel {
-                x_self: self.x_self,
-                X_other: self.X_other,
-                final_address_self: self.final_address_self.clone(),
-                final_address_other: self.final_address_other.clone(),
-                tx_f_body: self.tx_f,
-                current_state: ChannelState::Standard(StandardChannelState {
-                    balance: balance(
-                        self.split_outputs,
-                        &self.final_address_self,
-                        &self.final_address_other,
-                    ),
-                    tx_c: self.tx_c,
-                    encsig_tx_c_other: self.encsig_tx_c_other,
-                    r_self: self.r_self,
-                    R_other: self.R_other,
-                    y_self: self.y_self,
-                    Y_other: self.Y_other,
-                    signed_tx_s: self.signed_tx_s,
-                }),
-                revoked_states: vec![],
-            },
-            signed_tx_f,
-        ))
+        message: Message,
+        wallet: &W,
+    ) -> Result<Transition<Self, (Channel, Transaction)>>
+    where
+        W: BuildFundingPsbt + SignFundingPsbt,
+    {
+        let response = fulfil(self.interpret_request(&message)?, wallet).await?;
+
+        self.interpret_with(message, response)
+    }
+}
+
+impl Protocol for CreateState {
+    type Output = (Channel, Transaction);
+
+    fn compose_request(&self) -> Result<Option<WalletRequest>> {
+        let request = match &self.0 {
+            Step::Party5(state) => Some(WalletRequest::SignFundingPsbt(state.tx_f_psbt()?)),
+            _ => None,
+        };
+
+        Ok(request)
+    }
+
+    fn compose_with(&self, response: Option<PartiallySignedTransaction>) -> Result<Message> {
+        let message = match &self.0 {
+            Step::State0(state) => state.compose().into(),
+            Step::State1(state) => state.compose().into(),
+            Step::State2(state) => state.compose().into(),
+            Step::Party3(state) => state.compose().into(),
+            Step::Party4(state) => state.compose().into(),
+            Step::Party5(state) => state
+                .compose(response.context("wallet did not sign funding transaction")?)
+                .into(),
+        };
+
+        Ok(message)
+    }
+
+    fn interpret_request(&self, message: &Message) -> Result<Option<WalletRequest>> {
+        let request = match (&self.0, message) {
+            (Step::State0(state), Message::Create0(message)) => {
+                Some(state.funding_psbt_request(message))
+            }
+            (Step::Party5(_), Message::Create5(Message5 { tx_f_signed_once })) => {
+                Some(WalletRequest::SignFundingPsbt(tx_f_signed_once.clone()))
+            }
+            _ => None,
+        };
+
+        Ok(request)
+    }
+
+    fn interpret_with(
+        self,
+        message: Message,
+        response: Option<PartiallySignedTransaction>,
+    ) -> Result<Transition<Self, Self::Output>> {
+        let step = match self.0 {
+            Step::State0(state) => Step::State1(state.interpret(
+                message.try_into()?,
+                response.context("wallet did not build funding PSBT")?,
+            )?),
+            Step::State1(state) => Step::State2(state.interpret(message.try_into()?)?),
+            Step::State2(state) => Step::Party3(state.interpret(message.try_into()?)?),
+            Step::Party3(state) => Step::Party4(state.interpret(message.try_into()?)?),
+            Step::Party4(state) => Step::Party5(state.interpret(message.try_into()?)?),
+            Step::Party5(state) => {
+                let _: Message5 = message.try_into()?;
+
+                return Ok(Transition::Done(state.interpret(
+                    response.context("wallet did not sign funding transaction")?,
+                )));
+            }
+        };
+
+        Ok(Transition::Next(CreateState(step)))
+    }
+
+    fn funding_inputs(&self) -> Option<FundingInputs> {
+        let inputs = match &self.0 {
+            Step::State0(_) => return None,
+            Step::State1(state) => FundingInputs::Unsigned(state.input_psbt_self.clone()),
+            Step::State2(state) => FundingInputs::Unsigned(state.tx_f.clone().into_psbt().ok()?),
+            Step::Party3(state) => FundingInputs::Unsigned(state.tx_f.clone().into_psbt().ok()?),
+            Step::Party4(state) => FundingInputs::Unsigned(state.tx_f.clone().into_psbt().ok()?),
+            // Our signature for the funding input was sent in `Message5`
+            Step::Party5(state) => FundingInputs::Signed(state.tx_f_psbt().ok()?),
+        };
+
+        Some(inputs)
+    }
+
+    fn interrupted(&self) -> Option<Channel> {
+        match &self.0 {
+            Step::Party5(state) => Some(state.channel()),
+            _ => None,
+        }
     }
 }
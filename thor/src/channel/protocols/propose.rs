@@ -0,0 +1,332 @@
+//! Negotiation of the channel operations started by one of the parties.
+//!
+//! The party starting an operation sends a proposal describing it. The
+//! counterparty validates it, asks their `Policy` whether to accept it and
+//! replies with `Accept` or `Reject`. Once accepted, both parties run the
+//! protocol of the operation.
+
+use crate::{
+    channel::{abort, receive, receive_within, Channel, ReceiveMessage, SendMessage},
+    fee::split_output_fee_share,
+    transaction::balance,
+    AbortReason, Balance, Error, Message, Ptlc, Role, Splice, SplitOutput, UnexpectedMessage,
+};
+use anyhow::{bail, Result};
+use bitcoin::Amount;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Proposal to update the channel to a state with the given split outputs.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+pub struct ProposeUpdate {
+    split_outputs: Vec<SplitOutput>,
+    time_lock: u32,
+}
+
+/// Proposal to close the channel collaboratively.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug)]
+pub struct ProposeClose;
+
+/// Proposal to splice the channel, splicing in and out on the side of the
+/// party proposing it.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+pub struct ProposeSplice {
+    splice: Splice,
+}
+
+/// Acceptance of the proposal of the counterparty.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug)]
+pub struct Accept;
+
+/// Rejection of the proposal of the counterparty.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+pub struct Reject {
+    reason: String,
+}
+
+impl ProposeUpdate {
+    pub(crate) fn new(split_outputs: &[SplitOutput], time_lock: u32) -> Self {
+        // The secret of a PTLC must never be shared with the counterparty
+        let split_outputs = split_outputs
+            .iter()
+            .map(|output| match output {
+                SplitOutput::Ptlc(ptlc) => SplitOutput::Ptlc(Ptlc {
+                    role: Role::Bob {
+                        point: ptlc.point(),
+                    },
+                    ..ptlc.clone()
+                }),
+                output => output.clone(),
+            })
+            .collect();
+
+        Self {
+            split_outputs,
+            time_lock,
+        }
+    }
+}
+
+impl ProposeSplice {
+    pub(crate) fn new(splice: Splice) -> Self {
+        Self { splice }
+    }
+}
+
+/// Channel operation proposed by the counterparty, from our point of view.
+#[derive(Clone, Debug)]
+pub enum Proposal {
+    /// Update the channel to a state paying out `balance`.
+    Update {
+        balance: Balance,
+        /// Outputs of the `SplitTransaction` of the new state, including the
+        /// PTLC outputs.
+        split_outputs: Vec<SplitOutput>,
+        time_lock: u32,
+    },
+    /// Close the channel collaboratively.
+    Close,
+    /// Splice the channel, splicing in and out on the side of the
+    /// counterparty.
+    Splice(Splice),
+}
+
+/// Decides which of the proposals of the counterparty to accept.
+pub trait Policy {
+    /// Returning an error rejects the `proposal` for the `channel`. The error
+    /// is kept as the reason for the rejection in
+    /// `HandledProposal::Rejected`, but the counterparty only gets a fixed
+    /// description.
+    fn evaluate(&self, channel: &Channel, proposal: &Proposal) -> Result<()>;
+}
+
+/// `Policy` accepting every valid proposal.
+#[derive(Clone, Copy, Debug)]
+pub struct AcceptAll;
+
+impl Policy for AcceptAll {
+    fn evaluate(&self, _: &Channel, _: &Proposal) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Outcome of handling a proposal of the counterparty.
+#[derive(Debug)]
+pub enum HandledProposal {
+    /// The proposal was accepted and the operation has been carried out.
+    Accepted(Proposal),
+    /// The proposal was rejected by our `Policy`, for the `reason` it
+    /// returned.
+    Rejected {
+        proposal: Proposal,
+        reason: anyhow::Error,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("counterparty rejected the proposal: {reason}")]
+pub struct ProposalRejected {
+    pub reason: String,
+}
+
+/// Reason sent to the counterparty when their proposal is not valid for the
+/// channel. The details of the error are not disclosed.
+const INVALID_PROPOSAL: &str = "proposal is not valid for the channel";
+
+/// Reason sent to the counterparty when their proposal is not accepted by our
+/// `Policy`.
+pub(crate) const UNACCEPTABLE_PROPOSAL: &str = "proposal is not acceptable";
+
+/// Send the `proposal` to the counterparty and wait up to `timeout` for them
+/// to accept it.
+pub(crate) async fn propose<T>(
+    transport: &mut T,
+    proposal: impl Into<Message>,
+    timeout: Duration,
+) -> Result<()>
+where
+    T: SendMessage + ReceiveMessage,
+{
+    transport
+        .send_message(proposal.into())
+        .await
+        .map_err(Error::Transport)?;
+
+    match receive_within(transport, timeout).await? {
+        Message::Accept(_) => Ok(()),
+        Message::Reject(Reject { reason }) => Err(ProposalRejected { reason }.into()),
+        message => {
+            let error = UnexpectedMessage::new::<Accept>(message).into();
+
+            Err(abort(transport, AbortReason::UnexpectedMessage, error).await)
+        }
+    }
+}
+
+/// Receive the next proposal of the counterparty, and check that it is
+/// valid for the `channel`.
+pub(crate) async fn receive_proposal<T>(transport: &mut T, channel: &Channel) -> Result<Proposal>
+where
+    T: SendMessage + ReceiveMessage,
+{
+    let proposal = match receive(transport).await? {
+        Message::ProposeUpdate(ProposeUpdate {
+            split_outputs,
+            time_lock,
+        }) => validate_update(channel, split_outputs, time_lock).map(|split_outputs| {
+            Proposal::Update {
+                balance: balance(
+                    split_outputs.clone(),
+                    &channel.final_address_self,
+                    &channel.final_address_other,
+                ),
+                split_outputs,
+                time_lock,
+            }
+        }),
+        Message::ProposeClose(_) => Ok(Proposal::Close),
+        Message::ProposeSplice(ProposeSplice { splice }) => channel
+            .ensure_no_pending_splice()
+            .map(|_| Proposal::Splice(splice)),
+        message => {
+            let error = UnexpectedMessage::new::<ProposeUpdate>(message).into();
+
+            return Err(abort(transport, AbortReason::UnexpectedMessage, error).await);
+        }
+    };
+
+    match proposal {
+        Ok(proposal) => Ok(proposal),
+        Err(e) => {
+            reject(transport, INVALID_PROPOSAL).await?;
+
+            Err(e)
+        }
+    }
+}
+
+pub(crate) async fn accept<T>(transport: &mut T) -> Result<()>
+where
+    T: SendMessage,
+{
+    transport
+        .send_message(Accept.into())
+        .await
+        .map_err(|e| Error::Transport(e).into())
+}
+
+pub(crate) async fn reject<T>(transport: &mut T, reason: &str) -> Result<()>
+where
+    T: SendMessage,
+{
+    transport
+        .send_message(
+            Reject {
+                reason: reason.to_string(),
+            }
+            .into(),
+        )
+        .await
+        .map_err(|e| Error::Transport(e).into())
+}
+
+/// Check that the `split_outputs` proposed by the counterparty only
+/// redistribute the funds of the `channel` between both parties, keeping the
+/// PTLC outputs already in the channel, that the `time_lock` is not shorter
+/// than the one of the current state and that each output can pay for its
+/// share of the fees.
+///
+/// The proposed PTLC outputs are replaced by our own version of them if they
+/// are already in the channel, since the counterparty does not share the
+/// secret of a PTLC.
+fn validate_update(
+    channel: &Channel,
+    split_outputs: Vec<SplitOutput>,
+    time_lock: u32,
+) -> Result<Vec<SplitOutput>> {
+    // A shorter time lock would leave less time to punish the publication of
+    // a revoked state, or none at all
+    if time_lock < channel.time_lock() {
+        bail!(
+            "proposed time lock {} is shorter than the current one of {}",
+            time_lock,
+            channel.time_lock()
+        )
+    }
+
+    let X_self = channel.x_self.public();
+    let X_other = channel.X_other.clone();
+
+    let split_outputs = split_outputs
+        .into_iter()
+        .map(|output| match output {
+            SplitOutput::Balance { ref address, .. }
+                if *address == channel.final_address_self
+                    || *address == channel.final_address_other =>
+            {
+                Ok(output)
+            }
+            SplitOutput::Balance { address, .. } => bail!(
+                "proposed balance output pays to unknown address {}",
+                address
+            ),
+            SplitOutput::Ptlc(ptlc)
+                if (ptlc.X_funder == X_self && ptlc.X_redeemer == X_other)
+                    || (ptlc.X_funder == X_other && ptlc.X_redeemer == X_self) =>
+            {
+                match channel.current_state.ptlc(&ptlc.point()) {
+                    Some(signed_ptlc) => Ok(SplitOutput::Ptlc(signed_ptlc.ptlc.clone())),
+                    None => Ok(SplitOutput::Ptlc(ptlc)),
+                }
+            }
+            SplitOutput::Ptlc(_) => bail!("proposed PTLC output does not belong to the channel"),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // PTLC outputs are only removed by settling or failing them
+    for signed_ptlc in channel.current_state.ptlcs() {
+        let point = signed_ptlc.ptlc.point();
+        let is_kept = split_outputs.iter().any(|output| match output {
+            SplitOutput::Ptlc(ptlc) => ptlc.point() == point,
+            SplitOutput::Balance { .. } => false,
+        });
+        if !is_kept {
+            bail!("proposed outputs do not keep PTLC output of the channel")
+        }
+    }
+
+    let total = |outputs: &[SplitOutput]| {
+        outputs
+            .iter()
+            .fold(Amount::ZERO, |total, output| total + output.amount())
+    };
+    let current_split_outputs = channel.split_outputs_for_balance(channel.balance());
+    if total(&split_outputs) != total(&current_split_outputs) {
+        bail!(
+            "proposed outputs pay out {} instead of the {} in the channel",
+            total(&split_outputs),
+            total(&current_split_outputs)
+        )
+    }
+
+    // Each output pays for its share of the fees, which must not exceed its
+    // value at the highest fee rate that we may agree on
+    let fee_share = split_output_fee_share(channel.fee_rate_bounds.max, split_outputs.len());
+    if let Some(output) = split_outputs
+        .iter()
+        .find(|output| output.amount() < fee_share)
+    {
+        bail!(
+            "proposed output of {} does not cover its fee share of {}",
+            output.amount(),
+            fee_share
+        )
+    }
+
+    Ok(split_outputs)
+}
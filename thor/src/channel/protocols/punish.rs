@@ -1,45 +1,80 @@
 use crate::{
-    channel::{RevokedState, StandardChannelState},
-    keys::OwnershipKeyPair,
-    transaction::PunishTransaction,
+    channel::RevokedState,
+    keys::RevocationKeyPair,
+    transaction::{
+        CommitTransaction, FundingTransaction, PresignedPunishTransaction, PunishTransaction,
+    },
+    Channel,
 };
-use anyhow::Result;
-use bitcoin::{Address, Transaction};
+use anyhow::{anyhow, bail, Result};
+use bitcoin::Transaction;
 
 #[derive(Copy, Clone, Debug, thiserror::Error)]
 #[error("transaction cannot be punished")]
 pub struct NotOldCommitTransaction;
 
 pub(crate) fn punish(
-    x_self: &OwnershipKeyPair,
-    revoked_states: &[RevokedState],
-    final_address: Address,
+    channel: &Channel,
     old_commit_transaction: Transaction,
 ) -> Result<PunishTransaction> {
-    let (channel_state, r_other) = revoked_states
-        .iter()
-        .map(|state| {
-            (
-                StandardChannelState::from(state.channel_state.clone()),
-                state.r_other.clone(),
-            )
-        })
-        .find(|(state, _)| state.tx_c.txid() == old_commit_transaction.txid())
+    let (tx_f, revoked_state) = channel
+        .revoked_states
+        .find(&channel.tx_f_body, old_commit_transaction.txid())
         .ok_or_else(|| NotOldCommitTransaction)?;
 
-    let encsig_tx_c_self = channel_state.encsign_tx_c_self(x_self);
+    let tx_p = presign_punish_transaction(channel, tx_f, revoked_state)?;
 
-    let StandardChannelState { tx_c, Y_other, .. } = channel_state;
+    tx_p.complete(old_commit_transaction)
+}
 
-    let tx_p = PunishTransaction::new(
-        x_self,
-        final_address,
-        &tx_c,
-        &encsig_tx_c_self,
-        &r_other.into(),
-        Y_other,
-        old_commit_transaction,
+/// Rebuild the `CommitTransaction` of a revoked state, which spends `tx_f`,
+/// and sign the `PunishTransaction` spending it with every key we know.
+pub(crate) fn presign_punish_transaction(
+    channel: &Channel,
+    tx_f: &FundingTransaction,
+    revoked_state: &RevokedState,
+) -> Result<PresignedPunishTransaction> {
+    let r_other: RevocationKeyPair = channel
+        .revoked_states
+        .r_other(revoked_state.state_number)
+        .ok_or_else(|| {
+            anyhow!(
+                "unknown revocation secret key for state {}",
+                revoked_state.state_number
+            )
+        })?
+        .into();
+    let r_self = channel.keys.revocation_key_pair(revoked_state.state_number);
+
+    let tx_c = CommitTransaction::new(
+        tx_f,
+        [
+            (
+                channel.x_self.public(),
+                r_self.public(),
+                revoked_state.Y_self.clone(),
+            ),
+            (
+                channel.X_other.clone(),
+                r_other.public(),
+                revoked_state.Y_other.clone(),
+            ),
+        ],
+        revoked_state.time_lock,
+        revoked_state.fee_rate,
     )?;
+    if tx_c.txid() != revoked_state.tx_c_txid {
+        bail!("could not rebuild revoked commit transaction")
+    }
 
-    Ok(tx_p)
+    let encsig_tx_c_self = tx_c.encsign(&channel.x_self, revoked_state.Y_other.clone());
+
+    PresignedPunishTransaction::new(
+        &channel.x_self,
+        channel.final_address_self.clone(),
+        &tx_c,
+        &encsig_tx_c_self,
+        &r_other,
+        revoked_state.Y_other.clone(),
+    )
 }
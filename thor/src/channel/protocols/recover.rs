@@ -0,0 +1,164 @@
+//! Recovery of the funds locked in a channel whose state has been lost.
+//!
+//! A party who has lost their channel state, or who has restored an outdated
+//! version of it, _must not_ publish any `CommitTransaction` they know of,
+//! since it may have been revoked. Instead, they use a `StaticBackup` of the
+//! channel to ask the counterparty to force close it. The counterparty shares
+//! their latest `CommitTransaction` and `SplitTransaction`, so that the party
+//! recovering can publish them themselves if the counterparty fails to do so.
+
+use crate::{
+    channel::{Channel, StandardChannelState},
+    keys::{OwnershipKeyPair, OwnershipPublicKey},
+    transaction::{anchor, FundingTransaction},
+    Balance,
+};
+use anyhow::{bail, Result};
+use bitcoin::{Address, Amount, OutPoint, Transaction, Txid};
+use serde::{Deserialize, Serialize};
+
+/// Data needed to recover the funds of a channel in case its state is lost.
+///
+/// It only changes when the channel is created or spliced, so it does not have
+/// to be backed up after every channel update.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug)]
+pub struct StaticBackup {
+    x_self: OwnershipKeyPair,
+    X_other: OwnershipPublicKey,
+    final_address_self: Address,
+    final_address_other: Address,
+    tx_f_body: FundingTransaction,
+}
+
+impl From<&Channel> for StaticBackup {
+    fn from(channel: &Channel) -> Self {
+        Self {
+            x_self: channel.x_self.clone(),
+            X_other: channel.X_other.clone(),
+            final_address_self: channel.final_address_self.clone(),
+            final_address_other: channel.final_address_other.clone(),
+            tx_f_body: channel.tx_f_body.clone(),
+        }
+    }
+}
+
+/// Message sent by the party recovering a channel, asking the counterparty to
+/// force close it.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+pub struct RequestForceClose {
+    tx_f_txid: Txid,
+}
+
+/// Message sent in response to a `RequestForceClose`, containing the
+/// transactions published by the counterparty to force close the channel.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+pub struct ShareForceCloseTransactions {
+    signed_tx_c: Transaction,
+    signed_tx_s: Transaction,
+}
+
+/// Outcome of recovering a channel from a `StaticBackup`.
+#[derive(Clone, Debug)]
+pub struct Recovery {
+    /// The latest `CommitTransaction` of the counterparty, published by them
+    /// to force close the channel.
+    pub signed_tx_c: Transaction,
+    /// The `SplitTransaction` spending `signed_tx_c`. It can be published by
+    /// either party once the time lock on `signed_tx_c` has expired.
+    pub signed_tx_s: Transaction,
+    /// Amounts paid to the final address of each party by `signed_tx_s`.
+    pub balance: Balance,
+}
+
+#[derive(Debug)]
+pub(crate) struct State0 {
+    backup: StaticBackup,
+}
+
+impl State0 {
+    pub fn new(backup: StaticBackup) -> Self {
+        Self { backup }
+    }
+
+    pub fn compose(&self) -> RequestForceClose {
+        RequestForceClose {
+            tx_f_txid: self.backup.tx_f_body.txid(),
+        }
+    }
+
+    pub fn interpret(
+        self,
+        ShareForceCloseTransactions {
+            signed_tx_c,
+            signed_tx_s,
+        }: ShareForceCloseTransactions,
+    ) -> Result<Recovery> {
+        let fund_outpoint = self.backup.tx_f_body.as_txin().previous_output;
+        if signed_tx_c.input.len() != 1 || signed_tx_c.input[0].previous_output != fund_outpoint {
+            bail!("commit transaction sent by counterparty does not spend the fund output")
+        }
+
+        // Every transaction spending the fund output is built with the anchor
+        // outputs of both parties
+        let anchor_outputs =
+            anchor::build_outputs([self.backup.x_self.public(), self.backup.X_other.clone()]);
+        if !anchor_outputs
+            .iter()
+            .all(|anchor| signed_tx_c.output.contains(anchor))
+        {
+            bail!("commit transaction sent by counterparty does not belong to the channel")
+        }
+
+        let commit_outpoint = OutPoint::new(signed_tx_c.txid(), 0);
+        if signed_tx_s.input.len() != 1 || signed_tx_s.input[0].previous_output != commit_outpoint {
+            bail!("split transaction sent by counterparty does not spend the commit transaction")
+        }
+
+        let amount_paid_to = |address: &Address| {
+            Amount::from_sat(
+                signed_tx_s
+                    .output
+                    .iter()
+                    .filter(|output| output.script_pubkey == address.script_pubkey())
+                    .map(|output| output.value)
+                    .sum(),
+            )
+        };
+        let balance = Balance {
+            ours: amount_paid_to(&self.backup.final_address_self),
+            theirs: amount_paid_to(&self.backup.final_address_other),
+        };
+
+        Ok(Recovery {
+            signed_tx_c,
+            signed_tx_s,
+            balance,
+        })
+    }
+}
+
+/// Respond to a `RequestForceClose` sent by the counterparty with the
+/// transactions of the latest state of the `channel`.
+pub(crate) fn share_force_close_transactions(
+    channel: &Channel,
+    RequestForceClose { tx_f_txid }: RequestForceClose,
+) -> Result<ShareForceCloseTransactions> {
+    if tx_f_txid != channel.tx_f_body.txid() {
+        bail!(
+            "counterparty requested to force close unknown channel with fund transaction {}",
+            tx_f_txid
+        )
+    }
+
+    let state: &StandardChannelState = channel.latest_state().as_ref();
+    let signed_tx_c = state.signed_tx_c(&channel.tx_f_body, &channel.x_self, &channel.X_other)?;
+    let signed_tx_s = state.signed_tx_s.clone().into();
+
+    Ok(ShareForceCloseTransactions {
+        signed_tx_c,
+        signed_tx_s,
+    })
+}
@@ -0,0 +1,150 @@
+//! Resynchronisation of a channel after its update was interrupted.
+//!
+//! Once both parties have exchanged their signatures for the next state of
+//! the channel, each of them keeps it as a pending state until they receive
+//! the `RevocationSecretKey` of the current state from the counterparty. If
+//! the transport fails before that happens, the parties may end up disagreeing
+//! on which state is the latest one.
+//!
+//! To reestablish the channel, both parties first share the number of their
+//! current state and whether they have a pending state. Then they share the
+//! `RevocationSecretKey` needed by whoever is behind to roll forward to their
+//! pending state. If neither party is ahead and one of them has no pending
+//! state, the pending state of the other is discarded: messages are delivered
+//! in order, so the counterparty can only have received our
+//! `RevocationSecretKey` after receiving our signatures for the next state.
+
+use crate::{
+    channel::{Channel, ChannelState},
+    keys::RevocationSecretKey,
+};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// First message of the channel reestablish protocol.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug)]
+pub struct ShareStateNumber {
+    state_number: u64,
+    pending_update: bool,
+}
+
+/// Second and last message of the channel reestablish protocol.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+pub struct ShareRevocationSecretKey {
+    r: Option<RevocationSecretKey>,
+}
+
+#[derive(Debug)]
+pub(crate) struct State0 {
+    channel: Channel,
+}
+
+impl State0 {
+    pub fn new(channel: Channel) -> Self {
+        Self { channel }
+    }
+
+    pub fn compose(&self) -> ShareStateNumber {
+        ShareStateNumber {
+            state_number: self.channel.state_number,
+            pending_update: self.channel.pending_state.is_some(),
+        }
+    }
+
+    pub fn interpret(
+        self,
+        ShareStateNumber {
+            state_number: state_number_other,
+            pending_update: pending_update_other,
+        }: ShareStateNumber,
+    ) -> Result<State1> {
+        let mut channel = self.channel;
+        let state_number = channel.state_number;
+        let pending_state = channel.pending_state.take();
+
+        let (r_self, next_state) = match (pending_state, pending_update_other) {
+            // The counterparty completed the update, so they already have our
+            // `RevocationSecretKey`
+            (Some(pending_state), _) if state_number_other == state_number + 1 => {
+                (None, Some(pending_state))
+            }
+            (None, _) if state_number_other == state_number + 1 => bail!(
+                "counterparty is in state {} but we have no pending update to roll forward from state {}",
+                state_number_other,
+                state_number
+            ),
+            // We completed the update, so the counterparty needs the
+            // `RevocationSecretKey` of the state we revoked
+            (None, true) if state_number == state_number_other + 1 => {
+                let r_self = channel.keys.revocation_key_pair(state_number_other).into();
+
+                (Some(r_self), None)
+            }
+            (Some(pending_state), true) if state_number == state_number_other => {
+                let r_self = channel.keys.revocation_key_pair(state_number).into();
+
+                (Some(r_self), Some(pending_state))
+            }
+            (_, _) if state_number == state_number_other => (None, None),
+            (_, _) => bail!(
+                "cannot reestablish channel in state {} with counterparty in state {}",
+                state_number,
+                state_number_other
+            ),
+        };
+
+        Ok(State1 {
+            channel,
+            r_self,
+            next_state,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct State1 {
+    channel: Channel,
+    r_self: Option<RevocationSecretKey>,
+    /// The pending state to roll forward to, once the counterparty reveals
+    /// the `RevocationSecretKey` of the current state.
+    next_state: Option<ChannelState>,
+}
+
+impl State1 {
+    pub fn compose(&self) -> ShareRevocationSecretKey {
+        ShareRevocationSecretKey {
+            r: self.r_self.clone(),
+        }
+    }
+
+    pub fn interpret(
+        self,
+        ShareRevocationSecretKey { r: r_other }: ShareRevocationSecretKey,
+    ) -> Result<Channel> {
+        let mut channel = self.channel;
+
+        let next_state = match self.next_state {
+            Some(next_state) => next_state,
+            None => return Ok(channel),
+        };
+
+        let r_other = r_other.context(
+            "counterparty did not reveal the revocation secret key of the current state",
+        )?;
+
+        let revoked_state = channel.current_state.as_ref();
+        revoked_state
+            .R_other
+            .verify_revocation_secret_key(&r_other)?;
+        channel
+            .revoked_states
+            .insert(channel.state_number, revoked_state, &r_other)?;
+
+        channel.current_state = next_state;
+        channel.state_number += 1;
+
+        Ok(channel)
+    }
+}
@@ -1,23 +1,28 @@
 use crate::{
-    channel::{BuildFundingPsbt, ChannelState, SignFundingPsbt, StandardChannelState},
+    channel::{
+        machine::{fulfil, FundingInputs, Protocol, WalletRequest},
+        protocols::{
+            update::{SharePtlcSignatures, SignPtlcs},
+            Transition,
+        },
+        BuildFundingPsbt, ChannelState, RevokedStates, SignFundingPsbt, SignedPtlc,
+        StandardChannelState, Timeouts,
+    },
+    fee::{splice_transaction_weight, FeeRate, FeeRateBounds},
     keys::{
-        OwnershipKeyPair, OwnershipPublicKey, PublishingKeyPair, PublishingPublicKey,
+        ChannelKeys, OwnershipKeyPair, OwnershipPublicKey, PublishingKeyPair, PublishingPublicKey,
         RevocationKeyPair, RevocationPublicKey,
     },
-    transaction::{
-        CommitTransaction, FundOutput, FundingTransaction, SpliceTransaction, SplitTransaction,
-    },
-    Balance, Channel, SplitOutput, TX_FEE,
+    transaction::{CommitTransaction, FundingTransaction, SpliceTransaction, SplitTransaction},
+    Balance, Channel, Error, Message, Ptlc, SplitOutput,
 };
 
-use anyhow::{Context, Result};
-use bitcoin::{
-    consensus::serialize, util::psbt::PartiallySignedTransaction, Address, Amount, Transaction,
-    TxOut,
-};
+use anyhow::{bail, Context, Result};
+use bitcoin::{util::psbt::PartiallySignedTransaction, Address, Amount, Transaction, TxOut};
 use ecdsa_fun::{adaptor::EncryptedSignature, Signature};
 use miniscript::Descriptor;
 use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
 
 #[cfg(feature = "serde")]
 use crate::serde::partially_signed_transaction as pst;
@@ -29,6 +34,7 @@ use bitcoin::util::amount::serde::as_sat;
 pub struct Message0 {
     R: RevocationPublicKey,
     Y: PublishingPublicKey,
+    fee_rate: FeeRate,
     #[cfg_attr(feature = "serde", serde(default))]
     splice: Splice,
 }
@@ -57,6 +63,7 @@ pub struct Message3 {
     signed_TX_splice_psbt_input: Option<PartiallySignedTransaction>,
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Clone, Debug)]
 pub(crate) struct State0 {
     x_self: OwnershipKeyPair,
@@ -64,67 +71,128 @@ pub(crate) struct State0 {
     final_address_self: Address,
     final_address_other: Address,
     previous_balance: Balance,
+    previous_ptlcs: Vec<Ptlc>,
     previous_tx_f: FundingTransaction,
     time_lock: u32,
+    fee_rate_self: FeeRate,
+    fee_rate_bounds: FeeRateBounds,
+    timeouts: Timeouts,
+    protocol_version: u16,
+    state_number: u64,
+    keys: ChannelKeys,
+    revoked_states: RevokedStates,
     r_self: RevocationKeyPair,
     y_self: PublishingKeyPair,
     splice_self: Splice,
+    /// The splice the counterparty agreed to, if they are bound to one.
+    expected_splice_other: Option<crate::Splice>,
+}
+
+/// Changes to the side of the channel of one of the parties.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Splice {
+    splice_in: Option<SpliceIn>,
+    outs: Vec<TxOut>,
 }
 
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Clone, Debug)]
-pub(crate) enum Splice {
-    In {
-        #[cfg_attr(feature = "serde", serde(with = "as_sat"))]
-        amount: Amount,
-        #[cfg_attr(feature = "serde", serde(with = "pst"))]
-        input_psbt: PartiallySignedTransaction,
-    },
-    Out(TxOut),
-    None,
+pub(crate) struct SpliceIn {
+    #[cfg_attr(feature = "serde", serde(with = "as_sat"))]
+    amount: Amount,
+    /// Pays `amount` to the fund output of the `SpliceTransaction`.
+    #[cfg_attr(feature = "serde", serde(with = "pst"))]
+    input_psbt: PartiallySignedTransaction,
 }
 
-impl Default for Splice {
-    fn default() -> Self {
-        Splice::None
+impl Splice {
+    fn amount_in(&self) -> Amount {
+        self.splice_in
+            .as_ref()
+            .map_or(Amount::ZERO, |splice_in| splice_in.amount)
+    }
+
+    fn amount_out(&self) -> Amount {
+        self.outs.iter().fold(Amount::ZERO, |total, tx_out| {
+            total + Amount::from_sat(tx_out.value)
+        })
+    }
+
+    /// Each party splicing out pays for the part of the splice transaction
+    /// that they add, as well as for the shared input and output. What is
+    /// spliced in is paid for by the wallet which built the input PSBTs.
+    fn fee(&self, fee_rate: FeeRate) -> Amount {
+        if self.outs.is_empty() {
+            Amount::ZERO
+        } else {
+            fee_rate.fee(splice_transaction_weight(self.outs.len()))
+        }
+    }
+
+    /// The balance of the party who is splicing once the splice is applied to
+    /// their current `balance`, if it covers what they splice out.
+    fn apply(&self, balance: Amount, fee_rate: FeeRate) -> Option<Amount> {
+        (balance + self.amount_in()).checked_sub(self.amount_out() + self.fee(fee_rate))
+    }
+
+    /// Our input PSBT, if we are splicing in.
+    fn input_psbt(&self) -> Option<PartiallySignedTransaction> {
+        self.splice_in
+            .as_ref()
+            .map(|splice_in| splice_in.input_psbt.clone())
     }
 }
 
 impl State0 {
+    /// The `previous_ptlcs` are carried over to the spliced channel.
+    ///
+    /// The amount we splice in _must_ be funded by `input_psbt`.
     #[allow(clippy::too_many_arguments)]
-    pub async fn new<W>(
+    pub fn new(
         time_lock: u32,
+        fee_rate: FeeRate,
+        fee_rate_bounds: FeeRateBounds,
+        timeouts: Timeouts,
+        protocol_version: u16,
         final_address_self: Address,
         final_address_other: Address,
         previous_balance: Balance,
+        previous_ptlcs: Vec<Ptlc>,
         previous_tx_f: FundingTransaction,
         x_self: OwnershipKeyPair,
         X_other: OwnershipPublicKey,
+        keys: ChannelKeys,
+        state_number: u64,
+        revoked_states: RevokedStates,
         splice_self: crate::Splice,
-        wallet: &W,
-    ) -> Result<State0>
-    where
-        W: BuildFundingPsbt,
-    {
-        let splice_self = match splice_self {
-            crate::Splice::Out(tx_out) => {
-                if tx_out.value > previous_balance.ours.as_sat() {
-                    anyhow::bail!("Not enough balance to splice out {} sats", tx_out.value)
-                }
-                Splice::Out(tx_out)
-            }
-            crate::Splice::In(amount) => {
-                let fund_output = FundOutput::new([x_self.public(), X_other.clone()]);
-                let input_psbt = wallet
-                    .build_funding_psbt(fund_output.address(), amount)
-                    .await?;
-                Splice::In { input_psbt, amount }
-            }
-            crate::Splice::None => Splice::None,
+        input_psbt: Option<PartiallySignedTransaction>,
+    ) -> Result<State0> {
+        let amount = splice_self.amount_in;
+        let splice_in = match input_psbt {
+            Some(input_psbt) if amount > Amount::ZERO => Some(SpliceIn { amount, input_psbt }),
+            None if amount == Amount::ZERO => None,
+            Some(_) => bail!("cannot fund a splice which splices nothing in"),
+            None => bail!("cannot splice in without an input to fund it"),
         };
 
-        let r = RevocationKeyPair::new_random();
-        let y = PublishingKeyPair::new_random();
+        let splice_self = Splice {
+            splice_in,
+            outs: splice_self.outs,
+        };
+
+        // The fee is only known once the fee rate has been agreed on
+        let available = previous_balance.ours + splice_self.amount_in();
+        if splice_self.amount_out() > available {
+            return Err(Error::InsufficientBalance {
+                balance: available,
+                required: splice_self.amount_out(),
+            }
+            .into());
+        }
+
+        let r = keys.revocation_key_pair(state_number);
+        let y = keys.publishing_key_pair(state_number);
 
         Ok(State0 {
             x_self,
@@ -132,11 +200,20 @@ impl State0 {
             final_address_self,
             final_address_other,
             previous_balance,
+            previous_ptlcs,
             previous_tx_f,
+            state_number,
+            keys,
+            revoked_states,
             r_self: r,
             y_self: y,
             splice_self,
+            expected_splice_other: None,
             time_lock,
+            fee_rate_self: fee_rate,
+            fee_rate_bounds,
+            timeouts,
+            protocol_version,
         })
     }
 
@@ -144,6 +221,7 @@ impl State0 {
         Message0 {
             R: self.r_self.public(),
             Y: self.y_self.public(),
+            fee_rate: self.fee_rate_self,
             splice: self.splice_self.clone(),
         }
     }
@@ -153,81 +231,74 @@ impl State0 {
         Message0 {
             R: R_other,
             Y: Y_other,
+            fee_rate: fee_rate_other,
             splice: splice_other,
         }: Message0,
-    ) -> Result<State1> {
-        let mut our_balance = self.previous_balance.ours;
-        let mut their_balance = self.previous_balance.theirs;
-
-        let mut splice_outputs = vec![];
-        let mut splice_in_inputs = vec![];
-
-        match self.splice_self.clone() {
-            Splice::Out(tx_out) => {
-                if tx_out.value > self.previous_balance.ours.as_sat() {
-                    anyhow::bail!("We are splicing out more than we have");
-                } else {
-                    our_balance -= Amount::from_sat(tx_out.value) + Amount::from_sat(TX_FEE);
-                    splice_outputs.push(tx_out);
-                }
-            }
-            Splice::In { amount, input_psbt } => {
-                splice_in_inputs.push(input_psbt);
-                our_balance += amount;
+    ) -> Result<State1Kind> {
+        if let Some(expected_splice_other) = &self.expected_splice_other {
+            if splice_other.amount_in() != expected_splice_other.amount_in
+                || splice_other.outs != expected_splice_other.outs
+            {
+                bail!("splice of counterparty does not match the one they agreed to")
             }
-            Splice::None => (),
         }
 
-        match splice_other {
-            Splice::Out(tx_out) => {
-                if tx_out.value > self.previous_balance.theirs.as_sat() {
-                    anyhow::bail!("Counterpart is splicing out more than they have");
-                } else {
-                    // Need to pay the transaction fee, taking it out of the splice out.
-                    // TODO: split between splice in and splice out if there is both
-                    their_balance -= Amount::from_sat(tx_out.value) + Amount::from_sat(TX_FEE);
-                    splice_outputs.push(tx_out);
-                }
-            }
-            Splice::In { amount, input_psbt } => {
-                splice_in_inputs.push(input_psbt);
-                their_balance += amount;
-            }
-            Splice::None => (),
-        }
-
-        // Sort the PSBT inputs based on the ascending lexicographical order of
-        // bytes of their consensus serialization. Both parties _must_ do this so that
-        // they compute the same splice transaction.
-        splice_in_inputs.sort_by(|a, b| {
-            serialize(a)
-                .partial_cmp(&serialize(b))
-                .expect("comparison is possible")
-        });
-
-        let previous_funding_txin = self.previous_tx_f.as_txin();
-        let previous_funding_psbt = PartiallySignedTransaction::from_unsigned_tx(Transaction {
-            version: 2,
-            lock_time: 0,
-            input: vec![previous_funding_txin],
-            output: vec![],
-        })
-        .expect("Only fails if script_sig or witness is empty which is not the case.");
-
-        // The previous funding psbt MUST be the first input
-        let mut inputs = vec![previous_funding_psbt];
-
-        inputs.append(&mut splice_in_inputs);
+        self.fee_rate_bounds
+            .validate(fee_rate_other)
+            .context("fee rate proposed by counterparty is not acceptable")?;
+        let fee_rate = self.fee_rate_self.agree(fee_rate_other);
 
         let balance = Balance {
-            ours: our_balance,
-            theirs: their_balance,
+            ours: self
+                .splice_self
+                .apply(self.previous_balance.ours, fee_rate)
+                .context("We are splicing out more than we have")?,
+            theirs: splice_other
+                .apply(self.previous_balance.theirs, fee_rate)
+                .context("Counterparty is splicing out more than they have")?,
         };
 
-        let splice_transaction = SpliceTransaction::new(inputs, splice_outputs, [
-            (self.x_self.public(), balance.ours),
-            (self.X_other.clone(), balance.theirs),
-        ])?;
+        let splice_ins = self
+            .splice_self
+            .splice_in
+            .iter()
+            .chain(splice_other.splice_in.iter())
+            .map(|splice_in| (splice_in.input_psbt.clone(), splice_in.amount))
+            .collect();
+        let splice_outputs = self
+            .splice_self
+            .outs
+            .iter()
+            .chain(splice_other.outs.iter())
+            .cloned()
+            .collect();
+
+        // The PTLC outputs are still funded by the new fund output
+        let X_self = self.x_self.public();
+        let ptlc_amount = |X_funder: &OwnershipPublicKey| {
+            self.previous_ptlcs
+                .iter()
+                .filter(|ptlc| ptlc.X_funder == *X_funder)
+                .fold(Amount::ZERO, |total, ptlc| total + ptlc.amount)
+        };
+
+        // The splice-in PSBT of the counterparty is checked to pay what they
+        // claim to splice in, and the fee to be paid by the parties splicing
+        // out
+        let fee = self.splice_self.fee(fee_rate) + splice_other.fee(fee_rate);
+        let splice_transaction = SpliceTransaction::new(
+            &self.previous_tx_f,
+            splice_ins,
+            splice_outputs,
+            [
+                (X_self.clone(), balance.ours + ptlc_amount(&X_self)),
+                (
+                    self.X_other.clone(),
+                    balance.theirs + ptlc_amount(&self.X_other),
+                ),
+            ],
+            fee,
+        )?;
 
         // Signed to spend TX_f
         let sig_TX_splice_TX_f_input =
@@ -244,10 +315,11 @@ impl State0 {
                 (self.X_other.clone(), R_other.clone(), Y_other.clone()),
             ],
             self.time_lock,
+            fee_rate,
         )?;
         let encsig_tx_c_self = tx_c.encsign(&self.x_self, Y_other.clone());
 
-        let tx_s = SplitTransaction::new(&tx_c, vec![
+        let mut split_outputs = vec![
             SplitOutput::Balance {
                 amount: balance.ours,
                 address: self.final_address_self.clone(),
@@ -256,15 +328,37 @@ impl State0 {
                 amount: balance.theirs,
                 address: self.final_address_other.clone(),
             },
-        ])?;
+        ];
+        split_outputs.extend(self.previous_ptlcs.into_iter().map(SplitOutput::Ptlc));
+
+        let tx_s = SplitTransaction::new(&tx_c, split_outputs.clone())?;
         let sig_tx_s_self = tx_s.sign(&self.x_self);
 
-        Ok(State1 {
+        // The transactions spending the PTLC outputs must be signed again,
+        // since they spend the new `SplitTransaction`
+        let sign_ptlcs = SignPtlcs::new(
+            &self.x_self,
+            &self.X_other,
+            &tx_s,
+            &split_outputs,
+            &self.final_address_self,
+            &self.final_address_other,
+        )?;
+
+        // The revoked states of the previous funding transaction can still be
+        // published until the splice transaction is confirmed
+        let mut revoked_states = self.revoked_states;
+        revoked_states.splice(self.previous_tx_f.clone(), splice_transaction.txid());
+
+        let state = State1 {
             x_self: self.x_self,
             X_other: self.X_other,
             final_address_self: self.final_address_self,
             final_address_other: self.final_address_other,
             balance,
+            state_number: self.state_number,
+            keys: self.keys,
+            revoked_states,
             r_self: self.r_self,
             R_other,
             y_self: self.y_self,
@@ -276,11 +370,64 @@ impl State0 {
             tx_s,
             encsig_tx_c_self,
             sig_tx_s_self,
+            ptlcs: Vec::new(),
             splice_self: self.splice_self,
+            fee_rate_bounds: self.fee_rate_bounds,
+            timeouts: self.timeouts,
+            protocol_version: self.protocol_version,
+        };
+
+        if sign_ptlcs.is_empty() {
+            Ok(State1Kind::State1(state))
+        } else {
+            Ok(State1Kind::State1Ptlcs(State1Ptlcs {
+                inner: state,
+                sign_ptlcs,
+            }))
+        }
+    }
+}
+
+/// The two possible states in which a party can be in after receiving the
+/// first message.
+///
+/// If the channel has any PTLC outputs, the party will transition to
+/// `State1Ptlcs` to exchange signatures for the transactions spending each of
+/// them from the new `SplitTransaction`.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub(crate) enum State1Kind {
+    State1(State1),
+    State1Ptlcs(State1Ptlcs),
+}
+
+/// A party who has exchanged `RevocationPublicKey`s and `PublishingPublicKey`s
+/// with the counterparty and is ready to start exchanging signatures for the
+/// `RedeemTransaction`s and `RefundTransaction`s of the PTLC outputs carried
+/// over to the new `SplitTransaction`.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+pub(crate) struct State1Ptlcs {
+    inner: State1,
+    sign_ptlcs: SignPtlcs,
+}
+
+impl State1Ptlcs {
+    pub fn compose(&self) -> SharePtlcSignatures {
+        self.sign_ptlcs.compose()
+    }
+
+    pub fn interpret(self, message: SharePtlcSignatures) -> Result<State1> {
+        let ptlcs = self.sign_ptlcs.interpret(message)?;
+
+        Ok(State1 {
+            ptlcs,
+            ..self.inner
         })
     }
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug)]
 pub(crate) struct State1 {
     x_self: OwnershipKeyPair,
@@ -288,6 +435,9 @@ pub(crate) struct State1 {
     final_address_self: Address,
     final_address_other: Address,
     balance: Balance,
+    state_number: u64,
+    keys: ChannelKeys,
+    revoked_states: RevokedStates,
     r_self: RevocationKeyPair,
     R_other: RevocationPublicKey,
     y_self: PublishingKeyPair,
@@ -299,7 +449,11 @@ pub(crate) struct State1 {
     tx_s: SplitTransaction,
     encsig_tx_c_self: EncryptedSignature,
     sig_tx_s_self: Signature,
+    ptlcs: Vec<SignedPtlc>,
     splice_self: Splice,
+    fee_rate_bounds: FeeRateBounds,
+    timeouts: Timeouts,
+    protocol_version: u16,
 }
 
 impl State1 {
@@ -330,6 +484,9 @@ impl State1 {
             final_address_self: self.final_address_self,
             final_address_other: self.final_address_other,
             balance: self.balance,
+            state_number: self.state_number,
+            keys: self.keys,
+            revoked_states: self.revoked_states,
             r_self: self.r_self,
             R_other: self.R_other,
             y_self: self.y_self,
@@ -340,11 +497,16 @@ impl State1 {
             tx_c: self.tx_c,
             signed_tx_s: self.tx_s,
             encsig_tx_c_self: self.encsig_tx_c_self,
+            ptlcs: self.ptlcs,
             splice_self: self.splice_self,
+            fee_rate_bounds: self.fee_rate_bounds,
+            timeouts: self.timeouts,
+            protocol_version: self.protocol_version,
         })
     }
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug)]
 pub(crate) struct State2 {
     x_self: OwnershipKeyPair,
@@ -352,6 +514,9 @@ pub(crate) struct State2 {
     final_address_self: Address,
     final_address_other: Address,
     balance: Balance,
+    state_number: u64,
+    keys: ChannelKeys,
+    revoked_states: RevokedStates,
     r_self: RevocationKeyPair,
     R_other: RevocationPublicKey,
     y_self: PublishingKeyPair,
@@ -362,7 +527,11 @@ pub(crate) struct State2 {
     tx_c: CommitTransaction,
     signed_tx_s: SplitTransaction,
     encsig_tx_c_self: EncryptedSignature,
+    ptlcs: Vec<SignedPtlc>,
     splice_self: Splice,
+    fee_rate_bounds: FeeRateBounds,
+    timeouts: Timeouts,
+    protocol_version: u16,
 }
 
 impl State2 {
@@ -372,12 +541,22 @@ impl State2 {
         }
     }
 
-    pub async fn interpret(
+    /// The `SpliceTransaction` for the wallet to sign our splice-in inputs of,
+    /// if we are splicing in.
+    pub fn splice_psbt_to_sign(&self) -> Result<Option<PartiallySignedTransaction>> {
+        if self.splice_self.splice_in.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(self.splice_transaction.clone().into_psbt()?))
+    }
+
+    pub fn interpret(
         self,
         Message2 {
             encsig_tx_c: encsig_tx_c_other,
         }: Message2,
-        wallet: &impl SignFundingPsbt,
+        signed_TX_splice_psbt_self_input: Option<PartiallySignedTransaction>,
     ) -> Result<State3> {
         self.tx_c
             .verify_encsig(
@@ -387,15 +566,10 @@ impl State2 {
             )
             .context("failed to verify encsig_tx_c sent by counterparty")?;
 
-        // Signed to spend the splice-in input
-        let signed_TX_splice_psbt_self_input = match self.splice_self {
-            Splice::In { .. } => Some(
-                wallet
-                    .sign_funding_psbt(self.splice_transaction.clone().into_psbt()?)
-                    .await?,
-            ),
-            _ => None,
-        };
+        // Signed to spend the splice-in inputs
+        if self.splice_self.splice_in.is_some() && signed_TX_splice_psbt_self_input.is_none() {
+            bail!("splice-in inputs were not signed")
+        }
 
         Ok(State3 {
             x_self: self.x_self,
@@ -403,6 +577,9 @@ impl State2 {
             final_address_self: self.final_address_self,
             final_address_other: self.final_address_other,
             balance: self.balance,
+            state_number: self.state_number,
+            keys: self.keys,
+            revoked_states: self.revoked_states,
             r_self: self.r_self,
             R_other: self.R_other,
             y_self: self.y_self,
@@ -414,11 +591,16 @@ impl State2 {
             signed_tx_s: self.signed_tx_s,
             encsig_tx_c_self: self.encsig_tx_c_self,
             encsig_tx_c_other,
+            ptlcs: self.ptlcs,
             signed_TX_splice_psbt_self_input,
+            fee_rate_bounds: self.fee_rate_bounds,
+            timeouts: self.timeouts,
+            protocol_version: self.protocol_version,
         })
     }
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug)]
 pub(crate) struct State3 {
     x_self: OwnershipKeyPair,
@@ -426,6 +608,9 @@ pub(crate) struct State3 {
     final_address_self: Address,
     final_address_other: Address,
     balance: Balance,
+    state_number: u64,
+    keys: ChannelKeys,
+    revoked_states: RevokedStates,
     r_self: RevocationKeyPair,
     R_other: RevocationPublicKey,
     y_self: PublishingKeyPair,
@@ -437,7 +622,16 @@ pub(crate) struct State3 {
     signed_tx_s: SplitTransaction,
     encsig_tx_c_self: EncryptedSignature,
     encsig_tx_c_other: EncryptedSignature,
+    ptlcs: Vec<SignedPtlc>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "crate::serde::partially_signed_transaction::option")
+    )]
     signed_TX_splice_psbt_self_input: Option<PartiallySignedTransaction>,
+    fee_rate_bounds: FeeRateBounds,
+    timeouts: Timeouts,
+    protocol_version: u16,
 }
 
 impl State3 {
@@ -448,58 +642,328 @@ impl State3 {
         }
     }
 
+    /// The `SpliceTransaction` for the wallet to sign our splice-in input of,
+    /// if we are splicing in.
+    pub fn splice_psbt_to_sign(
+        &self,
+        message: &Message3,
+    ) -> Result<Option<PartiallySignedTransaction>> {
+        match self.signed_TX_splice_psbt_self_input {
+            Some(_) => Ok(Some(self.splice_psbt(message)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn splice_psbt(&self, message: &Message3) -> Result<PartiallySignedTransaction> {
+        // If the other party sent a splice-in signed tx_f, use it once we know that it
+        // is the transaction we expect, otherwise, use our unsigned tx_f
+        match &message.signed_TX_splice_psbt_input {
+            Some(signed_splice_transaction_other) => {
+                self.splice_transaction
+                    .validate_psbt(signed_splice_transaction_other)
+                    .context("counterparty sent invalid splice transaction")?;
+
+                Ok(signed_splice_transaction_other.clone())
+            }
+            None => self.splice_transaction.clone().into_psbt(),
+        }
+    }
+
     /// Returns the Channel and the transaction to broadcast.
-    pub async fn interpret(
+    ///
+    /// If we are splicing in, `signed_splice_psbt` _must_ be the result of
+    /// signing our input of `splice_psbt_to_sign`.
+    pub fn interpret(
         self,
-        Message3 {
-            sig_TX_splice_TX_f_input: sig_TX_splice_TX_f_input_other,
-            signed_TX_splice_psbt_input: signed_TX_splice_psbt_input_other,
-        }: Message3,
-        wallet: &impl SignFundingPsbt,
+        message: Message3,
+        signed_splice_psbt: Option<PartiallySignedTransaction>,
     ) -> Result<(Channel, Transaction)> {
-        // TODO: Check that the received splice transaction is the same than we expect
-        // If the other party sent a splice-in signed tx_f, use it, otherwise, use our
-        // unsigned tx_f
-        let splice_transaction = match signed_TX_splice_psbt_input_other {
-            Some(signed_splice_transaction_other) => signed_splice_transaction_other,
-            None => self.splice_transaction.clone().into_psbt()?,
-        };
-
         // If we have a splice-in input, we need to sign it, otherwise, use the previous
         // tx_f
         let splice_transaction = match self.signed_TX_splice_psbt_self_input {
-            Some(_) => wallet.sign_funding_psbt(splice_transaction).await?,
-            None => splice_transaction,
+            Some(_) => signed_splice_psbt.context("splice-in input was not signed")?,
+            None => self.splice_psbt(&message)?,
         };
+        let sig_TX_splice_TX_f_input_other = message.sig_TX_splice_TX_f_input;
+
+        let channel = self.channel();
 
         // Add the signatures to spend the previous tx_f
         let splice_transaction = SpliceTransaction::add_signatures(
             splice_transaction.extract_tx(),
             self.previous_tx_f_output_descriptor,
             (self.x_self.public(), self.sig_TX_splice_TX_f_input),
-            (self.X_other.clone(), sig_TX_splice_TX_f_input_other),
+            (self.X_other, sig_TX_splice_TX_f_input_other),
         )?;
 
-        Ok((
-            Channel {
-                x_self: self.x_self,
-                X_other: self.X_other,
-                final_address_self: self.final_address_self,
-                final_address_other: self.final_address_other,
-                tx_f_body: self.splice_transaction.into(),
-                current_state: ChannelState::Standard(StandardChannelState {
-                    balance: self.balance,
-                    tx_c: self.tx_c,
-                    encsig_tx_c_other: self.encsig_tx_c_other,
-                    r_self: self.r_self,
-                    R_other: self.R_other,
-                    y_self: self.y_self,
-                    Y_other: self.Y_other,
-                    signed_tx_s: self.signed_tx_s,
-                }),
-                revoked_states: vec![],
+        Ok((channel, splice_transaction))
+    }
+
+    /// The spliced channel, which the counterparty can fund by publishing the
+    /// `SpliceTransaction` as soon as they have our signatures for it.
+    fn channel(&self) -> Channel {
+        let standard_state = StandardChannelState {
+            balance: self.balance,
+            tx_c: self.tx_c.clone(),
+            encsig_tx_c_other: self.encsig_tx_c_other.clone(),
+            r_self: self.r_self.clone(),
+            R_other: self.R_other.clone(),
+            y_self: self.y_self.clone(),
+            Y_other: self.Y_other.clone(),
+            signed_tx_s: self.signed_tx_s.clone(),
+        };
+        let current_state = if self.ptlcs.is_empty() {
+            ChannelState::Standard(standard_state)
+        } else {
+            ChannelState::WithPtlcs {
+                inner: standard_state,
+                ptlcs: self.ptlcs.clone(),
+            }
+        };
+
+        Channel {
+            x_self: self.x_self.clone(),
+            X_other: self.X_other.clone(),
+            final_address_self: self.final_address_self.clone(),
+            final_address_other: self.final_address_other.clone(),
+            tx_f_body: self.splice_transaction.clone().into(),
+            current_state,
+            state_number: self.state_number,
+            keys: self.keys.clone(),
+            revoked_states: self.revoked_states.clone(),
+            pending_state: None,
+            fee_rate_bounds: self.fee_rate_bounds,
+            timeouts: self.timeouts,
+            protocol_version: self.protocol_version,
+            pending_splice: None,
+        }
+    }
+}
+
+/// A channel splice in progress.
+///
+/// It can be persisted after every step of the protocol, so that the splice
+/// can be resumed from the same step if the process crashes.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+pub struct SpliceState(Step);
+
+#[allow(clippy::large_enum_variant)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+enum Step {
+    State0(State0),
+    State1(State1),
+    State1Ptlcs(State1Ptlcs),
+    State2(State2),
+    State3(State3),
+}
+
+impl SpliceState {
+    /// Start splicing the `channel`.
+    ///
+    /// The time lock, final addresses, balance, PTLC outputs and ownership
+    /// keys of the channel are re-used. The `wallet` funds the amount we splice
+    /// in, if any.
+    pub async fn new<W>(
+        channel: Channel,
+        fee_rate: FeeRate,
+        splice: crate::Splice,
+        wallet: &W,
+    ) -> Result<Self>
+    where
+        W: BuildFundingPsbt,
+    {
+        channel.ensure_no_pending_splice()?;
+
+        let input_psbt = if splice.amount_in > Amount::ZERO {
+            Some(
+                wallet
+                    .build_funding_psbt(channel.fund_address(), splice.amount_in)
+                    .await
+                    .map_err(Error::Wallet)?,
+            )
+        } else {
+            None
+        };
+
+        Self::with_input_psbt(channel, fee_rate, splice, input_psbt)
+    }
+
+    /// Start splicing the `channel`, without access to the wallet.
+    ///
+    /// The amount we splice in _must_ be paid to
+    /// `Channel::fund_address` by `input_psbt`.
+    pub fn with_input_psbt(
+        channel: Channel,
+        fee_rate: FeeRate,
+        splice: crate::Splice,
+        input_psbt: Option<PartiallySignedTransaction>,
+    ) -> Result<Self> {
+        channel.ensure_no_pending_splice()?;
+
+        let previous_ptlcs = channel
+            .current_state
+            .ptlcs()
+            .iter()
+            .map(|signed_ptlc| signed_ptlc.ptlc.clone())
+            .collect();
+        let current_state = StandardChannelState::from(channel.current_state);
+        let time_lock = current_state.time_lock();
+        let balance = current_state.balance;
+
+        let state = State0::new(
+            time_lock,
+            fee_rate,
+            channel.fee_rate_bounds,
+            channel.timeouts,
+            channel.protocol_version,
+            channel.final_address_self,
+            channel.final_address_other,
+            balance,
+            previous_ptlcs,
+            channel.tx_f_body,
+            channel.x_self,
+            channel.X_other,
+            channel.keys,
+            // The spliced channel keeps using the keys of the current state,
+            // so that the next update revokes the current state on both
+            // funding transactions at once
+            channel.state_number,
+            channel.revoked_states,
+            splice,
+            input_psbt,
+        )?;
+
+        Ok(SpliceState(Step::State0(state)))
+    }
+
+    /// Require the counterparty to splice in and out exactly as in `splice`,
+    /// e.g. the splice they proposed and we accepted.
+    pub(crate) fn expect_splice_other(self, splice: crate::Splice) -> Self {
+        match self.0 {
+            Step::State0(state) => SpliceState(Step::State0(State0 {
+                expected_splice_other: Some(splice),
+                ..state
+            })),
+            step => SpliceState(step),
+        }
+    }
+
+    /// Message to be sent to the counterparty in the current step.
+    pub fn compose(&self) -> Message {
+        match &self.0 {
+            Step::State0(state) => state.compose().into(),
+            Step::State1(state) => state.compose().into(),
+            Step::State1Ptlcs(state) => state.compose().into(),
+            Step::State2(state) => state.compose().into(),
+            Step::State3(state) => state.compose().into(),
+        }
+    }
+
+    /// Transition to the next step given the `message` received from the
+    /// counterparty. The protocol is done once the spliced `Channel` is
+    /// returned, together with the signed `SpliceTransaction` which must be
+    /// broadcast.
+    pub async fn interpret<W>(
+        self,
+        message: Message,
+        wallet: &W,
+    ) -> Result<Transition<Self, (Channel, Transaction)>>
+    where
+        W: BuildFundingPsbt + SignFundingPsbt,
+    {
+        let response = fulfil(self.interpret_request(&message)?, wallet).await?;
+
+        self.interpret_with(message, response)
+    }
+}
+
+impl Protocol for SpliceState {
+    type Output = (Channel, Transaction);
+
+    fn compose_with(&self, _: Option<PartiallySignedTransaction>) -> Result<Message> {
+        Ok(self.compose())
+    }
+
+    fn interpret_request(&self, message: &Message) -> Result<Option<WalletRequest>> {
+        let psbt = match (&self.0, message) {
+            (Step::State2(state), _) => state.splice_psbt_to_sign()?,
+            (Step::State3(state), Message::Splice3(message)) => {
+                state.splice_psbt_to_sign(message)?
+            }
+            _ => None,
+        };
+
+        Ok(psbt.map(WalletRequest::SignFundingPsbt))
+    }
+
+    fn interpret_with(
+        self,
+        message: Message,
+        response: Option<PartiallySignedTransaction>,
+    ) -> Result<Transition<Self, Self::Output>> {
+        let step = match self.0 {
+            Step::State0(state) => match state.interpret(message.try_into()?)? {
+                State1Kind::State1(state) => Step::State1(state),
+                State1Kind::State1Ptlcs(state) => Step::State1Ptlcs(state),
             },
-            splice_transaction,
+            Step::State1(state) => Step::State2(state.interpret(message.try_into()?)?),
+            Step::State1Ptlcs(state) => Step::State1(state.interpret(message.try_into()?)?),
+            Step::State2(state) => Step::State3(state.interpret(message.try_into()?, response)?),
+            Step::State3(state) => {
+                return Ok(Transition::Done(
+                    state.interpret(message.try_into()?, response)?,
+                ))
+            }
+        };
+
+        Ok(Transition::Next(SpliceState(step)))
+    }
+
+    fn funding_inputs(&self) -> Option<FundingInputs> {
+        let splice_transaction = match &self.0 {
+            Step::State0(State0 { splice_self, .. }) => {
+                return splice_self.input_psbt().map(FundingInputs::Unsigned)
+            }
+            Step::State1(State1 {
+                splice_self,
+                splice_transaction,
+                ..
+            })
+            | Step::State1Ptlcs(State1Ptlcs {
+                inner:
+                    State1 {
+                        splice_self,
+                        splice_transaction,
+                        ..
+                    },
+                ..
+            })
+            | Step::State2(State2 {
+                splice_self,
+                splice_transaction,
+                ..
+            }) if splice_self.splice_in.is_some() => splice_transaction,
+            // Our signature for the splice-in input was sent in `Message3`
+            Step::State3(State3 {
+                signed_TX_splice_psbt_self_input: Some(psbt),
+                ..
+            }) => return Some(FundingInputs::Signed(psbt.clone())),
+            _ => return None,
+        };
+
+        Some(FundingInputs::Unsigned(
+            splice_transaction.clone().into_psbt().ok()?,
         ))
     }
+
+    // Our signature for the input spending the previous funding transaction
+    // was sent in `Message3`
+    fn interrupted(&self) -> Option<Channel> {
+        match &self.0 {
+            Step::State3(state) => Some(state.channel()),
+            _ => None,
+        }
+    }
 }
@@ -1,7 +1,11 @@
 use crate::{
-    channel::{ChannelState, RevokedState, StandardChannelState},
+    channel::{
+        machine::Protocol, protocols::Transition, ChannelState, PendingSplice, RevokedStates,
+        SignedPtlc, StandardChannelState, Timeouts,
+    },
+    fee::{FeeRate, FeeRateBounds},
     keys::{
-        OwnershipKeyPair, OwnershipPublicKey, PublishingKeyPair, PublishingPublicKey,
+        ChannelKeys, OwnershipKeyPair, OwnershipPublicKey, PublishingKeyPair, PublishingPublicKey,
         RevocationKeyPair, RevocationPublicKey, RevocationSecretKey,
     },
     transaction::{
@@ -9,12 +13,13 @@ use crate::{
         ptlc::{RedeemTransaction, RefundTransaction},
         CommitTransaction, FundingTransaction, SplitTransaction,
     },
-    Channel, Ptlc, SplitOutput,
+    Channel, Message, Ptlc, PtlcPoint, SplitOutput,
 };
 use anyhow::{bail, Context, Result};
-use bitcoin::Address;
+use bitcoin::{util::psbt::PartiallySignedTransaction, Address};
 use ecdsa_fun::{adaptor::EncryptedSignature, Signature};
 use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
 
 /// First message of the channel update protocol.
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -22,6 +27,7 @@ use serde::{Deserialize, Serialize};
 pub struct ShareKeys {
     R: RevocationPublicKey,
     Y: PublishingPublicKey,
+    fee_rate: FeeRate,
 }
 
 /// Second message of the channel update protocol.
@@ -40,11 +46,12 @@ pub struct ShareCommitEncryptedSignature {
 
 /// Fourth and last message of the channel update protocol.
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct RevealRevocationSecretKey {
     r: RevocationSecretKey,
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug)]
 pub struct State0 {
     x_self: OwnershipKeyPair,
@@ -53,17 +60,29 @@ pub struct State0 {
     final_address_other: Address,
     tx_f_body: FundingTransaction,
     current_state: ChannelState,
-    revoked_states: Vec<RevokedState>,
+    state_number: u64,
+    keys: ChannelKeys,
+    revoked_states: RevokedStates,
+    fee_rate_bounds: FeeRateBounds,
+    timeouts: Timeouts,
+    protocol_version: u16,
+    pending_splice: Option<PendingSplice>,
     new_split_outputs: Vec<SplitOutput>,
     time_lock: u32,
+    fee_rate_self: FeeRate,
     r_self: RevocationKeyPair,
     y_self: PublishingKeyPair,
 }
 
 impl State0 {
-    pub fn new(channel: Channel, new_split_outputs: Vec<SplitOutput>, time_lock: u32) -> Self {
-        let r_self = RevocationKeyPair::new_random();
-        let y_self = PublishingKeyPair::new_random();
+    pub fn new(
+        channel: Channel,
+        new_split_outputs: Vec<SplitOutput>,
+        time_lock: u32,
+        fee_rate: FeeRate,
+    ) -> Self {
+        let r_self = channel.keys.revocation_key_pair(channel.state_number + 1);
+        let y_self = channel.keys.publishing_key_pair(channel.state_number + 1);
 
         Self {
             x_self: channel.x_self,
@@ -72,9 +91,16 @@ impl State0 {
             final_address_other: channel.final_address_other,
             tx_f_body: channel.tx_f_body,
             current_state: channel.current_state,
+            state_number: channel.state_number,
+            keys: channel.keys,
             revoked_states: channel.revoked_states,
+            fee_rate_bounds: channel.fee_rate_bounds,
+            timeouts: channel.timeouts,
+            protocol_version: channel.protocol_version,
+            pending_splice: channel.pending_splice,
             new_split_outputs,
             time_lock,
+            fee_rate_self: fee_rate,
             r_self,
             y_self,
         }
@@ -84,6 +110,7 @@ impl State0 {
         ShareKeys {
             R: self.r_self.public(),
             Y: self.y_self.public(),
+            fee_rate: self.fee_rate_self,
         }
     }
 
@@ -92,8 +119,14 @@ impl State0 {
         ShareKeys {
             R: R_other,
             Y: Y_other,
+            fee_rate: fee_rate_other,
         }: ShareKeys,
     ) -> Result<State1Kind> {
+        self.fee_rate_bounds
+            .validate(fee_rate_other)
+            .context("fee rate proposed by counterparty is not acceptable")?;
+        let fee_rate = self.fee_rate_self.agree(fee_rate_other);
+
         let tx_c = CommitTransaction::new(
             &self.tx_f_body,
             [
@@ -105,6 +138,7 @@ impl State0 {
                 (self.X_other.clone(), R_other.clone(), Y_other.clone()),
             ],
             self.time_lock,
+            fee_rate,
         )?;
         let encsig_tx_c_self = tx_c.encsign(&self.x_self, Y_other.clone());
 
@@ -112,14 +146,20 @@ impl State0 {
         let sig_tx_s_self = tx_s.sign(&self.x_self);
 
         let state = State1 {
-            x_self: self.x_self.clone(),
+            x_self: self.x_self,
             X_other: self.X_other,
             final_address_self: self.final_address_self,
             final_address_other: self.final_address_other,
             tx_f: self.tx_f_body,
             current_state: self.current_state,
+            state_number: self.state_number,
+            keys: self.keys,
             revoked_states: self.revoked_states,
-            new_split_outputs: self.new_split_outputs.clone(),
+            fee_rate_bounds: self.fee_rate_bounds,
+            timeouts: self.timeouts,
+            protocol_version: self.protocol_version,
+            pending_splice: self.pending_splice,
+            new_split_outputs: self.new_split_outputs,
             r_self: self.r_self,
             R_other,
             y_self: self.y_self,
@@ -130,66 +170,215 @@ impl State0 {
             sig_tx_s_self,
         };
 
-        // NOTE: We assume that there's only one PTLC output
-        match self
-            .new_split_outputs
-            .into_iter()
-            .find_map(|output| match output {
-                SplitOutput::Ptlc(ptlc) => Some(ptlc),
-                SplitOutput::Balance { .. } => None,
-            }) {
-            None => Ok(State1Kind::State1(state)),
-            Some(ptlc) if ptlc.X_funder == self.x_self.public() => Ok(
-                State1Kind::State1PtlcFunder(State1PtlcFunder::new(state, ptlc)?),
-            ),
-            Some(ptlc) if ptlc.X_redeemer == self.x_self.public() => Ok(
-                State1Kind::State1PtlcRedeemer(State1PtlcRedeemer::new(state, ptlc)?),
-            ),
-            _ => bail!("ownership of PTLC output is not shared by X_self"),
+        let sign_ptlcs = SignPtlcs::new(
+            &state.x_self,
+            &state.X_other,
+            &state.tx_s,
+            &state.new_split_outputs,
+            &state.final_address_self,
+            &state.final_address_other,
+        )?;
+
+        if sign_ptlcs.is_empty() {
+            Ok(State1Kind::State1(state))
+        } else {
+            Ok(State1Kind::State1Ptlcs(State1Ptlcs {
+                inner: state,
+                sign_ptlcs,
+            }))
         }
     }
 }
 
-/// The three possible states in which a party can be in after receiving the
+/// The two possible states in which a party can be in after receiving the
 /// first message.
 ///
-/// If a `PtlcOutput` is found among the new `SplitOutput`s for the update,
-/// depending on whether the party is identified as the funder or the redeemer
-/// of said output, the party will transition to `State1PtlcFunder` or
-/// `State1PtlcRedeemer` respectively.
+/// If any `PtlcOutput`s are found among the new `SplitOutput`s for the update,
+/// the party will transition to `State1Ptlcs` to exchange signatures for the
+/// transactions spending each of them.
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum State1Kind {
     State1(State1),
-    State1PtlcFunder(State1PtlcFunder),
-    State1PtlcRedeemer(State1PtlcRedeemer),
+    State1Ptlcs(State1Ptlcs),
+}
+
+/// Message sent in a channel update protocol execution involving PTLC
+/// outputs. It contains the signatures of the sender for every PTLC output,
+/// depending on whether they are funding or redeeming it.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+pub struct SharePtlcSignatures {
+    funder: Vec<SignaturesPtlcFunder>,
+    redeemer: Vec<SignaturesPtlcRedeemer>,
 }
 
-/// Message sent by the PTLC funder in a channel update protocol execution
-/// involving a PTLC output.
+/// Signatures of the PTLC funder for the PTLC output identified by `point`.
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug)]
 pub struct SignaturesPtlcFunder {
+    point: PtlcPoint,
     encsig_tx_ptlc_redeem_funder: EncryptedSignature,
     sig_tx_ptlc_refund_funder: Signature,
 }
 
-/// Message sent by the PTLC redeemer in a channel update protocol execution
-/// involving a PTLC output.
+/// Signatures of the PTLC redeemer for the PTLC output identified by `point`.
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug)]
 pub struct SignaturesPtlcRedeemer {
+    point: PtlcPoint,
     sig_tx_ptlc_redeem_redeemer: Signature,
     sig_tx_ptlc_refund_redeemer: Signature,
 }
 
 /// A party who has exchanged `RevocationPublicKey`s and `PublishingPublicKey`s
 /// with the counterparty and is ready to start exchanging signatures for the
-/// `RedeemTransaction` and `RefundTransaction` involving a PTLC
-/// output which they are funding.
+/// `RedeemTransaction`s and `RefundTransaction`s of the PTLC outputs of the
+/// new `SplitTransaction`.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug)]
-pub struct State1PtlcFunder {
+pub struct State1Ptlcs {
     inner: State1,
+    sign_ptlcs: SignPtlcs,
+}
+
+impl State1Ptlcs {
+    pub fn compose(&self) -> SharePtlcSignatures {
+        self.sign_ptlcs.compose()
+    }
+
+    pub fn interpret(self, message: SharePtlcSignatures) -> Result<WithPtlcs<State1>> {
+        let ptlcs = self.sign_ptlcs.interpret(message)?;
+
+        Ok(WithPtlcs {
+            state: self.inner,
+            ptlcs,
+        })
+    }
+}
+
+/// Exchange of signatures for the `RedeemTransaction` and `RefundTransaction`
+/// of every PTLC output of a `SplitTransaction`.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+pub(crate) struct SignPtlcs {
+    x_self: OwnershipKeyPair,
+    X_other: OwnershipPublicKey,
+    funder: Vec<PtlcFunder>,
+    redeemer: Vec<PtlcRedeemer>,
+}
+
+impl SignPtlcs {
+    pub fn new(
+        x_self: &OwnershipKeyPair,
+        X_other: &OwnershipPublicKey,
+        tx_s: &SplitTransaction,
+        split_outputs: &[SplitOutput],
+        final_address_self: &Address,
+        final_address_other: &Address,
+    ) -> Result<Self> {
+        let mut funder = Vec::new();
+        let mut redeemer = Vec::new();
+        let mut points = Vec::<PtlcPoint>::new();
+
+        for ptlc in split_outputs.iter().filter_map(|output| match output {
+            SplitOutput::Ptlc(ptlc) => Some(ptlc),
+            SplitOutput::Balance { .. } => None,
+        }) {
+            let point = ptlc.point();
+            if points.contains(&point) {
+                bail!("PTLC outputs must not share the same point")
+            }
+            points.push(point);
+
+            if ptlc.X_funder == x_self.public() && ptlc.X_redeemer == *X_other {
+                funder.push(PtlcFunder::new(
+                    x_self,
+                    tx_s,
+                    ptlc.clone(),
+                    final_address_self,
+                    final_address_other,
+                )?);
+            } else if ptlc.X_redeemer == x_self.public() && ptlc.X_funder == *X_other {
+                redeemer.push(PtlcRedeemer::new(
+                    x_self,
+                    tx_s,
+                    ptlc.clone(),
+                    final_address_self,
+                    final_address_other,
+                )?);
+            } else {
+                bail!("ownership of PTLC output is not shared by X_self")
+            }
+        }
+
+        Ok(Self {
+            x_self: x_self.clone(),
+            X_other: X_other.clone(),
+            funder,
+            redeemer,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.funder.is_empty() && self.redeemer.is_empty()
+    }
+
+    pub fn compose(&self) -> SharePtlcSignatures {
+        SharePtlcSignatures {
+            funder: self.funder.iter().map(PtlcFunder::compose).collect(),
+            redeemer: self.redeemer.iter().map(PtlcRedeemer::compose).collect(),
+        }
+    }
+
+    /// Verify the signatures sent by the counterparty for every PTLC output.
+    ///
+    /// The counterparty redeems the PTLC outputs that we fund and vice versa,
+    /// so they _must_ send exactly one set of signatures for each of them.
+    pub fn interpret(
+        self,
+        SharePtlcSignatures {
+            funder: mut sigs_funder_other,
+            redeemer: mut sigs_redeemer_other,
+        }: SharePtlcSignatures,
+    ) -> Result<Vec<SignedPtlc>> {
+        if sigs_redeemer_other.len() != self.funder.len()
+            || sigs_funder_other.len() != self.redeemer.len()
+        {
+            bail!("counterparty sent signatures for an unexpected number of PTLC outputs")
+        }
+
+        let mut ptlcs = Vec::with_capacity(self.funder.len() + self.redeemer.len());
+
+        for state in self.funder {
+            let position = sigs_redeemer_other
+                .iter()
+                .position(|sigs| sigs.point == state.ptlc.point())
+                .context("counterparty did not send signatures for PTLC output")?;
+            let sigs = sigs_redeemer_other.remove(position);
+
+            ptlcs.push(state.interpret(&self.x_self, &self.X_other, sigs)?);
+        }
+
+        for state in self.redeemer {
+            let position = sigs_funder_other
+                .iter()
+                .position(|sigs| sigs.point == state.ptlc.point())
+                .context("counterparty did not send signatures for PTLC output")?;
+            let sigs = sigs_funder_other.remove(position);
+
+            ptlcs.push(state.interpret(&self.X_other, sigs)?);
+        }
+
+        Ok(ptlcs)
+    }
+}
+
+/// A party who is ready to exchange signatures for the `RedeemTransaction`
+/// and `RefundTransaction` involving a PTLC output which they are funding.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+struct PtlcFunder {
     ptlc: Ptlc,
     tx_ptlc_redeem: RedeemTransaction,
     tx_ptlc_refund: RefundTransaction,
@@ -197,18 +386,23 @@ pub struct State1PtlcFunder {
     sig_tx_ptlc_refund_funder: Signature,
 }
 
-impl State1PtlcFunder {
-    pub fn new(state: State1, ptlc: Ptlc) -> Result<Self> {
+impl PtlcFunder {
+    fn new(
+        x_self: &OwnershipKeyPair,
+        tx_s: &SplitTransaction,
+        ptlc: Ptlc,
+        final_address_self: &Address,
+        final_address_other: &Address,
+    ) -> Result<Self> {
         let tx_ptlc_redeem =
-            RedeemTransaction::new(&state.tx_s, ptlc.clone(), state.final_address_other.clone())?;
-        let encsig_tx_ptlc_redeem_funder = tx_ptlc_redeem.encsign(&state.x_self, ptlc.point());
+            RedeemTransaction::new(tx_s, ptlc.clone(), final_address_other.clone())?;
+        let encsig_tx_ptlc_redeem_funder = tx_ptlc_redeem.encsign(x_self, ptlc.point());
 
         let tx_ptlc_refund =
-            RefundTransaction::new(&state.tx_s, ptlc.clone(), state.final_address_self.clone())?;
-        let sig_tx_ptlc_refund_funder = tx_ptlc_refund.sign(&state.x_self);
+            RefundTransaction::new(tx_s, ptlc.clone(), final_address_self.clone())?;
+        let sig_tx_ptlc_refund_funder = tx_ptlc_refund.sign(x_self);
 
         Ok(Self {
-            inner: state,
             ptlc,
             tx_ptlc_redeem,
             tx_ptlc_refund,
@@ -217,35 +411,31 @@ impl State1PtlcFunder {
         })
     }
 
-    pub fn compose(&self) -> SignaturesPtlcFunder {
+    fn compose(&self) -> SignaturesPtlcFunder {
         SignaturesPtlcFunder {
+            point: self.ptlc.point(),
             encsig_tx_ptlc_redeem_funder: self.encsig_tx_ptlc_redeem_funder.clone(),
             sig_tx_ptlc_refund_funder: self.sig_tx_ptlc_refund_funder.clone(),
         }
     }
 
-    pub fn interpret(self, message: SignaturesPtlcRedeemer) -> Result<WithPtlc<State1>> {
+    fn interpret(
+        self,
+        x_self: &OwnershipKeyPair,
+        X_other: &OwnershipPublicKey,
+        message: SignaturesPtlcRedeemer,
+    ) -> Result<SignedPtlc> {
         self.tx_ptlc_refund
-            .verify_sig(
-                self.inner.X_other.clone(),
-                &message.sig_tx_ptlc_refund_redeemer,
-            )
+            .verify_sig(X_other.clone(), &message.sig_tx_ptlc_refund_redeemer)
             .context("failed to verify sig_tx_ptlc_refund sent by PTLC redeemer")?;
 
         let mut tx_ptlc_refund = self.tx_ptlc_refund;
         tx_ptlc_refund.add_signatures(
-            (
-                self.inner.x_self.public(),
-                self.sig_tx_ptlc_refund_funder.clone(),
-            ),
-            (
-                self.inner.X_other.clone(),
-                message.sig_tx_ptlc_refund_redeemer.clone(),
-            ),
+            (x_self.public(), self.sig_tx_ptlc_refund_funder.clone()),
+            (X_other.clone(), message.sig_tx_ptlc_refund_redeemer.clone()),
         )?;
 
-        Ok(WithPtlc {
-            state: self.inner,
+        Ok(SignedPtlc {
             ptlc: self.ptlc,
             tx_ptlc_redeem: self.tx_ptlc_redeem,
             tx_ptlc_refund,
@@ -257,13 +447,11 @@ impl State1PtlcFunder {
     }
 }
 
-/// A party who has exchanged `RevocationPublicKey`s and `PublishingPublicKey`s
-/// with the counterparty and is ready to start exchanging signatures for the
-/// `RedeemTransaction` and `RefundTransaction` involving a PTLC
-/// output which they are redeeming.
+/// A party who is ready to exchange signatures for the `RedeemTransaction`
+/// and `RefundTransaction` involving a PTLC output which they are redeeming.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug)]
-pub struct State1PtlcRedeemer {
-    inner: State1,
+struct PtlcRedeemer {
     ptlc: Ptlc,
     tx_ptlc_redeem: RedeemTransaction,
     tx_ptlc_refund: RefundTransaction,
@@ -271,18 +459,23 @@ pub struct State1PtlcRedeemer {
     sig_tx_ptlc_refund_redeemer: Signature,
 }
 
-impl State1PtlcRedeemer {
-    pub fn new(state: State1, ptlc: Ptlc) -> Result<Self> {
+impl PtlcRedeemer {
+    fn new(
+        x_self: &OwnershipKeyPair,
+        tx_s: &SplitTransaction,
+        ptlc: Ptlc,
+        final_address_self: &Address,
+        final_address_other: &Address,
+    ) -> Result<Self> {
         let tx_ptlc_redeem =
-            RedeemTransaction::new(&state.tx_s, ptlc.clone(), state.final_address_self.clone())?;
-        let sig_tx_ptlc_redeem_redeemer = tx_ptlc_redeem.sign(&state.x_self);
+            RedeemTransaction::new(tx_s, ptlc.clone(), final_address_self.clone())?;
+        let sig_tx_ptlc_redeem_redeemer = tx_ptlc_redeem.sign(x_self);
 
         let tx_ptlc_refund =
-            RefundTransaction::new(&state.tx_s, ptlc.clone(), state.final_address_other.clone())?;
-        let sig_tx_ptlc_refund_redeemer = tx_ptlc_refund.sign(&state.x_self);
+            RefundTransaction::new(tx_s, ptlc.clone(), final_address_other.clone())?;
+        let sig_tx_ptlc_refund_redeemer = tx_ptlc_refund.sign(x_self);
 
         Ok(Self {
-            inner: state,
             ptlc,
             tx_ptlc_redeem,
             tx_ptlc_refund,
@@ -291,24 +484,28 @@ impl State1PtlcRedeemer {
         })
     }
 
-    pub fn compose(&self) -> SignaturesPtlcRedeemer {
+    fn compose(&self) -> SignaturesPtlcRedeemer {
         SignaturesPtlcRedeemer {
+            point: self.ptlc.point(),
             sig_tx_ptlc_redeem_redeemer: self.sig_tx_ptlc_redeem_redeemer.clone(),
             sig_tx_ptlc_refund_redeemer: self.sig_tx_ptlc_refund_redeemer.clone(),
         }
     }
 
-    pub fn interpret(self, message: SignaturesPtlcFunder) -> Result<WithPtlc<State1>> {
+    fn interpret(
+        self,
+        X_other: &OwnershipPublicKey,
+        message: SignaturesPtlcFunder,
+    ) -> Result<SignedPtlc> {
         self.tx_ptlc_redeem
             .verify_encsig(
-                self.inner.X_other.clone(),
+                X_other.clone(),
                 self.ptlc.point().into(),
                 &message.encsig_tx_ptlc_redeem_funder,
             )
             .context("failed to verify encsig_tx_ptlc_redeem sent by PTLC funder")?;
 
-        Ok(WithPtlc {
-            state: self.inner,
+        Ok(SignedPtlc {
             ptlc: self.ptlc,
             tx_ptlc_redeem: self.tx_ptlc_redeem,
             tx_ptlc_refund: self.tx_ptlc_refund,
@@ -322,6 +519,7 @@ impl State1PtlcRedeemer {
 
 /// A party who has exchanged `RevocationPublicKey`s and `PublishingPublicKey`s
 /// with the counterparty and is ready to start exchanging signatures.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Clone, Debug)]
 pub struct State1 {
     x_self: OwnershipKeyPair,
@@ -330,7 +528,13 @@ pub struct State1 {
     final_address_other: Address,
     tx_f: FundingTransaction,
     current_state: ChannelState,
-    revoked_states: Vec<RevokedState>,
+    state_number: u64,
+    keys: ChannelKeys,
+    revoked_states: RevokedStates,
+    fee_rate_bounds: FeeRateBounds,
+    timeouts: Timeouts,
+    protocol_version: u16,
+    pending_splice: Option<PendingSplice>,
     new_split_outputs: Vec<SplitOutput>,
     r_self: RevocationKeyPair,
     R_other: RevocationPublicKey,
@@ -371,7 +575,13 @@ impl State1 {
             final_address_other: self.final_address_other,
             tx_f: self.tx_f,
             current_state: self.current_state,
+            state_number: self.state_number,
+            keys: self.keys,
             revoked_states: self.revoked_states,
+            fee_rate_bounds: self.fee_rate_bounds,
+            timeouts: self.timeouts,
+            protocol_version: self.protocol_version,
+            pending_splice: self.pending_splice,
             new_split_outputs: self.new_split_outputs,
             r_self: self.r_self,
             R_other: self.R_other,
@@ -387,6 +597,7 @@ impl State1 {
 /// A party who has exchanged signatures for the `SplitTransaction`
 /// and is ready to start exchanging encrypted signatures for the
 /// `CommitTransaction`.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug)]
 pub struct State2 {
     x_self: OwnershipKeyPair,
@@ -395,7 +606,13 @@ pub struct State2 {
     final_address_other: Address,
     tx_f: FundingTransaction,
     current_state: ChannelState,
-    revoked_states: Vec<RevokedState>,
+    state_number: u64,
+    keys: ChannelKeys,
+    revoked_states: RevokedStates,
+    fee_rate_bounds: FeeRateBounds,
+    timeouts: Timeouts,
+    protocol_version: u16,
+    pending_splice: Option<PendingSplice>,
     new_split_outputs: Vec<SplitOutput>,
     r_self: RevocationKeyPair,
     R_other: RevocationPublicKey,
@@ -434,7 +651,13 @@ impl State2 {
             final_address_other: self.final_address_other,
             tx_f: self.tx_f,
             current_state: self.current_state,
+            state_number: self.state_number,
+            keys: self.keys,
             revoked_states: self.revoked_states,
+            fee_rate_bounds: self.fee_rate_bounds,
+            timeouts: self.timeouts,
+            protocol_version: self.protocol_version,
+            pending_splice: self.pending_splice,
             new_split_outputs: self.new_split_outputs,
             r_self: self.r_self,
             R_other: self.R_other,
@@ -451,6 +674,7 @@ impl State2 {
 /// A party who has exchanged all necessary signatures to complete a
 /// channel update and just needs to collaborate with the counterparty
 /// to revoke the previous `CommitTransaction`.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug)]
 pub struct State3 {
     x_self: OwnershipKeyPair,
@@ -459,7 +683,13 @@ pub struct State3 {
     final_address_other: Address,
     tx_f: FundingTransaction,
     current_state: ChannelState,
-    revoked_states: Vec<RevokedState>,
+    state_number: u64,
+    keys: ChannelKeys,
+    revoked_states: RevokedStates,
+    fee_rate_bounds: FeeRateBounds,
+    timeouts: Timeouts,
+    protocol_version: u16,
+    pending_splice: Option<PendingSplice>,
     new_split_outputs: Vec<SplitOutput>,
     r_self: RevocationKeyPair,
     R_other: RevocationPublicKey,
@@ -472,6 +702,45 @@ pub struct State3 {
 }
 
 impl State3 {
+    /// The state the channel transitions to once the update is completed.
+    pub(crate) fn next_state(&self) -> ChannelState {
+        ChannelState::Standard(StandardChannelState {
+            balance: balance(
+                self.new_split_outputs.clone(),
+                &self.final_address_self,
+                &self.final_address_other,
+            ),
+            tx_c: self.tx_c.clone(),
+            encsig_tx_c_other: self.encsig_tx_c_other.clone(),
+            r_self: self.r_self.clone(),
+            R_other: self.R_other.clone(),
+            y_self: self.y_self.clone(),
+            Y_other: self.Y_other.clone(),
+            signed_tx_s: self.signed_tx_s.clone(),
+        })
+    }
+
+    /// The channel before the update, keeping `next_state` as its pending
+    /// state.
+    fn interrupted(&self, next_state: ChannelState) -> Channel {
+        Channel {
+            x_self: self.x_self.clone(),
+            X_other: self.X_other.clone(),
+            final_address_self: self.final_address_self.clone(),
+            final_address_other: self.final_address_other.clone(),
+            tx_f_body: self.tx_f.clone(),
+            current_state: self.current_state.clone(),
+            state_number: self.state_number,
+            keys: self.keys.clone(),
+            revoked_states: self.revoked_states.clone(),
+            pending_state: Some(next_state),
+            fee_rate_bounds: self.fee_rate_bounds,
+            timeouts: self.timeouts,
+            protocol_version: self.protocol_version,
+            pending_splice: self.pending_splice.clone(),
+        }
+    }
+
     pub fn compose(&self) -> RevealRevocationSecretKey {
         RevealRevocationSecretKey {
             r: StandardChannelState::from(self.current_state.clone())
@@ -484,31 +753,15 @@ impl State3 {
         self,
         RevealRevocationSecretKey { r: r_other }: RevealRevocationSecretKey,
     ) -> Result<Channel> {
-        StandardChannelState::from(self.current_state.clone())
+        let current_state = self.next_state();
+
+        let revoked_state = StandardChannelState::from(self.current_state);
+        revoked_state
             .R_other
             .verify_revocation_secret_key(&r_other)?;
 
-        let revoked_state = RevokedState {
-            channel_state: self.current_state,
-            r_other,
-        };
         let mut revoked_states = self.revoked_states;
-        revoked_states.push(revoked_state);
-
-        let current_state = ChannelState::Standard(StandardChannelState {
-            balance: balance(
-                self.new_split_outputs,
-                &self.final_address_self,
-                &self.final_address_other,
-            ),
-            tx_c: self.tx_c,
-            encsig_tx_c_other: self.encsig_tx_c_other,
-            r_self: self.r_self,
-            R_other: self.R_other,
-            y_self: self.y_self,
-            Y_other: self.Y_other,
-            signed_tx_s: self.signed_tx_s,
-        });
+        revoked_states.insert(self.state_number, &revoked_state, &r_other)?;
 
         Ok(Channel {
             x_self: self.x_self,
@@ -517,66 +770,63 @@ impl State3 {
             final_address_other: self.final_address_other,
             tx_f_body: self.tx_f,
             current_state,
+            state_number: self.state_number + 1,
+            keys: self.keys,
             revoked_states,
+            pending_state: None,
+            fee_rate_bounds: self.fee_rate_bounds,
+            timeouts: self.timeouts,
+            protocol_version: self.protocol_version,
+            pending_splice: self.pending_splice,
         })
     }
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Clone, Debug)]
-pub struct WithPtlc<S> {
+pub struct WithPtlcs<S> {
     state: S,
-    ptlc: Ptlc,
-    tx_ptlc_redeem: RedeemTransaction,
-    tx_ptlc_refund: RefundTransaction,
-    encsig_tx_ptlc_redeem_funder: EncryptedSignature,
-    sig_tx_ptlc_redeem_redeemer: Signature,
-    sig_tx_ptlc_refund_funder: Signature,
-    sig_tx_ptlc_refund_redeemer: Signature,
+    ptlcs: Vec<SignedPtlc>,
 }
 
-impl WithPtlc<State1> {
+impl WithPtlcs<State1> {
     pub fn compose(&self) -> ShareSplitSignature {
         self.state.compose()
     }
 
-    pub fn interpret(self, message: ShareSplitSignature) -> Result<WithPtlc<State2>> {
+    pub fn interpret(self, message: ShareSplitSignature) -> Result<WithPtlcs<State2>> {
         let state = self.state.interpret(message)?;
 
-        Ok(WithPtlc {
+        Ok(WithPtlcs {
             state,
-            ptlc: self.ptlc,
-            tx_ptlc_redeem: self.tx_ptlc_redeem,
-            tx_ptlc_refund: self.tx_ptlc_refund,
-            encsig_tx_ptlc_redeem_funder: self.encsig_tx_ptlc_redeem_funder,
-            sig_tx_ptlc_redeem_redeemer: self.sig_tx_ptlc_redeem_redeemer,
-            sig_tx_ptlc_refund_funder: self.sig_tx_ptlc_refund_funder,
-            sig_tx_ptlc_refund_redeemer: self.sig_tx_ptlc_refund_redeemer,
+            ptlcs: self.ptlcs,
         })
     }
 }
 
-impl WithPtlc<State2> {
+impl WithPtlcs<State2> {
     pub fn compose(&self) -> ShareCommitEncryptedSignature {
         self.state.compose()
     }
 
-    pub fn interpret(self, message: ShareCommitEncryptedSignature) -> Result<WithPtlc<State3>> {
+    pub fn interpret(self, message: ShareCommitEncryptedSignature) -> Result<WithPtlcs<State3>> {
         let state = self.state.interpret(message)?;
 
-        Ok(WithPtlc {
+        Ok(WithPtlcs {
             state,
-            ptlc: self.ptlc,
-            tx_ptlc_redeem: self.tx_ptlc_redeem,
-            tx_ptlc_refund: self.tx_ptlc_refund,
-            encsig_tx_ptlc_redeem_funder: self.encsig_tx_ptlc_redeem_funder,
-            sig_tx_ptlc_redeem_redeemer: self.sig_tx_ptlc_redeem_redeemer,
-            sig_tx_ptlc_refund_funder: self.sig_tx_ptlc_refund_funder,
-            sig_tx_ptlc_refund_redeemer: self.sig_tx_ptlc_refund_redeemer,
+            ptlcs: self.ptlcs,
         })
     }
 }
 
-impl WithPtlc<State3> {
+impl WithPtlcs<State3> {
+    pub(crate) fn next_state(&self) -> ChannelState {
+        ChannelState::WithPtlcs {
+            inner: self.state.next_state().into(),
+            ptlcs: self.ptlcs.clone(),
+        }
+    }
+
     pub fn compose(&self) -> RevealRevocationSecretKey {
         self.state.compose()
     }
@@ -584,19 +834,132 @@ impl WithPtlc<State3> {
     pub fn interpret(self, message: RevealRevocationSecretKey) -> Result<Channel> {
         let mut channel = self.state.interpret(message)?;
 
-        let current_state = ChannelState::WithPtlc {
+        channel.current_state = ChannelState::WithPtlcs {
             inner: channel.current_state.into(),
-            ptlc: self.ptlc,
-            tx_ptlc_redeem: self.tx_ptlc_redeem,
-            tx_ptlc_refund: self.tx_ptlc_refund,
-            encsig_tx_ptlc_redeem_funder: self.encsig_tx_ptlc_redeem_funder,
-            sig_tx_ptlc_redeem_redeemer: self.sig_tx_ptlc_redeem_redeemer,
-            sig_tx_ptlc_refund_funder: self.sig_tx_ptlc_refund_funder,
-            sig_tx_ptlc_refund_redeemer: self.sig_tx_ptlc_refund_redeemer,
+            ptlcs: self.ptlcs,
         };
 
-        channel.current_state = current_state;
-
         Ok(channel)
     }
 }
+
+/// A channel update in progress.
+///
+/// It can be persisted after every step of the protocol, so that the update
+/// can be resumed from the same step if the process crashes.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+pub struct UpdateState(Step);
+
+#[allow(clippy::large_enum_variant)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+enum Step {
+    State0(State0),
+    State1(State1),
+    State1Ptlcs(State1Ptlcs),
+    State1WithPtlcs(WithPtlcs<State1>),
+    State2(State2),
+    State2WithPtlcs(WithPtlcs<State2>),
+    State3(State3),
+    State3WithPtlcs(WithPtlcs<State3>),
+}
+
+impl From<State0> for UpdateState {
+    fn from(from: State0) -> Self {
+        UpdateState(Step::State0(from))
+    }
+}
+
+impl UpdateState {
+    /// Message to be sent to the counterparty in the current step.
+    pub fn compose(&self) -> Message {
+        match &self.0 {
+            Step::State0(state) => state.compose().into(),
+            Step::State1(state) => state.compose().into(),
+            Step::State1Ptlcs(state) => state.compose().into(),
+            Step::State1WithPtlcs(state) => state.compose().into(),
+            Step::State2(state) => state.compose().into(),
+            Step::State2WithPtlcs(state) => state.compose().into(),
+            Step::State3(state) => state.compose().into(),
+            Step::State3WithPtlcs(state) => state.compose().into(),
+        }
+    }
+
+    /// Transition to the next step given the `message` received from the
+    /// counterparty. The protocol is done once the updated `Channel` is
+    /// returned.
+    pub fn interpret(self, message: Message) -> Result<Transition<Self, Channel>> {
+        let step = match self.0 {
+            Step::State0(state) => match state.interpret(message.try_into()?)? {
+                State1Kind::State1(state) => Step::State1(state),
+                State1Kind::State1Ptlcs(state) => Step::State1Ptlcs(state),
+            },
+            Step::State1(state) => Step::State2(state.interpret(message.try_into()?)?),
+            Step::State1Ptlcs(state) => {
+                Step::State1WithPtlcs(state.interpret(message.try_into()?)?)
+            }
+            Step::State1WithPtlcs(state) => {
+                Step::State2WithPtlcs(state.interpret(message.try_into()?)?)
+            }
+            Step::State2(state) => Step::State3(state.interpret(message.try_into()?)?),
+            Step::State2WithPtlcs(state) => {
+                Step::State3WithPtlcs(state.interpret(message.try_into()?)?)
+            }
+            Step::State3(state) => {
+                return Ok(Transition::Done(state.interpret(message.try_into()?)?))
+            }
+            Step::State3WithPtlcs(state) => {
+                return Ok(Transition::Done(state.interpret(message.try_into()?)?))
+            }
+        };
+
+        Ok(Transition::Next(UpdateState(step)))
+    }
+
+    /// The state the channel transitions to, once both parties have
+    /// exchanged all the signatures for it.
+    pub(crate) fn next_state(&self) -> Option<ChannelState> {
+        match &self.0 {
+            Step::State3(state) => Some(state.next_state()),
+            Step::State3WithPtlcs(state) => Some(state.next_state()),
+            _ => None,
+        }
+    }
+
+    /// The channel which must replace the one the update started from if
+    /// the update is interrupted in the current step.
+    ///
+    /// Once both parties have exchanged all the signatures for the next
+    /// state, the counterparty may be able to revoke the current one, so the
+    /// next state is kept as the pending state of the channel until it is
+    /// reestablished. Before that, `None` is returned and the channel the
+    /// update started from is still valid.
+    pub fn interrupted(&self) -> Option<Channel> {
+        match &self.0 {
+            Step::State3(state) => Some(state.interrupted(state.next_state())),
+            Step::State3WithPtlcs(state) => Some(state.state.interrupted(state.next_state())),
+            _ => None,
+        }
+    }
+}
+
+impl Protocol for UpdateState {
+    type Output = Channel;
+
+    fn compose_with(&self, _: Option<PartiallySignedTransaction>) -> Result<Message> {
+        Ok(self.compose())
+    }
+
+    fn interpret_with(
+        self,
+        message: Message,
+        _: Option<PartiallySignedTransaction>,
+    ) -> Result<Transition<Self, Self::Output>> {
+        self.interpret(message)
+    }
+
+    fn interrupted(&self) -> Option<Channel> {
+        UpdateState::interrupted(self)
+    }
+}
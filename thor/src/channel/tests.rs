@@ -1,14 +1,618 @@
 pub mod harness;
 
-use crate::{MedianTime, PtlcSecret, TX_FEE};
+#[cfg(feature = "serde")]
+use crate::watchtower::JusticeKit;
+use crate::{
+    channel::{
+        machine::{self, Action, UpdateMachine},
+        protocols::propose::{ProposeSplice, ProposeUpdate},
+        AcceptAll, ReceiveMessage, SendMessage, SpliceState, StandardChannelState, Timeouts,
+        Transition,
+    },
+    fee::{
+        ANCHOR_OUTPUT_VALUE, COMMIT_TRANSACTION_WEIGHT, PTLC_TRANSACTION_WEIGHT,
+        PUNISH_TRANSACTION_WEIGHT,
+    },
+    swap::{SafetyMargins, SwapConfig},
+    watchtower::{Event, WatchTransactions, Watchtower},
+    Balance, Channel, Confirmation, Error, FeeRateBounds, GetConfirmations, KeyManager, MedianTime,
+    Message, PtlcSecret, Splice, WatchConfirmations,
+};
 use harness::{
-    create_channels, init_bitcoind, init_cli, swap_beta_ptlc_bob, update_balances, FUND,
+    create_channels, fee_deduction_per_split_output, generate_balances, init_bitcoind, init_cli,
+    make_transports, make_wallets, swap_beta_ptlc_bob, update_balances, Transport, FEE_RATE, FUND,
 };
 
-use bitcoin::Amount;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use bitcoin::{Amount, OutPoint, Transaction, Txid};
+
+/// Chain source which reports a single transaction as the one spending any
+/// outpoint.
+struct SpendingTransaction(Transaction);
+
+#[async_trait]
+impl WatchTransactions for SpendingTransaction {
+    async fn watch_for_spending_transaction(&self, _: OutPoint) -> Result<Transaction> {
+        Ok(self.0.clone())
+    }
+}
+
+/// Chain source which only reports the spending of a single outpoint, which is
+/// spent by `transaction`.
+struct SpentOutpoint {
+    outpoint: OutPoint,
+    transaction: Transaction,
+}
+
+#[async_trait]
+impl WatchTransactions for SpentOutpoint {
+    async fn watch_for_spending_transaction(&self, outpoint: OutPoint) -> Result<Transaction> {
+        if outpoint != self.outpoint {
+            futures::future::pending::<()>().await;
+        }
+
+        Ok(self.transaction.clone())
+    }
+}
+
+/// Chain source which reports the same number of confirmations for every
+/// transaction.
+struct Confirmations(u32);
+
+#[async_trait]
+impl GetConfirmations for Confirmations {
+    async fn get_confirmations(&self, _: Txid) -> Result<u32> {
+        Ok(self.0)
+    }
+}
+
+/// Chain source which reports the same outcome for every transaction
+/// watched.
+struct Watched(Confirmation);
+
+#[async_trait]
+impl WatchConfirmations for Watched {
+    async fn watch_for_confirmations(&self, _: Txid, _: u32) -> Result<Confirmation> {
+        Ok(self.0)
+    }
+}
+
+/// Transport which loses the connection after receiving a number of
+/// messages.
+struct InterruptedTransport<'a> {
+    transport: &'a mut Transport,
+    messages_left: usize,
+}
+
+#[async_trait]
+impl SendMessage for InterruptedTransport<'_> {
+    async fn send_message(&mut self, message: Message) -> Result<()> {
+        self.transport.send_message(message).await
+    }
+}
+
+#[async_trait]
+impl ReceiveMessage for InterruptedTransport<'_> {
+    async fn receive_message(&mut self) -> Result<Message> {
+        if self.messages_left == 0 {
+            bail!("connection lost")
+        }
+        self.messages_left -= 1;
+
+        self.transport.receive_message().await
+    }
+}
+
+#[tokio::test]
+async fn e2e_punish_publication_of_revoked_commit_transaction() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (
+        mut a_channel,
+        mut b_channel,
+        mut a_transport,
+        mut b_transport,
+        a_wallet,
+        b_wallet,
+        time_lock,
+        _,
+    ) = create_channels(&bitcoind).await;
+
+    let a_balance_after_open = a_wallet.balance().await.unwrap();
+    let b_balance_after_open = b_wallet.balance().await.unwrap();
+
+    // Parties agree on a new channel balance: Alice pays 0.5 a Bitcoin to Bob
+    let payment = Amount::from_btc(0.5).unwrap();
+    let a_balance = FUND - payment;
+    let b_balance = FUND + payment;
+
+    // Alice keeps the commit transaction of the state that is about to be revoked.
+    let signed_revoked_tx_c = a_channel.signed_tx_c().unwrap();
+
+    update_balances(
+        &mut a_channel,
+        &mut b_channel,
+        &mut a_transport,
+        &mut b_transport,
+        &a_wallet,
+        &b_wallet,
+        a_balance,
+        b_balance,
+        time_lock,
+    )
+    .await;
+
+    // Alice attempts to cheat by publishing a revoked commit transaction.
+    a_wallet
+        .0
+        .send_raw_transaction(signed_revoked_tx_c.clone())
+        .await
+        .unwrap();
+
+    // Bob sees the transaction and punishes Alice.
+    b_channel
+        .punish(&b_wallet, signed_revoked_tx_c)
+        .await
+        .unwrap();
+
+    let a_balance_after_punish = a_wallet.balance().await.unwrap();
+    let b_balance_after_punish = b_wallet.balance().await.unwrap();
+
+    assert_eq!(
+        a_balance_after_punish, a_balance_after_open,
+        "Alice should get no money back after being punished"
+    );
+    assert_eq!(
+        b_balance_after_punish,
+        b_balance_after_open + FUND * 2
+            - FEE_RATE.fee(COMMIT_TRANSACTION_WEIGHT)
+            - Amount::from_sat(ANCHOR_OUTPUT_VALUE) * 2
+            - FEE_RATE.fee(PUNISH_TRANSACTION_WEIGHT),
+        "Bob should get all the money back after punishing Alice"
+    );
+}
+
+#[tokio::test]
+async fn punish_publication_of_commit_transaction_revoked_before_splice() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (
+        mut a_channel,
+        mut b_channel,
+        mut a_transport,
+        mut b_transport,
+        a_wallet,
+        b_wallet,
+        time_lock,
+        _,
+    ) = create_channels(&bitcoind).await;
+
+    let b_balance_after_open = b_wallet.balance().await.unwrap();
+
+    // Alice keeps the commit transaction of the state that is about to be revoked.
+    let signed_revoked_tx_c = a_channel.signed_tx_c().unwrap();
+
+    let payment = Amount::from_btc(0.5).unwrap();
+    update_balances(
+        &mut a_channel,
+        &mut b_channel,
+        &mut a_transport,
+        &mut b_transport,
+        &a_wallet,
+        &b_wallet,
+        FUND - payment,
+        FUND + payment,
+        time_lock,
+    )
+    .await;
+
+    // Both parties sign a splice transaction, which is not broadcast
+    let timeout = Timeouts::default().splice;
+    let a_splice = SpliceState::new(a_channel, FEE_RATE, Splice::default(), &a_wallet)
+        .await
+        .unwrap();
+    let b_splice = SpliceState::new(b_channel, FEE_RATE, Splice::default(), &b_wallet)
+        .await
+        .unwrap();
+    let (_, (mut b_channel, _)) = futures::future::try_join(
+        machine::run(a_splice, &mut a_transport, &a_wallet, timeout),
+        machine::run(b_splice, &mut b_transport, &b_wallet, timeout),
+    )
+    .await
+    .unwrap();
+
+    // The revoked state is kept while the splice transaction is not confirmed
+    // deep enough
+    b_channel
+        .forget_spliced_fundings(&Confirmations(0), 6)
+        .await
+        .unwrap();
+    assert!(b_channel.is_revoked_tx_c(signed_revoked_tx_c.txid()));
+
+    // Alice attempts to cheat by publishing a revoked commit transaction
+    // spending the previous fund transaction.
+    a_wallet
+        .0
+        .send_raw_transaction(signed_revoked_tx_c.clone())
+        .await
+        .unwrap();
+
+    // Bob sees the transaction and punishes Alice.
+    b_channel
+        .punish(&b_wallet, signed_revoked_tx_c.clone())
+        .await
+        .unwrap();
+
+    let b_balance_after_punish = b_wallet.balance().await.unwrap();
+
+    assert_eq!(
+        b_balance_after_punish,
+        b_balance_after_open + FUND * 2
+            - FEE_RATE.fee(COMMIT_TRANSACTION_WEIGHT)
+            - Amount::from_sat(ANCHOR_OUTPUT_VALUE) * 2
+            - FEE_RATE.fee(PUNISH_TRANSACTION_WEIGHT),
+        "Bob should get all the money back after punishing Alice"
+    );
+
+    // Once the splice transaction is deep enough, the revoked state is
+    // forgotten
+    b_channel
+        .forget_spliced_fundings(&Confirmations(6), 6)
+        .await
+        .unwrap();
+    assert!(!b_channel.is_revoked_tx_c(signed_revoked_tx_c.txid()));
+}
+
+#[tokio::test]
+async fn update_channel_on_both_funding_transactions_while_splice_is_pending() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (a_channel, b_channel, mut a_transport, mut b_transport, a_wallet, b_wallet, time_lock, _) =
+        create_channels(&bitcoind).await;
+
+    // Alice keeps the commit transaction of the current state on the funding
+    // transaction which is about to be spliced.
+    let signed_tx_c_before_splice = a_channel.signed_tx_c().unwrap();
+
+    let a_splice_in = Amount::from_btc(0.5).unwrap();
+    let a_splice = a_channel.splice(&mut a_transport, &a_wallet, Splice::splice_in(a_splice_in));
+    let b_splice = b_channel.splice(&mut b_transport, &b_wallet, Splice::default());
+    let (mut a_channel, mut b_channel) =
+        futures::future::try_join(a_splice, b_splice).await.unwrap();
+
+    assert_eq!(a_channel.pending_splice().unwrap().balance(), Balance {
+        ours: FUND,
+        theirs: FUND
+    });
+
+    let payment = Amount::from_btc(0.2).unwrap();
+    update_balances(
+        &mut a_channel,
+        &mut b_channel,
+        &mut a_transport,
+        &mut b_transport,
+        &a_wallet,
+        &b_wallet,
+        FUND + a_splice_in - payment,
+        FUND + payment,
+        time_lock,
+    )
+    .await;
+
+    // The payment is made on both funding transactions
+    assert_eq!(a_channel.balance(), Balance {
+        ours: FUND + a_splice_in - payment,
+        theirs: FUND + payment
+    });
+    assert_eq!(a_channel.pending_splice().unwrap().balance(), Balance {
+        ours: FUND - payment,
+        theirs: FUND + payment
+    });
+    assert_eq!(b_channel.pending_splice().unwrap().balance(), Balance {
+        ours: FUND + payment,
+        theirs: FUND - payment
+    });
+
+    // The state of before the splice is revoked on the previous funding
+    // transaction too
+    assert!(b_channel.is_revoked_tx_c(signed_tx_c_before_splice.txid()));
+
+    // Once the splice transaction is confirmed, the spliced channel is the
+    // only one left
+    let a_confirmation = a_channel
+        .resolve_pending_splice(&Watched(Confirmation::Confirmed), 1)
+        .await
+        .unwrap();
+    let b_confirmation = b_channel
+        .resolve_pending_splice(&Watched(Confirmation::Confirmed), 1)
+        .await
+        .unwrap();
+
+    assert_eq!(a_confirmation, Confirmation::Confirmed);
+    assert_eq!(b_confirmation, Confirmation::Confirmed);
+    assert!(a_channel.pending_splice().is_none());
+    assert!(b_channel.pending_splice().is_none());
+    assert_eq!(a_channel.balance(), Balance {
+        ours: FUND + a_splice_in - payment,
+        theirs: FUND + payment
+    });
+}
+
+#[tokio::test]
+async fn fall_back_to_previous_funding_transaction_if_splice_is_double_spent() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (a_channel, b_channel, mut a_transport, mut b_transport, a_wallet, b_wallet, time_lock, _) =
+        create_channels(&bitcoind).await;
+
+    let tx_f_txid_before_splice = a_channel.tx_f_txid();
+
+    let a_splice_in = Amount::from_btc(0.5).unwrap();
+    let a_splice = a_channel.splice(&mut a_transport, &a_wallet, Splice::splice_in(a_splice_in));
+    let b_splice = b_channel.splice(&mut b_transport, &b_wallet, Splice::default());
+    let (mut a_channel, mut b_channel) =
+        futures::future::try_join(a_splice, b_splice).await.unwrap();
+
+    // The channel cannot be spliced again while the splice is pending
+    let error = a_channel
+        .clone()
+        .splice(&mut a_transport, &a_wallet, Splice::default())
+        .await
+        .unwrap_err();
+    assert!(matches!(error, Error::Validation(_)));
+
+    let payment = Amount::from_btc(0.2).unwrap();
+    update_balances(
+        &mut a_channel,
+        &mut b_channel,
+        &mut a_transport,
+        &mut b_transport,
+        &a_wallet,
+        &b_wallet,
+        FUND + a_splice_in - payment,
+        FUND + payment,
+        time_lock,
+    )
+    .await;
+
+    // The splice-in input of Alice is spent elsewhere, so the channel falls
+    // back to the previous funding transaction, keeping the payment
+    let a_confirmation = a_channel
+        .resolve_pending_splice(&Watched(Confirmation::DoubleSpent), 1)
+        .await
+        .unwrap();
+    let b_confirmation = b_channel
+        .resolve_pending_splice(&Watched(Confirmation::DoubleSpent), 1)
+        .await
+        .unwrap();
+
+    assert_eq!(a_confirmation, Confirmation::DoubleSpent);
+    assert_eq!(b_confirmation, Confirmation::DoubleSpent);
+    assert!(a_channel.pending_splice().is_none());
+    assert_eq!(a_channel.tx_f_txid(), tx_f_txid_before_splice);
+    assert_eq!(b_channel.tx_f_txid(), tx_f_txid_before_splice);
+    assert_eq!(a_channel.balance(), Balance {
+        ours: FUND - payment,
+        theirs: FUND + payment
+    });
+    assert_eq!(b_channel.balance(), Balance {
+        ours: FUND + payment,
+        theirs: FUND - payment
+    });
+}
+
+#[tokio::test]
+async fn watchtower_punishes_publication_of_revoked_commit_transaction() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (
+        mut a_channel,
+        mut b_channel,
+        mut a_transport,
+        mut b_transport,
+        a_wallet,
+        b_wallet,
+        time_lock,
+        _,
+    ) = create_channels(&bitcoind).await;
+
+    let b_balance_after_open = b_wallet.balance().await.unwrap();
+
+    let b_watchtower = Watchtower::new(b_channel.clone());
+
+    // Alice keeps the commit transaction of the state that is about to be revoked.
+    let signed_revoked_tx_c = a_channel.signed_tx_c().unwrap();
+
+    let payment = Amount::from_btc(0.5).unwrap();
+    update_balances(
+        &mut a_channel,
+        &mut b_channel,
+        &mut a_transport,
+        &mut b_transport,
+        &a_wallet,
+        &b_wallet,
+        FUND - payment,
+        FUND + payment,
+        time_lock,
+    )
+    .await;
+
+    b_watchtower.update_channel(b_channel.clone());
+
+    // Alice attempts to cheat by publishing a revoked commit transaction.
+    a_wallet
+        .0
+        .send_raw_transaction(signed_revoked_tx_c.clone())
+        .await
+        .unwrap();
+
+    // Bob's watchtower sees the transaction and punishes Alice.
+    let event = b_watchtower
+        .watch(&SpendingTransaction(signed_revoked_tx_c.clone()), &b_wallet)
+        .await
+        .unwrap();
+
+    assert_eq!(event, Event::Punished {
+        tx_c: signed_revoked_tx_c.txid()
+    });
+
+    let b_balance_after_punish = b_wallet.balance().await.unwrap();
+
+    assert_eq!(
+        b_balance_after_punish,
+        b_balance_after_open + FUND * 2
+            - FEE_RATE.fee(COMMIT_TRANSACTION_WEIGHT)
+            - Amount::from_sat(ANCHOR_OUTPUT_VALUE) * 2
+            - FEE_RATE.fee(PUNISH_TRANSACTION_WEIGHT),
+        "Bob should get all the money back after punishing Alice"
+    );
+}
+
+#[tokio::test]
+async fn watchtower_punishes_publication_of_commit_transaction_revoked_before_splice() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (
+        mut a_channel,
+        mut b_channel,
+        mut a_transport,
+        mut b_transport,
+        a_wallet,
+        b_wallet,
+        time_lock,
+        _,
+    ) = create_channels(&bitcoind).await;
+
+    // Alice keeps the commit transaction of the state that is about to be revoked.
+    let signed_revoked_tx_c = a_channel.signed_tx_c().unwrap();
+
+    let payment = Amount::from_btc(0.5).unwrap();
+    update_balances(
+        &mut a_channel,
+        &mut b_channel,
+        &mut a_transport,
+        &mut b_transport,
+        &a_wallet,
+        &b_wallet,
+        FUND - payment,
+        FUND + payment,
+        time_lock,
+    )
+    .await;
+
+    // Both parties sign a splice transaction, which is not broadcast
+    let timeout = Timeouts::default().splice;
+    let a_splice = SpliceState::new(a_channel, FEE_RATE, Splice::default(), &a_wallet)
+        .await
+        .unwrap();
+    let b_splice = SpliceState::new(b_channel, FEE_RATE, Splice::default(), &b_wallet)
+        .await
+        .unwrap();
+    let (_, (b_channel, _)) = futures::future::try_join(
+        machine::run(a_splice, &mut a_transport, &a_wallet, timeout),
+        machine::run(b_splice, &mut b_transport, &b_wallet, timeout),
+    )
+    .await
+    .unwrap();
+
+    let b_watchtower = Watchtower::new(b_channel);
+
+    // Alice attempts to cheat by publishing a revoked commit transaction
+    // spending the previous fund output.
+    a_wallet
+        .0
+        .send_raw_transaction(signed_revoked_tx_c.clone())
+        .await
+        .unwrap();
+
+    // Bob's watchtower sees the spending of the previous fund output and
+    // punishes Alice.
+    let chain = SpentOutpoint {
+        outpoint: signed_revoked_tx_c.input[0].previous_output,
+        transaction: signed_revoked_tx_c.clone(),
+    };
+    let event = b_watchtower.watch(&chain, &b_wallet).await.unwrap();
+
+    assert_eq!(event, Event::Punished {
+        tx_c: signed_revoked_tx_c.txid()
+    });
+}
+
+#[cfg(feature = "serde")]
+#[tokio::test]
+async fn third_party_punishes_publication_of_revoked_commit_transaction_with_justice_kit() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (
+        mut a_channel,
+        mut b_channel,
+        mut a_transport,
+        mut b_transport,
+        a_wallet,
+        b_wallet,
+        time_lock,
+        _,
+    ) = create_channels(&bitcoind).await;
+
+    let b_balance_after_open = b_wallet.balance().await.unwrap();
+
+    // Alice keeps the commit transaction of the state that is about to be revoked.
+    let signed_revoked_tx_c = a_channel.signed_tx_c().unwrap();
+
+    let payment = Amount::from_btc(0.5).unwrap();
+    update_balances(
+        &mut a_channel,
+        &mut b_channel,
+        &mut a_transport,
+        &mut b_transport,
+        &a_wallet,
+        &b_wallet,
+        FUND - payment,
+        FUND + payment,
+        time_lock,
+    )
+    .await;
+
+    // Bob hands over his justice kits to a third party.
+    let justice_kits = serde_cbor::to_vec(&b_channel.justice_kits().unwrap()).unwrap();
+    let justice_kits: Vec<JusticeKit> = serde_cbor::from_slice(&justice_kits).unwrap();
+
+    // Alice attempts to cheat by publishing a revoked commit transaction.
+    a_wallet
+        .0
+        .send_raw_transaction(signed_revoked_tx_c.clone())
+        .await
+        .unwrap();
+
+    // The third party sees the transaction and punishes Alice on Bob's behalf.
+    let hint = JusticeKit::hint_for(&signed_revoked_tx_c.txid());
+    let justice_kit = justice_kits
+        .iter()
+        .find(|kit| kit.hint() == hint)
+        .expect("justice kit for revoked commit transaction");
+    let punish_transaction = justice_kit.punish_transaction(signed_revoked_tx_c).unwrap();
+
+    a_wallet
+        .0
+        .send_raw_transaction(punish_transaction)
+        .await
+        .unwrap();
+
+    let b_balance_after_punish = b_wallet.balance().await.unwrap();
+
+    assert_eq!(
+        b_balance_after_punish,
+        b_balance_after_open + FUND * 2
+            - FEE_RATE.fee(COMMIT_TRANSACTION_WEIGHT)
+            - Amount::from_sat(ANCHOR_OUTPUT_VALUE) * 2
+            - FEE_RATE.fee(PUNISH_TRANSACTION_WEIGHT),
+        "Bob should get all the money back after the third party punishes Alice"
+    );
+}
 
 #[tokio::test]
-async fn e2e_punish_publication_of_revoked_commit_transaction() {
+async fn reestablish_channel_after_update_is_interrupted() {
     let cli = init_cli();
     let bitcoind = init_bitcoind(&cli).await;
     let (
@@ -22,51 +626,338 @@ async fn e2e_punish_publication_of_revoked_commit_transaction() {
         _,
     ) = create_channels(&bitcoind).await;
 
-    let a_balance_after_open = a_wallet.balance().await.unwrap();
-    let b_balance_after_open = b_wallet.balance().await.unwrap();
+    let revoked_tx_c_txid = a_channel.current_tx_c_txid();
 
-    // Parties agree on a new channel balance: Alice pays 0.5 a Bitcoin to Bob
     let payment = Amount::from_btc(0.5).unwrap();
-    let a_balance = FUND - payment;
-    let b_balance = FUND + payment;
+    let a_balance = Balance {
+        ours: FUND - payment,
+        theirs: FUND + payment,
+    };
+    let b_balance = Balance {
+        ours: FUND + payment,
+        theirs: FUND - payment,
+    };
+
+    // Alice loses the connection right before receiving Bob's revocation
+    // secret key, after having revealed hers.
+    let mut a_interrupted_transport = InterruptedTransport {
+        transport: &mut a_transport,
+        messages_left: 3,
+    };
+    let (a_update, b_update) = futures::future::join(
+        a_channel.update_balance(
+            &mut a_interrupted_transport,
+            &a_wallet,
+            a_balance,
+            time_lock,
+        ),
+        b_channel.update_balance(&mut b_transport, &b_wallet, b_balance, time_lock),
+    )
+    .await;
+
+    assert!(a_update.is_err());
+    assert!(b_update.is_ok());
+    assert_eq!(a_channel.state_number, 0);
+    assert!(a_channel.pending_state.is_some());
+    assert_eq!(b_channel.state_number, 1);
+
+    // Updating the channel is not possible until it is reestablished
+    let (mut a_transport, _b_transport) = make_transports();
+    assert!(a_channel
+        .update_balance(&mut a_transport, &a_wallet, a_balance, time_lock)
+        .await
+        .is_err());
+
+    let (mut a_transport, mut b_transport) = make_transports();
+    futures::future::try_join(
+        a_channel.reestablish(&mut a_transport),
+        b_channel.reestablish(&mut b_transport),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(a_channel.state_number, 1);
+    assert!(a_channel.pending_state.is_none());
+    assert_eq!(a_channel.balance(), a_balance);
+    assert_eq!(b_channel.balance(), b_balance);
+    assert!(a_channel.is_revoked_tx_c(revoked_tx_c_txid));
+    assert!(b_channel.is_revoked_tx_c(revoked_tx_c_txid));
 
+    // The channel can be updated again
     update_balances(
         &mut a_channel,
         &mut b_channel,
         &mut a_transport,
         &mut b_transport,
-        a_balance,
-        b_balance,
+        &a_wallet,
+        &b_wallet,
+        FUND,
+        FUND,
         time_lock,
     )
     .await;
+}
 
-    // Alice attempts to cheat by publishing a revoked commit transaction.
-    let signed_revoked_tx_c = a_channel.latest_revoked_signed_tx_c().unwrap().unwrap();
-    a_wallet
-        .0
-        .send_raw_transaction(signed_revoked_tx_c.clone())
-        .await
-        .unwrap();
+#[tokio::test]
+async fn update_is_rolled_back_if_interrupted_before_signing_on_previous_funding_transaction() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (a_channel, b_channel, mut a_transport, mut b_transport, a_wallet, b_wallet, time_lock, _) =
+        create_channels(&bitcoind).await;
 
-    // Bob sees the transaction and punishes Alice.
-    b_channel
-        .punish(&b_wallet, signed_revoked_tx_c)
-        .await
-        .unwrap();
+    // Alice keeps the commit transaction of the current state on the funding
+    // transaction which is about to be spliced.
+    let signed_tx_c_before_splice = a_channel.signed_tx_c().unwrap();
 
-    let a_balance_after_punish = a_wallet.balance().await.unwrap();
-    let b_balance_after_punish = b_wallet.balance().await.unwrap();
+    let a_splice_in = Amount::from_btc(0.5).unwrap();
+    let a_splice = a_channel.splice(&mut a_transport, &a_wallet, Splice::splice_in(a_splice_in));
+    let b_splice = b_channel.splice(&mut b_transport, &b_wallet, Splice::default());
+    let (mut a_channel, mut b_channel) =
+        futures::future::try_join(a_splice, b_splice).await.unwrap();
 
-    assert_eq!(
-        a_balance_after_punish, a_balance_after_open,
-        "Alice should get no money back after being punished"
+    let payment = Amount::from_btc(0.2).unwrap();
+    let a_balance = Balance {
+        ours: FUND + a_splice_in - payment,
+        theirs: FUND + payment,
+    };
+    let b_balance = Balance {
+        ours: FUND + payment,
+        theirs: FUND + a_splice_in - payment,
+    };
+
+    // Both parties lose the connection after signing the next state on the
+    // spliced funding transaction, while signing it on the previous one.
+    let mut a_interrupted_transport = InterruptedTransport {
+        transport: &mut a_transport,
+        messages_left: 4,
+    };
+    let mut b_interrupted_transport = InterruptedTransport {
+        transport: &mut b_transport,
+        messages_left: 4,
+    };
+    let (a_update, b_update) = futures::future::join(
+        a_channel.update_balance(
+            &mut a_interrupted_transport,
+            &a_wallet,
+            a_balance,
+            time_lock,
+        ),
+        b_channel.update_balance(
+            &mut b_interrupted_transport,
+            &b_wallet,
+            b_balance,
+            time_lock,
+        ),
+    )
+    .await;
+
+    assert!(a_update.is_err());
+    assert!(b_update.is_err());
+    assert!(a_channel.pending_state.is_some());
+    assert!(a_channel
+        .pending_splice()
+        .unwrap()
+        .previous
+        .pending_state
+        .is_none());
+
+    // The current state on the previous funding transaction is not revoked,
+    // so Alice can still fall back to it if the splice is double-spent
+    assert!(!b_channel.is_revoked_tx_c(signed_tx_c_before_splice.txid()));
+
+    let (mut a_transport, mut b_transport) = make_transports();
+    futures::future::try_join(
+        a_channel.reestablish(&mut a_transport),
+        b_channel.reestablish(&mut b_transport),
+    )
+    .await
+    .unwrap();
+
+    assert!(a_channel.pending_state.is_none());
+    assert_eq!(a_channel.balance(), Balance {
+        ours: FUND + a_splice_in,
+        theirs: FUND
+    });
+    assert_eq!(a_channel.pending_splice().unwrap().balance(), Balance {
+        ours: FUND,
+        theirs: FUND
+    });
+    assert!(!b_channel.is_revoked_tx_c(signed_tx_c_before_splice.txid()));
+
+    // The channel can be updated again, on both funding transactions
+    update_balances(
+        &mut a_channel,
+        &mut b_channel,
+        &mut a_transport,
+        &mut b_transport,
+        &a_wallet,
+        &b_wallet,
+        a_balance.ours,
+        a_balance.theirs,
+        time_lock,
+    )
+    .await;
+
+    assert_eq!(a_channel.pending_splice().unwrap().balance(), Balance {
+        ours: FUND - payment,
+        theirs: FUND + payment
+    });
+    assert!(b_channel.is_revoked_tx_c(signed_tx_c_before_splice.txid()));
+}
+
+#[tokio::test]
+async fn update_channel_by_feeding_messages_to_machines() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (a_channel, b_channel, _, _, _, _, time_lock, _) = create_channels(&bitcoind).await;
+
+    let payment = Amount::from_btc(0.5).unwrap();
+    let a_balance = Balance {
+        ours: FUND - payment,
+        theirs: FUND + payment,
+    };
+    let b_balance = Balance {
+        ours: FUND + payment,
+        theirs: FUND - payment,
+    };
+
+    let mut a_machine = UpdateMachine::new(
+        a_channel
+            .start_update_balance(a_balance, time_lock, FEE_RATE)
+            .unwrap(),
     );
-    assert_eq!(
-        b_balance_after_punish,
-        b_balance_after_open + FUND * 2 - Amount::from_sat(TX_FEE) * 2,
-        "Bob should get all the money back after punishing Alice"
+    let mut b_machine = UpdateMachine::new(
+        b_channel
+            .start_update_balance(b_balance, time_lock, FEE_RATE)
+            .unwrap(),
+    );
+
+    let mut a_actions = a_machine.start().unwrap();
+    let mut b_actions = b_machine.start().unwrap();
+    let mut a_channel = None;
+    let mut b_channel = None;
+
+    // Deliver the messages sent by each party to the other one, until both
+    // machines are done
+    while !a_actions.is_empty() || !b_actions.is_empty() {
+        let mut a_next_actions = Vec::new();
+        let mut b_next_actions = Vec::new();
+
+        for action in a_actions {
+            match action {
+                Action::SendMessage(message) => {
+                    b_next_actions.extend(b_machine.handle_message(message).unwrap())
+                }
+                Action::Done(channel) => a_channel = Some(channel),
+                action => panic!("unexpected action {:?}", action),
+            }
+        }
+        for action in b_actions {
+            match action {
+                Action::SendMessage(message) => {
+                    a_next_actions.extend(a_machine.handle_message(message).unwrap())
+                }
+                Action::Done(channel) => b_channel = Some(channel),
+                action => panic!("unexpected action {:?}", action),
+            }
+        }
+
+        a_actions = a_next_actions;
+        b_actions = b_next_actions;
+    }
+
+    let a_channel = a_channel.expect("Alice's update should be done");
+    let b_channel = b_channel.expect("Bob's update should be done");
+
+    assert_eq!(a_channel.balance(), a_balance);
+    assert_eq!(b_channel.balance(), b_balance);
+    assert_eq!(a_channel.state_number, 1);
+    assert_eq!(b_channel.state_number, 1);
+}
+
+#[tokio::test]
+async fn machine_keeps_pending_state_of_interrupted_update() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (a_channel, b_channel, _, _, _, _, time_lock, _) = create_channels(&bitcoind).await;
+
+    let payment = Amount::from_btc(0.5).unwrap();
+    let a_balance = Balance {
+        ours: FUND - payment,
+        theirs: FUND + payment,
+    };
+    let b_balance = Balance {
+        ours: FUND + payment,
+        theirs: FUND - payment,
+    };
+
+    let mut a_machine = UpdateMachine::new(
+        a_channel
+            .start_update_balance(a_balance, time_lock, FEE_RATE)
+            .unwrap(),
     );
+    let mut b_machine = UpdateMachine::new(
+        b_channel
+            .start_update_balance(b_balance, time_lock, FEE_RATE)
+            .unwrap(),
+    );
+    assert!(a_machine.interrupted().is_none());
+
+    let mut a_actions = a_machine.start().unwrap();
+    let mut b_actions = b_machine.start().unwrap();
+    let mut b_channel = None;
+
+    // Alice never receives Bob's revocation secret key, after having revealed
+    // hers
+    while !a_actions.is_empty() || !b_actions.is_empty() {
+        let mut a_next_actions = Vec::new();
+        let mut b_next_actions = Vec::new();
+
+        for action in a_actions {
+            match action {
+                Action::SendMessage(message) => {
+                    b_next_actions.extend(b_machine.handle_message(message).unwrap())
+                }
+                action => panic!("unexpected action {:?}", action),
+            }
+        }
+        for action in b_actions {
+            match action {
+                Action::SendMessage(Message::Update3(_)) => {}
+                Action::SendMessage(message) => {
+                    a_next_actions.extend(a_machine.handle_message(message).unwrap())
+                }
+                Action::Done(channel) => b_channel = Some(channel),
+                action => panic!("unexpected action {:?}", action),
+            }
+        }
+
+        a_actions = a_next_actions;
+        b_actions = b_next_actions;
+    }
+
+    let mut a_channel = a_machine
+        .interrupted()
+        .cloned()
+        .expect("Alice's update should be interrupted");
+    let mut b_channel = b_channel.expect("Bob's update should be done");
+
+    assert_eq!(a_channel.state_number, 0);
+    assert!(a_channel.pending_state.is_some());
+    assert_eq!(b_channel.state_number, 1);
+
+    let (mut a_transport, mut b_transport) = make_transports();
+    futures::future::try_join(
+        a_channel.reestablish(&mut a_transport),
+        b_channel.reestablish(&mut b_transport),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(a_channel.state_number, 1);
+    assert!(a_channel.pending_state.is_none());
+    assert_eq!(a_channel.balance(), a_balance);
+    assert_eq!(b_channel.balance(), b_balance);
 }
 
 #[tokio::test]
@@ -109,12 +1000,24 @@ async fn bob_can_refund_ptlc_if_alice_holds_onto_secret_after_first_update() {
     // secret
     let add_ptlc_alice = a_channel.add_ptlc_redeemer(
         &mut a_transport,
+        &a_wallet,
         ptlc_amount,
         secret,
         split_transaction_relative_expiry,
         ptlc_absolute_expiry,
     );
 
+    // The expiries are unrealistically short to not have to wait for long in
+    // the test, so the safety margins have to be equally small
+    let config = SwapConfig {
+        safety_margins: SafetyMargins {
+            block_time: 1,
+            ptlc_redeem: 0,
+            alpha_redeem: 0,
+        },
+        ..Default::default()
+    };
+
     let skip_final_update = false;
     let swap_beta_ptlc_bob = swap_beta_ptlc_bob(
         &mut b_channel,
@@ -125,6 +1028,7 @@ async fn bob_can_refund_ptlc_if_alice_holds_onto_secret_after_first_update() {
         alpha_absolute_expiry,
         split_transaction_relative_expiry,
         ptlc_absolute_expiry,
+        config,
         skip_final_update,
     );
 
@@ -141,7 +1045,7 @@ async fn bob_can_refund_ptlc_if_alice_holds_onto_secret_after_first_update() {
 
     // The fees are distributed evenly between the outputs.
     let fee_deduction_per_split_output =
-        Amount::from_sat(TX_FEE + TX_FEE) / n_outputs_split_transaction;
+        fee_deduction_per_split_output(n_outputs_split_transaction);
 
     // Alice will just claim her balance output
     let a_split_transaction_fee = fee_deduction_per_split_output;
@@ -149,9 +1053,9 @@ async fn bob_can_refund_ptlc_if_alice_holds_onto_secret_after_first_update() {
     // Bob will claim his balance output and refund the PTLC output
     let b_split_transaction_fee = fee_deduction_per_split_output * 2;
 
-    // Additionally, Bob pays an extra `TX_FEE` to be able to refund the
-    // PTLC output.
-    let fee_deduction_for_ptlc_refund = Amount::from_sat(TX_FEE);
+    // Additionally, Bob pays the fee of the `RefundTransaction` to be able to
+    // refund the PTLC output.
+    let fee_deduction_for_ptlc_refund = FEE_RATE.fee(PTLC_TRANSACTION_WEIGHT);
 
     assert_eq!(
         a_balance_after_close,
@@ -167,3 +1071,352 @@ async fn bob_can_refund_ptlc_if_alice_holds_onto_secret_after_first_update() {
          plus PTLC amount, minus transaction fees"
     );
 }
+
+#[tokio::test]
+async fn alice_publishes_next_state_if_bob_withholds_revocation_after_ptlc_is_redeemed() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (
+        mut a_channel,
+        mut b_channel,
+        mut a_transport,
+        mut b_transport,
+        a_wallet,
+        b_wallet,
+        _time_lock,
+        _tx_fee,
+    ) = create_channels(&bitcoind).await;
+
+    let secret = PtlcSecret::new_random();
+    let point = secret.point();
+    let ptlc_amount = Amount::from_btc(0.5).unwrap();
+
+    let (ptlc_absolute_expiry, split_transaction_relative_expiry) = {
+        let now = a_wallet.median_time().await.unwrap();
+
+        let one_hour = 60 * 60;
+        let ptlc_absolute = now + one_hour;
+
+        let split_transaction_relative = 1;
+
+        (ptlc_absolute, split_transaction_relative)
+    };
+
+    let add_ptlc_alice = a_channel.add_ptlc_redeemer(
+        &mut a_transport,
+        &a_wallet,
+        ptlc_amount,
+        secret.clone(),
+        split_transaction_relative_expiry,
+        ptlc_absolute_expiry,
+    );
+    let add_ptlc_bob = b_channel.add_ptlc_funder(
+        &mut b_transport,
+        &b_wallet,
+        ptlc_amount,
+        point.clone(),
+        split_transaction_relative_expiry,
+        ptlc_absolute_expiry,
+    );
+    futures::future::try_join(add_ptlc_alice, add_ptlc_bob)
+        .await
+        .unwrap();
+
+    let config = SwapConfig {
+        safety_margins: SafetyMargins {
+            block_time: 1,
+            ptlc_redeem: 0,
+            alpha_redeem: 0,
+        },
+        settlement_timeout: Some(std::time::Duration::from_secs(5)),
+        ..Default::default()
+    };
+
+    // Alice reveals the secret and tries to merge the PTLC output into her
+    // balance output
+    let a_redeem = a_channel.redeem_ptlc_redeemer(
+        &mut a_transport,
+        &a_wallet,
+        ptlc_amount,
+        secret,
+        split_transaction_relative_expiry,
+        ptlc_absolute_expiry,
+        config,
+    );
+
+    // Bob signs the next state, but never reveals the revocation secret key
+    // of the current one, after receiving Alice's
+    let b_withhold = async {
+        let message = b_transport.receive_message().await.unwrap();
+        assert!(matches!(message, Message::Secret(_)));
+
+        let Balance { ours, theirs } = b_channel.balance();
+        let mut new_split_outputs = vec![
+            b_channel.split_balance_output_ours(ours),
+            b_channel.split_balance_output_theirs(theirs + ptlc_amount),
+        ];
+        new_split_outputs.extend(b_channel.split_ptlc_outputs_except(&point));
+
+        let mut state = b_channel
+            .start_update(
+                new_split_outputs,
+                split_transaction_relative_expiry,
+                FEE_RATE,
+            )
+            .unwrap();
+        while state.next_state().is_none() {
+            b_transport.send_message(state.compose()).await.unwrap();
+            let message = b_transport.receive_message().await.unwrap();
+
+            state = match state.interpret(message).unwrap() {
+                Transition::Next(state) => state,
+                Transition::Done(_) => panic!("update should not be done yet"),
+            };
+        }
+
+        let message = b_transport.receive_message().await.unwrap();
+        assert!(matches!(message, Message::Update3(_)));
+
+        let next_state = StandardChannelState::from(state.next_state().unwrap());
+        next_state.tx_c.txid()
+    };
+
+    let (a_redeem, next_tx_c_txid) = futures::future::join(a_redeem, b_withhold).await;
+    a_redeem.unwrap();
+
+    // Bob can punish the publication of the current state, so Alice publishes
+    // the next one instead
+    assert!(a_wallet.0.get_raw_transaction(next_tx_c_txid).await.is_ok());
+}
+
+#[tokio::test]
+async fn alice_can_redeem_multiple_ptlcs_by_force_closing() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (
+        mut a_channel,
+        mut b_channel,
+        mut a_transport,
+        mut b_transport,
+        a_wallet,
+        b_wallet,
+        _time_lock,
+        _tx_fee,
+    ) = create_channels(&bitcoind).await;
+
+    let a_balance_after_open = a_wallet.balance().await.unwrap();
+    let b_balance_after_open = b_wallet.balance().await.unwrap();
+
+    let ptlc_amount = Amount::from_btc(0.25).unwrap();
+
+    let (ptlc_absolute_expiry, split_transaction_relative_expiry) = {
+        let now = a_wallet.median_time().await.unwrap();
+
+        let one_hour = 60 * 60;
+        let ptlc_absolute = now + one_hour;
+
+        let split_transaction_relative = 1;
+
+        (ptlc_absolute, split_transaction_relative)
+    };
+
+    // Bob funds two PTLCs for Alice, one after the other, so that both of them are
+    // in the channel at the same time
+    for _ in 0..2 {
+        let secret = PtlcSecret::new_random();
+        let point = secret.point();
+
+        let add_ptlc_alice = a_channel.add_ptlc_redeemer(
+            &mut a_transport,
+            &a_wallet,
+            ptlc_amount,
+            secret,
+            split_transaction_relative_expiry,
+            ptlc_absolute_expiry,
+        );
+
+        let add_ptlc_bob = b_channel.add_ptlc_funder(
+            &mut b_transport,
+            &b_wallet,
+            ptlc_amount,
+            point,
+            split_transaction_relative_expiry,
+            ptlc_absolute_expiry,
+        );
+
+        futures::future::try_join(add_ptlc_alice, add_ptlc_bob)
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(a_channel.current_state.ptlcs().len(), 2);
+    assert_eq!(b_channel.current_state.ptlcs().len(), 2);
+
+    // Alice force closes the channel, redeeming both PTLCs in the process
+    a_channel.force_close(&a_wallet).await.unwrap();
+
+    let a_balance_after_close = a_wallet.0.balance().await.unwrap();
+    let b_balance_after_close = b_wallet.0.balance().await.unwrap();
+
+    // The `SplitTransaction` has 2 balance outputs and 2 PTLC outputs
+    let n_outputs_split_transaction = 4;
+
+    // The fees are distributed evenly between the outputs.
+    let fee_deduction_per_split_output =
+        fee_deduction_per_split_output(n_outputs_split_transaction);
+
+    // Alice pays the fee of a `RedeemTransaction` for each PTLC output.
+    let fee_deduction_for_ptlc_redeem = FEE_RATE.fee(PTLC_TRANSACTION_WEIGHT);
+
+    assert_eq!(
+        a_balance_after_close,
+        a_balance_after_open + FUND - fee_deduction_per_split_output
+            + (ptlc_amount - fee_deduction_per_split_output - fee_deduction_for_ptlc_redeem) * 2,
+        "Alice should get her balance output and both PTLC outputs, minus transaction fees"
+    );
+
+    assert_eq!(
+        b_balance_after_close,
+        b_balance_after_open + FUND - ptlc_amount * 2 - fee_deduction_per_split_output,
+        "Bob should only get his balance output, minus transaction fees"
+    );
+}
+
+#[tokio::test]
+async fn proposal_with_shorter_time_lock_is_rejected() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (a_channel, mut b_channel, mut a_transport, mut b_transport, _, b_wallet, time_lock, _) =
+        create_channels(&bitcoind).await;
+
+    // Alice proposes a state which cannot be punished
+    let split_outputs = a_channel.split_outputs_for_balance(a_channel.balance());
+    a_transport
+        .send_message(ProposeUpdate::new(&split_outputs, time_lock - 1).into())
+        .await
+        .unwrap();
+
+    let handled = b_channel
+        .handle_incoming(&mut b_transport, &b_wallet, &AcceptAll)
+        .await;
+
+    assert!(handled.is_err());
+    assert!(matches!(
+        a_transport.receive_message().await.unwrap(),
+        Message::Reject(_)
+    ));
+}
+
+#[tokio::test]
+async fn proposal_with_output_below_its_fee_share_is_rejected() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (a_channel, mut b_channel, mut a_transport, mut b_transport, _, b_wallet, time_lock, _) =
+        create_channels(&bitcoind).await;
+
+    // Alice proposes to leave Bob an output which cannot pay for its share of
+    // the fees
+    let dust = Amount::from_sat(100);
+    let split_outputs = a_channel.split_outputs_for_balance(Balance {
+        ours: FUND * 2 - dust,
+        theirs: dust,
+    });
+    a_transport
+        .send_message(ProposeUpdate::new(&split_outputs, time_lock).into())
+        .await
+        .unwrap();
+
+    let handled = b_channel
+        .handle_incoming(&mut b_transport, &b_wallet, &AcceptAll)
+        .await;
+
+    assert!(handled.is_err());
+    assert!(matches!(
+        a_transport.receive_message().await.unwrap(),
+        Message::Reject(_)
+    ));
+}
+
+#[tokio::test]
+async fn splice_must_match_accepted_proposal() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (a_channel, mut b_channel, mut a_transport, mut b_transport, a_wallet, b_wallet, ..) =
+        create_channels(&bitcoind).await;
+
+    // Alice proposes to splice nothing in, but then splices in anyway
+    let a_splice = async {
+        a_transport
+            .send_message(ProposeSplice::new(Splice::default()).into())
+            .await
+            .unwrap();
+        assert!(matches!(
+            a_transport.receive_message().await.unwrap(),
+            Message::Accept(_)
+        ));
+
+        let splice = Splice {
+            amount_in: Amount::from_btc(0.1).unwrap(),
+            outs: Vec::new(),
+        };
+        a_channel.splice(&mut a_transport, &a_wallet, splice).await
+    };
+    let (a_splice, b_splice) = futures::future::join(
+        a_splice,
+        b_channel.handle_incoming(&mut b_transport, &b_wallet, &AcceptAll),
+    )
+    .await;
+
+    assert!(a_splice.is_err());
+    assert!(b_splice.is_err());
+    assert!(b_channel.pending_splice.is_none());
+}
+
+#[tokio::test]
+async fn channel_is_kept_if_creation_is_interrupted_after_signing_funding_transaction() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (mut a_transport, mut b_transport) = make_transports();
+    let (a_balance, b_balance) = generate_balances(FUND);
+    let (a_wallet, b_wallet) = make_wallets(&bitcoind, FUND).await.unwrap();
+    let time_lock = 1;
+
+    // Alice loses the connection right before receiving Bob's signature for
+    // the funding transaction, after having sent hers
+    let mut a_interrupted_transport = InterruptedTransport {
+        transport: &mut a_transport,
+        messages_left: 5,
+    };
+    let (a_create, b_create) = futures::future::join(
+        Channel::create(
+            &mut a_interrupted_transport,
+            &a_wallet,
+            KeyManager::new_random().channel_keys(0),
+            a_balance,
+            time_lock,
+            FeeRateBounds::default(),
+            Timeouts::default(),
+        ),
+        Channel::create(
+            &mut b_transport,
+            &b_wallet,
+            KeyManager::new_random().channel_keys(0),
+            b_balance,
+            time_lock,
+            FeeRateBounds::default(),
+            Timeouts::default(),
+        ),
+    )
+    .await;
+
+    // Bob published the funding transaction, so Alice needs the channel to
+    // recover her funds
+    let b_channel = b_create.unwrap();
+    let a_channel = match a_create {
+        Err(Error::Interrupted { channel, .. }) => channel,
+        result => panic!("Alice should keep the channel: {:?}", result),
+    };
+
+    assert_eq!(a_channel.id(), b_channel.id());
+    assert_eq!(a_channel.balance(), a_balance);
+}
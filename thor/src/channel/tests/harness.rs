@@ -1,7 +1,8 @@
 //! This module is a copy of the code over in `thor/tests/harness/` with the
 //! following changes:
 //! - Use `harness.rs` instead of `mod.rs`
-//! - Remove unused code: `SwapExpiries` and `generate_expiries`.
+//! - Remove unused code: `SwapExpiries`, `generate_expiries` and
+//!   `make_envelope_transports`.
 //!
 //! The reason we duplicate the test harness is because we want the integration
 //! tests to remain outside of this crate in order to enforce usage of the
@@ -14,8 +15,10 @@
 //
 
 use crate::{
-    channel::{ReceiveMessage, SendMessage},
-    Balance, Channel, Message, PtlcPoint,
+    channel::{ReceiveMessage, SendMessage, Timeouts},
+    fee::{split_transaction_weight, ANCHOR_OUTPUT_VALUE, COMMIT_TRANSACTION_WEIGHT},
+    swap::SwapConfig,
+    Balance, Channel, FeeRateBounds, KeyManager, Message, PtlcPoint,
 };
 
 use anyhow::{anyhow, Context, Result};
@@ -35,7 +38,7 @@ use testcontainers::clients::Cli;
 
 mod wallet;
 
-pub use wallet::{make_wallets, Wallet};
+pub use wallet::{make_wallets, Wallet, FEE_RATE};
 
 // Alice and Bob both fund the channel with this much.
 pub const FUND: Amount = Amount::ONE_BTC;
@@ -61,8 +64,24 @@ pub async fn create_channels(
 
     let initial_balance = a_wallet.balance().await.unwrap();
 
-    let a_create = Channel::create(&mut a_transport, &a_wallet, a_balance, time_lock);
-    let b_create = Channel::create(&mut b_transport, &b_wallet, b_balance, time_lock);
+    let a_create = Channel::create(
+        &mut a_transport,
+        &a_wallet,
+        KeyManager::new_random().channel_keys(0),
+        a_balance,
+        time_lock,
+        FeeRateBounds::default(),
+        Timeouts::default(),
+    );
+    let b_create = Channel::create(
+        &mut b_transport,
+        &b_wallet,
+        KeyManager::new_random().channel_keys(0),
+        b_balance,
+        time_lock,
+        FeeRateBounds::default(),
+        Timeouts::default(),
+    );
 
     let (a_channel, b_channel) = future::try_join(a_create, b_create)
         .await
@@ -111,17 +130,21 @@ pub async fn init_bitcoind(tc_client: &Cli) -> Bitcoind<'_> {
     bitcoind
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn update_balances(
     a_channel: &mut Channel,
     b_channel: &mut Channel,
     a_transport: &mut Transport,
     b_transport: &mut Transport,
+    a_wallet: &Wallet,
+    b_wallet: &Wallet,
     a_balance: Amount,
     b_balance: Amount,
     time_lock: u32,
 ) {
     let a_update = a_channel.update_balance(
         a_transport,
+        a_wallet,
         Balance {
             ours: a_balance,
             theirs: b_balance,
@@ -130,6 +153,7 @@ pub async fn update_balances(
     );
     let b_update = b_channel.update_balance(
         b_transport,
+        b_wallet,
         Balance {
             ours: b_balance,
             theirs: a_balance,
@@ -142,6 +166,16 @@ pub async fn update_balances(
         .expect("update failed");
 }
 
+/// Amount deducted from each output of a `SplitTransaction` with `n_outputs`
+/// outputs, to pay for both the `CommitTransaction` and the `SplitTransaction`
+/// fees and anchor outputs.
+pub fn fee_deduction_per_split_output(n_outputs: u64) -> Amount {
+    let anchors = Amount::from_sat(ANCHOR_OUTPUT_VALUE) * 2;
+
+    (FEE_RATE.fee(COMMIT_TRANSACTION_WEIGHT) + anchors) / n_outputs
+        + (FEE_RATE.fee(split_transaction_weight(n_outputs as usize)) + anchors) / n_outputs
+}
+
 pub fn generate_balances(fund_amount: Amount) -> (Balance, Balance) {
     let a_balance = Balance {
         ours: fund_amount,
@@ -168,6 +202,7 @@ pub async fn swap_beta_ptlc_bob(
     alpha_absolute_expiry: u32,
     TX_s_time_lock: u32,
     ptlc_redeem_time_lock: u32,
+    config: SwapConfig,
     skip_update: bool,
 ) -> Result<()> {
     let mut swap_beta_ptlc_bob = channel.swap_beta_ptlc_bob(
@@ -178,6 +213,7 @@ pub async fn swap_beta_ptlc_bob(
         alpha_absolute_expiry,
         TX_s_time_lock,
         ptlc_redeem_time_lock,
+        config,
     );
 
     match swap_beta_ptlc_bob.async_resume().await {
@@ -219,17 +255,17 @@ pub fn make_transports() -> (Transport, Transport) {
 
 #[derive(Debug)]
 pub struct Transport {
-    // Using String instead of `Message` implicitly tests the `use-serde` feature.
-    sender: Sender<String>,
-    receiver: Receiver<String>,
+    // Using bytes instead of `Message` implicitly tests the wire format.
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
 }
 
 #[async_trait]
 impl SendMessage for Transport {
     async fn send_message(&mut self, message: Message) -> Result<()> {
-        let str = serde_json::to_string(&message).context("failed to encode message")?;
+        let bytes = message.encode().context("failed to encode message")?;
         self.sender
-            .send(str)
+            .send(bytes)
             .await
             .map_err(|_| anyhow!("failed to send message"))
     }
@@ -238,12 +274,12 @@ impl SendMessage for Transport {
 #[async_trait]
 impl ReceiveMessage for Transport {
     async fn receive_message(&mut self) -> Result<Message> {
-        let str = self
+        let bytes = self
             .receiver
             .next()
             .await
             .ok_or_else(|| anyhow!("failed to receive message"))?;
-        let message = serde_json::from_str(&str).context("failed to decode message")?;
+        let message = Message::decode(&bytes).context("failed to decode message")?;
         Ok(message)
     }
 }
@@ -1,16 +1,23 @@
 use crate::{
-    channel::{BroadcastSignedTransaction, BuildFundingPsbt, NewAddress, SignFundingPsbt},
-    GetRawTransaction, MedianTime,
+    channel::{
+        BroadcastSignedTransaction, BuildFundingPsbt, NewAddress, ReleaseFundingInputs,
+        SignFundingPsbt,
+    },
+    EstimateFeeRate, FeeRate, GetRawTransaction, MedianTime,
 };
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use async_trait::async_trait;
-use bitcoin::{util::psbt::PartiallySignedTransaction, Address, Amount};
+use bitcoin::{util::psbt::PartiallySignedTransaction, Address, Amount, Transaction, TxOut};
 use bitcoin_harness::{bitcoind_rpc::PsbtBase64, Bitcoind};
 use reqwest::Url;
 use std::time::Duration;
 use tokio::time;
 
+/// Fee rate estimated by every wallet. Using a constant allows tests to
+/// predict the fees paid by the channel transactions.
+pub const FEE_RATE: FeeRate = FeeRate::from_sat_per_vbyte(10);
+
 #[derive(Debug)]
 pub struct Wallet(pub bitcoin_harness::Wallet);
 
@@ -87,6 +94,56 @@ impl SignFundingPsbt for Wallet {
     }
 }
 
+#[async_trait]
+impl ReleaseFundingInputs for Wallet {
+    async fn release_funding_inputs(&self, _: PartiallySignedTransaction) -> Result<()> {
+        // bitcoind does not lock the unspent outputs selected to fund a PSBT
+        Ok(())
+    }
+
+    async fn double_spend_funding_inputs(&self, psbt: PartiallySignedTransaction) -> Result<()> {
+        // The wallet only signs the inputs which belong to it
+        let signed_psbt = self.sign_funding_psbt(psbt).await?;
+        let inputs = signed_psbt
+            .global
+            .unsigned_tx
+            .input
+            .into_iter()
+            .zip(signed_psbt.inputs)
+            .filter(|(_, input)| input.final_script_witness.is_some())
+            .map(|(txin, _)| txin)
+            .collect::<Vec<_>>();
+        if inputs.is_empty() {
+            bail!("none of the inputs belong to the wallet")
+        }
+
+        let mut value = 0;
+        for input in inputs.iter() {
+            let previous_transaction = self.get_raw_transaction(input.previous_output.txid).await?;
+            value += previous_transaction.output[input.previous_output.vout as usize].value;
+        }
+
+        // Generous estimate of the weight of a transaction spending P2WPKH
+        // inputs to a single output
+        let weight = 500 * inputs.len() as u64 + 200;
+        let address = self.new_address().await?;
+
+        let transaction = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: inputs,
+            output: vec![TxOut {
+                value: value - FEE_RATE.fee(weight).as_sat(),
+                script_pubkey: address.script_pubkey(),
+            }],
+        };
+        let psbt = PartiallySignedTransaction::from_unsigned_tx(transaction)?;
+        let transaction = self.sign_funding_psbt(psbt).await?.extract_tx();
+
+        self.broadcast_signed_transaction(transaction).await
+    }
+}
+
 #[async_trait]
 impl BroadcastSignedTransaction for Wallet {
     async fn broadcast_signed_transaction(&self, transaction: bitcoin::Transaction) -> Result<()> {
@@ -122,3 +179,10 @@ impl GetRawTransaction for Wallet {
         self.0.get_raw_transaction(txid).await.map_err(Into::into)
     }
 }
+
+#[async_trait]
+impl EstimateFeeRate for Wallet {
+    async fn estimate_fee_rate(&self) -> Result<FeeRate> {
+        Ok(FEE_RATE)
+    }
+}
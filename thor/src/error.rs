@@ -0,0 +1,161 @@
+use crate::{
+    channel::{protocols::punish::NotOldCommitTransaction, Channel, ProposalRejected, TimedOut},
+    fee::FeeRateOutOfBounds,
+    keys::WrongRevocationSecretKey,
+    signature::{InvalidEncryptedSignature, InvalidSignature},
+    swap::{UnsafeExpiries, UnsafeToRevealSecret},
+    transaction::{self, InvalidSpliceTransaction, PunishError},
+    wire::UnsupportedProtocolVersion,
+    ProtocolAborted, UnexpectedMessage,
+};
+use bitcoin::Amount;
+
+/// Error returned by the public `Channel` API.
+///
+/// The variants are grouped by where the error comes from, so that callers
+/// can decide whether retrying makes sense:
+///
+/// - protocol: the counterparty did not follow a channel protocol;
+/// - wallet: a call to the wallet failed;
+/// - transport: sending a message to or receiving it from the counterparty
+///   failed;
+/// - validation: the requested operation is not possible in the current state
+///   of the channel;
+/// - chain: the state of the blockchain does not allow the operation.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unexpected message from counterparty")]
+    UnexpectedMessage(#[from] UnexpectedMessage),
+    #[error("counterparty aborted the protocol")]
+    ProtocolAborted(#[from] ProtocolAborted),
+    #[error("counterparty rejected the proposal")]
+    ProposalRejected(#[from] ProposalRejected),
+    #[error("invalid signature from counterparty")]
+    InvalidSignature(#[from] InvalidSignature),
+    #[error("invalid splice transaction")]
+    InvalidSpliceTransaction(#[from] InvalidSpliceTransaction),
+    /// Any other violation of a channel protocol by the counterparty.
+    #[error("counterparty violated the protocol")]
+    Protocol(#[source] anyhow::Error),
+
+    #[error("wallet failed")]
+    Wallet(#[source] anyhow::Error),
+
+    #[error("transport failed")]
+    Transport(#[source] anyhow::Error),
+    #[error("counterparty did not respond in time")]
+    TimedOut(#[from] TimedOut),
+
+    #[error("balance {balance} does not cover amount {required}")]
+    InsufficientBalance { balance: Amount, required: Amount },
+    #[error("invalid transaction")]
+    Transaction(#[from] transaction::Error),
+    /// Any other reason why the operation is not possible in the current
+    /// state of the channel.
+    #[error("invalid operation")]
+    Validation(#[source] anyhow::Error),
+
+    #[error("transaction cannot be punished")]
+    NotOldCommitTransaction(#[from] NotOldCommitTransaction),
+    #[error("failed to punish counterparty")]
+    Punish(#[from] PunishError),
+    /// Any other failure to query the blockchain.
+    #[error("blockchain query failed")]
+    Chain(#[source] anyhow::Error),
+
+    /// The operation failed after the counterparty received our signatures
+    /// for the transaction funding the `channel`. Our inputs to it, if any,
+    /// are double-spent, but the counterparty may still publish it first, in
+    /// which case the `channel` is needed to recover the funds.
+    #[error("operation on the channel was interrupted")]
+    Interrupted {
+        channel: Box<Channel>,
+        #[source]
+        source: Box<Error>,
+    },
+
+    /// Errors which do not fit in any of the other categories.
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl Error {
+    /// Error caused by the counterparty sending an invalid message, unless it
+    /// is known to be caused by something else.
+    pub(crate) fn protocol(error: anyhow::Error) -> Self {
+        match Error::from(error) {
+            Error::Other(error) => Error::Protocol(error),
+            error => error,
+        }
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        macro_rules! downcast {
+            ($error:expr, $ty:ty => $variant:expr) => {
+                match $error.downcast::<$ty>() {
+                    Ok(error) => return $variant(error),
+                    Err(error) => error,
+                }
+            };
+        }
+
+        // Errors tagged with their category further down the call stack
+        let error = downcast!(error, Error => std::convert::identity);
+
+        let error = downcast!(error, UnexpectedMessage => Error::UnexpectedMessage);
+        let error = downcast!(error, ProtocolAborted => Error::ProtocolAborted);
+        let error = downcast!(error, ProposalRejected => Error::ProposalRejected);
+        let error = downcast!(error, InvalidSignature => Error::InvalidSignature);
+        let error = downcast!(error, InvalidSpliceTransaction => Error::InvalidSpliceTransaction);
+        let error = downcast!(error, TimedOut => Error::TimedOut);
+        let error = downcast!(error, transaction::Error => Error::Transaction);
+        let error = downcast!(error, NotOldCommitTransaction => Error::NotOldCommitTransaction);
+        let error = downcast!(error, PunishError => Error::Punish);
+
+        if error.is::<InvalidEncryptedSignature>()
+            || error.is::<WrongRevocationSecretKey>()
+            || error.is::<FeeRateOutOfBounds>()
+            || error.is::<UnsupportedProtocolVersion>()
+        {
+            return Error::Protocol(error);
+        }
+
+        if error.is::<UnsafeExpiries>() || error.is::<UnsafeToRevealSecret>() {
+            return Error::Validation(error);
+        }
+
+        Error::Other(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn categorised_error_survives_anyhow_round_trip() {
+        let error = anyhow::Error::from(Error::Wallet(anyhow!("no funds")));
+
+        assert!(matches!(Error::from(error), Error::Wallet(_)));
+    }
+
+    #[test]
+    fn known_errors_are_categorised() {
+        let error = anyhow::Error::from(InvalidSignature).context("failed to verify");
+        assert!(matches!(Error::from(error), Error::InvalidSignature(_)));
+
+        let error = anyhow::Error::from(NotOldCommitTransaction);
+        assert!(matches!(
+            Error::from(error),
+            Error::NotOldCommitTransaction(_)
+        ));
+
+        let error = anyhow!("something else");
+        assert!(matches!(Error::from(error), Error::Other(_)));
+        let error = anyhow!("something else");
+        assert!(matches!(Error::protocol(error), Error::Protocol(_)));
+    }
+}
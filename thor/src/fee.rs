@@ -0,0 +1,217 @@
+//! Fee computation for the transactions involved in the protocol.
+//!
+//! Fees are derived from a [`FeeRate`] agreed upon by both parties and an
+//! estimation of the weight of each transaction. Weight estimations are upper
+//! bounds, assuming DER-encoded signatures of maximum size and P2WSH outputs.
+
+use bitcoin::Amount;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Value of each anchor output, in satoshi. It is the dust limit of a P2WSH
+/// output, i.e. the smallest amount that a standard output can hold.
+pub const ANCHOR_OUTPUT_VALUE: u64 = 330;
+
+/// Weight of the parts of a segwit transaction which do not depend on its
+/// inputs or outputs: version, lock time, input and output counts, segwit
+/// marker and flag.
+const TX_OVERHEAD_WEIGHT: u64 = (4 + 4 + 1 + 1) * 4 + 2;
+
+/// Weight of the non-witness part of an input: outpoint, empty script sig and
+/// sequence number.
+const INPUT_WEIGHT: u64 = (36 + 1 + 4) * 4;
+
+/// Weight of an output paying to a P2WSH script. Other standard output types
+/// are not bigger than this.
+const OUTPUT_WEIGHT: u64 = (8 + 1 + 34) * 4;
+
+/// Weight of the anchor outputs added to a transaction, one for each party.
+const ANCHOR_OUTPUTS_WEIGHT: u64 = 2 * OUTPUT_WEIGHT;
+
+/// Weight of the witness spending an anchor output: one signature and the
+/// witness script.
+const ANCHOR_WITNESS_WEIGHT: u64 = 1 + (1 + 73) + (1 + 35);
+
+/// Weight of the witness spending a 2-of-2 shared output: two signatures and
+/// the witness script.
+const SHARED_OUTPUT_WITNESS_WEIGHT: u64 = 1 + 2 * (1 + 73) + (1 + 70);
+
+/// Weight of the witness spending the time-locked path of the output of a
+/// `CommitTransaction`: two signatures, a branch selector and the witness
+/// script.
+const COMMIT_OUTPUT_CHANNEL_STATE_WITNESS_WEIGHT: u64 = 1 + 2 * (1 + 73) + 1 + (3 + 229);
+
+/// Weight of the witness spending one of the punish paths of the output of a
+/// `CommitTransaction`: three signatures, three public keys, two branch
+/// selectors and the witness script.
+const COMMIT_OUTPUT_PUNISH_WITNESS_WEIGHT: u64 =
+    1 + 3 * (1 + 73) + 3 * (1 + 33) + 2 * (1 + 1) + (3 + 229);
+
+/// Estimated weight of a `CommitTransaction`.
+pub const COMMIT_TRANSACTION_WEIGHT: u64 = TX_OVERHEAD_WEIGHT
+    + INPUT_WEIGHT
+    + SHARED_OUTPUT_WITNESS_WEIGHT
+    + OUTPUT_WEIGHT
+    + ANCHOR_OUTPUTS_WEIGHT;
+
+/// Estimated weight of a `PunishTransaction`.
+pub const PUNISH_TRANSACTION_WEIGHT: u64 =
+    TX_OVERHEAD_WEIGHT + INPUT_WEIGHT + COMMIT_OUTPUT_PUNISH_WITNESS_WEIGHT + OUTPUT_WEIGHT;
+
+/// Estimated weight of a `CloseTransaction`.
+pub const CLOSE_TRANSACTION_WEIGHT: u64 =
+    TX_OVERHEAD_WEIGHT + INPUT_WEIGHT + SHARED_OUTPUT_WITNESS_WEIGHT + 2 * OUTPUT_WEIGHT;
+
+/// Estimated weight of a transaction spending a PTLC output, i.e. a PTLC
+/// `RedeemTransaction` or `RefundTransaction`.
+pub const PTLC_TRANSACTION_WEIGHT: u64 =
+    TX_OVERHEAD_WEIGHT + INPUT_WEIGHT + SHARED_OUTPUT_WITNESS_WEIGHT + OUTPUT_WEIGHT;
+
+/// Estimated weight of the part of a CPFP transaction which is paid for by the
+/// channel: the input spending our anchor output.
+///
+/// Inputs and change outputs used to pay for the fee bump are paid for by the
+/// wallet which built them.
+pub const CPFP_TRANSACTION_WEIGHT: u64 = TX_OVERHEAD_WEIGHT + INPUT_WEIGHT + ANCHOR_WITNESS_WEIGHT;
+
+/// Estimated weight of a `SplitTransaction` with `n_outputs` outputs, not
+/// counting its anchor outputs.
+pub fn split_transaction_weight(n_outputs: usize) -> u64 {
+    TX_OVERHEAD_WEIGHT
+        + INPUT_WEIGHT
+        + COMMIT_OUTPUT_CHANNEL_STATE_WITNESS_WEIGHT
+        + n_outputs as u64 * OUTPUT_WEIGHT
+        + ANCHOR_OUTPUTS_WEIGHT
+}
+
+/// Share of the fees of the `CommitTransaction` and of a `SplitTransaction`
+/// with `n_outputs` outputs which is deducted from each of its outputs,
+/// including the value of the anchor outputs of both transactions.
+pub fn split_output_fee_share(fee_rate: FeeRate, n_outputs: usize) -> Amount {
+    let anchors_value = Amount::from_sat(2 * ANCHOR_OUTPUT_VALUE);
+    let tx_c_cost = fee_rate.fee(COMMIT_TRANSACTION_WEIGHT) + anchors_value;
+    let tx_s_cost = fee_rate.fee(split_transaction_weight(n_outputs)) + anchors_value;
+
+    tx_c_cost / n_outputs as u64 + tx_s_cost / n_outputs as u64
+}
+
+/// Estimated weight of the part of a `SpliceTransaction` which is paid for by
+/// the parties splicing out: the input spending the previous fund output, the
+/// new fund output and `n_splice_outputs` splice-out outputs.
+///
+/// Inputs and change outputs used to splice in are paid for by the wallet
+/// which built them.
+pub fn splice_transaction_weight(n_splice_outputs: usize) -> u64 {
+    TX_OVERHEAD_WEIGHT
+        + INPUT_WEIGHT
+        + SHARED_OUTPUT_WITNESS_WEIGHT
+        + (1 + n_splice_outputs as u64) * OUTPUT_WEIGHT
+}
+
+/// Fee rate, in satoshi per virtual byte.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FeeRate(u64);
+
+impl FeeRate {
+    pub const fn from_sat_per_vbyte(sat_per_vbyte: u64) -> Self {
+        Self(sat_per_vbyte)
+    }
+
+    pub fn as_sat_per_vbyte(&self) -> u64 {
+        self.0
+    }
+
+    /// Fee to be paid by a transaction of the given `weight`.
+    pub fn fee(&self, weight: u64) -> Amount {
+        let vbytes = (weight + 3) / 4;
+
+        Amount::from_sat(self.0 * vbytes)
+    }
+
+    /// The fee rate used by both parties, given the fee rate proposed by each
+    /// of them. Both parties _must_ use this so that they compute the same
+    /// transactions.
+    pub(crate) fn agree(self, other: FeeRate) -> FeeRate {
+        std::cmp::max(self, other)
+    }
+}
+
+impl fmt::Display for FeeRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} sat/vB", self.0)
+    }
+}
+
+/// Range of fee rates that we are willing to accept from the counterparty.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FeeRateBounds {
+    pub min: FeeRate,
+    pub max: FeeRate,
+}
+
+impl Default for FeeRateBounds {
+    fn default() -> Self {
+        Self {
+            min: FeeRate::from_sat_per_vbyte(1),
+            max: FeeRate::from_sat_per_vbyte(500),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, thiserror::Error)]
+#[error("fee rate {fee_rate} is outside of the accepted bounds [{min}, {max}]")]
+pub struct FeeRateOutOfBounds {
+    fee_rate: FeeRate,
+    min: FeeRate,
+    max: FeeRate,
+}
+
+impl FeeRateBounds {
+    pub fn validate(&self, fee_rate: FeeRate) -> Result<(), FeeRateOutOfBounds> {
+        if fee_rate < self.min || fee_rate > self.max {
+            return Err(FeeRateOutOfBounds {
+                fee_rate,
+                min: self.min,
+                max: self.max,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fee_rounds_up_to_the_next_virtual_byte() {
+        let fee_rate = FeeRate::from_sat_per_vbyte(2);
+
+        assert_eq!(fee_rate.fee(400), Amount::from_sat(200));
+        assert_eq!(fee_rate.fee(401), Amount::from_sat(202));
+    }
+
+    #[test]
+    fn agreed_fee_rate_is_symmetric() {
+        let a = FeeRate::from_sat_per_vbyte(5);
+        let b = FeeRate::from_sat_per_vbyte(7);
+
+        assert_eq!(a.agree(b), b.agree(a));
+    }
+
+    #[test]
+    fn fee_rate_outside_of_bounds_is_rejected() {
+        let bounds = FeeRateBounds {
+            min: FeeRate::from_sat_per_vbyte(2),
+            max: FeeRate::from_sat_per_vbyte(10),
+        };
+
+        assert!(bounds.validate(FeeRate::from_sat_per_vbyte(1)).is_err());
+        assert!(bounds.validate(FeeRate::from_sat_per_vbyte(2)).is_ok());
+        assert!(bounds.validate(FeeRate::from_sat_per_vbyte(10)).is_ok());
+        assert!(bounds.validate(FeeRate::from_sat_per_vbyte(11)).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::shachain::RevocationSeed;
 use ::serde::{Deserialize, Serialize};
 #[cfg(test)]
 use anyhow::anyhow;
@@ -5,13 +6,116 @@ use anyhow::{bail, Result};
 use bitcoin::{hashes::Hash, SigHash};
 use ecdsa_fun::{
     adaptor::{Adaptor, EncryptedSignature},
-    fun::{Point, Scalar},
+    fun::{
+        marker::{Mark, NonZero},
+        Point, Scalar,
+    },
     nonce::Deterministic,
     Signature, ECDSA,
 };
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
 use std::fmt;
 
+/// Source of the keys of every channel, all of which are derived from a single
+/// master seed.
+///
+/// Backing up the master seed is enough to recover the keys of any channel,
+/// given the index of the channel and the public data shared by the
+/// counterparty.
+///
+/// The seed is neither copied implicitly nor printed by `Debug`.
+#[allow(missing_copy_implementations)]
+#[derive(Clone)]
+pub struct KeyManager {
+    seed: [u8; 32],
+}
+
+impl fmt::Debug for KeyManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyManager")
+            .field("seed", &"[redacted]")
+            .finish()
+    }
+}
+
+impl KeyManager {
+    pub fn new(seed: [u8; 32]) -> Self {
+        Self { seed }
+    }
+
+    pub fn new_random() -> Self {
+        Self::new(rand::random())
+    }
+
+    /// Derive the keys of the channel with index `channel_index`.
+    ///
+    /// Each channel _must_ use a different index, otherwise keys would be
+    /// reused across channels.
+    pub fn channel_keys(&self, channel_index: u32) -> ChannelKeys {
+        ChannelKeys {
+            seed: derive(&self.seed, b"channel", &channel_index.to_be_bytes()),
+        }
+    }
+}
+
+/// Keys of a single channel, as derived by a `KeyManager`.
+///
+/// The `OwnershipKeyPair` is the same for the whole channel, whereas a new
+/// `RevocationKeyPair` and `PublishingKeyPair` are derived for every channel
+/// state, identified by its state number.
+#[allow(missing_copy_implementations)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone)]
+pub struct ChannelKeys {
+    seed: [u8; 32],
+}
+
+impl fmt::Debug for ChannelKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChannelKeys")
+            .field("seed", &"[redacted]")
+            .finish()
+    }
+}
+
+impl ChannelKeys {
+    pub(crate) fn ownership_key_pair(&self) -> OwnershipKeyPair {
+        derive_scalar(&self.seed, b"ownership", &[]).into()
+    }
+
+    pub(crate) fn revocation_key_pair(&self, state_number: u64) -> RevocationKeyPair {
+        self.revocation_seed().key_pair(state_number)
+    }
+
+    pub(crate) fn publishing_key_pair(&self, state_number: u64) -> PublishingKeyPair {
+        derive_scalar(&self.seed, b"publishing", &state_number.to_be_bytes()).into()
+    }
+
+    /// The seed from which the `RevocationKeyPair` of every state is derived.
+    /// They are not derived independently, so that the counterparty can store
+    /// the ones we reveal in logarithmic space.
+    fn revocation_seed(&self) -> RevocationSeed {
+        RevocationSeed::from(derive(&self.seed, b"revocation", &[]))
+    }
+}
+
+/// Derive 32 bytes from `seed`, using `tag` to separate the derivation of
+/// different kinds of keys and `index` to tell apart keys of the same kind.
+fn derive(seed: &[u8; 32], tag: &[u8], index: &[u8]) -> [u8; 32] {
+    let digest = Sha256::new().chain(seed).chain(tag).chain(index).finalize();
+
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(&digest);
+
+    bytes
+}
+
+fn derive_scalar(seed: &[u8; 32], tag: &[u8], index: &[u8]) -> Scalar {
+    Scalar::from_bytes(derive(seed, tag, index))
+        .and_then(|scalar| scalar.mark::<NonZero>())
+        .expect("derived bytes are a valid scalar with overwhelming probability")
+}
+
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Clone, Debug)]
 pub struct OwnershipKeyPair {
@@ -24,15 +128,6 @@ pub struct OwnershipKeyPair {
 pub struct OwnershipPublicKey(Point);
 
 impl OwnershipKeyPair {
-    pub fn new_random() -> OwnershipKeyPair {
-        let (secret_key, public_key) = random_key_pair();
-
-        Self {
-            secret_key,
-            public_key,
-        }
-    }
-
     pub fn public(&self) -> OwnershipPublicKey {
         OwnershipPublicKey(self.public_key.clone())
     }
@@ -93,15 +188,6 @@ pub struct RevocationSecretKey(Scalar);
 pub struct RevocationPublicKey(Point);
 
 impl RevocationKeyPair {
-    pub fn new_random() -> Self {
-        let (secret_key, public_key) = random_key_pair();
-
-        Self {
-            secret_key,
-            public_key,
-        }
-    }
-
     pub fn public(&self) -> RevocationPublicKey {
         RevocationPublicKey(self.public_key.clone())
     }
@@ -137,6 +223,20 @@ impl From<RevocationPublicKey> for Point {
     }
 }
 
+impl RevocationSecretKey {
+    /// Interpret `bytes` as a `RevocationSecretKey`. Returns `None` if they do
+    /// not encode a valid non-zero scalar.
+    pub(crate) fn from_bytes(bytes: [u8; 32]) -> Option<Self> {
+        Scalar::from_bytes(bytes)
+            .and_then(|secret_key| secret_key.mark::<NonZero>())
+            .map(Self)
+    }
+
+    pub(crate) fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+}
+
 impl From<RevocationKeyPair> for RevocationSecretKey {
     fn from(from: RevocationKeyPair) -> Self {
         RevocationSecretKey(from.secret_key)
@@ -170,15 +270,6 @@ pub struct PublishingSecretKey(Scalar);
 pub struct PublishingPublicKey(Point);
 
 impl PublishingKeyPair {
-    pub fn new_random() -> Self {
-        let (secret_key, public_key) = random_key_pair();
-
-        Self {
-            secret_key,
-            public_key,
-        }
-    }
-
     pub fn public(&self) -> PublishingPublicKey {
         PublishingPublicKey(self.public_key.clone())
     }
@@ -261,6 +352,12 @@ impl PtlcSecret {
 #[derive(Clone, Debug, PartialEq)]
 pub struct PtlcPoint(Point);
 
+impl PartialOrd for PtlcPoint {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.0.to_bytes().cmp(&other.0.to_bytes()))
+    }
+}
+
 impl From<PtlcPoint> for Point {
     fn from(from: PtlcPoint) -> Self {
         from.0
@@ -297,13 +394,6 @@ impl From<PublishingPublicKey> for bitcoin::secp256k1::PublicKey {
     }
 }
 
-fn random_key_pair() -> (Scalar, Point) {
-    let secret_key = Scalar::random(&mut rand::thread_rng());
-    let public_key = public_key(&secret_key);
-
-    (secret_key, public_key)
-}
-
 fn public_key(secret_key: &Scalar) -> Point {
     let ecdsa = ECDSA::<()>::default();
 
@@ -335,11 +425,49 @@ mod tests {
 
     #[test]
     fn ownership_public_key_deser_round() {
-        let pubkey = OwnershipKeyPair::new_random().public();
+        let pubkey = KeyManager::new_random()
+            .channel_keys(0)
+            .ownership_key_pair()
+            .public();
 
         let str = serde_json::to_string(&pubkey).unwrap();
         let res = serde_json::from_str(&str).unwrap();
 
         assert_eq!(pubkey, res);
     }
+
+    #[test]
+    fn channel_keys_are_recovered_from_master_seed() {
+        let seed = rand::random();
+
+        let keys = KeyManager::new(seed).channel_keys(7);
+        let recovered_keys = KeyManager::new(seed).channel_keys(7);
+
+        assert_eq!(
+            keys.ownership_key_pair().public(),
+            recovered_keys.ownership_key_pair().public()
+        );
+        assert_eq!(
+            keys.publishing_key_pair(3).public().to_string(),
+            recovered_keys.publishing_key_pair(3).public().to_string()
+        );
+        assert_ne!(
+            keys.ownership_key_pair().public(),
+            KeyManager::new(seed)
+                .channel_keys(8)
+                .ownership_key_pair()
+                .public()
+        );
+    }
+
+    #[test]
+    fn debug_does_not_reveal_seed() {
+        let seed = [0xab; 32];
+
+        let key_manager = format!("{:?}", KeyManager::new(seed));
+        let channel_keys = format!("{:?}", KeyManager::new(seed).channel_keys(0));
+
+        assert!(!key_manager.contains("171"));
+        assert!(!channel_keys.contains(&format!("{:?}", derive(&seed, b"channel", &[0; 4]))));
+    }
 }
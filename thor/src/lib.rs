@@ -18,16 +18,28 @@
 pub(crate) mod serde;
 
 pub mod channel;
+mod error;
+pub mod fee;
 mod keys;
+pub mod multiplex;
+mod shachain;
 mod signature;
+pub mod swap;
 mod transaction;
+pub mod watchtower;
+pub mod wire;
 
 pub use ::bitcoin;
 pub use channel::Channel;
-pub use keys::{PtlcPoint, PtlcSecret};
+pub use error::Error;
+pub use fee::{FeeRate, FeeRateBounds};
+pub use keys::{ChannelKeys, KeyManager, PtlcPoint, PtlcSecret};
+pub use multiplex::ChannelId;
+pub use signature::InvalidSignature;
+pub use transaction::{Error as TransactionError, InvalidSpliceTransaction, PunishError};
 
 use crate::{
-    channel::protocols::{close, create, splice, update},
+    channel::protocols::{close, create, propose, recover, reestablish, splice, update},
     keys::OwnershipPublicKey,
     transaction::{CommitTransaction, SplitTransaction},
 };
@@ -41,12 +53,6 @@ use std::convert::TryFrom;
 #[cfg(feature = "serde")]
 use bitcoin::util::amount::serde::as_sat;
 
-// TODO: We should handle fees dynamically
-
-// TODO: Have it as an `Amount` instead
-/// Flat fee used for all transactions involved in the protocol, in satoshi.
-pub const TX_FEE: u64 = 10_000;
-
 #[async_trait::async_trait]
 pub trait MedianTime {
     async fn median_time(&self) -> Result<u32>;
@@ -57,12 +63,71 @@ pub trait GetRawTransaction {
     async fn get_raw_transaction(&self, txid: Txid) -> Result<Transaction>;
 }
 
-#[derive(Clone, Debug)]
-pub enum Splice {
-    /// Useful if the other party wants to splice in or out
-    None,
-    In(Amount),
-    Out(TxOut),
+/// Find out how deep a transaction is buried in the blockchain.
+#[async_trait::async_trait]
+pub trait GetConfirmations {
+    /// Number of blocks confirming the transaction with id `txid`, which is
+    /// zero while it is unconfirmed.
+    async fn get_confirmations(&self, txid: Txid) -> Result<u32>;
+}
+
+/// Get notified once a transaction is confirmed, or can no longer be.
+#[async_trait::async_trait]
+pub trait WatchConfirmations {
+    /// Wait until the transaction with id `txid` is confirmed by `depth`
+    /// blocks, or until a transaction double-spending one of its inputs is.
+    async fn watch_for_confirmations(&self, txid: Txid, depth: u32) -> Result<Confirmation>;
+}
+
+/// Outcome of watching a transaction with `WatchConfirmations`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Confirmation {
+    /// The transaction is confirmed deep enough.
+    Confirmed,
+    /// A transaction double-spending one of its inputs is confirmed deep
+    /// enough, so it will never be.
+    DoubleSpent,
+}
+
+/// Estimate the fee rate needed for a transaction to be confirmed in a
+/// reasonable amount of time.
+#[async_trait::async_trait]
+pub trait EstimateFeeRate {
+    async fn estimate_fee_rate(&self) -> Result<FeeRate>;
+}
+
+/// Changes to our side of the channel when splicing it.
+///
+/// Both parties can splice in and out in the same splice. The default, which
+/// changes nothing on our side, is useful if only the other party wants to
+/// splice in or out.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Splice {
+    /// Amount added to our balance, funded by a PSBT from our wallet. Zero if
+    /// we splice nothing in.
+    #[cfg_attr(feature = "serde", serde(with = "as_sat"))]
+    pub amount_in: Amount,
+    /// Outputs of the `SpliceTransaction` paid out of our balance.
+    pub outs: Vec<TxOut>,
+}
+
+impl Splice {
+    /// Add `amount` to our balance.
+    pub fn splice_in(amount: Amount) -> Self {
+        Self {
+            amount_in: amount,
+            outs: Vec::new(),
+        }
+    }
+
+    /// Pay out `output` from our balance.
+    pub fn splice_out(output: TxOut) -> Self {
+        Self {
+            amount_in: Amount::ZERO,
+            outs: vec![output],
+        }
+    }
 }
 
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -134,8 +199,7 @@ pub enum Message {
     Create4(create::Message4),
     Create5(create::Message5),
     Update0(update::ShareKeys),
-    UpdatePtlcFunder(update::SignaturesPtlcFunder),
-    UpdatePtlcRedeemer(update::SignaturesPtlcRedeemer),
+    UpdatePtlcs(update::SharePtlcSignatures),
     Update1(update::ShareSplitSignature),
     Update2(update::ShareCommitEncryptedSignature),
     Update3(update::RevealRevocationSecretKey),
@@ -145,6 +209,16 @@ pub enum Message {
     Splice1(splice::Message1),
     Splice2(splice::Message2),
     Splice3(splice::Message3),
+    Recover0(recover::RequestForceClose),
+    Recover1(recover::ShareForceCloseTransactions),
+    Reestablish0(reestablish::ShareStateNumber),
+    Reestablish1(reestablish::ShareRevocationSecretKey),
+    Abort(Abort),
+    ProposeUpdate(propose::ProposeUpdate),
+    ProposeClose(propose::ProposeClose),
+    ProposeSplice(propose::ProposeSplice),
+    Accept(propose::Accept),
+    Reject(propose::Reject),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -165,6 +239,114 @@ impl UnexpectedMessage {
     }
 }
 
+/// Message sent to the counterparty when we stop running a channel protocol
+/// because of an error, so that they do not wait for our next message.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug)]
+pub struct Abort {
+    reason_code: u16,
+    details: String,
+}
+
+/// Reason why a channel protocol was aborted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AbortReason {
+    /// The message received was not the one expected in the current step.
+    UnexpectedMessage,
+    /// The message received was invalid, e.g. it contained an invalid
+    /// signature or proposed an unacceptable fee rate.
+    InvalidMessage,
+    /// The step failed for reasons unrelated to the message received, e.g. the
+    /// wallet failed to build or sign a transaction.
+    Internal,
+    /// The counterparty did not send the next message in time.
+    TimedOut,
+    /// Reason not known to this version of the library.
+    Unknown(u16),
+}
+
+impl AbortReason {
+    fn description(self) -> &'static str {
+        match self {
+            AbortReason::UnexpectedMessage => "unexpected message",
+            AbortReason::InvalidMessage => "invalid message",
+            AbortReason::Internal => "internal error",
+            AbortReason::TimedOut => "timed out waiting for message",
+            AbortReason::Unknown(_) => "unknown reason",
+        }
+    }
+}
+
+impl From<u16> for AbortReason {
+    fn from(code: u16) -> Self {
+        match code {
+            1 => AbortReason::UnexpectedMessage,
+            2 => AbortReason::InvalidMessage,
+            3 => AbortReason::Internal,
+            4 => AbortReason::TimedOut,
+            code => AbortReason::Unknown(code),
+        }
+    }
+}
+
+impl From<AbortReason> for u16 {
+    fn from(reason: AbortReason) -> Self {
+        match reason {
+            AbortReason::UnexpectedMessage => 1,
+            AbortReason::InvalidMessage => 2,
+            AbortReason::Internal => 3,
+            AbortReason::TimedOut => 4,
+            AbortReason::Unknown(code) => code,
+        }
+    }
+}
+
+impl Abort {
+    /// Abort message for a step which failed with `error`.
+    ///
+    /// The `reason` is overridden if the step failed because of an
+    /// `UnexpectedMessage`. Only a fixed description of the `reason` is sent
+    /// along with it, so that the `error` is not disclosed to the
+    /// counterparty.
+    pub fn new(reason: AbortReason, error: &anyhow::Error) -> Self {
+        let reason = if error.is::<UnexpectedMessage>() {
+            AbortReason::UnexpectedMessage
+        } else {
+            reason
+        };
+
+        Self {
+            reason_code: reason.into(),
+            details: reason.description().to_string(),
+        }
+    }
+
+    pub fn reason(&self) -> AbortReason {
+        self.reason_code.into()
+    }
+
+    pub fn details(&self) -> &str {
+        &self.details
+    }
+}
+
+/// Error returned when the counterparty aborts a channel protocol.
+#[derive(Clone, Debug, thiserror::Error)]
+#[error("counterparty aborted the protocol ({reason:?}): {details}")]
+pub struct ProtocolAborted {
+    pub reason: AbortReason,
+    pub details: String,
+}
+
+impl From<Abort> for ProtocolAborted {
+    fn from(abort: Abort) -> Self {
+        Self {
+            reason: abort.reason(),
+            details: abort.details,
+        }
+    }
+}
+
 impl From<create::Message0> for Message {
     fn from(m: create::Message0) -> Self {
         Message::Create0(m)
@@ -305,40 +487,20 @@ impl TryFrom<Message> for update::ShareKeys {
     }
 }
 
-impl From<update::SignaturesPtlcFunder> for Message {
-    fn from(m: update::SignaturesPtlcFunder) -> Self {
-        Message::UpdatePtlcFunder(m)
+impl From<update::SharePtlcSignatures> for Message {
+    fn from(m: update::SharePtlcSignatures) -> Self {
+        Message::UpdatePtlcs(m)
     }
 }
 
-impl TryFrom<Message> for update::SignaturesPtlcFunder {
+impl TryFrom<Message> for update::SharePtlcSignatures {
     type Error = UnexpectedMessage;
 
     fn try_from(m: Message) -> Result<Self, Self::Error> {
         match m {
-            Message::UpdatePtlcFunder(m) => Ok(m),
+            Message::UpdatePtlcs(m) => Ok(m),
             _ => Err(UnexpectedMessage {
-                expected_type: "UpdatePtlcFunder".to_string(),
-                received: m,
-            }),
-        }
-    }
-}
-
-impl From<update::SignaturesPtlcRedeemer> for Message {
-    fn from(m: update::SignaturesPtlcRedeemer) -> Self {
-        Message::UpdatePtlcRedeemer(m)
-    }
-}
-
-impl TryFrom<Message> for update::SignaturesPtlcRedeemer {
-    type Error = UnexpectedMessage;
-
-    fn try_from(m: Message) -> Result<Self, Self::Error> {
-        match m {
-            Message::UpdatePtlcRedeemer(m) => Ok(m),
-            _ => Err(UnexpectedMessage {
-                expected_type: "UpdatePtlcRedeemer".to_string(),
+                expected_type: "UpdatePtlcs".to_string(),
                 received: m,
             }),
         }
@@ -524,3 +686,203 @@ impl TryFrom<Message> for splice::Message3 {
         }
     }
 }
+
+impl From<recover::RequestForceClose> for Message {
+    fn from(m: recover::RequestForceClose) -> Self {
+        Message::Recover0(m)
+    }
+}
+
+impl TryFrom<Message> for recover::RequestForceClose {
+    type Error = UnexpectedMessage;
+
+    fn try_from(m: Message) -> Result<Self, Self::Error> {
+        match m {
+            Message::Recover0(m) => Ok(m),
+            _ => Err(UnexpectedMessage {
+                expected_type: "Recover0".to_string(),
+                received: m,
+            }),
+        }
+    }
+}
+
+impl From<recover::ShareForceCloseTransactions> for Message {
+    fn from(m: recover::ShareForceCloseTransactions) -> Self {
+        Message::Recover1(m)
+    }
+}
+
+impl TryFrom<Message> for recover::ShareForceCloseTransactions {
+    type Error = UnexpectedMessage;
+
+    fn try_from(m: Message) -> Result<Self, Self::Error> {
+        match m {
+            Message::Recover1(m) => Ok(m),
+            _ => Err(UnexpectedMessage {
+                expected_type: "Recover1".to_string(),
+                received: m,
+            }),
+        }
+    }
+}
+
+impl From<reestablish::ShareStateNumber> for Message {
+    fn from(m: reestablish::ShareStateNumber) -> Self {
+        Message::Reestablish0(m)
+    }
+}
+
+impl TryFrom<Message> for reestablish::ShareStateNumber {
+    type Error = UnexpectedMessage;
+
+    fn try_from(m: Message) -> Result<Self, Self::Error> {
+        match m {
+            Message::Reestablish0(m) => Ok(m),
+            _ => Err(UnexpectedMessage {
+                expected_type: "Reestablish0".to_string(),
+                received: m,
+            }),
+        }
+    }
+}
+
+impl From<reestablish::ShareRevocationSecretKey> for Message {
+    fn from(m: reestablish::ShareRevocationSecretKey) -> Self {
+        Message::Reestablish1(m)
+    }
+}
+
+impl TryFrom<Message> for reestablish::ShareRevocationSecretKey {
+    type Error = UnexpectedMessage;
+
+    fn try_from(m: Message) -> Result<Self, Self::Error> {
+        match m {
+            Message::Reestablish1(m) => Ok(m),
+            _ => Err(UnexpectedMessage {
+                expected_type: "Reestablish1".to_string(),
+                received: m,
+            }),
+        }
+    }
+}
+
+impl From<Abort> for Message {
+    fn from(m: Abort) -> Self {
+        Message::Abort(m)
+    }
+}
+
+impl TryFrom<Message> for Abort {
+    type Error = UnexpectedMessage;
+
+    fn try_from(m: Message) -> Result<Self, Self::Error> {
+        match m {
+            Message::Abort(m) => Ok(m),
+            _ => Err(UnexpectedMessage {
+                expected_type: "Abort".to_string(),
+                received: m,
+            }),
+        }
+    }
+}
+
+impl From<propose::ProposeUpdate> for Message {
+    fn from(m: propose::ProposeUpdate) -> Self {
+        Message::ProposeUpdate(m)
+    }
+}
+
+impl TryFrom<Message> for propose::ProposeUpdate {
+    type Error = UnexpectedMessage;
+
+    fn try_from(m: Message) -> Result<Self, Self::Error> {
+        match m {
+            Message::ProposeUpdate(m) => Ok(m),
+            _ => Err(UnexpectedMessage {
+                expected_type: "ProposeUpdate".to_string(),
+                received: m,
+            }),
+        }
+    }
+}
+
+impl From<propose::ProposeClose> for Message {
+    fn from(m: propose::ProposeClose) -> Self {
+        Message::ProposeClose(m)
+    }
+}
+
+impl TryFrom<Message> for propose::ProposeClose {
+    type Error = UnexpectedMessage;
+
+    fn try_from(m: Message) -> Result<Self, Self::Error> {
+        match m {
+            Message::ProposeClose(m) => Ok(m),
+            _ => Err(UnexpectedMessage {
+                expected_type: "ProposeClose".to_string(),
+                received: m,
+            }),
+        }
+    }
+}
+
+impl From<propose::ProposeSplice> for Message {
+    fn from(m: propose::ProposeSplice) -> Self {
+        Message::ProposeSplice(m)
+    }
+}
+
+impl TryFrom<Message> for propose::ProposeSplice {
+    type Error = UnexpectedMessage;
+
+    fn try_from(m: Message) -> Result<Self, Self::Error> {
+        match m {
+            Message::ProposeSplice(m) => Ok(m),
+            _ => Err(UnexpectedMessage {
+                expected_type: "ProposeSplice".to_string(),
+                received: m,
+            }),
+        }
+    }
+}
+
+impl From<propose::Accept> for Message {
+    fn from(m: propose::Accept) -> Self {
+        Message::Accept(m)
+    }
+}
+
+impl TryFrom<Message> for propose::Accept {
+    type Error = UnexpectedMessage;
+
+    fn try_from(m: Message) -> Result<Self, Self::Error> {
+        match m {
+            Message::Accept(m) => Ok(m),
+            _ => Err(UnexpectedMessage {
+                expected_type: "Accept".to_string(),
+                received: m,
+            }),
+        }
+    }
+}
+
+impl From<propose::Reject> for Message {
+    fn from(m: propose::Reject) -> Self {
+        Message::Reject(m)
+    }
+}
+
+impl TryFrom<Message> for propose::Reject {
+    type Error = UnexpectedMessage;
+
+    fn try_from(m: Message) -> Result<Self, Self::Error> {
+        match m {
+            Message::Reject(m) => Ok(m),
+            _ => Err(UnexpectedMessage {
+                expected_type: "Reject".to_string(),
+                received: m,
+            }),
+        }
+    }
+}
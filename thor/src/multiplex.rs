@@ -0,0 +1,318 @@
+//! Multiplexing of many channels with the same counterparty over a single
+//! connection.
+//!
+//! Every message sent over the connection is wrapped in an `Envelope` which
+//! identifies the channel it belongs to. The `Multiplexer` routes the messages
+//! received over the connection to the `ChannelTransport` of each channel,
+//! which can be used to run any of the channel protocols.
+
+use crate::{
+    channel::{ReceiveMessage, SendMessage},
+    Message,
+};
+use ::serde::{Deserialize, Serialize};
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use bitcoin::{
+    hashes::{hex::ToHex, Hash},
+    OutPoint,
+};
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future, Future, StreamExt, TryFutureExt,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// Identifier of a channel, unique among all the channels with the same
+/// counterparty.
+///
+/// Once a channel has been created, its identifier is derived from the
+/// outpoint of the fund output, so it changes every time the channel is
+/// spliced. Before that, the parties must agree on a temporary identifier to
+/// create the channel, e.g. one chosen by the party initiating it using
+/// `ChannelId::random`.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ChannelId([u8; 32]);
+
+impl ChannelId {
+    /// Random identifier to be used until the channel has been created.
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+}
+
+impl From<OutPoint> for ChannelId {
+    fn from(outpoint: OutPoint) -> Self {
+        let mut id: [u8; 32] = outpoint.txid.into_inner();
+
+        // Combine the transaction id with the output index, as done for
+        // Lightning channel identifiers
+        #[allow(clippy::cast_possible_truncation)]
+        let vout = outpoint.vout as u16;
+        id[30] ^= (vout >> 8) as u8;
+        id[31] ^= vout as u8;
+
+        Self(id)
+    }
+}
+
+impl fmt::Display for ChannelId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.to_hex())
+    }
+}
+
+/// A `Message` together with the identifier of the channel it belongs to.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+pub struct Envelope {
+    pub channel_id: ChannelId,
+    pub message: Message,
+}
+
+#[async_trait]
+pub trait SendEnvelope {
+    async fn send_envelope(&mut self, envelope: Envelope) -> Result<()>;
+}
+
+#[async_trait]
+pub trait ReceiveEnvelope {
+    async fn receive_envelope(&mut self) -> Result<Envelope>;
+}
+
+/// Handle to a connection multiplexing many channels with the same
+/// counterparty.
+#[derive(Clone, Debug)]
+pub struct Multiplexer {
+    outgoing: UnboundedSender<Envelope>,
+    routes: Arc<Mutex<Routes>>,
+}
+
+/// Maximum number of channels which have not been opened yet for which
+/// messages are kept. Messages for any other such channel are dropped.
+const MAX_UNCLAIMED_CHANNELS: usize = 16;
+
+/// Maximum number of messages kept for a channel which has not been opened
+/// yet. Any further message for the channel is dropped.
+const MAX_UNCLAIMED_MESSAGES_PER_CHANNEL: usize = 16;
+
+#[derive(Debug, Default)]
+struct Routes {
+    channels: HashMap<ChannelId, UnboundedSender<Message>>,
+    /// Messages received for channels which have not been opened yet, e.g.
+    /// because the counterparty started creating the channel before us.
+    unclaimed: HashMap<ChannelId, Vec<Message>>,
+}
+
+impl Routes {
+    /// Deliver `message` to the transport of the channel identified by
+    /// `channel_id`, or keep it until the channel is opened.
+    ///
+    /// Only a bounded number of messages is kept for channels which have not
+    /// been opened yet, so that the counterparty cannot make us buffer
+    /// messages indefinitely.
+    fn route(&mut self, channel_id: ChannelId, message: Message) {
+        if let Some(incoming) = self.channels.get(&channel_id) {
+            if incoming.unbounded_send(message).is_err() {
+                // The transport is being dropped
+                self.channels.remove(&channel_id);
+            }
+
+            return;
+        }
+
+        if !self.unclaimed.contains_key(&channel_id)
+            && self.unclaimed.len() >= MAX_UNCLAIMED_CHANNELS
+        {
+            return;
+        }
+
+        let messages = self.unclaimed.entry(channel_id).or_default();
+        if messages.len() < MAX_UNCLAIMED_MESSAGES_PER_CHANNEL {
+            messages.push(message);
+        }
+    }
+}
+
+impl Multiplexer {
+    /// Multiplex channels over the connection made up of `sender` and
+    /// `receiver`.
+    ///
+    /// The returned future moves envelopes over the connection and _must_ be
+    /// polled for as long as the channels are in use. It only resolves if the
+    /// connection fails.
+    pub fn new<S, R>(sender: S, receiver: R) -> (Self, impl Future<Output = Result<()>>)
+    where
+        S: SendEnvelope,
+        R: ReceiveEnvelope,
+    {
+        let (outgoing, outgoing_receiver) = mpsc::unbounded();
+        let routes = Arc::new(Mutex::new(Routes::default()));
+
+        let connection = future::try_join(
+            send_outgoing(sender, outgoing_receiver),
+            route_incoming(receiver, routes.clone()),
+        )
+        .map_ok(|_| ());
+
+        (Self { outgoing, routes }, connection)
+    }
+
+    /// Open a transport for the channel identified by `channel_id`.
+    ///
+    /// Messages for the channel which were received before it was opened are
+    /// delivered first, up to `MAX_UNCLAIMED_MESSAGES_PER_CHANNEL` of them.
+    /// Only one transport can be open per channel at a time.
+    pub fn open(&self, channel_id: ChannelId) -> Result<ChannelTransport> {
+        let mut routes = self.routes.lock().expect("routes lock is not poisoned");
+
+        if routes.channels.contains_key(&channel_id) {
+            bail!("transport for channel {} is already open", channel_id)
+        }
+
+        let (incoming_sender, incoming) = mpsc::unbounded();
+        for message in routes.unclaimed.remove(&channel_id).unwrap_or_default() {
+            incoming_sender
+                .unbounded_send(message)
+                .expect("receiver is alive");
+        }
+        routes.channels.insert(channel_id, incoming_sender);
+
+        Ok(ChannelTransport {
+            channel_id,
+            outgoing: self.outgoing.clone(),
+            incoming,
+            routes: self.routes.clone(),
+        })
+    }
+}
+
+async fn send_outgoing<S>(mut sender: S, mut outgoing: UnboundedReceiver<Envelope>) -> Result<()>
+where
+    S: SendEnvelope,
+{
+    while let Some(envelope) = outgoing.next().await {
+        sender.send_envelope(envelope).await?;
+    }
+
+    Ok(())
+}
+
+async fn route_incoming<R>(mut receiver: R, routes: Arc<Mutex<Routes>>) -> Result<()>
+where
+    R: ReceiveEnvelope,
+{
+    loop {
+        let envelope = receiver.receive_envelope().await;
+
+        let mut routes = routes.lock().expect("routes lock is not poisoned");
+        let Envelope {
+            channel_id,
+            message,
+        } = match envelope {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                // Let every open transport know that the connection is closed
+                routes.channels.clear();
+
+                return Err(e);
+            }
+        };
+
+        routes.route(channel_id, message);
+    }
+}
+
+/// Transport for a single channel multiplexed over a shared connection.
+#[derive(Debug)]
+pub struct ChannelTransport {
+    channel_id: ChannelId,
+    outgoing: UnboundedSender<Envelope>,
+    incoming: UnboundedReceiver<Message>,
+    routes: Arc<Mutex<Routes>>,
+}
+
+impl ChannelTransport {
+    pub fn channel_id(&self) -> ChannelId {
+        self.channel_id
+    }
+}
+
+impl Drop for ChannelTransport {
+    fn drop(&mut self) {
+        if let Ok(mut routes) = self.routes.lock() {
+            routes.channels.remove(&self.channel_id);
+        }
+    }
+}
+
+#[async_trait]
+impl SendMessage for ChannelTransport {
+    async fn send_message(&mut self, message: Message) -> Result<()> {
+        self.outgoing
+            .unbounded_send(Envelope {
+                channel_id: self.channel_id,
+                message,
+            })
+            .map_err(|_| anyhow!("multiplexed connection is closed"))
+    }
+}
+
+#[async_trait]
+impl ReceiveMessage for ChannelTransport {
+    async fn receive_message(&mut self) -> Result<Message> {
+        self.incoming
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("multiplexed connection is closed"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PtlcSecret;
+
+    fn message() -> Message {
+        Message::Secret(PtlcSecret::new_random())
+    }
+
+    #[test]
+    fn messages_for_unopened_channels_are_bounded() {
+        let mut routes = Routes::default();
+
+        let channel_id = ChannelId::random();
+        for _ in 0..MAX_UNCLAIMED_MESSAGES_PER_CHANNEL + 1 {
+            routes.route(channel_id, message());
+        }
+        assert_eq!(
+            routes.unclaimed[&channel_id].len(),
+            MAX_UNCLAIMED_MESSAGES_PER_CHANNEL
+        );
+
+        for _ in 0..MAX_UNCLAIMED_CHANNELS {
+            routes.route(ChannelId::random(), message());
+        }
+        assert_eq!(routes.unclaimed.len(), MAX_UNCLAIMED_CHANNELS);
+    }
+
+    #[test]
+    fn messages_for_dropped_transport_are_discarded() {
+        let mut routes = Routes::default();
+
+        let channel_id = ChannelId::random();
+        let (incoming_sender, incoming) = mpsc::unbounded();
+        routes.channels.insert(channel_id, incoming_sender);
+        drop(incoming);
+
+        routes.route(channel_id, message());
+
+        assert!(routes.channels.is_empty());
+        assert!(routes.unclaimed.is_empty());
+    }
+}
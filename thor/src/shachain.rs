@@ -0,0 +1,205 @@
+//! Derivation and compact storage of `RevocationSecretKey`s.
+//!
+//! The `RevocationSecretKey` of every channel state is derived from a single
+//! seed using the scheme described in BOLT 3, under "Efficient Per-commitment
+//! Secret Storage". Since each secret can be derived from any secret revealed
+//! after it, the counterparty only needs to keep `O(log n)` secrets after `n`
+//! channel updates to be able to punish the publication of any revoked state.
+
+use crate::keys::{RevocationKeyPair, RevocationSecretKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Number of bits of the index of a secret.
+const INDEX_BITS: u8 = 48;
+
+/// Index of the secret of the first channel state. Indices decrease as the
+/// channel is updated, so that every secret can be derived from the ones
+/// revealed after it.
+const MAX_INDEX: u64 = (1 << INDEX_BITS) - 1;
+
+/// Seed from which our `RevocationKeyPair` for every channel state is
+/// derived.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug)]
+pub(crate) struct RevocationSeed([u8; 32]);
+
+impl From<[u8; 32]> for RevocationSeed {
+    fn from(seed: [u8; 32]) -> Self {
+        Self(seed)
+    }
+}
+
+impl RevocationSeed {
+    /// Derive the `RevocationKeyPair` of the channel state with number
+    /// `state_number`.
+    pub fn key_pair(&self, state_number: u64) -> RevocationKeyPair {
+        let secret = derive_secret(self.0, INDEX_BITS, index(state_number));
+
+        RevocationSecretKey::from_bytes(secret)
+            .expect("derived secret is a valid scalar with overwhelming probability")
+            .into()
+    }
+}
+
+#[derive(Clone, Copy, Debug, thiserror::Error)]
+#[error("revocation secret key for state {state_number} is not consistent with the ones revealed before")]
+pub struct InconsistentRevocationSecretKey {
+    state_number: u64,
+}
+
+/// Storage for the `RevocationSecretKey`s revealed by the counterparty.
+///
+/// At most one secret per bit of the index is kept, every other secret is
+/// derived on demand.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug)]
+pub(crate) struct RevocationSecretStore {
+    known: Vec<Option<KnownSecret>>,
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug)]
+struct KnownSecret {
+    index: u64,
+    secret: [u8; 32],
+}
+
+impl Default for RevocationSecretStore {
+    fn default() -> Self {
+        Self {
+            known: vec![None; usize::from(INDEX_BITS) + 1],
+        }
+    }
+}
+
+impl RevocationSecretStore {
+    /// Store the `RevocationSecretKey` of the channel state with number
+    /// `state_number`.
+    ///
+    /// Secrets _must_ be inserted in order of increasing state number. The
+    /// new secret is rejected if any of the stored secrets cannot be derived
+    /// from it, as we would otherwise lose the ability to punish the
+    /// publication of their states.
+    pub fn insert(
+        &mut self,
+        state_number: u64,
+        secret_key: &RevocationSecretKey,
+    ) -> Result<(), InconsistentRevocationSecretKey> {
+        let index = index(state_number);
+        let secret = secret_key.to_bytes();
+        let bucket = bucket(index);
+
+        for known in self.known[..usize::from(bucket)].iter().flatten() {
+            if derive_secret(secret, bucket, known.index) != known.secret {
+                return Err(InconsistentRevocationSecretKey { state_number });
+            }
+        }
+
+        self.known[usize::from(bucket)] = Some(KnownSecret { index, secret });
+
+        Ok(())
+    }
+
+    /// Retrieve the `RevocationSecretKey` of the channel state with number
+    /// `state_number`, if it has been revealed.
+    pub fn get(&self, state_number: u64) -> Option<RevocationSecretKey> {
+        let index = index(state_number);
+
+        self.known
+            .iter()
+            .enumerate()
+            .filter_map(|(bucket, known)| known.map(|known| (bucket, known)))
+            .find(|(bucket, known)| index & !((1 << bucket) - 1) == known.index)
+            .and_then(|(bucket, known)| {
+                #[allow(clippy::cast_possible_truncation)]
+                let bits = bucket as u8;
+                let secret = derive_secret(known.secret, bits, index);
+
+                RevocationSecretKey::from_bytes(secret)
+            })
+    }
+}
+
+fn index(state_number: u64) -> u64 {
+    MAX_INDEX - state_number
+}
+
+/// Position in which the secret with index `index` is stored, i.e. the
+/// number of trailing zeros of the index.
+#[allow(clippy::cast_possible_truncation)]
+fn bucket(index: u64) -> u8 {
+    std::cmp::min(index.trailing_zeros(), u32::from(INDEX_BITS)) as u8
+}
+
+/// Derive the secret with index `index` from `base`, flipping and hashing for
+/// each of the lowest `bits` bits of the index which are set.
+fn derive_secret(mut base: [u8; 32], bits: u8, index: u64) -> [u8; 32] {
+    for bit in (0..bits).rev() {
+        if index & (1 << bit) != 0 {
+            base[usize::from(bit / 8)] ^= 1 << (bit % 8);
+            base.copy_from_slice(&Sha256::digest(&base));
+        }
+    }
+
+    base
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(hex: &str) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&hex::decode(hex).unwrap());
+
+        bytes
+    }
+
+    #[test]
+    fn derive_secret_matches_bolt_3_test_vectors() {
+        assert_eq!(
+            derive_secret([0x00; 32], INDEX_BITS, 281_474_976_710_655),
+            from_hex("02a40c85b6f28da08dfdbe0926c53fab2de6d28c10301f8f7c4073d5e42e3148")
+        );
+        assert_eq!(
+            derive_secret([0xFF; 32], INDEX_BITS, 281_474_976_710_655),
+            from_hex("7cc854b54e3e0dcdb010d7a3fee464a9687be6e8db3be6854c475621e007a5dc")
+        );
+        assert_eq!(
+            derive_secret([0xFF; 32], INDEX_BITS, 0xaaa_aaaa_aaaa),
+            from_hex("56f4008fb007ca9acf0e15b054d5c9fd12ee06cea347914ddbaed70d1c13a528")
+        );
+    }
+
+    #[test]
+    fn all_revealed_secrets_can_be_retrieved_from_store() {
+        let seed = RevocationSeed::from(rand::random::<[u8; 32]>());
+        let mut store = RevocationSecretStore::default();
+
+        for state_number in 0..100 {
+            let secret_key = seed.key_pair(state_number).into();
+            store.insert(state_number, &secret_key).unwrap();
+        }
+
+        assert!(store.known.iter().flatten().count() <= usize::from(INDEX_BITS) + 1);
+        for state_number in 0..100 {
+            let expected = seed.key_pair(state_number).public();
+            let secret_key = store.get(state_number).unwrap();
+
+            assert!(expected.verify_revocation_secret_key(&secret_key).is_ok());
+        }
+        assert!(store.get(100).is_none());
+    }
+
+    #[test]
+    fn secret_from_different_seed_is_rejected() {
+        let seed = RevocationSeed::from(rand::random::<[u8; 32]>());
+        let mut store = RevocationSecretStore::default();
+
+        store.insert(0, &seed.key_pair(0).into()).unwrap();
+
+        let other_seed = RevocationSeed::from(rand::random::<[u8; 32]>());
+        assert!(store.insert(1, &other_seed.key_pair(1).into()).is_err());
+    }
+}
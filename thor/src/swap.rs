@@ -0,0 +1,273 @@
+//! Safety checks for atomic swaps with a thor channel as beta ledger.
+//!
+//! In such a swap, Alice reveals the secret to redeem the PTLC output in the
+//! channel and Bob uses it to redeem the alpha asset. Both parties must be
+//! able to react on-chain before the relevant expiry, so the expiries have to
+//! leave enough room for:
+//!
+//! - Alice to publish the `CommitTransaction`, wait for the relative time lock
+//!   of the `SplitTransaction` and redeem the PTLC output before it can be
+//!   refunded by Bob.
+//! - Bob to redeem the alpha asset with the secret before it can be refunded by
+//!   Alice.
+//!
+//! All expiries are compared against the median time past of the blockchain,
+//! as given by [`MedianTime`](crate::MedianTime).
+
+use std::time::Duration;
+
+/// Configuration of an atomic swap with a thor channel as beta ledger.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SwapConfig {
+    /// Time to wait for the counterparty to merge the PTLC output into our
+    /// balance after revealing the secret, before force closing the channel.
+    /// If `None`, it is derived from the expiries of the PTLC.
+    pub settlement_timeout: Option<Duration>,
+    /// Time to wait between checks of the state of the blockchain, e.g. when
+    /// waiting for the PTLC output to expire.
+    pub polling_interval: Duration,
+    pub safety_margins: SafetyMargins,
+}
+
+impl Default for SwapConfig {
+    fn default() -> Self {
+        Self {
+            settlement_timeout: None,
+            polling_interval: Duration::from_secs(1),
+            safety_margins: SafetyMargins::default(),
+        }
+    }
+}
+
+impl SwapConfig {
+    /// Time to wait for the counterparty to cooperate after revealing the
+    /// secret at `median_time`.
+    ///
+    /// Unless configured explicitly, it is half of the time left until the
+    /// deadline to reveal the secret, so that the PTLC output can still be
+    /// redeemed on-chain comfortably if we end up force closing the channel.
+    pub fn settlement_timeout(
+        &self,
+        median_time: u32,
+        tx_s_time_lock: u32,
+        ptlc_refund_time_lock: u32,
+    ) -> Duration {
+        self.settlement_timeout.unwrap_or_else(|| {
+            let deadline = self
+                .safety_margins
+                .reveal_secret_deadline(tx_s_time_lock, ptlc_refund_time_lock);
+
+            Duration::from_secs(u64::from(deadline.saturating_sub(median_time) / 2))
+        })
+    }
+}
+
+/// Margins used to decide if the expiries of an atomic swap are safe.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SafetyMargins {
+    /// Estimated time between blocks, in seconds. It is used to convert
+    /// relative time locks expressed in blocks into seconds.
+    pub block_time: u32,
+    /// Time, in seconds, needed to get a transaction spending the PTLC
+    /// output confirmed once the `SplitTransaction` can be published.
+    pub ptlc_redeem: u32,
+    /// Time, in seconds, needed by Bob to redeem the alpha asset after the
+    /// PTLC output has expired.
+    pub alpha_redeem: u32,
+}
+
+impl Default for SafetyMargins {
+    fn default() -> Self {
+        Self {
+            block_time: 10 * 60,
+            ptlc_redeem: 60 * 60,
+            alpha_redeem: 2 * 60 * 60,
+        }
+    }
+}
+
+impl SafetyMargins {
+    /// Latest median time at which the secret can be revealed so that, if the
+    /// counterparty does not cooperate, the PTLC output can still be redeemed
+    /// on-chain before it expires.
+    pub fn reveal_secret_deadline(&self, tx_s_time_lock: u32, ptlc_refund_time_lock: u32) -> u32 {
+        ptlc_refund_time_lock
+            .saturating_sub(tx_s_time_lock.saturating_mul(self.block_time))
+            .saturating_sub(self.ptlc_redeem)
+    }
+}
+
+#[derive(Clone, Copy, Debug, thiserror::Error)]
+pub enum UnsafeExpiries {
+    #[error("PTLC expiry {ptlc_refund_time_lock} leaves no time to redeem it on-chain, it must be after {min}")]
+    PtlcExpiresTooSoon {
+        ptlc_refund_time_lock: u32,
+        min: u32,
+    },
+    #[error("alpha expiry {alpha_absolute_expiry} leaves no time to redeem alpha after the PTLC expires, it must be after {min}")]
+    AlphaExpiresTooSoon {
+        alpha_absolute_expiry: u32,
+        min: u32,
+    },
+}
+
+#[derive(Clone, Copy, Debug, thiserror::Error)]
+#[error("revealing the secret at {median_time} is no longer safe, deadline was {deadline}")]
+pub struct UnsafeToRevealSecret {
+    median_time: u32,
+    deadline: u32,
+}
+
+/// Check that the expiries of an atomic swap leave enough time for both
+/// parties to act on-chain, given the current `median_time`.
+///
+/// It _must_ be called by both parties before adding the PTLC output to the
+/// channel.
+pub fn validate_expiries(
+    margins: &SafetyMargins,
+    median_time: u32,
+    alpha_absolute_expiry: u32,
+    tx_s_time_lock: u32,
+    ptlc_refund_time_lock: u32,
+) -> Result<(), UnsafeExpiries> {
+    let deadline = margins.reveal_secret_deadline(tx_s_time_lock, ptlc_refund_time_lock);
+    if deadline <= median_time {
+        return Err(UnsafeExpiries::PtlcExpiresTooSoon {
+            ptlc_refund_time_lock,
+            min: ptlc_refund_time_lock.saturating_add(median_time - deadline),
+        });
+    }
+
+    let min_alpha_absolute_expiry = ptlc_refund_time_lock.saturating_add(margins.alpha_redeem);
+    if alpha_absolute_expiry < min_alpha_absolute_expiry {
+        return Err(UnsafeExpiries::AlphaExpiresTooSoon {
+            alpha_absolute_expiry,
+            min: min_alpha_absolute_expiry,
+        });
+    }
+
+    Ok(())
+}
+
+/// Check that revealing the secret at `median_time` still allows the PTLC
+/// output to be redeemed on-chain before it expires.
+pub fn ensure_safe_to_reveal_secret(
+    margins: &SafetyMargins,
+    median_time: u32,
+    tx_s_time_lock: u32,
+    ptlc_refund_time_lock: u32,
+) -> Result<(), UnsafeToRevealSecret> {
+    let deadline = margins.reveal_secret_deadline(tx_s_time_lock, ptlc_refund_time_lock);
+    if median_time >= deadline {
+        return Err(UnsafeToRevealSecret {
+            median_time,
+            deadline,
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u32 = 1_600_000_000;
+    const HOUR: u32 = 60 * 60;
+
+    #[test]
+    fn safe_expiries_are_accepted() {
+        let margins = SafetyMargins::default();
+
+        let ptlc_refund_time_lock = NOW + 12 * HOUR;
+        let alpha_absolute_expiry = ptlc_refund_time_lock + 12 * HOUR;
+
+        assert!(validate_expiries(
+            &margins,
+            NOW,
+            alpha_absolute_expiry,
+            1,
+            ptlc_refund_time_lock
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn ptlc_expiring_before_split_transaction_can_be_published_is_rejected() {
+        let margins = SafetyMargins::default();
+
+        let tx_s_time_lock = 144;
+        let ptlc_refund_time_lock = NOW + 12 * HOUR;
+        let alpha_absolute_expiry = ptlc_refund_time_lock + 12 * HOUR;
+
+        assert!(matches!(
+            validate_expiries(
+                &margins,
+                NOW,
+                alpha_absolute_expiry,
+                tx_s_time_lock,
+                ptlc_refund_time_lock
+            ),
+            Err(UnsafeExpiries::PtlcExpiresTooSoon { .. })
+        ));
+    }
+
+    #[test]
+    fn alpha_expiring_too_close_to_ptlc_expiry_is_rejected() {
+        let margins = SafetyMargins::default();
+
+        let ptlc_refund_time_lock = NOW + 12 * HOUR;
+        let alpha_absolute_expiry = ptlc_refund_time_lock + HOUR;
+
+        assert!(matches!(
+            validate_expiries(
+                &margins,
+                NOW,
+                alpha_absolute_expiry,
+                1,
+                ptlc_refund_time_lock
+            ),
+            Err(UnsafeExpiries::AlphaExpiresTooSoon { .. })
+        ));
+    }
+
+    #[test]
+    fn default_settlement_timeout_is_derived_from_expiries() {
+        let config = SwapConfig::default();
+
+        let tx_s_time_lock = 1;
+        let ptlc_refund_time_lock = NOW + 12 * HOUR;
+        let deadline = config
+            .safety_margins
+            .reveal_secret_deadline(tx_s_time_lock, ptlc_refund_time_lock);
+
+        assert_eq!(
+            config.settlement_timeout(NOW, tx_s_time_lock, ptlc_refund_time_lock),
+            Duration::from_secs(u64::from(deadline - NOW) / 2)
+        );
+    }
+
+    #[test]
+    fn revealing_secret_after_deadline_is_unsafe() {
+        let margins = SafetyMargins::default();
+
+        let tx_s_time_lock = 1;
+        let ptlc_refund_time_lock = NOW + 12 * HOUR;
+        let deadline = margins.reveal_secret_deadline(tx_s_time_lock, ptlc_refund_time_lock);
+
+        assert!(ensure_safe_to_reveal_secret(
+            &margins,
+            deadline - 1,
+            tx_s_time_lock,
+            ptlc_refund_time_lock
+        )
+        .is_ok());
+        assert!(ensure_safe_to_reveal_secret(
+            &margins,
+            deadline,
+            tx_s_time_lock,
+            ptlc_refund_time_lock
+        )
+        .is_err());
+    }
+}
@@ -1,9 +1,15 @@
 use crate::{
+    fee::{
+        split_transaction_weight, FeeRate, CLOSE_TRANSACTION_WEIGHT, COMMIT_TRANSACTION_WEIGHT,
+        PUNISH_TRANSACTION_WEIGHT,
+    },
     keys::{
         OwnershipKeyPair, OwnershipPublicKey, PublishingKeyPair, PublishingPublicKey,
         RevocationKeyPair, RevocationPublicKey,
     },
-    signature, Balance, Ptlc, SplitOutput, TX_FEE,
+    signature,
+    transaction::anchor::Anchor,
+    Balance, Ptlc, PtlcPoint, SplitOutput,
 };
 use anyhow::{anyhow, bail, Result};
 use arrayvec::ArrayVec;
@@ -11,8 +17,13 @@ use bitcoin::{
     consensus::encode::serialize,
     hashes::{hash160, Hash},
     secp256k1,
-    util::{amount::serde::as_sat, bip143::SighashComponents, psbt::PartiallySignedTransaction},
-    Address, Amount, Network, OutPoint, Script, SigHash, Transaction, TxIn, TxOut, Txid,
+    util::{
+        amount::serde::as_sat,
+        bip143::SighashComponents,
+        psbt::{self, PartiallySignedTransaction},
+    },
+    Address, Amount, Network, OutPoint, Script, SigHash, SigHashType, Transaction, TxIn, TxOut,
+    Txid,
 };
 use ecdsa_fun::{
     self,
@@ -24,8 +35,12 @@ use miniscript::{self, Descriptor, Segwitv0};
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
 use signature::{verify_encsig, verify_sig};
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
 
+pub(crate) mod anchor;
 pub(crate) mod ptlc;
 
 #[derive(Clone, Debug)]
@@ -162,6 +177,7 @@ pub(crate) struct CommitTransaction {
     digest: SigHash,
     #[cfg_attr(feature = "serde", serde(with = "as_sat"))]
     fee: Amount,
+    fee_rate: FeeRate,
 }
 
 impl CommitTransaction {
@@ -169,19 +185,38 @@ impl CommitTransaction {
         tx_f: &FundingTransaction,
         keys: [(OwnershipPublicKey, RevocationPublicKey, PublishingPublicKey); 2],
         time_lock: u32,
+        fee_rate: FeeRate,
     ) -> Result<Self> {
+        let anchor_outputs = anchor::build_outputs([keys[0].0.clone(), keys[1].0.clone()]);
         let output_descriptor = Self::build_descriptor(keys, time_lock)?;
 
         let input = tx_f.as_txin();
-        let fee = Amount::from_sat(TX_FEE);
+        let fee = fee_rate.fee(COMMIT_TRANSACTION_WEIGHT);
+        let anchors_value =
+            Amount::from_sat(anchor_outputs.iter().map(|output| output.value).sum());
+        let value = tx_f
+            .value()
+            .checked_sub(fee + anchors_value)
+            .ok_or_else(|| Error::InsufficientFunds {
+                input: tx_f.value(),
+                output: anchors_value,
+                fee,
+            })?;
+
+        // The output spendable by the `SplitTransaction` _must_ be the first output,
+        // followed by the anchor outputs
         let tx_c = Transaction {
             version: 2,
             lock_time: 0,
             input: vec![input],
-            output: vec![TxOut {
-                value: tx_f.value().as_sat() - fee.as_sat(),
-                script_pubkey: output_descriptor.script_pubkey(),
-            }],
+            output: vec![
+                vec![TxOut {
+                    value: value.as_sat(),
+                    script_pubkey: output_descriptor.script_pubkey(),
+                }],
+                anchor_outputs,
+            ]
+            .concat(),
         };
 
         let digest = Self::compute_digest(&tx_c, tx_f);
@@ -192,6 +227,7 @@ impl CommitTransaction {
             time_lock,
             digest,
             fee,
+            fee_rate,
         })
     }
 
@@ -302,6 +338,22 @@ impl CommitTransaction {
         self.fee
     }
 
+    fn anchor_outputs(&self) -> Vec<TxOut> {
+        self.inner.output[1..].to_vec()
+    }
+
+    /// Our anchor output, which can be spent to bump the fee of the
+    /// `CommitTransaction`.
+    pub fn anchor(&self, X_self: OwnershipPublicKey) -> Result<Anchor> {
+        Anchor::new(&self.inner, X_self, COMMIT_TRANSACTION_WEIGHT, self.fee)
+    }
+
+    /// The fee rate agreed upon for this state of the channel. It is also used
+    /// for all the transactions which spend from this one.
+    pub fn fee_rate(&self) -> FeeRate {
+        self.fee_rate
+    }
+
     // TODO: Remove code duplication.
     fn compute_digest(tx_c: &Transaction, tx_f: &FundingTransaction) -> SigHash {
         SighashComponents::new(&tx_c).sighash_all(
@@ -382,10 +434,15 @@ pub(crate) struct SplitTransaction {
     inner: Transaction,
     input_descriptor: Descriptor<bitcoin::PublicKey>,
     digest: SigHash,
+    #[cfg_attr(feature = "serde", serde(with = "as_sat"))]
+    fee: Amount,
+    fee_rate: FeeRate,
+    /// Index of the output of each PTLC, identified by its point.
+    ptlc_vouts: Vec<(PtlcPoint, u32)>,
 }
 
 #[derive(Clone, Copy, Debug, thiserror::Error)]
-pub(crate) enum Error {
+pub enum Error {
     #[error(
     "input amount {input} does not cover total transaction output amount {output} and fee {fee}"
     )]
@@ -412,70 +469,113 @@ pub(crate) enum Error {
 
 impl SplitTransaction {
     pub(crate) fn new(tx_c: &CommitTransaction, outputs: Vec<SplitOutput>) -> Result<Self, Error> {
-        debug_assert!(outputs.len() >= 2); // One for each party and optionally a PTLC.
+        debug_assert!(outputs.len() >= 2); // One for each party and optionally PTLCs.
         let total_input = tx_c.value();
         let total_output =
             Amount::from_sat(outputs.iter().map(|output| output.amount().as_sat()).sum());
-        let tx_s_fee = Amount::from_sat(TX_FEE);
-        if total_input < total_output - tx_c.fee() - tx_s_fee {
-            return Err(Error::InsufficientFunds {
-                input: total_input,
-                output: total_output - tx_c.fee(),
-                fee: tx_s_fee,
-            });
-        }
-
         let n_outputs = outputs.len();
+        let tx_s_fee = tx_c.fee_rate().fee(split_transaction_weight(n_outputs));
+
+        // The `SplitTransaction` has the same anchor outputs as the `CommitTransaction`
+        let anchor_outputs = tx_c.anchor_outputs();
+        let anchors_value =
+            Amount::from_sat(anchor_outputs.iter().map(|output| output.value).sum());
+
+        // The anchor outputs are paid for like fees
+        let tx_c_cost = tx_c.fee() + anchors_value;
+        let tx_s_cost = tx_s_fee + anchors_value;
+        match total_output.checked_sub(tx_c_cost + tx_s_cost) {
+            Some(total_output_after_fees) if total_output_after_fees <= total_input => (),
+            _ => {
+                return Err(Error::InsufficientFunds {
+                    input: total_input,
+                    output: total_output,
+                    fee: tx_c_cost + tx_s_cost,
+                })
+            }
+        }
 
         // Distribute transaction tx_c fee costs evenly between outputs
-        let tx_c_fee_per_output = tx_c.fee() / n_outputs as u64;
+        let tx_c_fee_per_output = tx_c_cost / n_outputs as u64;
 
         // Distribute transaction tx_s fee costs evenly between outputs
-        let tx_s_fee_per_output = tx_s_fee / n_outputs as u64;
+        let tx_s_fee_per_output = tx_s_cost / n_outputs as u64;
+
+        let fee_per_output = tx_c_fee_per_output + tx_s_fee_per_output;
 
         let mut outputs = outputs
             .iter()
             .map(|output| match output {
-                SplitOutput::Ptlc(Ptlc {
-                    amount,
-                    X_funder,
-                    X_redeemer,
-                    ..
-                }) => {
+                SplitOutput::Ptlc(ptlc) => {
                     // Both parties _must_ insert the ownership public keys into the script in
                     // ascending lexicographical order of bytes
-                    let mut Xs = [X_funder, X_redeemer];
+                    let mut Xs = [&ptlc.X_funder, &ptlc.X_redeemer];
                     Xs.sort_by(|a, b| a.partial_cmp(b).expect("comparison is possible"));
                     let descriptor = build_shared_output_descriptor(Xs[0].clone(), Xs[1].clone());
 
-                    TxOut {
-                        value: amount.as_sat(),
+                    let output = TxOut {
+                        value: ptlc.amount.as_sat(),
                         script_pubkey: descriptor.script_pubkey(),
-                    }
+                    };
+
+                    (output, Some(ptlc.point()))
+                }
+                SplitOutput::Balance { amount, address } => {
+                    let output = TxOut {
+                        value: amount.as_sat(),
+                        script_pubkey: address.script_pubkey(),
+                    };
+
+                    (output, None)
                 }
-                SplitOutput::Balance { amount, address } => TxOut {
-                    value: amount.as_sat(),
-                    script_pubkey: address.script_pubkey(),
-                },
             })
-            .map(
-                |TxOut {
-                     value,
-                     script_pubkey,
-                 }| TxOut {
-                    // Distribute transaction fee costs evenly between outputs
-                    // TODO: Currently fails if there value is too small. Proposal would be to
-                    // exclude outputs smaller than the fees
-                    value: value - tx_c_fee_per_output.as_sat() - tx_s_fee_per_output.as_sat(),
-                    script_pubkey,
-                },
-            )
-            .collect::<Vec<_>>();
+            .map(|(output, point)| {
+                // Distribute transaction fee costs evenly between outputs, each of
+                // which must cover its share
+                let value = Amount::from_sat(output.value)
+                    .checked_sub(fee_per_output)
+                    .ok_or(Error::InsufficientFunds {
+                        input: Amount::from_sat(output.value),
+                        output: Amount::ZERO,
+                        fee: fee_per_output,
+                    })?;
+
+                let output = TxOut {
+                    value: value.as_sat(),
+                    script_pubkey: output.script_pubkey,
+                };
+
+                Ok((output, point))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
 
         // Sort outputs based on the ascending lexicographical order of script_pubkey
-        // bytes. Both parties _must_ do this so that they compute the same split
-        // transaction
-        outputs.sort_by(|a, b| a.script_pubkey.cmp(&b.script_pubkey));
+        // bytes. PTLC outputs between the same parties have the same script_pubkey,
+        // so they are further sorted based on the ascending lexicographical order of
+        // bytes of their point. Both parties _must_ do this so that they compute the
+        // same split transaction
+        outputs.sort_by(|(a, point_a), (b, point_b)| {
+            a.script_pubkey.cmp(&b.script_pubkey).then_with(|| {
+                point_a
+                    .partial_cmp(point_b)
+                    .expect("comparison is possible")
+            })
+        });
+
+        #[allow(clippy::cast_possible_truncation)]
+        let ptlc_vouts = outputs
+            .iter()
+            .enumerate()
+            .filter_map(|(vout, (_, point))| point.clone().map(|point| (point, vout as u32)))
+            .collect();
+
+        let mut outputs = outputs
+            .into_iter()
+            .map(|(output, _)| output)
+            .collect::<Vec<_>>();
+
+        // The anchor outputs _must_ go after all the other outputs
+        outputs.extend(anchor_outputs);
 
         let input = tx_c.as_txin_for_tx_s();
 
@@ -497,6 +597,9 @@ impl SplitTransaction {
             inner: tx_s,
             input_descriptor,
             digest,
+            fee: tx_s_fee,
+            fee_rate: tx_c.fee_rate(),
+            ptlc_vouts,
         })
     }
 
@@ -505,11 +608,11 @@ impl SplitTransaction {
     pub(crate) fn fees(
         tx_c_value: Amount,
         tx_c_fee: Amount,
+        tx_s_fee: Amount,
         amount_0: Amount,
         amount_1: Amount,
     ) -> Result<(Amount, Amount), Error> {
         let total_output = amount_0 + amount_1;
-        let tx_s_fee = Amount::from_sat(TX_FEE);
 
         if total_output != tx_c_value {
             return Err(Error::OutputMismatch {
@@ -622,6 +725,32 @@ impl SplitTransaction {
         self.inner.txid()
     }
 
+    pub fn fee_rate(&self) -> FeeRate {
+        self.fee_rate
+    }
+
+    /// Index of the output of the PTLC identified by `point`.
+    pub fn ptlc_vout(&self, point: &PtlcPoint) -> Option<u32> {
+        self.ptlc_vouts
+            .iter()
+            .find(|(candidate, _)| candidate == point)
+            .map(|(_, vout)| *vout)
+    }
+
+    /// Our anchor output, which can be spent to bump the fee of the
+    /// `SplitTransaction`.
+    pub fn anchor(&self, X_self: OwnershipPublicKey) -> Result<Anchor> {
+        // Each party has an anchor output, which does not count as a split output
+        let n_outputs = self.inner.output.len() - 2;
+
+        Anchor::new(
+            &self.inner,
+            X_self,
+            split_transaction_weight(n_outputs),
+            self.fee,
+        )
+    }
+
     fn compute_digest(tx_s: &Transaction, tx_c: &CommitTransaction) -> SigHash {
         SighashComponents::new(&tx_s).sighash_all(
             &tx_c.as_txin_for_tx_s(),
@@ -641,14 +770,34 @@ impl From<SplitTransaction> for Transaction {
 pub(crate) struct PunishTransaction(Transaction);
 
 #[derive(Debug, thiserror::Error)]
-pub(crate) enum PunishError {
+pub enum PunishError {
     #[error("no signatures found in witness stack")]
     NoSignatures,
     #[error("could not recover PublishingSecretKey from signatures in transaction")]
     RecoveryFailure,
 }
 
-impl PunishTransaction {
+/// A `PunishTransaction` signed with every key except for the
+/// `PublishingSecretKey` of the counterparty.
+///
+/// The missing key can be recovered from the revoked `CommitTransaction` once
+/// it has been published, so this can be handed over to a third party who will
+/// be able to punish the counterparty without knowing any of our secret keys.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug)]
+pub(crate) struct PresignedPunishTransaction {
+    inner: Transaction,
+    input_descriptor: Descriptor<bitcoin::PublicKey>,
+    digest: SigHash,
+    X_self: OwnershipPublicKey,
+    sig_x_self: Signature,
+    R_other: RevocationPublicKey,
+    sig_r_other: Signature,
+    Y_other: PublishingPublicKey,
+    encsig_tx_c_self: EncryptedSignature,
+}
+
+impl PresignedPunishTransaction {
     pub(crate) fn new(
         x_self: &OwnershipKeyPair,
         final_address: Address,
@@ -656,8 +805,54 @@ impl PunishTransaction {
         encsig_tx_c_self: &EncryptedSignature,
         r_other: &RevocationKeyPair,
         Y_other: PublishingPublicKey,
-        revoked_tx_c_candidate: Transaction,
     ) -> Result<Self> {
+        let tx_p = {
+            let fee = tx_c.fee_rate().fee(PUNISH_TRANSACTION_WEIGHT);
+            let value = tx_c
+                .value()
+                .checked_sub(fee)
+                .ok_or_else(|| Error::InsufficientFunds {
+                    input: tx_c.value(),
+                    output: Amount::ZERO,
+                    fee,
+                })?;
+            let output = TxOut {
+                value: value.as_sat(),
+                script_pubkey: final_address.script_pubkey(),
+            };
+            Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec![tx_c.as_txin_for_tx_p()],
+                output: vec![output],
+            }
+        };
+
+        let digest = Self::compute_digest(&tx_p, &tx_c);
+
+        Ok(Self {
+            inner: tx_p,
+            input_descriptor: tx_c.output_descriptor(),
+            digest,
+            X_self: x_self.public(),
+            sig_x_self: x_self.sign(digest),
+            R_other: r_other.public(),
+            sig_r_other: r_other.sign(digest),
+            Y_other,
+            encsig_tx_c_self: encsig_tx_c_self.clone(),
+        })
+    }
+
+    /// Transaction id of the revoked `CommitTransaction` that this transaction
+    /// punishes.
+    pub(crate) fn revoked_tx_c_txid(&self) -> Txid {
+        self.inner.input[0].previous_output.txid
+    }
+
+    /// Recover the `PublishingSecretKey` of the counterparty from the
+    /// published `revoked_tx_c_candidate` and use it to complete the
+    /// signatures.
+    pub(crate) fn complete(self, revoked_tx_c_candidate: Transaction) -> Result<PunishTransaction> {
         let adaptor = Adaptor::<Sha256, Deterministic<Sha256>>::default();
 
         // CommitTransaction's only have one input
@@ -686,57 +881,49 @@ impl PunishTransaction {
             .into_iter()
             .find_map(|sig| {
                 adaptor
-                    .recover_decryption_key(&Y_other.clone().into(), &sig.into(), &encsig_tx_c_self)
+                    .recover_decryption_key(
+                        &self.Y_other.clone().into(),
+                        &sig.into(),
+                        &self.encsig_tx_c_self,
+                    )
                     .map(PublishingKeyPair::from)
             })
             .ok_or_else(|| PunishError::RecoveryFailure)?;
 
-        let mut tx_p = {
-            let output = TxOut {
-                value: tx_c.value().as_sat() - TX_FEE,
-                script_pubkey: final_address.script_pubkey(),
-            };
-            Transaction {
-                version: 2,
-                lock_time: 0,
-                input: vec![tx_c.as_txin_for_tx_p()],
-                output: vec![output],
-            }
-        };
-
-        let digest = Self::compute_digest(&tx_p, &tx_c);
+        let mut tx_p = self.inner;
 
         let satisfier = {
             let mut satisfier = HashMap::with_capacity(3);
 
-            let X_self = bitcoin::secp256k1::PublicKey::from(x_self.public());
+            let X_self = bitcoin::secp256k1::PublicKey::from(self.X_self);
             let X_self_hash = hash160::Hash::hash(&X_self.serialize()[..]);
             let X_self = bitcoin::PublicKey {
                 compressed: true,
                 key: X_self,
             };
-            let sig_x_self = x_self.sign(digest);
 
-            let Y_other = bitcoin::secp256k1::PublicKey::from(Y_other);
+            let Y_other = bitcoin::secp256k1::PublicKey::from(self.Y_other);
             let Y_other_hash = hash160::Hash::hash(&Y_other.serialize()[..]);
             let Y_other = bitcoin::PublicKey {
                 compressed: true,
                 key: Y_other,
             };
-            let sig_y_other = y_other.sign(digest);
+            let sig_y_other = y_other.sign(self.digest);
 
-            let R_other = bitcoin::secp256k1::PublicKey::from(r_other.public());
+            let R_other = bitcoin::secp256k1::PublicKey::from(self.R_other);
             let R_other_hash = hash160::Hash::hash(&R_other.serialize()[..]);
             let R_other = bitcoin::PublicKey {
                 compressed: true,
                 key: R_other,
             };
-            let sig_r_other = r_other.sign(digest);
 
             // The order in which these are inserted doesn't matter
             satisfier.insert(
                 X_self_hash,
-                (X_self, (sig_x_self.into(), ::bitcoin::SigHashType::All)),
+                (
+                    X_self,
+                    (self.sig_x_self.into(), ::bitcoin::SigHashType::All),
+                ),
             );
             satisfier.insert(
                 Y_other_hash,
@@ -744,16 +931,19 @@ impl PunishTransaction {
             );
             satisfier.insert(
                 R_other_hash,
-                (R_other, (sig_r_other.into(), ::bitcoin::SigHashType::All)),
+                (
+                    R_other,
+                    (self.sig_r_other.into(), ::bitcoin::SigHashType::All),
+                ),
             );
 
             satisfier
         };
 
-        tx_c.output_descriptor()
+        self.input_descriptor
             .satisfy(&mut tx_p.input[0], satisfier)?;
 
-        Ok(Self(tx_p))
+        Ok(PunishTransaction(tx_p))
     }
 
     fn compute_digest(tx_p: &Transaction, tx_c: &CommitTransaction) -> SigHash {
@@ -771,6 +961,7 @@ impl From<PunishTransaction> for Transaction {
     }
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Clone, Debug, PartialEq)]
 pub(crate) struct CloseTransaction {
     inner: Transaction,
@@ -782,11 +973,12 @@ impl CloseTransaction {
     pub(crate) fn new(
         tx_f: &FundingTransaction,
         mut outputs: [(Amount, Address); 2],
+        fee_rate: FeeRate,
     ) -> Result<Self, Error> {
         let total_input = tx_f.value();
         let total_output =
             Amount::from_sat(outputs.iter().map(|(amount, _)| amount.as_sat()).sum());
-        let close_transaction_fee = Amount::from_sat(TX_FEE);
+        let close_transaction_fee = fee_rate.fee(CLOSE_TRANSACTION_WEIGHT);
         if total_input <= total_output - close_transaction_fee {
             return Err(Error::InsufficientFunds {
                 input: total_input,
@@ -935,6 +1127,41 @@ pub(crate) fn balance(
     )
 }
 
+/// Reason why a splice transaction is not the one expected, or cannot be
+/// published.
+#[derive(Clone, Copy, Debug, thiserror::Error)]
+pub enum InvalidSpliceTransaction {
+    #[error("splice-in PSBT pays {actual} instead of {expected} into the fund output")]
+    SpliceInAmount { expected: Amount, actual: Amount },
+    #[error("value of splice-in input {0} is unknown")]
+    UnknownInputValue(OutPoint),
+    #[error("input amount {input} does not cover output amount {output}")]
+    InsufficientFunds { input: Amount, output: Amount },
+    #[error("splice transaction does not spend the previous fund output {0}")]
+    MissingFundInput(OutPoint),
+    #[error("splice transaction spends unexpected inputs")]
+    UnexpectedInputs,
+    #[error("splice transaction does not pay into the fund output of the channel")]
+    MissingFundOutput,
+    #[error("splice transaction pays {actual} instead of {expected} into the fund output")]
+    FundOutputAmount { expected: Amount, actual: Amount },
+    #[error("splice transaction pays to unexpected outputs")]
+    UnexpectedOutputs,
+    #[error("splice transaction has unexpected version or lock time")]
+    UnexpectedHeader,
+    #[error("splice transaction pays {actual} in fees instead of at least {expected}")]
+    InsufficientFee { expected: Amount, actual: Amount },
+    #[error("splice transaction is signed with a sighash type other than ALL")]
+    UnexpectedSigHashType,
+    #[error("splice transaction spends the previous fund output with unexpected UTXO information")]
+    UnexpectedFundInputUtxo,
+    #[error("splice transaction spends {0} more than once")]
+    DuplicateInput(OutPoint),
+}
+
+/// Sighash type appended to every signature of a splice transaction.
+const SIGHASH_ALL: u8 = 0x01;
+
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Clone, PartialEq, Debug)]
 pub(crate) struct SpliceTransaction {
@@ -944,22 +1171,29 @@ pub(crate) struct SpliceTransaction {
     amount_0: Amount,
     #[cfg_attr(feature = "serde", serde(with = "as_sat"))]
     amount_1: Amount,
+    previous_fund_outpoint: OutPoint,
+    previous_fund_output: TxOut,
 }
 
 impl SpliceTransaction {
+    /// Build the transaction spending the fund output of `previous_tx_f` into
+    /// a new fund output paying out `channel_balance`.
+    ///
+    /// Each of the `splice_ins` is the PSBT of a party splicing in, which
+    /// _must_ pay the amount spliced in into the new fund output. The
+    /// transaction _must_ pay at least the `fee` agreed upon by the parties
+    /// splicing out, on top of the fees paid by the splice-in PSBTs.
     pub fn new(
-        mut inputs: Vec<PartiallySignedTransaction>,
+        previous_tx_f: &FundingTransaction,
+        mut splice_ins: Vec<(PartiallySignedTransaction, Amount)>,
         mut splice_outputs: Vec<TxOut>,
         channel_balance: [(OwnershipPublicKey, Amount); 2],
+        fee: Amount,
     ) -> Result<Self> {
-        if inputs.is_empty() {
-            bail!("Cannot build a transaction without inputs")
-        }
-
         // Sort the tuples of arguments based on the ascending lexicographical order of
         // bytes of each consensus encoded PSBT. Both parties _must_ do this so that
         // they compute the same funding transaction
-        inputs.sort_by(|a, b| {
+        splice_ins.sort_by(|(a, _), (b, _)| {
             serialize(a)
                 .partial_cmp(&serialize(b))
                 .expect("comparison is possible")
@@ -970,10 +1204,34 @@ impl SpliceTransaction {
         let fund_output = FundOutput::new([X_0, X_1]);
         let fund_output_descriptor = fund_output.descriptor();
 
-        // Extract inputs and change_outputs from each party's input_psbt
-        let (inputs, mut change_outputs) = inputs
+        let mut input_value = previous_tx_f.value();
+        for (psbt, amount) in splice_ins.iter() {
+            let paid_in = psbt
+                .global
+                .unsigned_tx
+                .output
+                .iter()
+                .filter(|output| output.script_pubkey == fund_output_descriptor.script_pubkey())
+                .fold(Amount::ZERO, |total, output| {
+                    total + Amount::from_sat(output.value)
+                });
+            if paid_in != *amount {
+                bail!(InvalidSpliceTransaction::SpliceInAmount {
+                    expected: *amount,
+                    actual: paid_in,
+                })
+            }
+
+            input_value += psbt_input_value(psbt)?;
+        }
+
+        // The previous fund output _must_ be spent by the first input. The
+        // inputs and change outputs of each party's splice-in PSBT follow
+        let previous_fund_input = previous_tx_f.as_txin();
+        let previous_fund_outpoint = previous_fund_input.previous_output;
+        let (inputs, mut change_outputs) = splice_ins
             .into_iter()
-            .map(|psbt| {
+            .map(|(psbt, _)| {
                 let Transaction { input, output, .. } = psbt.extract_tx();
 
                 let change_output: Vec<TxOut> = output
@@ -983,9 +1241,23 @@ impl SpliceTransaction {
 
                 (input, change_output)
             })
-            .fold((vec![], vec![]), |acc, (inputs, outputs)| {
-                (vec![acc.0, inputs].concat(), vec![acc.1, outputs].concat())
-            });
+            .fold(
+                (vec![previous_fund_input], vec![]),
+                |acc, (inputs, outputs)| {
+                    (vec![acc.0, inputs].concat(), vec![acc.1, outputs].concat())
+                },
+            );
+
+        // Inputs selected by different PSBTs could be the same
+        let mut outpoints = HashSet::new();
+        if let Some(input) = inputs
+            .iter()
+            .find(|input| !outpoints.insert(input.previous_output))
+        {
+            bail!(InvalidSpliceTransaction::DuplicateInput(
+                input.previous_output
+            ))
+        }
 
         // Build shared fund output based on the amounts and ownership public keys
         // provided by both parties
@@ -998,6 +1270,22 @@ impl SpliceTransaction {
         outputs.append(&mut change_outputs);
         outputs.append(&mut splice_outputs);
 
+        let output_value = outputs.iter().fold(Amount::ZERO, |total, output| {
+            total + Amount::from_sat(output.value)
+        });
+        if output_value > input_value {
+            bail!(InvalidSpliceTransaction::InsufficientFunds {
+                input: input_value,
+                output: output_value,
+            })
+        }
+        if input_value - output_value < fee {
+            bail!(InvalidSpliceTransaction::InsufficientFee {
+                expected: fee,
+                actual: input_value - output_value,
+            })
+        }
+
         // Both parties _must_ insert inputs and outputs in the order defined above
         let tx_f = Transaction {
             version: 2,
@@ -1006,14 +1294,99 @@ impl SpliceTransaction {
             output: outputs,
         };
 
+        let previous_fund_output = TxOut {
+            value: previous_tx_f.value().as_sat(),
+            script_pubkey: previous_tx_f.fund_output_descriptor().script_pubkey(),
+        };
+
         Ok(Self {
             inner: tx_f,
             fund_output_descriptor,
             amount_0,
             amount_1,
+            previous_fund_outpoint,
+            previous_fund_output,
         })
     }
 
+    pub fn txid(&self) -> Txid {
+        self.inner.txid()
+    }
+
+    /// Check that the `psbt` sent by the counterparty is this transaction,
+    /// before signing any of its inputs.
+    ///
+    /// Its inputs must only be signed with `SIGHASH_ALL`, so that the
+    /// counterparty cannot change the transaction once we have signed it,
+    /// and the UTXO information of the input spending the previous fund output
+    /// must not misrepresent it.
+    pub fn validate_psbt(
+        &self,
+        psbt: &PartiallySignedTransaction,
+    ) -> Result<(), InvalidSpliceTransaction> {
+        let expected = &self.inner;
+        let actual = &psbt.global.unsigned_tx;
+
+        for (txin, input) in actual.input.iter().zip(psbt.inputs.iter()) {
+            if !is_signed_with_sighash_all(input) {
+                return Err(InvalidSpliceTransaction::UnexpectedSigHashType);
+            }
+
+            if txin.previous_output == self.previous_fund_outpoint {
+                match &input.witness_utxo {
+                    Some(utxo) if *utxo != self.previous_fund_output => {
+                        return Err(InvalidSpliceTransaction::UnexpectedFundInputUtxo)
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if !actual
+            .input
+            .iter()
+            .any(|input| input.previous_output == self.previous_fund_outpoint)
+        {
+            return Err(InvalidSpliceTransaction::MissingFundInput(
+                self.previous_fund_outpoint,
+            ));
+        }
+
+        let spent = |transaction: &Transaction| {
+            transaction
+                .input
+                .iter()
+                .map(|input| (input.previous_output, input.sequence))
+                .collect::<Vec<_>>()
+        };
+        if spent(actual) != spent(expected) {
+            return Err(InvalidSpliceTransaction::UnexpectedInputs);
+        }
+
+        let fund_output = actual
+            .output
+            .iter()
+            .find(|output| output.script_pubkey == self.fund_output_descriptor.script_pubkey())
+            .ok_or(InvalidSpliceTransaction::MissingFundOutput)?;
+        let fund_output_amount = self.amount_0 + self.amount_1;
+        if fund_output.value != fund_output_amount.as_sat() {
+            return Err(InvalidSpliceTransaction::FundOutputAmount {
+                expected: fund_output_amount,
+                actual: Amount::from_sat(fund_output.value),
+            });
+        }
+
+        if actual.output != expected.output {
+            return Err(InvalidSpliceTransaction::UnexpectedOutputs);
+        }
+
+        if actual.version != expected.version || actual.lock_time != expected.lock_time {
+            return Err(InvalidSpliceTransaction::UnexpectedHeader);
+        }
+
+        Ok(())
+    }
+
     pub fn into_psbt(self) -> Result<PartiallySignedTransaction> {
         PartiallySignedTransaction::from_unsigned_tx(self.inner)
             .map_err(|_| anyhow!("could not convert to psbt"))
@@ -1063,6 +1436,54 @@ impl SpliceTransaction {
     }
 }
 
+/// Whether the `input` of a PSBT only requests or carries signatures with
+/// `SIGHASH_ALL`.
+fn is_signed_with_sighash_all(input: &psbt::Input) -> bool {
+    let requests_sighash_all = input
+        .sighash_type
+        .map_or(true, |sighash_type| sighash_type == SigHashType::All);
+
+    let is_sighash_all = |signature: &Vec<u8>| signature.last() == Some(&SIGHASH_ALL);
+    let partial_sigs_are_sighash_all = input.partial_sigs.values().all(is_sighash_all);
+
+    // Only the items of the witness which are signatures end with a sighash
+    // type
+    let witness_sigs_are_sighash_all = input.final_script_witness.iter().flatten().all(|item| {
+        let is_signature =
+            !item.is_empty() && secp256k1::Signature::from_der(&item[..item.len() - 1]).is_ok();
+
+        !is_signature || is_sighash_all(item)
+    });
+
+    requests_sighash_all && partial_sigs_are_sighash_all && witness_sigs_are_sighash_all
+}
+
+/// Total value of the outputs spent by the inputs of the `psbt`, as given by
+/// the UTXO information of each input.
+fn psbt_input_value(psbt: &PartiallySignedTransaction) -> Result<Amount, InvalidSpliceTransaction> {
+    psbt.global
+        .unsigned_tx
+        .input
+        .iter()
+        .zip(psbt.inputs.iter())
+        .try_fold(Amount::ZERO, |total, (txin, input)| {
+            let outpoint = txin.previous_output;
+            let value = match (&input.witness_utxo, &input.non_witness_utxo) {
+                (Some(utxo), _) => utxo.value,
+                (None, Some(transaction)) => {
+                    transaction
+                        .output
+                        .get(outpoint.vout as usize)
+                        .ok_or(InvalidSpliceTransaction::UnknownInputValue(outpoint))?
+                        .value
+                }
+                (None, None) => return Err(InvalidSpliceTransaction::UnknownInputValue(outpoint)),
+            };
+
+            Ok(total + Amount::from_sat(value))
+        })
+}
+
 impl From<SpliceTransaction> for FundingTransaction {
     fn from(splice_tx: SpliceTransaction) -> Self {
         FundingTransaction {
@@ -1076,7 +1497,7 @@ impl From<SpliceTransaction> for FundingTransaction {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::keys::point_from_str;
+    use crate::keys::{point_from_str, KeyManager};
     use miniscript::Miniscript;
     use proptest::prelude::*;
 
@@ -1145,6 +1566,218 @@ mod tests {
         assert_eq!(witness_script, "Script(OP_IF OP_IF OP_DUP OP_HASH160 OP_PUSHBYTES_20 635de934904ad5406559beebcc3ca0d119721323 OP_EQUALVERIFY OP_CHECKSIGVERIFY OP_DUP OP_HASH160 OP_PUSHBYTES_20 be60bbce0058cb25f268d70559e1a3433d75f557 OP_EQUALVERIFY OP_CHECKSIGVERIFY OP_DUP OP_HASH160 OP_PUSHBYTES_20 4c8a3449333f92f386b4b8a202353719016261e8 OP_EQUALVERIFY OP_ELSE OP_DUP OP_HASH160 OP_PUSHBYTES_20 1b08ea4a2fbbe0121205f63068f78564ff204995 OP_EQUALVERIFY OP_CHECKSIGVERIFY OP_DUP OP_HASH160 OP_PUSHBYTES_20 ea92d4bb15b4babd0c216c12f61fe7083ed06e3b OP_EQUALVERIFY OP_CHECKSIGVERIFY OP_DUP OP_HASH160 OP_PUSHBYTES_20 565dd1650db6ffae1c2dd67d83a5709aa0ddd2e9 OP_EQUALVERIFY OP_ENDIF OP_ELSE OP_PUSHBYTES_2 9000 OP_CSV OP_VERIFY OP_PUSHBYTES_33 032a34617a9141231baa27bcadf622322eed1e16b6036fdf15f42a85f7250c4823 OP_CHECKSIGVERIFY OP_PUSHBYTES_33 03437a3813f17a264e2c8fc41fb0895634d34c7c9cb9147c553cc67ff37293b1cd OP_ENDIF OP_CHECKSIG)");
     }
 
+    #[test]
+    fn split_output_must_cover_its_fee_share() {
+        let keys = [KeyManager::new_random(), KeyManager::new_random()]
+            .iter()
+            .map(|key_manager| key_manager.channel_keys(0))
+            .collect::<Vec<_>>();
+        let X_0 = keys[0].ownership_key_pair().public();
+        let X_1 = keys[1].ownership_key_pair().public();
+        let amount = Amount::from_sat(1_000_000);
+
+        let fund_output = FundOutput::new([X_0.clone(), X_1.clone()]);
+        let input_psbt = |vout| {
+            PartiallySignedTransaction::from_unsigned_tx(Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec![TxIn {
+                    previous_output: OutPoint::new(Txid::default(), vout),
+                    script_sig: Script::new(),
+                    sequence: 0xFFFF_FFFF,
+                    witness: Vec::new(),
+                }],
+                output: vec![TxOut {
+                    value: amount.as_sat(),
+                    script_pubkey: fund_output.address().script_pubkey(),
+                }],
+            })
+            .unwrap()
+        };
+        let tx_f = FundingTransaction::new([input_psbt(0), input_psbt(1)], [
+            (X_0.clone(), amount),
+            (X_1.clone(), amount),
+        ])
+        .unwrap();
+
+        let tx_c = CommitTransaction::new(
+            &tx_f,
+            [
+                (
+                    X_0,
+                    keys[0].revocation_key_pair(0).public(),
+                    keys[0].publishing_key_pair(0).public(),
+                ),
+                (
+                    X_1,
+                    keys[1].revocation_key_pair(0).public(),
+                    keys[1].publishing_key_pair(0).public(),
+                ),
+            ],
+            144,
+            FeeRate::from_sat_per_vbyte(1),
+        )
+        .unwrap();
+
+        let address = Address::p2wsh(&Script::new(), Network::Regtest);
+        let dust = Amount::from_sat(100);
+        let error = SplitTransaction::new(&tx_c, vec![
+            SplitOutput::Balance {
+                amount: dust,
+                address: address.clone(),
+            },
+            SplitOutput::Balance {
+                amount: amount * 2 - dust,
+                address,
+            },
+        ])
+        .unwrap_err();
+
+        assert!(matches!(error, Error::InsufficientFunds { .. }));
+    }
+
+    #[test]
+    fn splice_transaction_from_counterparty_must_match_ours() {
+        let X_0 = KeyManager::new_random()
+            .channel_keys(0)
+            .ownership_key_pair()
+            .public();
+        let X_1 = KeyManager::new_random()
+            .channel_keys(0)
+            .ownership_key_pair()
+            .public();
+        let amount = Amount::from_sat(1_000_000);
+
+        let input_psbt = |vout| {
+            let fund_output = FundOutput::new([X_0.clone(), X_1.clone()]);
+            PartiallySignedTransaction::from_unsigned_tx(Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec![TxIn {
+                    previous_output: OutPoint::new(Txid::default(), vout),
+                    script_sig: Script::new(),
+                    sequence: 0xFFFF_FFFF,
+                    witness: Vec::new(),
+                }],
+                output: vec![TxOut {
+                    value: amount.as_sat(),
+                    script_pubkey: fund_output.address().script_pubkey(),
+                }],
+            })
+            .unwrap()
+        };
+        let tx_f = FundingTransaction::new([input_psbt(0), input_psbt(1)], [
+            (X_0.clone(), amount),
+            (X_1.clone(), amount),
+        ])
+        .unwrap();
+
+        let splice_out = TxOut {
+            value: 100_000,
+            script_pubkey: Script::new(),
+        };
+        let fee = Amount::from_sat(10_000);
+        let splice_transaction = SpliceTransaction::new(
+            &tx_f,
+            vec![],
+            vec![splice_out.clone()],
+            [
+                (X_0.clone(), amount - Amount::from_sat(110_000)),
+                (X_1.clone(), amount),
+            ],
+            fee,
+        )
+        .unwrap();
+        let psbt = splice_transaction.clone().into_psbt().unwrap();
+
+        assert!(splice_transaction.validate_psbt(&psbt).is_ok());
+
+        let mut stealing_psbt = psbt.clone();
+        stealing_psbt.global.unsigned_tx.output[1].value += 10_000;
+        assert!(matches!(
+            splice_transaction.validate_psbt(&stealing_psbt),
+            Err(InvalidSpliceTransaction::UnexpectedOutputs)
+        ));
+
+        let mut underfunded_psbt = psbt.clone();
+        underfunded_psbt.global.unsigned_tx.output[0].value -= 10_000;
+        assert!(matches!(
+            splice_transaction.validate_psbt(&underfunded_psbt),
+            Err(InvalidSpliceTransaction::FundOutputAmount { .. })
+        ));
+
+        let mut unrelated_psbt = psbt.clone();
+        unrelated_psbt.global.unsigned_tx.input[0].previous_output = OutPoint::default();
+        assert!(matches!(
+            splice_transaction.validate_psbt(&unrelated_psbt),
+            Err(InvalidSpliceTransaction::MissingFundInput(_))
+        ));
+
+        let mut single_psbt = psbt.clone();
+        single_psbt.inputs[0].sighash_type = Some(SigHashType::Single);
+        assert!(matches!(
+            splice_transaction.validate_psbt(&single_psbt),
+            Err(InvalidSpliceTransaction::UnexpectedSigHashType)
+        ));
+
+        let mut misrepresented_psbt = psbt;
+        misrepresented_psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 3 * amount.as_sat(),
+            script_pubkey: tx_f.fund_output_descriptor().script_pubkey(),
+        });
+        assert!(matches!(
+            splice_transaction.validate_psbt(&misrepresented_psbt),
+            Err(InvalidSpliceTransaction::UnexpectedFundInputUtxo)
+        ));
+
+        let error = SpliceTransaction::new(
+            &tx_f,
+            vec![],
+            vec![splice_out.clone()],
+            [(X_0.clone(), amount), (X_1.clone(), amount)],
+            fee,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast::<InvalidSpliceTransaction>(),
+            Ok(InvalidSpliceTransaction::InsufficientFunds { .. })
+        ));
+
+        let error = SpliceTransaction::new(
+            &tx_f,
+            vec![],
+            vec![splice_out],
+            [
+                (X_0.clone(), amount - Amount::from_sat(105_000)),
+                (X_1.clone(), amount),
+            ],
+            fee,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast::<InvalidSpliceTransaction>(),
+            Ok(InvalidSpliceTransaction::InsufficientFee { .. })
+        ));
+
+        let mut splice_in = input_psbt(2);
+        splice_in.inputs[0].witness_utxo = Some(TxOut {
+            value: amount.as_sat(),
+            script_pubkey: Script::new(),
+        });
+        let error = SpliceTransaction::new(
+            &tx_f,
+            vec![(splice_in.clone(), amount), (splice_in, amount)],
+            vec![],
+            [(X_0, amount * 2), (X_1, amount * 2)],
+            Amount::ZERO,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast::<InvalidSpliceTransaction>(),
+            Ok(InvalidSpliceTransaction::DuplicateInput(_))
+        ));
+    }
+
     prop_compose! {
         fn arb_amount()(sats in any::<u32>()) -> Amount {
             Amount::from_sat(sats as u64)
@@ -1156,12 +1789,14 @@ mod tests {
         fn check_fees_are_unreachable(
                 tx_c_value in arb_amount(),
                 tx_c_fee in arb_amount(),
+                tx_s_fee in arb_amount(),
                 amount_0 in arb_amount(),
                 amount_1 in arb_amount()
             ) {
                 let _ = SplitTransaction::fees(
                             tx_c_value,
                             tx_c_fee,
+                            tx_s_fee,
                             amount_0,
                             amount_1,
                         );
@@ -0,0 +1,212 @@
+use crate::{
+    fee::{FeeRate, ANCHOR_OUTPUT_VALUE, CPFP_TRANSACTION_WEIGHT},
+    keys::{OwnershipKeyPair, OwnershipPublicKey},
+};
+
+use anyhow::{anyhow, bail, Result};
+use bitcoin::{
+    secp256k1,
+    util::{bip143::SighashComponents, psbt::PartiallySignedTransaction},
+    Address, Amount, OutPoint, Script, Transaction, TxIn, TxOut,
+};
+use miniscript::{Descriptor, Segwitv0};
+use std::{cmp, collections::HashMap, str::FromStr};
+
+/// Build the anchor outputs of a transaction, one for each party.
+///
+/// Anchor outputs allow either party to bump the fee of a transaction that was
+/// signed in the past by spending their anchor output in a child transaction,
+/// a technique known as Child Pays For Parent (CPFP).
+pub(crate) fn build_outputs(mut Xs: [OwnershipPublicKey; 2]) -> Vec<TxOut> {
+    // Both parties _must_ insert the anchor outputs in ascending lexicographical
+    // order of bytes of the ownership public keys
+    Xs.sort_by(|a, b| a.partial_cmp(b).expect("comparison is possible"));
+
+    Xs.iter()
+        .map(|X| TxOut {
+            value: ANCHOR_OUTPUT_VALUE,
+            script_pubkey: build_descriptor(X.clone()).script_pubkey(),
+        })
+        .collect()
+}
+
+fn build_descriptor(X: OwnershipPublicKey) -> Descriptor<bitcoin::PublicKey> {
+    // Describes the spending policy of an anchor output: only the owner of `X`
+    // can spend it.
+    const MINISCRIPT_TEMPLATE: &str = "c:pk_k(X)";
+
+    let X = hex::encode(secp256k1::PublicKey::from(X).serialize().to_vec());
+
+    let miniscript = MINISCRIPT_TEMPLATE.replace("X", &X);
+
+    let miniscript = miniscript::Miniscript::<bitcoin::PublicKey, Segwitv0>::from_str(&miniscript)
+        .expect("a valid miniscript");
+
+    Descriptor::Wsh(miniscript)
+}
+
+/// Our anchor output in a transaction whose fee we may want to bump.
+#[derive(Clone, Debug)]
+pub(crate) struct Anchor {
+    outpoint: OutPoint,
+    descriptor: Descriptor<bitcoin::PublicKey>,
+    parent_weight: u64,
+    parent_fee: Amount,
+}
+
+impl Anchor {
+    /// Find the anchor output owned by `X_self` in the `parent` transaction.
+    ///
+    /// The estimated weight and the fee of the `parent` transaction are used
+    /// to compute how much the child transaction has to pay in fees.
+    pub fn new(
+        parent: &Transaction,
+        X_self: OwnershipPublicKey,
+        parent_weight: u64,
+        parent_fee: Amount,
+    ) -> Result<Self> {
+        let descriptor = build_descriptor(X_self);
+
+        #[allow(clippy::cast_possible_truncation)]
+        let vout = parent
+            .output
+            .iter()
+            .position(|output| output.script_pubkey == descriptor.script_pubkey())
+            .ok_or_else(|| anyhow!("transaction does not contain our anchor output"))?
+            as u32;
+
+        Ok(Self {
+            outpoint: OutPoint::new(parent.txid(), vout),
+            descriptor,
+            parent_weight,
+            parent_fee,
+        })
+    }
+
+    /// Amount that the wallet has to contribute to the child transaction for
+    /// the package formed by it and the parent transaction to pay `fee_rate`.
+    pub fn bump_amount(&self, fee_rate: FeeRate) -> Result<Amount> {
+        let package_fee = fee_rate.fee(self.parent_weight + CPFP_TRANSACTION_WEIGHT);
+        let paid = self.parent_fee + Amount::from_sat(ANCHOR_OUTPUT_VALUE);
+
+        let bump_amount = package_fee.checked_sub(paid).ok_or_else(|| {
+            anyhow!(
+                "fee rate {} does not increase the fee of the transaction",
+                fee_rate
+            )
+        })?;
+
+        // The wallet cannot build an output below the dust limit, so we might pay
+        // slightly more than needed
+        Ok(cmp::max(bump_amount, Amount::from_sat(ANCHOR_OUTPUT_VALUE)))
+    }
+
+    fn output(&self) -> TxOut {
+        TxOut {
+            value: ANCHOR_OUTPUT_VALUE,
+            script_pubkey: self.descriptor.script_pubkey(),
+        }
+    }
+}
+
+/// Child transaction spending our anchor output, used to bump the fee of its
+/// parent transaction.
+#[derive(Clone, Debug)]
+pub(crate) struct CpfpTransaction {
+    psbt: PartiallySignedTransaction,
+    anchor: Anchor,
+}
+
+impl CpfpTransaction {
+    /// Build a transaction spending the `anchor` output together with the
+    /// inputs of a `wallet_psbt` which pays the amount needed to bump the fee
+    /// to `bump_address`.
+    ///
+    /// The output paying to `bump_address` is left out, so that its amount
+    /// goes to fees. The rest of the outputs in the `wallet_psbt`, i.e. its
+    /// change outputs, are kept.
+    pub fn new(
+        anchor: Anchor,
+        wallet_psbt: PartiallySignedTransaction,
+        bump_address: &Address,
+    ) -> Result<Self> {
+        let wallet_inputs = wallet_psbt.inputs.clone();
+        let Transaction { input, output, .. } = wallet_psbt.extract_tx();
+
+        let output = output
+            .into_iter()
+            .filter(|output| output.script_pubkey != bump_address.script_pubkey())
+            .collect::<Vec<_>>();
+        if output.is_empty() {
+            bail!("wallet PSBT does not contain a change output")
+        }
+
+        let anchor_input = TxIn {
+            previous_output: anchor.outpoint,
+            script_sig: Script::new(),
+            sequence: 0xFFFF_FFFF,
+            witness: Vec::new(),
+        };
+
+        // The anchor input _must_ be the first input
+        let transaction = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![vec![anchor_input], input].concat(),
+            output,
+        };
+
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(transaction)
+            .map_err(|_| anyhow!("could not convert to psbt"))?;
+
+        psbt.inputs[0].witness_utxo = Some(anchor.output());
+        psbt.inputs[0].witness_script = Some(anchor.descriptor.witness_script());
+        psbt.inputs[1..].clone_from_slice(&wallet_inputs);
+
+        Ok(Self { psbt, anchor })
+    }
+
+    /// PSBT to be signed by the wallet which provided the inputs paying for
+    /// the fee bump.
+    pub fn psbt(&self) -> PartiallySignedTransaction {
+        self.psbt.clone()
+    }
+
+    /// Sign the anchor input of the PSBT signed by the wallet.
+    pub fn add_signatures(
+        self,
+        signed_psbt: PartiallySignedTransaction,
+        x_self: &OwnershipKeyPair,
+    ) -> Result<Transaction> {
+        let mut transaction = signed_psbt.extract_tx();
+        if transaction.ntxid() != self.psbt.global.unsigned_tx.ntxid() {
+            bail!("wallet modified the CPFP transaction")
+        }
+
+        let digest = SighashComponents::new(&transaction).sighash_all(
+            &transaction.input[0],
+            &self.anchor.descriptor.witness_script(),
+            ANCHOR_OUTPUT_VALUE,
+        );
+        let sig_self = x_self.sign(digest);
+
+        let satisfier = {
+            let mut satisfier = HashMap::with_capacity(1);
+
+            let X_self = ::bitcoin::PublicKey {
+                compressed: true,
+                key: x_self.public().into(),
+            };
+
+            satisfier.insert(X_self, (sig_self.into(), ::bitcoin::SigHashType::All));
+
+            satisfier
+        };
+
+        self.anchor
+            .descriptor
+            .satisfy(&mut transaction.input[0], satisfier)?;
+
+        Ok(transaction)
+    }
+}
@@ -1,15 +1,16 @@
 use crate::{
+    fee::PTLC_TRANSACTION_WEIGHT,
     keys::{OwnershipKeyPair, OwnershipPublicKey},
     signature,
-    transaction::{build_shared_output_descriptor, SplitTransaction},
-    Ptlc, PtlcPoint, PtlcSecret, TX_FEE,
+    transaction::{build_shared_output_descriptor, Error, SplitTransaction},
+    Ptlc, PtlcPoint, PtlcSecret,
 };
 
 use anyhow::{anyhow, bail, Context, Result};
 use arrayvec::ArrayVec;
 use bitcoin::{
-    util::bip143::SighashComponents, Address, OutPoint, Script, SigHash, Transaction, TxIn, TxOut,
-    Txid,
+    util::bip143::SighashComponents, Address, Amount, OutPoint, Script, SigHash, Transaction, TxIn,
+    TxOut, Txid,
 };
 use ecdsa_fun::{
     self,
@@ -175,23 +176,27 @@ pub(crate) fn spend_transaction(
     let ptlc_output_descriptor = build_shared_output_descriptor(Xs[0].clone(), Xs[1].clone());
 
     let vout = tx_s
-        .inner
-        .output
-        .iter()
-        .position(|output| output.script_pubkey == ptlc_output_descriptor.script_pubkey())
+        .ptlc_vout(&ptlc.point())
         .ok_or_else(|| anyhow!("tx_s does not contain PTLC output"))?;
 
-    #[allow(clippy::cast_possible_truncation)]
     let input = TxIn {
-        previous_output: OutPoint::new(tx_s.txid(), vout as u32),
+        previous_output: OutPoint::new(tx_s.txid(), vout),
         script_sig: Script::new(),
         sequence: 0xFFFF_FFFF,
         witness: Vec::new(),
     };
 
-    let ptlc_output_value = tx_s.inner.output[vout].value;
+    let ptlc_output_value = tx_s.inner.output[vout as usize].value;
+    let fee = tx_s.fee_rate().fee(PTLC_TRANSACTION_WEIGHT);
+    let value = Amount::from_sat(ptlc_output_value)
+        .checked_sub(fee)
+        .ok_or_else(|| Error::InsufficientFunds {
+            input: Amount::from_sat(ptlc_output_value),
+            output: Amount::ZERO,
+            fee,
+        })?;
     let output = TxOut {
-        value: ptlc_output_value - TX_FEE,
+        value: value.as_sat(),
         script_pubkey: refund_address.script_pubkey(),
     };
 
@@ -0,0 +1,191 @@
+//! Watch the blockchain on behalf of a channel and react to the counterparty
+//! publishing a revoked state.
+//!
+//! The only transactions that can spend the fund output of a channel are the
+//! `CommitTransaction` of any of its states, the `CloseTransaction` and a
+//! `SpliceTransaction`. Publishing the `CommitTransaction` of a revoked state
+//! is cheating, so the watchtower punishes the counterparty as soon as it
+//! sees it.
+//!
+//! Punishment can also be delegated to a third-party watchtower by handing it
+//! a [`JusticeKit`] for each revoked state. Each kit is encrypted using the
+//! transaction id of the revoked `CommitTransaction`, so the third party can
+//! only read it once said transaction has been published.
+
+#[cfg(feature = "serde")]
+use crate::transaction::PresignedPunishTransaction;
+use crate::{channel::BroadcastSignedTransaction, Channel};
+use anyhow::Result;
+use async_trait::async_trait;
+use bitcoin::{OutPoint, Transaction, Txid};
+#[cfg(feature = "serde")]
+use chacha20poly1305::{
+    aead::{generic_array::GenericArray, Aead, NewAead},
+    ChaCha20Poly1305,
+};
+use futures::future;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "serde")]
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+
+/// Source of the transactions published on the blockchain.
+#[async_trait]
+pub trait WatchTransactions {
+    /// Wait until a transaction spending `outpoint` is published and return
+    /// it.
+    async fn watch_for_spending_transaction(&self, outpoint: OutPoint) -> Result<Transaction>;
+}
+
+/// Something that happened to the watched channel on the blockchain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// The counterparty published the revoked `CommitTransaction` with id
+    /// `tx_c` and has been punished by publishing a `PunishTransaction`.
+    Punished { tx_c: Txid },
+    /// The `CommitTransaction` of a state which is not revoked, with id
+    /// `tx_c`, was published by either party. The channel has been force
+    /// closed.
+    ForceClosed { tx_c: Txid },
+    /// The fund output was spent by a transaction which is not a
+    /// `CommitTransaction`, i.e. the channel was closed collaboratively or
+    /// spliced.
+    FundOutputSpent { txid: Txid },
+}
+
+#[derive(Debug)]
+pub struct Watchtower {
+    channel: Mutex<Channel>,
+}
+
+impl Watchtower {
+    pub fn new(channel: Channel) -> Self {
+        Self {
+            channel: Mutex::new(channel),
+        }
+    }
+
+    /// Replace the watched channel with its latest version.
+    ///
+    /// It _must_ be called after every channel update, otherwise the
+    /// watchtower won't be able to recognise the `CommitTransaction` of the
+    /// newly revoked state.
+    pub fn update_channel(&self, channel: Channel) {
+        *self.channel.lock().expect("lock is not poisoned") = channel;
+    }
+
+    /// Wait until a fund output of the channel is spent and react to it.
+    ///
+    /// Besides the current fund output, the fund outputs of the previous
+    /// `FundingTransaction`s are watched for as long as revoked states can be
+    /// published on them. If the spending transaction is a revoked
+    /// `CommitTransaction`, the `PunishTransaction` is broadcast using the
+    /// `wallet`.
+    pub async fn watch<C, W>(&self, chain: &C, wallet: &W) -> Result<Event>
+    where
+        C: WatchTransactions,
+        W: BroadcastSignedTransaction,
+    {
+        let watches = self
+            .channel()
+            .fund_outpoints()
+            .into_iter()
+            .map(|fund_outpoint| chain.watch_for_spending_transaction(fund_outpoint));
+        let (transaction, ..) = future::select_all(watches).await;
+        let transaction = transaction?;
+
+        // The channel might have been updated while we were waiting, but the
+        // revoked states it knows about are still punishable
+        let channel = self.channel();
+
+        let txid = transaction.txid();
+        if channel.is_revoked_tx_c(txid) {
+            channel.punish(wallet, transaction).await?;
+
+            return Ok(Event::Punished { tx_c: txid });
+        }
+
+        if channel.is_unrevoked_tx_c(txid) {
+            return Ok(Event::ForceClosed { tx_c: txid });
+        }
+
+        Ok(Event::FundOutputSpent { txid })
+    }
+
+    fn channel(&self) -> Channel {
+        self.channel.lock().expect("lock is not poisoned").clone()
+    }
+}
+
+/// Encrypted data that allows anyone who knows the revoked `CommitTransaction`
+/// of a channel state to punish its publication.
+///
+/// Publishing the resulting `PunishTransaction` sends all the funds of the
+/// channel to our final address.
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JusticeKit {
+    hint: [u8; 16],
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+#[cfg(feature = "serde")]
+impl JusticeKit {
+    pub(crate) fn new(tx_p: &PresignedPunishTransaction) -> Result<Self> {
+        let revoked_tx_c_txid = tx_p.revoked_tx_c_txid();
+
+        let plaintext = serde_cbor::to_vec(tx_p)?;
+        let nonce = rand::random::<[u8; 12]>();
+        let ciphertext = cipher(&revoked_tx_c_txid)
+            .encrypt(GenericArray::from_slice(&nonce), plaintext.as_slice())
+            .map_err(|_| anyhow::anyhow!("failed to encrypt justice kit"))?;
+
+        Ok(Self {
+            hint: Self::hint_for(&revoked_tx_c_txid),
+            nonce,
+            ciphertext,
+        })
+    }
+
+    /// Hint used to look up the kit which corresponds to a published
+    /// transaction, without revealing its transaction id.
+    pub fn hint(&self) -> [u8; 16] {
+        self.hint
+    }
+
+    /// Compute the hint of the kit which would punish the transaction with id
+    /// `txid`.
+    pub fn hint_for(txid: &Txid) -> [u8; 16] {
+        let digest = Sha256::digest(&txid[..]);
+
+        let mut hint = [0u8; 16];
+        hint.copy_from_slice(&digest[..16]);
+
+        hint
+    }
+
+    /// Decrypt the kit using the published `revoked_tx_c` and build the
+    /// `PunishTransaction` spending it, ready to be broadcast.
+    pub fn punish_transaction(&self, revoked_tx_c: Transaction) -> Result<Transaction> {
+        let plaintext = cipher(&revoked_tx_c.txid())
+            .decrypt(
+                GenericArray::from_slice(&self.nonce),
+                self.ciphertext.as_slice(),
+            )
+            .map_err(|_| anyhow::anyhow!("justice kit does not correspond to transaction"))?;
+        let tx_p: PresignedPunishTransaction = serde_cbor::from_slice(&plaintext)?;
+
+        let tx_p = tx_p.complete(revoked_tx_c)?;
+
+        Ok(tx_p.into())
+    }
+}
+
+/// The cipher used to encrypt the justice kit of a revoked state, whose key
+/// is the transaction id of the revoked `CommitTransaction`.
+#[cfg(feature = "serde")]
+fn cipher(revoked_tx_c_txid: &Txid) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(GenericArray::from_slice(&revoked_tx_c_txid[..]))
+}
@@ -0,0 +1,337 @@
+//! Wire format of the messages exchanged between the parties of a channel.
+//!
+//! Every `Message` is encoded as a frame made up of the following fields:
+//!
+//! | Field   | Size           | Description                                  |
+//! |---------|----------------|----------------------------------------------|
+//! | version | 1 byte         | Version of the wire format, currently `1`.   |
+//! | type    | 2 bytes        | Type of the message, big endian. See below.  |
+//! | length  | 4 bytes        | Length of the payload in bytes, big endian.  |
+//! | payload | `length` bytes | Fields of the message, encoded as a CBOR map. |
+//!
+//! The payload is a CBOR map ([RFC 7049]) from the name of each field of the
+//! message to its value. Every CBOR item is itself length-prefixed, so that
+//! decoders can skip unknown fields. PSBTs are encoded as the hex string of
+//! their BIP 174 serialization.
+//!
+//! The keys of the payload of each message type are listed below, and are
+//! part of the wire format: renaming a field of a message requires keeping its
+//! key with `#[serde(rename)]`.
+//!
+//! - `Create0`: `X`, `final_address`, `fee_rate` and `protocol_version`.
+//! - `Create1`: `input_psbt`.
+//! - `Create2`: `R` and `Y`.
+//! - `Create3`: `sig_tx_s`.
+//! - `Create4`: `encsig_tx_c`.
+//! - `Create5`: `tx_f_signed_once`.
+//! - `Update0`: `R`, `Y` and `fee_rate`.
+//! - `UpdatePtlcs`: `funder` and `redeemer`.
+//! - `Update1`: `sig_tx_s`.
+//! - `Update2`: `encsig_tx_c`.
+//! - `Update3`: `r`.
+//! - `Close0`: `sig_close_transaction`.
+//! - `Splice0`: `R`, `Y`, `fee_rate` and `splice`.
+//! - `Splice1`: `sig_tx_s`.
+//! - `Splice2`: `encsig_tx_c`.
+//! - `Splice3`: `sig_TX_splice_TX_f_input` and `signed_TX_splice_psbt_input`.
+//! - `Recover0`: `tx_f_txid`.
+//! - `Recover1`: `signed_tx_c` and `signed_tx_s`.
+//! - `Reestablish0`: `state_number` and `pending_update`.
+//! - `Reestablish1`: `r`.
+//! - `Abort`: `reason_code` and `details`.
+//! - `ProposeUpdate`: `split_outputs` and `time_lock`.
+//! - `ProposeSplice`: `splice`.
+//! - `Reject`: `reason`.
+//! - `Secret` is encoded as the secret itself, and `ProposeClose` and `Accept`
+//!   as CBOR null.
+//!
+//! The entries of `funder` in `UpdatePtlcs` have the keys `point`,
+//! `encsig_tx_ptlc_redeem_funder` and `sig_tx_ptlc_refund_funder`, and those
+//! of `redeemer` the keys `point`, `sig_tx_ptlc_redeem_redeemer` and
+//! `sig_tx_ptlc_refund_redeemer`. A `splice` has the keys `amount_in`, the
+//! amount spliced in in satoshi, and `outs`, the outputs spliced out.
+//!
+//! Message types are assigned in blocks of 16 per protocol:
+//!
+//! - `0x0000..=0x0005`: channel creation, `Create0` to `Create5`.
+//! - `0x0010..=0x0014`: channel update, `Update0`, `UpdatePtlcs`, `Update1`,
+//!   `Update2` and `Update3`.
+//! - `0x0020`: PTLC secret, `Secret`.
+//! - `0x0030`: collaborative close, `Close0`.
+//! - `0x0040..=0x0043`: channel splice, `Splice0` to `Splice3`. A splice of a
+//!   channel with PTLC outputs also uses `UpdatePtlcs`, after `Splice0`.
+//! - `0x0050..=0x0051`: channel recovery, `Recover0` and `Recover1`.
+//! - `0x0060..=0x0061`: channel reestablish, `Reestablish0` and `Reestablish1`.
+//! - `0x0070`: protocol abort, `Abort`.
+//! - `0x0080..=0x0084`: proposals, `ProposeUpdate`, `ProposeClose`,
+//!   `ProposeSplice`, `Accept` and `Reject`.
+//!
+//! Type assignments are never changed once released, and new messages are
+//! given new types. Breaking changes to the frame layout require a new wire
+//! format version.
+//!
+//! Independently of the wire format, the parties agree on the version of the
+//! channel protocols when creating a channel, see `PROTOCOL_VERSION` and
+//! `Channel::protocol_version`.
+//!
+//! [RFC 7049]: https://tools.ietf.org/html/rfc7049
+
+#[cfg(feature = "serde")]
+use crate::Message;
+#[cfg(feature = "serde")]
+use std::convert::TryFrom;
+
+/// Version of the wire format produced by `Message::encode`.
+pub const WIRE_VERSION: u8 = 1;
+
+/// Highest version of the channel protocols supported by this release.
+///
+/// It is sent to the counterparty when creating a channel, and the lowest of
+/// the two versions is used for the channel and kept by it.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Lowest version of the channel protocols supported by this release.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Length of the header of a frame: version, type and length.
+#[cfg(feature = "serde")]
+const HEADER_LEN: usize = 1 + 2 + 4;
+
+#[cfg(feature = "serde")]
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeError {
+    #[error("frame is too short: {0} bytes")]
+    TooShort(usize),
+    #[error("unsupported wire format version {0}")]
+    UnsupportedVersion(u8),
+    #[error("unknown message type {0:#06x}")]
+    UnknownType(u16),
+    #[error("payload length {expected} does not match remaining {actual} bytes")]
+    LengthMismatch { expected: u32, actual: usize },
+    #[error("failed to decode payload of message type {message_type:#06x}")]
+    Payload {
+        message_type: u16,
+        #[source]
+        source: serde_cbor::Error,
+    },
+}
+
+#[cfg(feature = "serde")]
+#[derive(Debug, thiserror::Error)]
+pub enum EncodeError {
+    #[error("failed to encode payload")]
+    Payload(#[from] serde_cbor::Error),
+    #[error("payload of {0} bytes is too long")]
+    TooLong(usize),
+}
+
+/// Agree on the version of the channel protocols given the highest version
+/// supported by the counterparty.
+pub(crate) fn negotiate_protocol_version(
+    protocol_version_other: u16,
+) -> Result<u16, UnsupportedProtocolVersion> {
+    if protocol_version_other < MIN_PROTOCOL_VERSION {
+        return Err(UnsupportedProtocolVersion(protocol_version_other));
+    }
+
+    Ok(std::cmp::min(PROTOCOL_VERSION, protocol_version_other))
+}
+
+#[derive(Clone, Copy, Debug, thiserror::Error)]
+#[error(
+    "protocol version {0} of counterparty is not supported, the minimum is {}",
+    MIN_PROTOCOL_VERSION
+)]
+pub struct UnsupportedProtocolVersion(u16);
+
+/// Implement the mapping between `Message` variants and message types.
+#[cfg(feature = "serde")]
+macro_rules! message_types {
+    ($($message_type:literal => $variant:ident,)*) => {
+        impl Message {
+            fn message_type(&self) -> u16 {
+                match self {
+                    $(Message::$variant(_) => $message_type,)*
+                }
+            }
+
+            fn encode_payload(&self) -> Result<Vec<u8>, serde_cbor::Error> {
+                match self {
+                    $(Message::$variant(message) => serde_cbor::to_vec(message),)*
+                }
+            }
+
+            fn decode_payload(message_type: u16, payload: &[u8]) -> Result<Self, DecodeError> {
+                match message_type {
+                    $($message_type => serde_cbor::from_slice(payload)
+                        .map(Message::$variant)
+                        .map_err(|source| DecodeError::Payload {
+                            message_type,
+                            source,
+                        }),)*
+                    _ => Err(DecodeError::UnknownType(message_type)),
+                }
+            }
+        }
+    };
+}
+
+#[cfg(feature = "serde")]
+message_types! {
+    0x0000 => Create0,
+    0x0001 => Create1,
+    0x0002 => Create2,
+    0x0003 => Create3,
+    0x0004 => Create4,
+    0x0005 => Create5,
+    0x0010 => Update0,
+    0x0011 => UpdatePtlcs,
+    0x0012 => Update1,
+    0x0013 => Update2,
+    0x0014 => Update3,
+    0x0020 => Secret,
+    0x0030 => Close0,
+    0x0040 => Splice0,
+    0x0041 => Splice1,
+    0x0042 => Splice2,
+    0x0043 => Splice3,
+    0x0050 => Recover0,
+    0x0051 => Recover1,
+    0x0060 => Reestablish0,
+    0x0061 => Reestablish1,
+    0x0070 => Abort,
+    0x0080 => ProposeUpdate,
+    0x0081 => ProposeClose,
+    0x0082 => ProposeSplice,
+    0x0083 => Accept,
+    0x0084 => Reject,
+}
+
+#[cfg(feature = "serde")]
+impl Message {
+    /// Encode the message using the wire format described in the `wire`
+    /// module.
+    pub fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        let payload = self.encode_payload()?;
+        let length =
+            u32::try_from(payload.len()).map_err(|_| EncodeError::TooLong(payload.len()))?;
+
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+        frame.push(WIRE_VERSION);
+        frame.extend_from_slice(&self.message_type().to_be_bytes());
+        frame.extend_from_slice(&length.to_be_bytes());
+        frame.extend_from_slice(&payload);
+
+        Ok(frame)
+    }
+
+    /// Decode a message encoded using the wire format described in the
+    /// `wire` module.
+    pub fn decode(frame: &[u8]) -> Result<Self, DecodeError> {
+        if frame.len() < HEADER_LEN {
+            return Err(DecodeError::TooShort(frame.len()));
+        }
+
+        let version = frame[0];
+        if version != WIRE_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+
+        let message_type = u16::from_be_bytes([frame[1], frame[2]]);
+        let length = u32::from_be_bytes([frame[3], frame[4], frame[5], frame[6]]);
+
+        let payload = &frame[HEADER_LEN..];
+        if u32::try_from(payload.len()).ok() != Some(length) {
+            return Err(DecodeError::LengthMismatch {
+                expected: length,
+                actual: payload.len(),
+            });
+        }
+
+        Message::decode_payload(message_type, payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "serde")]
+    use crate::PtlcSecret;
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn encoded_message_can_be_decoded() {
+        let secret = PtlcSecret::new_random();
+        let message = Message::Secret(secret.clone());
+
+        let frame = message.encode().unwrap();
+
+        assert_eq!(frame[0], WIRE_VERSION);
+        assert_eq!(&frame[1..3], &[0x00, 0x20]);
+
+        let decoded = Message::decode(&frame).unwrap();
+        assert_eq!(decoded.into_secret().unwrap().point(), secret.point());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn frame_with_unknown_version_or_type_is_rejected() {
+        let mut frame = Message::Secret(PtlcSecret::new_random()).encode().unwrap();
+
+        frame[1] = 0xff;
+        assert!(matches!(
+            Message::decode(&frame),
+            Err(DecodeError::UnknownType(0xff20))
+        ));
+
+        frame[0] = WIRE_VERSION + 1;
+        assert!(matches!(
+            Message::decode(&frame),
+            Err(DecodeError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn payload_is_keyed_by_field_name() {
+        use crate::{Abort, AbortReason};
+        use serde_cbor::Value;
+
+        let error = anyhow::anyhow!("invalid signature");
+        let message = Message::Abort(Abort::new(AbortReason::InvalidMessage, &error));
+        let frame = message.encode().unwrap();
+
+        let payload: Value = serde_cbor::from_slice(&frame[HEADER_LEN..]).unwrap();
+        let keys = match payload {
+            Value::Map(map) => map.into_iter().map(|(key, _)| key).collect::<Vec<_>>(),
+            _ => panic!("payload is not a map"),
+        };
+
+        assert_eq!(keys, vec![
+            Value::Text("details".to_string()),
+            Value::Text("reason_code".to_string())
+        ]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn truncated_frame_is_rejected() {
+        let frame = Message::Secret(PtlcSecret::new_random()).encode().unwrap();
+
+        assert!(Message::decode(&frame[..frame.len() - 1]).is_err());
+        assert!(Message::decode(&frame[..3]).is_err());
+    }
+
+    #[test]
+    fn protocol_version_is_the_lowest_supported_by_both_parties() {
+        assert_eq!(
+            negotiate_protocol_version(PROTOCOL_VERSION + 1).unwrap(),
+            PROTOCOL_VERSION
+        );
+        assert_eq!(
+            negotiate_protocol_version(PROTOCOL_VERSION).unwrap(),
+            PROTOCOL_VERSION
+        );
+        assert!(negotiate_protocol_version(MIN_PROTOCOL_VERSION - 1).is_err());
+    }
+}
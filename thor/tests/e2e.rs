@@ -3,14 +3,27 @@
 mod harness;
 
 use harness::{
-    assert_channel_balances, create_channels, generate_expiries, init_bitcoind, init_cli,
-    swap_beta_ptlc_bob, update_balances, FUND,
+    assert_channel_balances, create_channels, fee_deduction_per_split_output, generate_expiries,
+    init_bitcoind, init_cli, make_envelope_transports, make_transports, make_wallets,
+    swap_beta_ptlc_bob, update_balances, FEE_RATE, FUND,
+};
+use thor::{
+    channel::{
+        AcceptAll, CreateState, ForceCloseTransaction, HandledProposal, NewAddress, Policy,
+        Proposal, ProposalRejected, PtlcDirection, ReceiveMessage, SendMessage, Timeouts,
+        Transition, UpdateState,
+    },
+    fee::{splice_transaction_weight, CLOSE_TRANSACTION_WEIGHT, PTLC_TRANSACTION_WEIGHT},
+    multiplex::Multiplexer,
+    swap::SwapConfig,
+    AbortReason, Balance, Channel, ChannelId, Error, FeeRate, FeeRateBounds, KeyManager, Message,
+    ProtocolAborted, PtlcSecret, Splice,
 };
-use thor::{PtlcSecret, Splice, TX_FEE};
 
 use bitcoin::{Amount, TxOut};
-use futures::future;
+use futures::{future, TryFutureExt};
 use spectral::prelude::*;
+use std::time::Duration;
 
 // NOTE: For some reason running these tests overflows the stack. In order to
 // mitigate this run them with:
@@ -22,8 +35,16 @@ async fn e2e_channel_update() {
     // TODO: Work out how to declare cli and bitcoind inside create_channels().
     let cli = init_cli();
     let bitcoind = init_bitcoind(&cli).await;
-    let (mut a_channel, mut b_channel, mut a_transport, mut b_transport, _, _, time_lock, _) =
-        create_channels(&bitcoind).await;
+    let (
+        mut a_channel,
+        mut b_channel,
+        mut a_transport,
+        mut b_transport,
+        a_wallet,
+        b_wallet,
+        time_lock,
+        _,
+    ) = create_channels(&bitcoind).await;
 
     // Parties agree on a new channel balance: Alice pays 0.5 a Bitcoin to Bob
     let payment = Amount::from_btc(0.5).expect("failed to create amount");
@@ -35,6 +56,8 @@ async fn e2e_channel_update() {
         &mut b_channel,
         &mut a_transport,
         &mut b_transport,
+        &a_wallet,
+        &b_wallet,
         a_balance,
         b_balance,
         time_lock,
@@ -44,6 +67,393 @@ async fn e2e_channel_update() {
     assert_channel_balances(&a_channel, &b_channel, a_balance, b_balance);
 }
 
+#[tokio::test]
+async fn e2e_channel_update_proposed_by_one_party() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (
+        mut a_channel,
+        mut b_channel,
+        mut a_transport,
+        mut b_transport,
+        a_wallet,
+        b_wallet,
+        time_lock,
+        _,
+    ) = create_channels(&bitcoind).await;
+
+    // Alice proposes to pay 0.5 a Bitcoin to Bob, who accepts anything
+    let payment = Amount::from_btc(0.5).expect("failed to create amount");
+    let a_balance = FUND - payment;
+    let b_balance = FUND + payment;
+
+    let (a_update, b_handled) = future::join(
+        a_channel.propose_update_balance(
+            &mut a_transport,
+            &a_wallet,
+            Balance {
+                ours: a_balance,
+                theirs: b_balance,
+            },
+            time_lock,
+        ),
+        b_channel.handle_incoming(&mut b_transport, &b_wallet, &AcceptAll),
+    )
+    .await;
+
+    a_update.unwrap();
+    match b_handled.unwrap() {
+        HandledProposal::Accepted(Proposal::Update { balance, .. }) => {
+            assert_eq!(balance, Balance {
+                ours: b_balance,
+                theirs: a_balance
+            })
+        }
+        handled => panic!("unexpected outcome of proposal: {:?}", handled),
+    }
+
+    assert_channel_balances(&a_channel, &b_channel, a_balance, b_balance);
+}
+
+#[tokio::test]
+async fn e2e_channel_update_proposal_rejected_by_policy() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (
+        mut a_channel,
+        mut b_channel,
+        mut a_transport,
+        mut b_transport,
+        a_wallet,
+        b_wallet,
+        time_lock,
+        _,
+    ) = create_channels(&bitcoind).await;
+
+    // Bob never agrees to pay anything to Alice
+    struct NoPayments;
+
+    impl Policy for NoPayments {
+        fn evaluate(&self, channel: &Channel, proposal: &Proposal) -> anyhow::Result<()> {
+            match proposal {
+                Proposal::Update { balance, .. } if balance.ours < channel.balance().ours => {
+                    anyhow::bail!("payments are not allowed")
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    let payment = Amount::from_btc(0.5).expect("failed to create amount");
+
+    let (a_update, b_handled) = future::join(
+        a_channel.propose_update_balance(
+            &mut a_transport,
+            &a_wallet,
+            Balance {
+                ours: FUND + payment,
+                theirs: FUND - payment,
+            },
+            time_lock,
+        ),
+        b_channel.handle_incoming(&mut b_transport, &b_wallet, &NoPayments),
+    )
+    .await;
+
+    // Alice only learns that the proposal is not acceptable, not why
+    match a_update.unwrap_err() {
+        Error::ProposalRejected(ProposalRejected { reason }) => {
+            assert!(!reason.contains("payments are not allowed"))
+        }
+        error => panic!(
+            "Alice should learn that Bob rejected the proposal: {:?}",
+            error
+        ),
+    }
+    match b_handled.unwrap() {
+        HandledProposal::Rejected {
+            proposal: Proposal::Update { .. },
+            reason,
+        } => assert!(reason.to_string().contains("payments are not allowed")),
+        handled => panic!("Bob should reject the proposal: {:?}", handled),
+    }
+
+    assert_channel_balances(&a_channel, &b_channel, FUND, FUND);
+}
+
+#[tokio::test]
+async fn e2e_channel_creation_aborted_by_counterparty() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (a_wallet, b_wallet) = make_wallets(&bitcoind, FUND).await.unwrap();
+    let (mut a_transport, mut b_transport) = make_transports();
+    let time_lock = 1;
+
+    let balance = Balance {
+        ours: FUND,
+        theirs: FUND,
+    };
+
+    // Bob does not accept the fee rate proposed by Alice
+    let b_fee_rate_bounds = FeeRateBounds {
+        min: FeeRate::from_sat_per_vbyte(1),
+        max: FeeRate::from_sat_per_vbyte(FEE_RATE.as_sat_per_vbyte() - 1),
+    };
+
+    let (a_create, b_create) = future::join(
+        Channel::create(
+            &mut a_transport,
+            &a_wallet,
+            KeyManager::new_random().channel_keys(0),
+            balance,
+            time_lock,
+            FeeRateBounds::default(),
+            Timeouts::default(),
+        ),
+        Channel::create(
+            &mut b_transport,
+            &b_wallet,
+            KeyManager::new_random().channel_keys(0),
+            balance,
+            time_lock,
+            b_fee_rate_bounds,
+            Timeouts::default(),
+        ),
+    )
+    .await;
+
+    // Bob blames the fee rate proposed by Alice
+    assert!(matches!(b_create.unwrap_err(), Error::Protocol(_)));
+
+    match a_create.unwrap_err() {
+        Error::ProtocolAborted(ProtocolAborted { reason, .. }) => {
+            assert_eq!(reason, AbortReason::InvalidMessage)
+        }
+        error => panic!(
+            "Alice should learn that Bob aborted the protocol: {:?}",
+            error
+        ),
+    }
+}
+
+#[tokio::test]
+async fn e2e_channel_creation_times_out_if_counterparty_stops_responding() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (a_wallet, b_wallet) = make_wallets(&bitcoind, FUND).await.unwrap();
+    let (mut a_transport, mut b_transport) = make_transports();
+    let time_lock = 1;
+
+    let balance = Balance {
+        ours: FUND,
+        theirs: FUND,
+    };
+
+    // Bob only sends the first message of the protocol, after which Alice's
+    // funding input is added to the channel
+    let b_state = CreateState::new(
+        KeyManager::new_random().channel_keys(0),
+        balance,
+        time_lock,
+        b_wallet.new_address().await.unwrap(),
+        FEE_RATE,
+        FeeRateBounds::default(),
+        Timeouts::default(),
+    );
+    b_transport
+        .send_message(b_state.compose(&b_wallet).await.unwrap())
+        .await
+        .unwrap();
+
+    let a_timeouts = Timeouts {
+        create: Duration::from_secs(2),
+        ..Timeouts::default()
+    };
+    let a_create = Channel::create(
+        &mut a_transport,
+        &a_wallet,
+        KeyManager::new_random().channel_keys(0),
+        balance,
+        time_lock,
+        FeeRateBounds::default(),
+        a_timeouts,
+    )
+    .await;
+
+    assert!(matches!(a_create.unwrap_err(), Error::TimedOut(_)));
+
+    // Alice lets Bob know that she gave up, after her first two messages
+    let _ = b_transport.receive_message().await.unwrap();
+    let _ = b_transport.receive_message().await.unwrap();
+    match b_transport.receive_message().await.unwrap() {
+        Message::Abort(abort) => assert_eq!(abort.reason(), AbortReason::TimedOut),
+        message => panic!("Alice should abort the protocol: {:?}", message),
+    }
+}
+
+#[tokio::test]
+async fn e2e_channel_update_resumed_from_persisted_state() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (a_channel, mut b_channel, mut a_transport, mut b_transport, _, b_wallet, time_lock, _) =
+        create_channels(&bitcoind).await;
+
+    let payment = Amount::from_btc(0.5).expect("failed to create amount");
+    let a_balance = FUND - payment;
+    let b_balance = FUND + payment;
+
+    // Alice drives the update herself, restoring her state from storage
+    // before every step as if her process had crashed in between
+    let a_update = async {
+        let mut persisted = serde_json::to_string(&a_channel.start_update_balance(
+            Balance {
+                ours: a_balance,
+                theirs: b_balance,
+            },
+            time_lock,
+            FEE_RATE,
+        )?)?;
+
+        loop {
+            let state: UpdateState = serde_json::from_str(&persisted)?;
+
+            a_transport.send_message(state.compose()).await?;
+            let response = a_transport.receive_message().await?;
+
+            match state.interpret(response)? {
+                Transition::Next(state) => persisted = serde_json::to_string(&state)?,
+                Transition::Done(channel) => return Ok::<_, anyhow::Error>(channel),
+            }
+        }
+    };
+    let b_update = b_channel.update_balance(
+        &mut b_transport,
+        &b_wallet,
+        Balance {
+            ours: b_balance,
+            theirs: a_balance,
+        },
+        time_lock,
+    );
+
+    let (a_channel, _) = future::try_join(a_update, b_update.err_into())
+        .await
+        .expect("update failed");
+
+    assert_channel_balances(&a_channel, &b_channel, a_balance, b_balance);
+}
+
+#[tokio::test]
+async fn e2e_update_channels_multiplexed_over_one_connection() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (a_wallet, b_wallet) = make_wallets(&bitcoind, FUND).await.unwrap();
+    let time_lock = 1;
+
+    let ((a_sender, a_receiver), (b_sender, b_receiver)) = make_envelope_transports();
+    let (a_multiplexer, a_connection) = Multiplexer::new(a_sender, a_receiver);
+    let (b_multiplexer, b_connection) = Multiplexer::new(b_sender, b_receiver);
+    tokio::spawn(a_connection);
+    tokio::spawn(b_connection);
+
+    let a_keys = KeyManager::new_random();
+    let b_keys = KeyManager::new_random();
+    let fund = FUND / 2;
+    let balance = Balance {
+        ours: fund,
+        theirs: fund,
+    };
+
+    // The channels are created one after the other, so that the wallets do not
+    // select the same coins to fund both of them
+    let mut a_channels = Vec::new();
+    let mut b_channels = Vec::new();
+    for index in 0..2 {
+        let temporary_id = ChannelId::random();
+        let mut a_transport = a_multiplexer.open(temporary_id).unwrap();
+        let mut b_transport = b_multiplexer.open(temporary_id).unwrap();
+
+        let (a_channel, b_channel) = future::try_join(
+            Channel::create(
+                &mut a_transport,
+                &a_wallet,
+                a_keys.channel_keys(index),
+                balance,
+                time_lock,
+                FeeRateBounds::default(),
+                Timeouts::default(),
+            ),
+            Channel::create(
+                &mut b_transport,
+                &b_wallet,
+                b_keys.channel_keys(index),
+                balance,
+                time_lock,
+                FeeRateBounds::default(),
+                Timeouts::default(),
+            ),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(a_channel.id(), b_channel.id());
+        a_channels.push(a_channel);
+        b_channels.push(b_channel);
+    }
+    assert_ne!(a_channels[0].id(), a_channels[1].id());
+
+    // Both channels are updated concurrently, with a different payment each
+    let updates = a_channels
+        .iter_mut()
+        .zip(b_channels.iter_mut())
+        .zip(vec![0.1, 0.2])
+        .map(|((a_channel, b_channel), payment)| {
+            let payment = Amount::from_btc(payment).unwrap();
+            let mut a_transport = a_multiplexer.open(a_channel.id()).unwrap();
+            let mut b_transport = b_multiplexer.open(b_channel.id()).unwrap();
+            let a_wallet = &a_wallet;
+            let b_wallet = &b_wallet;
+
+            async move {
+                future::try_join(
+                    a_channel.update_balance(
+                        &mut a_transport,
+                        a_wallet,
+                        Balance {
+                            ours: fund - payment,
+                            theirs: fund + payment,
+                        },
+                        time_lock,
+                    ),
+                    b_channel.update_balance(
+                        &mut b_transport,
+                        b_wallet,
+                        Balance {
+                            ours: fund + payment,
+                            theirs: fund - payment,
+                        },
+                        time_lock,
+                    ),
+                )
+                .await
+            }
+        });
+    future::try_join_all(updates).await.unwrap();
+
+    assert_channel_balances(
+        &a_channels[0],
+        &b_channels[0],
+        fund - Amount::from_btc(0.1).unwrap(),
+        fund + Amount::from_btc(0.1).unwrap(),
+    );
+    assert_channel_balances(
+        &a_channels[1],
+        &b_channels[1],
+        fund - Amount::from_btc(0.2).unwrap(),
+        fund + Amount::from_btc(0.2).unwrap(),
+    );
+}
+
 #[tokio::test]
 async fn e2e_channel_collaborative_close() {
     let cli = init_cli();
@@ -73,11 +483,11 @@ async fn e2e_channel_collaborative_close() {
     let a_balance_after_close = a_wallet.balance().await.unwrap();
     let b_balance_after_close = b_wallet.balance().await.unwrap();
 
-    // We pay half a `TX_FEE` per output in fees for each transaction after
-    // the `FundingTransaction`. Collaboratively closing the channel requires
-    // publishing a single `CloseTransaction`, so each party pays
-    // one half `TX_FEE`, which is deducted from their output.
-    let fee_deduction_per_output = Amount::from_sat(TX_FEE) / 2;
+    // Fees are split evenly between the outputs of each transaction after the
+    // `FundingTransaction`. Collaboratively closing the channel requires
+    // publishing a single `CloseTransaction`, so each party pays half of its
+    // fee, which is deducted from their output.
+    let fee_deduction_per_output = FEE_RATE.fee(CLOSE_TRANSACTION_WEIGHT) / 2;
 
     // The balance after closing channel should equal balance after opening plus the
     // (refunded) fund amount minus tx fee.
@@ -102,11 +512,11 @@ async fn e2e_force_close_channel() {
     let a_balance_after_close = a_wallet.balance().await.unwrap();
     let b_balance_after_close = b_wallet.balance().await.unwrap();
 
-    // We pay half a `TX_FEE` per output in fees for each transaction after
-    // the `FundingTransaction`. Force closing the channel requires publishing
-    // both the `CommitTransaction` and the `SplitTransaction`, so each party pays
-    // one `TX_FEE`, which is deducted from their output.
-    let fee_deduction_per_output = Amount::from_sat(TX_FEE);
+    // Fees are split evenly between the outputs of each transaction after the
+    // `FundingTransaction`. Force closing the channel requires publishing both
+    // the `CommitTransaction` and the `SplitTransaction`, so each party pays half
+    // of their fees and anchor outputs, which is deducted from their output.
+    let fee_deduction_per_output = fee_deduction_per_split_output(2);
 
     // The balance after closing channel should equal balance after opening plus the
     // (refunded) fund amount minus tx fee.
@@ -144,6 +554,8 @@ async fn e2e_force_close_after_updates() {
         &mut b_channel,
         &mut a_transport,
         &mut b_transport,
+        &a_wallet,
+        &b_wallet,
         a_balance,
         b_balance,
         time_lock,
@@ -155,7 +567,7 @@ async fn e2e_force_close_after_updates() {
     let a_balance_after_close = a_wallet.balance().await.unwrap();
     let b_balance_after_close = b_wallet.balance().await.unwrap();
 
-    let fee_deduction_per_output = Amount::from_sat(TX_FEE);
+    let fee_deduction_per_output = fee_deduction_per_split_output(2);
 
     assert_eq!(
         a_balance_after_close,
@@ -169,6 +581,92 @@ async fn e2e_force_close_after_updates() {
     );
 }
 
+#[tokio::test]
+async fn e2e_recover_channel_from_static_backup() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (
+        mut a_channel,
+        mut b_channel,
+        mut a_transport,
+        mut b_transport,
+        a_wallet,
+        b_wallet,
+        time_lock,
+        _,
+    ) = create_channels(&bitcoind).await;
+
+    let a_backup = a_channel.static_backup();
+
+    let a_balance_after_open = a_wallet.balance().await.unwrap();
+
+    // Alice pays Bob 0.1 BTC
+    let payment = Amount::from_btc(0.1).unwrap();
+    update_balances(
+        &mut a_channel,
+        &mut b_channel,
+        &mut a_transport,
+        &mut b_transport,
+        &a_wallet,
+        &b_wallet,
+        FUND - payment,
+        FUND + payment,
+        time_lock,
+    )
+    .await;
+
+    // Alice loses her channel state and recovers her funds with the help of Bob
+    drop(a_channel);
+
+    let a_recover = Channel::recover(&mut a_transport, a_backup, Duration::from_secs(60));
+    let b_assist = b_channel.assist_recovery(&mut b_transport, &b_wallet);
+
+    let (recovery, _) = future::try_join(a_recover, b_assist).await.unwrap();
+
+    let fee_deduction_per_output = fee_deduction_per_split_output(2);
+
+    assert_eq!(recovery.balance, Balance {
+        ours: FUND - payment - fee_deduction_per_output,
+        theirs: FUND + payment - fee_deduction_per_output,
+    });
+
+    let a_balance_after_recovery = a_wallet.balance().await.unwrap();
+
+    assert_eq!(
+        a_balance_after_recovery,
+        a_balance_after_open + recovery.balance.ours,
+        "Alice should get her balance back after Bob force closes the channel"
+    );
+}
+
+#[tokio::test]
+async fn e2e_bump_force_close() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (a_channel, _, _, _, a_wallet, ..) = create_channels(&bitcoind).await;
+
+    a_channel.force_close(&a_wallet).await.unwrap();
+
+    let a_balance_after_close = a_wallet.balance().await.unwrap();
+
+    let bump_fee_rate = FeeRate::from_sat_per_vbyte(FEE_RATE.as_sat_per_vbyte() * 2);
+    a_channel
+        .bump_force_close(&a_wallet, ForceCloseTransaction::Commit, bump_fee_rate)
+        .await
+        .unwrap();
+    a_channel
+        .bump_force_close(&a_wallet, ForceCloseTransaction::Split, bump_fee_rate)
+        .await
+        .unwrap();
+
+    let a_balance_after_bump = a_wallet.balance().await.unwrap();
+
+    assert!(
+        a_balance_after_bump < a_balance_after_close,
+        "Alice should pay for bumping the fees of the force close transactions"
+    );
+}
+
 #[tokio::test]
 async fn e2e_splice_in() {
     let cli = init_cli();
@@ -195,8 +693,8 @@ async fn e2e_splice_in() {
     let a_splice_in = Amount::from_btc(0.5).unwrap();
     let b_splice_in = Amount::from_btc(0.1).unwrap();
 
-    let a_splice = a_channel.splice(&mut a_transport, &a_wallet, Splice::In(a_splice_in));
-    let b_splice = b_channel.splice(&mut b_transport, &b_wallet, Splice::In(b_splice_in));
+    let a_splice = a_channel.splice(&mut a_transport, &a_wallet, Splice::splice_in(a_splice_in));
+    let b_splice = b_channel.splice(&mut b_transport, &b_wallet, Splice::splice_in(b_splice_in));
     let (a_channel, b_channel) = future::try_join(a_splice, b_splice).await.unwrap();
 
     // Assert the channel balances are as expected.
@@ -236,16 +734,17 @@ async fn e2e_splice_out() {
     //  Bob splices out (withdraws) 0.2 BTC
     let b_splice_address = b_wallet.0.new_address().await.unwrap();
     let b_splice_out = Amount::from_btc(0.2).unwrap();
-    let b_splice = Splice::Out(TxOut {
+    let b_splice = Splice::splice_out(TxOut {
         script_pubkey: b_splice_address.script_pubkey(),
         value: b_splice_out.as_sat(),
     });
 
-    let a_splice = a_channel.splice(&mut a_transport, &a_wallet, Splice::None);
+    let a_splice = a_channel.splice(&mut a_transport, &a_wallet, Splice::default());
     let b_splice = b_channel.splice(&mut b_transport, &b_wallet, b_splice);
     let (a_channel, b_channel) = future::try_join(a_splice, b_splice).await.unwrap();
 
-    let fee_deduction_per_output = Amount::from_sat(TX_FEE);
+    // Bob pays for the splice transaction since he is the only one splicing out
+    let fee_deduction_per_output = FEE_RATE.fee(splice_transaction_weight(1));
 
     // Assert the channel balances are as expected.
     let a_want = FUND;
@@ -261,6 +760,150 @@ async fn e2e_splice_out() {
     assert_that!(b_got).is_equal_to(b_want);
 }
 
+#[tokio::test]
+async fn e2e_splice_in_and_out() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (
+        a_channel,
+        b_channel,
+        mut a_transport,
+        mut b_transport,
+        a_wallet,
+        b_wallet,
+        _time_lock,
+        tx_fee,
+    ) = create_channels(&bitcoind).await;
+
+    let a_wallet_balance_before_splice = a_wallet.balance().await.unwrap();
+    let b_wallet_balance_before_splice = b_wallet.balance().await.unwrap();
+
+    //  Alice splices in 0.3 BTC and splices out 0.1 BTC.
+    //  Bob splices in 0.2 BTC and splices out 0.1 BTC to two outputs.
+    let a_splice_in = Amount::from_btc(0.3).unwrap();
+    let a_splice_out = Amount::from_btc(0.1).unwrap();
+    let a_splice = Splice {
+        amount_in: a_splice_in,
+        outs: vec![TxOut {
+            script_pubkey: a_wallet.0.new_address().await.unwrap().script_pubkey(),
+            value: a_splice_out.as_sat(),
+        }],
+    };
+
+    let b_splice_in = Amount::from_btc(0.2).unwrap();
+    let b_splice_out = Amount::from_btc(0.05).unwrap();
+    let b_splice = Splice {
+        amount_in: b_splice_in,
+        outs: vec![
+            TxOut {
+                script_pubkey: b_wallet.0.new_address().await.unwrap().script_pubkey(),
+                value: b_splice_out.as_sat(),
+            },
+            TxOut {
+                script_pubkey: b_wallet.0.new_address().await.unwrap().script_pubkey(),
+                value: b_splice_out.as_sat(),
+            },
+        ],
+    };
+
+    let a_splice = a_channel.splice(&mut a_transport, &a_wallet, a_splice);
+    let b_splice = b_channel.splice(&mut b_transport, &b_wallet, b_splice);
+    let (a_channel, b_channel) = future::try_join(a_splice, b_splice).await.unwrap();
+
+    // Each party pays for the shared input and output, and for their own
+    // splice-out outputs
+    let a_fee = FEE_RATE.fee(splice_transaction_weight(1));
+    let b_fee = FEE_RATE.fee(splice_transaction_weight(2));
+
+    // Assert the channel balances are as expected.
+    let a_want = FUND + a_splice_in - a_splice_out - a_fee;
+    let b_want = FUND + b_splice_in - b_splice_out - b_splice_out - b_fee;
+    assert_channel_balances(&a_channel, &b_channel, a_want, b_want);
+
+    // Assert the wallet balances are as expected.
+    let a_want = a_wallet_balance_before_splice - a_splice_in - tx_fee + a_splice_out;
+    let b_want =
+        b_wallet_balance_before_splice - b_splice_in - tx_fee + b_splice_out + b_splice_out;
+    let a_got = a_wallet.balance().await.unwrap();
+    let b_got = b_wallet.balance().await.unwrap();
+    assert_that!(a_got).is_equal_to(a_want);
+    assert_that!(b_got).is_equal_to(b_want);
+}
+
+#[tokio::test]
+async fn e2e_splice_with_ptlc() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (
+        mut a_channel,
+        mut b_channel,
+        mut a_transport,
+        mut b_transport,
+        a_wallet,
+        b_wallet,
+        _time_lock,
+        _tx_fee,
+    ) = create_channels(&bitcoind).await;
+
+    let ptlc_amount = Amount::from_btc(0.25).unwrap();
+    let expiries = generate_expiries(&a_wallet).await.unwrap();
+    let secret = PtlcSecret::new_random();
+
+    // Bob offers a PTLC to Alice
+    future::try_join(
+        a_channel.add_ptlc(
+            &mut a_transport,
+            &a_wallet,
+            PtlcDirection::Received,
+            ptlc_amount,
+            secret.point(),
+            expiries.ptlc_absolute,
+        ),
+        b_channel.add_ptlc(
+            &mut b_transport,
+            &b_wallet,
+            PtlcDirection::Offered,
+            ptlc_amount,
+            secret.point(),
+            expiries.ptlc_absolute,
+        ),
+    )
+    .await
+    .unwrap();
+
+    // Alice splices in 0.5 BTC while the PTLC is in flight
+    let a_splice_in = Amount::from_btc(0.5).unwrap();
+    let a_splice = a_channel.splice(&mut a_transport, &a_wallet, Splice::splice_in(a_splice_in));
+    let b_splice = b_channel.splice(&mut b_transport, &b_wallet, Splice::default());
+    let (mut a_channel, mut b_channel) = future::try_join(a_splice, b_splice).await.unwrap();
+
+    assert_that!(a_channel.ptlcs()).is_equal_to(vec![secret.point()]);
+    assert_that!(b_channel.ptlcs()).is_equal_to(vec![secret.point()]);
+    assert_channel_balances(
+        &a_channel,
+        &b_channel,
+        FUND + a_splice_in,
+        FUND - ptlc_amount,
+    );
+
+    // The PTLC can still be settled, paying to Alice
+    future::try_join(
+        a_channel.settle_ptlc(&mut a_transport, &a_wallet, secret.clone()),
+        b_channel.settle_ptlc(&mut b_transport, &b_wallet, secret),
+    )
+    .await
+    .unwrap();
+
+    assert_that!(a_channel.ptlcs()).is_empty();
+    assert_that!(b_channel.ptlcs()).is_empty();
+    assert_channel_balances(
+        &a_channel,
+        &b_channel,
+        FUND + a_splice_in + ptlc_amount,
+        FUND - ptlc_amount,
+    );
+}
+
 // TODO: Fund alpha ledger (Bitcoin on-chain) and use the secret to redeem it as
 // Bob
 #[tokio::test]
@@ -297,6 +940,7 @@ async fn e2e_atomic_swap_happy() {
         expiries.alpha_absolute,
         expiries.split_transaction_relative,
         expiries.ptlc_absolute,
+        SwapConfig::default(),
     );
 
     let skip_final_update = false;
@@ -309,6 +953,7 @@ async fn e2e_atomic_swap_happy() {
         expiries.alpha_absolute,
         expiries.split_transaction_relative,
         expiries.ptlc_absolute,
+        SwapConfig::default(),
         skip_final_update,
     );
 
@@ -321,7 +966,7 @@ async fn e2e_atomic_swap_happy() {
     let a_balance_after_close = a_wallet.balance().await.unwrap();
     let b_balance_after_close = b_wallet.balance().await.unwrap();
 
-    let fee_deduction_per_output = Amount::from_sat(TX_FEE);
+    let fee_deduction_per_output = fee_deduction_per_split_output(2);
 
     assert_eq!(
         a_balance_after_close,
@@ -363,6 +1008,12 @@ async fn e2e_atomic_swap_unresponsive_bob_after_secret_reveal() {
 
     let expiries = generate_expiries(&a_wallet).await.unwrap();
 
+    // Alice does not wait until the PTLC is close to expiry for Bob to cooperate
+    let a_config = SwapConfig {
+        settlement_timeout: Some(Duration::from_secs(10)),
+        ..Default::default()
+    };
+
     let swap_beta_ptlc_alice = a_channel.swap_beta_ptlc_alice(
         &mut a_transport,
         &a_wallet,
@@ -371,6 +1022,7 @@ async fn e2e_atomic_swap_unresponsive_bob_after_secret_reveal() {
         expiries.alpha_absolute,
         expiries.split_transaction_relative,
         expiries.ptlc_absolute,
+        a_config,
     );
 
     let skip_final_update = true;
@@ -383,6 +1035,7 @@ async fn e2e_atomic_swap_unresponsive_bob_after_secret_reveal() {
         expiries.alpha_absolute,
         expiries.split_transaction_relative,
         expiries.ptlc_absolute,
+        SwapConfig::default(),
         skip_final_update,
     );
 
@@ -402,7 +1055,7 @@ async fn e2e_atomic_swap_unresponsive_bob_after_secret_reveal() {
 
     // The fees are distributed evenly between the outputs.
     let fee_deduction_per_split_output =
-        Amount::from_sat(TX_FEE + TX_FEE) / n_outputs_split_transaction;
+        fee_deduction_per_split_output(n_outputs_split_transaction);
 
     // Alice will claim her balance output and a PTLC output.
     let split_transaction_fee_alice = fee_deduction_per_split_output * 2;
@@ -410,9 +1063,9 @@ async fn e2e_atomic_swap_unresponsive_bob_after_secret_reveal() {
     // Bob will just claim his balance output.
     let split_transaction_fee_bob = fee_deduction_per_split_output;
 
-    // Additionally, Alice pays an extra `TX_FEE` to be able to redeem the
-    // PTLC output.
-    let fee_deduction_for_ptlc_redeem = Amount::from_sat(TX_FEE);
+    // Additionally, Alice pays the fee of the `RedeemTransaction` to be able to
+    // redeem the PTLC output.
+    let fee_deduction_for_ptlc_redeem = FEE_RATE.fee(PTLC_TRANSACTION_WEIGHT);
 
     assert_eq!(
         a_balance_after_close,
@@ -426,3 +1079,87 @@ async fn e2e_atomic_swap_unresponsive_bob_after_secret_reveal() {
         "Balance after closing channel should equal balance after opening minus PTLC amount, minus transaction fees"
     );
 }
+
+#[tokio::test]
+async fn e2e_add_settle_and_fail_ptlcs() {
+    let cli = init_cli();
+    let bitcoind = init_bitcoind(&cli).await;
+    let (
+        mut a_channel,
+        mut b_channel,
+        mut a_transport,
+        mut b_transport,
+        a_wallet,
+        b_wallet,
+        _time_lock,
+        _tx_fee,
+    ) = create_channels(&bitcoind).await;
+
+    let ptlc_amount = Amount::from_btc(0.25).unwrap();
+    let expiries = generate_expiries(&a_wallet).await.unwrap();
+
+    let settled_secret = PtlcSecret::new_random();
+    let failed_point = PtlcSecret::new_random().point();
+
+    // Bob offers two PTLCs to Alice
+    for point in vec![settled_secret.point(), failed_point.clone()] {
+        future::try_join(
+            a_channel.add_ptlc(
+                &mut a_transport,
+                &a_wallet,
+                PtlcDirection::Received,
+                ptlc_amount,
+                point.clone(),
+                expiries.ptlc_absolute,
+            ),
+            b_channel.add_ptlc(
+                &mut b_transport,
+                &b_wallet,
+                PtlcDirection::Offered,
+                ptlc_amount,
+                point,
+                expiries.ptlc_absolute,
+            ),
+        )
+        .await
+        .unwrap();
+    }
+
+    assert_that!(a_channel.ptlcs()).has_length(2);
+    assert_that!(b_channel.ptlcs()).has_length(2);
+    assert_channel_balances(&a_channel, &b_channel, FUND, FUND - ptlc_amount * 2);
+
+    // The first PTLC is settled, paying to Alice
+    future::try_join(
+        a_channel.settle_ptlc(&mut a_transport, &a_wallet, settled_secret.clone()),
+        b_channel.settle_ptlc(&mut b_transport, &b_wallet, settled_secret),
+    )
+    .await
+    .unwrap();
+
+    assert_that!(a_channel.ptlcs()).is_equal_to(vec![failed_point.clone()]);
+    assert_that!(b_channel.ptlcs()).is_equal_to(vec![failed_point.clone()]);
+    assert_channel_balances(
+        &a_channel,
+        &b_channel,
+        FUND + ptlc_amount,
+        FUND - ptlc_amount * 2,
+    );
+
+    // The second PTLC fails, going back to Bob
+    future::try_join(
+        a_channel.fail_ptlc(&mut a_transport, &a_wallet, failed_point.clone()),
+        b_channel.fail_ptlc(&mut b_transport, &b_wallet, failed_point),
+    )
+    .await
+    .unwrap();
+
+    assert_that!(a_channel.ptlcs()).is_empty();
+    assert_that!(b_channel.ptlcs()).is_empty();
+    assert_channel_balances(
+        &a_channel,
+        &b_channel,
+        FUND + ptlc_amount,
+        FUND - ptlc_amount,
+    );
+}
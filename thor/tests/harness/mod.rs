@@ -1,7 +1,8 @@
 //! This module is a copy of the code over in `thor/src/channel/test/harness*`
 //! with the following changes:
 //! - Use `mod.rs` instead of `harness.rs` (required by the build system)
-//! - Add some additional code: `SwapExpiries` and `generate_expiries`.
+//! - Add some additional code: `SwapExpiries`, `generate_expiries` and
+//!   `make_envelope_transports`.
 //!
 //! The reason we duplicate the test harness is because we want the integration
 //! tests to remain outside of this crate in order to enforce usage of the
@@ -14,8 +15,11 @@
 //
 
 use thor::{
-    channel::{ReceiveMessage, SendMessage},
-    Balance, Channel, MedianTime, Message, PtlcPoint,
+    channel::{ReceiveMessage, SendMessage, Timeouts},
+    fee::{split_transaction_weight, ANCHOR_OUTPUT_VALUE, COMMIT_TRANSACTION_WEIGHT},
+    multiplex::{Envelope, ReceiveEnvelope, SendEnvelope},
+    swap::SwapConfig,
+    Balance, Channel, FeeRateBounds, KeyManager, MedianTime, Message, PtlcPoint,
 };
 
 use anyhow::{anyhow, Context, Result};
@@ -35,7 +39,7 @@ use testcontainers::clients::Cli;
 
 mod wallet;
 
-pub use wallet::{make_wallets, Wallet};
+pub use wallet::{make_wallets, Wallet, FEE_RATE};
 
 // Alice and Bob both fund the channel with this much.
 pub const FUND: Amount = Amount::ONE_BTC;
@@ -61,8 +65,24 @@ pub async fn create_channels(
 
     let initial_balance = a_wallet.balance().await.unwrap();
 
-    let a_create = Channel::create(&mut a_transport, &a_wallet, a_balance, time_lock);
-    let b_create = Channel::create(&mut b_transport, &b_wallet, b_balance, time_lock);
+    let a_create = Channel::create(
+        &mut a_transport,
+        &a_wallet,
+        KeyManager::new_random().channel_keys(0),
+        a_balance,
+        time_lock,
+        FeeRateBounds::default(),
+        Timeouts::default(),
+    );
+    let b_create = Channel::create(
+        &mut b_transport,
+        &b_wallet,
+        KeyManager::new_random().channel_keys(0),
+        b_balance,
+        time_lock,
+        FeeRateBounds::default(),
+        Timeouts::default(),
+    );
 
     let (a_channel, b_channel) = future::try_join(a_create, b_create)
         .await
@@ -111,17 +131,21 @@ pub async fn init_bitcoind(tc_client: &Cli) -> Bitcoind<'_> {
     bitcoind
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn update_balances(
     a_channel: &mut Channel,
     b_channel: &mut Channel,
     a_transport: &mut Transport,
     b_transport: &mut Transport,
+    a_wallet: &Wallet,
+    b_wallet: &Wallet,
     a_balance: Amount,
     b_balance: Amount,
     time_lock: u32,
 ) {
     let a_update = a_channel.update_balance(
         a_transport,
+        a_wallet,
         Balance {
             ours: a_balance,
             theirs: b_balance,
@@ -130,6 +154,7 @@ pub async fn update_balances(
     );
     let b_update = b_channel.update_balance(
         b_transport,
+        b_wallet,
         Balance {
             ours: b_balance,
             theirs: a_balance,
@@ -142,6 +167,16 @@ pub async fn update_balances(
         .expect("update failed");
 }
 
+/// Amount deducted from each output of a `SplitTransaction` with `n_outputs`
+/// outputs, to pay for both the `CommitTransaction` and the `SplitTransaction`
+/// fees and anchor outputs.
+pub fn fee_deduction_per_split_output(n_outputs: u64) -> Amount {
+    let anchors = Amount::from_sat(ANCHOR_OUTPUT_VALUE) * 2;
+
+    (FEE_RATE.fee(COMMIT_TRANSACTION_WEIGHT) + anchors) / n_outputs
+        + (FEE_RATE.fee(split_transaction_weight(n_outputs as usize)) + anchors) / n_outputs
+}
+
 pub fn generate_balances(fund_amount: Amount) -> (Balance, Balance) {
     let a_balance = Balance {
         ours: fund_amount,
@@ -194,8 +229,9 @@ pub async fn swap_beta_ptlc_bob(
     alpha_absolute_expiry: u32,
     TX_s_time_lock: u32,
     ptlc_redeem_time_lock: u32,
+    config: SwapConfig,
     skip_update: bool,
-) -> Result<()> {
+) -> Result<(), thor::Error> {
     let mut swap_beta_ptlc_bob = channel.swap_beta_ptlc_bob(
         transport,
         wallet,
@@ -204,6 +240,7 @@ pub async fn swap_beta_ptlc_bob(
         alpha_absolute_expiry,
         TX_s_time_lock,
         ptlc_redeem_time_lock,
+        config,
     );
 
     match swap_beta_ptlc_bob.async_resume().await {
@@ -243,19 +280,34 @@ pub fn make_transports() -> (Transport, Transport) {
     (a_transport, b_transport)
 }
 
+/// Make the halves of a connection over which `Envelope`s are sent, for
+/// Alice and Bob respectively.
+pub fn make_envelope_transports() -> (
+    (EnvelopeSender, EnvelopeReceiver),
+    (EnvelopeSender, EnvelopeReceiver),
+) {
+    let (a_sender, b_receiver) = mpsc::channel(5);
+    let (b_sender, a_receiver) = mpsc::channel(5);
+
+    (
+        (EnvelopeSender(a_sender), EnvelopeReceiver(a_receiver)),
+        (EnvelopeSender(b_sender), EnvelopeReceiver(b_receiver)),
+    )
+}
+
 #[derive(Debug)]
 pub struct Transport {
-    // Using String instead of `Message` implicitly tests the `use-serde` feature.
-    sender: Sender<String>,
-    receiver: Receiver<String>,
+    // Using bytes instead of `Message` implicitly tests the wire format.
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
 }
 
 #[async_trait]
 impl SendMessage for Transport {
     async fn send_message(&mut self, message: Message) -> Result<()> {
-        let str = serde_json::to_string(&message).context("failed to encode message")?;
+        let bytes = message.encode().context("failed to encode message")?;
         self.sender
-            .send(str)
+            .send(bytes)
             .await
             .map_err(|_| anyhow!("failed to send message"))
     }
@@ -264,12 +316,42 @@ impl SendMessage for Transport {
 #[async_trait]
 impl ReceiveMessage for Transport {
     async fn receive_message(&mut self) -> Result<Message> {
-        let str = self
+        let bytes = self
             .receiver
             .next()
             .await
             .ok_or_else(|| anyhow!("failed to receive message"))?;
-        let message = serde_json::from_str(&str).context("failed to decode message")?;
+        let message = Message::decode(&bytes).context("failed to decode message")?;
         Ok(message)
     }
 }
+
+#[derive(Debug)]
+pub struct EnvelopeSender(Sender<String>);
+
+#[derive(Debug)]
+pub struct EnvelopeReceiver(Receiver<String>);
+
+#[async_trait]
+impl SendEnvelope for EnvelopeSender {
+    async fn send_envelope(&mut self, envelope: Envelope) -> Result<()> {
+        let str = serde_json::to_string(&envelope).context("failed to encode envelope")?;
+        self.0
+            .send(str)
+            .await
+            .map_err(|_| anyhow!("failed to send envelope"))
+    }
+}
+
+#[async_trait]
+impl ReceiveEnvelope for EnvelopeReceiver {
+    async fn receive_envelope(&mut self) -> Result<Envelope> {
+        let str = self
+            .0
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("failed to receive envelope"))?;
+        let envelope = serde_json::from_str(&str).context("failed to decode envelope")?;
+        Ok(envelope)
+    }
+}